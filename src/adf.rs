@@ -0,0 +1,162 @@
+//
+// Speedball 2 Sound player
+//
+// adf.rs: Read files out of Amiga disk images (.adf), so that banks
+// can be found without extracting them by hand first. Handles both
+// the original (OFS) and fast (FFS) file systems.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use crate::sound_player::long;
+
+const BLOCK_SIZE: usize = 512;
+
+// Block types and secondary types.
+const T_HEADER: u32 = 2;
+const T_LIST: u32 = 16;
+const ST_ROOT: u32 = 1;
+const ST_USERDIR: u32 = 2;
+const ST_FILE: u32 = 0xffff_fffd;
+
+// Offsets within header blocks.
+const HIGH_SEQ: usize = 0x08;
+const TABLE: usize = 0x18;
+const TABLE_SIZE: usize = 72;
+const BYTE_SIZE: usize = 0x144;
+const NAME: usize = 0x1b0;
+const HASH_CHAIN: usize = 0x1f0;
+const EXTENSION: usize = 0x1f8;
+const SEC_TYPE: usize = 0x1fc;
+
+// OFS data blocks have a header in front of the data.
+const OFS_DATA_SIZE: usize = 0x0c;
+const OFS_HEADER_LEN: usize = 0x18;
+
+// Guard against loops in corrupt images.
+const MAX_BLOCKS: usize = 1 << 16;
+
+pub struct File {
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+struct Disk<'a> {
+    image: &'a [u8],
+    fast: bool,
+}
+
+impl<'a> Disk<'a> {
+    fn block(&self, num: u32) -> Result<&'a [u8], String> {
+        let start = num as usize * BLOCK_SIZE;
+        self.image
+            .get(start..start + BLOCK_SIZE)
+            .ok_or_else(|| format!("Block {} is outside the image", num))
+    }
+
+    fn name(block: &[u8]) -> String {
+        let len = (block[NAME] as usize).min(30);
+        block[NAME + 1..][..len]
+            .iter()
+            .map(|&c| c as char)
+            .collect()
+    }
+
+    // Data block pointers are stored backwards from the end of the
+    // table.
+    fn table_entries(block: &[u8]) -> impl Iterator<Item = u32> + '_ {
+        let count = (long(block, HIGH_SEQ) as usize).min(TABLE_SIZE);
+        (0..count).map(move |idx| long(block, TABLE + (TABLE_SIZE - 1 - idx) * 4))
+    }
+
+    fn read_file(&self, header: &[u8]) -> Result<Vec<u8>, String> {
+        let size = long(header, BYTE_SIZE) as usize;
+        // The size is only a guess until the blocks are read, and no
+        // file can be bigger than the image.
+        let mut data = Vec::with_capacity(size.min(self.image.len()));
+
+        let mut table_block = header;
+        for _ in 0..MAX_BLOCKS {
+            for num in Disk::table_entries(table_block) {
+                let block = self.block(num)?;
+                if self.fast {
+                    data.extend_from_slice(block);
+                } else {
+                    let len =
+                        (long(block, OFS_DATA_SIZE) as usize).min(BLOCK_SIZE - OFS_HEADER_LEN);
+                    data.extend_from_slice(&block[OFS_HEADER_LEN..][..len]);
+                }
+            }
+            match long(table_block, EXTENSION) {
+                0 => break,
+                next => {
+                    table_block = self.block(next)?;
+                    if long(table_block, 0) != T_LIST {
+                        return Err(format!("Block {} isn't a file extension block", next));
+                    }
+                }
+            }
+        }
+
+        if data.len() < size {
+            return Err(format!(
+                "File is 0x{:x} bytes, but only found 0x{:x}",
+                size,
+                data.len()
+            ));
+        }
+        data.truncate(size);
+        Ok(data)
+    }
+
+    // Walk a directory's hash table, and all the chains hanging off
+    // it.
+    fn read_dir(&self, dir: &[u8], prefix: &str, files: &mut Vec<File>) -> Result<(), String> {
+        for idx in 0..TABLE_SIZE {
+            let mut num = long(dir, TABLE + idx * 4);
+            let mut count = 0;
+            while num != 0 && count < MAX_BLOCKS {
+                let block = self.block(num)?;
+                if long(block, 0) != T_HEADER {
+                    return Err(format!("Block {} isn't a header block", num));
+                }
+                let path = format!("{}{}", prefix, Disk::name(block));
+                match long(block, SEC_TYPE) {
+                    ST_USERDIR => self.read_dir(block, &format!("{}/", path), files)?,
+                    ST_FILE => files.push(File {
+                        data: self.read_file(block)?,
+                        path,
+                    }),
+                    _ => (),
+                }
+                num = long(block, HASH_CHAIN);
+                count += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Read all the files from an AmigaDOS disk image. Many games use
+// their own disk formats, in which case this fails, and the best we
+// can do is scan the raw image.
+pub fn files(image: &[u8]) -> Result<Vec<File>, String> {
+    if image.len() < BLOCK_SIZE || &image[..3] != b"DOS" {
+        return Err("Not an AmigaDOS disk".to_string());
+    }
+    let disk = Disk {
+        image,
+        fast: image[3] & 1 != 0,
+    };
+
+    // The root block is in the middle of the disk.
+    let root_num = (image.len() / BLOCK_SIZE / 2) as u32;
+    let root = disk.block(root_num)?;
+    if long(root, 0) != T_HEADER || long(root, SEC_TYPE) != ST_ROOT {
+        return Err(format!("Block {} isn't a root block", root_num));
+    }
+
+    let mut files = Vec::new();
+    disk.read_dir(root, "", &mut files)?;
+    Ok(files)
+}
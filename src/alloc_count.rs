@@ -0,0 +1,65 @@
+//
+// Speedball 2 Sound player
+//
+// alloc_count.rs: Count heap allocations made by each thread, in
+// debug builds, so that the audio callback can check it never makes
+// any. Allocating in the callback can block for an unbounded time,
+// which glitches the output.
+//
+// Release builds use the system allocator directly, and always
+// report zero.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(debug_assertions)]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local! {
+        pub static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    pub struct Counting;
+
+    fn count() {
+        // May fail while the thread is being torn down, when we
+        // don't care.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            count();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            count();
+            System.realloc(ptr, layout, new_size)
+        }
+
+        // Freeing doesn't count: dropping the last reference to
+        // something, e.g. a replaced bank, is hard to avoid.
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: Counting = Counting;
+}
+
+// Allocations made by this thread so far.
+pub fn allocations() -> usize {
+    #[cfg(debug_assertions)]
+    return counting::ALLOCATIONS.with(|count| count.get());
+    #[cfg(not(debug_assertions))]
+    0
+}
@@ -0,0 +1,169 @@
+//
+// Speedball 2 Sound player
+//
+// annotations.rs: Human names for a bank's contents, loaded from a
+// JSON sidecar file, since "Sequence 05" isn't very memorable. The
+// file looks like:
+//
+//   {
+//     "instruments": {"0x12": "crowd roar"},
+//     "sequences": {"0x05": "title theme"},
+//     "sounds": {"0x2c": "title music"},
+//     "pitches": {"0x12": "C2"},
+//     "quirks": {"preset": "speedball2-ntsc"}
+//   }
+//
+// Indices may be hex or decimal, and every section is optional.
+// "pitches" gives the notes instruments are auditioned at, named as in
+// listings, for those the default doesn't suit. "quirks" gives the
+// driver quirks profile to play the bank with (see quirks.rs).
+//
+// The browser sets the pitches, and saves them back to the file,
+// rewriting it in this form.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+#[cfg(feature = "gui")]
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "gui")]
+use crate::disasm;
+use crate::json::{self, Value};
+use crate::quirks::Quirks;
+
+#[derive(Clone, Debug, Default)]
+pub struct Annotations {
+    pub instruments: HashMap<usize, String>,
+    pub sequences: HashMap<usize, String>,
+    pub sounds: HashMap<usize, String>,
+    // Notes to audition instruments at, counting C0 as 0.
+    #[cfg(feature = "gui")]
+    pub pitches: HashMap<usize, usize>,
+    pub quirks: Option<Quirks>,
+    // Where they were loaded from, or last saved to.
+    #[cfg(feature = "gui")]
+    pub path: Option<PathBuf>,
+}
+
+// A section of entries by index, each read with `parse`, which says
+// what it expected if it can't.
+fn entries<T>(
+    doc: &Value,
+    section: &str,
+    parse: impl Fn(&Value) -> Result<T, &'static str>,
+) -> Result<HashMap<usize, T>, String> {
+    let fields = match doc.get(section) {
+        Some(Value::Object(fields)) => fields,
+        Some(_) => return Err(format!("'{}' should be an object", section)),
+        None => return Ok(HashMap::new()),
+    };
+    fields
+        .iter()
+        .map(|(key, value)| {
+            let idx = Value::String(key.clone())
+                .as_usize()
+                .ok_or_else(|| format!("Bad index '{}' in '{}'", key, section))?;
+            let entry = parse(value).map_err(|expected| {
+                format!("Entry for '{}' in '{}' isn't {}", key, section, expected)
+            })?;
+            Ok((idx, entry))
+        })
+        .collect()
+}
+
+fn name(value: &Value) -> Result<String, &'static str> {
+    value.as_str().map(str::to_string).ok_or("a string")
+}
+
+#[cfg(feature = "gui")]
+fn pitch(value: &Value) -> Result<usize, &'static str> {
+    value
+        .as_str()
+        .and_then(disasm::parse_note)
+        .ok_or("a note, like \"C#3\"")
+}
+
+// The other way, sorted by index, for stable files.
+#[cfg(feature = "gui")]
+fn section<T>(entries: &HashMap<usize, T>, to_json: impl Fn(&T) -> Value) -> Value {
+    let mut indices = entries.keys().copied().collect::<Vec<_>>();
+    indices.sort();
+    Value::Object(
+        indices
+            .into_iter()
+            .map(|idx| (format!("0x{:02x}", idx), to_json(&entries[&idx])))
+            .collect(),
+    )
+}
+
+impl Annotations {
+    pub fn load(file_name: &str) -> Result<Annotations, String> {
+        let text =
+            std::fs::read_to_string(file_name).map_err(|err| format!("{}: {}", file_name, err))?;
+        let doc = json::parse(&text).map_err(|err| format!("{}: {}", file_name, err))?;
+        let in_file = |err: String| format!("{}: {}", file_name, err);
+        Ok(Annotations {
+            instruments: entries(&doc, "instruments", name).map_err(in_file)?,
+            sequences: entries(&doc, "sequences", name).map_err(in_file)?,
+            sounds: entries(&doc, "sounds", name).map_err(in_file)?,
+            #[cfg(feature = "gui")]
+            pitches: entries(&doc, "pitches", pitch).map_err(in_file)?,
+            quirks: doc
+                .get("quirks")
+                .map(Quirks::from_json)
+                .transpose()
+                .map_err(in_file)?,
+            #[cfg(feature = "gui")]
+            path: Some(PathBuf::from(file_name)),
+        })
+    }
+
+    // Write everything back out, in the form load() reads, and
+    // remember where.
+    #[cfg(feature = "gui")]
+    pub fn save(&mut self, path: &Path) -> Result<(), String> {
+        let as_name = |name: &String| Value::from(name.as_str());
+        let mut fields = vec![
+            ("instruments", section(&self.instruments, as_name)),
+            ("sequences", section(&self.sequences, as_name)),
+            ("sounds", section(&self.sounds, as_name)),
+            (
+                "pitches",
+                section(&self.pitches, |&note| disasm::note_name(note).into()),
+            ),
+        ];
+        if let Some(quirks) = self.quirks {
+            fields.push(("quirks", quirks.to_json()));
+        }
+        std::fs::write(path, format!("{}\n", json::object(fields)))
+            .map_err(|err| format!("Couldn't write '{}': {}", path.display(), err))?;
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    // e.g. "Sequence 05: title theme", or just "Sequence 05".
+    #[cfg(feature = "gui")]
+    fn label(names: &HashMap<usize, String>, kind: &str, idx: usize) -> String {
+        match names.get(&idx) {
+            Some(name) => format!("{} {:02x}: {}", kind, idx, name),
+            None => format!("{} {:02x}", kind, idx),
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn instrument_label(&self, idx: usize) -> String {
+        Annotations::label(&self.instruments, "Instrument", idx)
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn sequence_label(&self, idx: usize) -> String {
+        Annotations::label(&self.sequences, "Sequence", idx)
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn sound_label(&self, idx: usize) -> String {
+        Annotations::label(&self.sounds, "Sound", idx)
+    }
+}
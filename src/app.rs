@@ -0,0 +1,720 @@
+//
+// Speedball 2 Sound player
+//
+// app.rs: The interactive player: bank browser, synth controls, and
+// the various tool windows around them.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use eframe::{App, Frame, NativeOptions};
+use egui::{CentralPanel, Color32, Context};
+
+use crate::cpal_wrapper;
+use crate::{annotations, browser, compare, coverage, driver, duplicates, export, gaps};
+use crate::{archive, config_name, load_from_archive, load_from_config, Args, Bank, Loader};
+use crate::{help, hexview, preview, report, script, sound_player, stats, timeline, tour};
+use crate::{trace, tuner};
+
+// Loads the intro and game banks, and compares them.
+type Comparer = Box<dyn Fn() -> Result<compare::Comparison, String>>;
+
+// Loads a bank from a file in an archive, given the archive's name and
+// the file's.
+type ArchiveLoader = Arc<dyn Fn(&str, &str) -> Result<sound_player::SoundBank, String>>;
+
+// The audio device, and what's needed to reopen it with different
+// settings.
+struct Audio {
+    // None if it couldn't be opened.
+    output: Option<cpal_wrapper::Output>,
+    commands: cpal_wrapper::Commands<sound_player::Command>,
+    num_outputs: Option<u16>,
+    preset: &'static cpal_wrapper::Preset,
+}
+
+// A bank that can be switched to, and how to reload it.
+struct Source {
+    // Name of the bank, for links and the bank selector.
+    name: String,
+    loader: Loader,
+}
+
+struct PlayerApp {
+    // Only locked to start the audio. Everything else goes through
+    // `commands`, and what's playing is read from `published`.
+    synth: Arc<Mutex<sound_player::Synth>>,
+    published: Arc<Mutex<sound_player::Published>>,
+    mixer: sound_player::Mixer,
+    browser: browser::Browser,
+    commands: Sender<cpal_wrapper::Timed<sound_player::Command>>,
+    audio: Audio,
+    show_audio: bool,
+    // The banks loaded, with the latest version of each, and which of
+    // them is being browsed.
+    sources: Vec<Source>,
+    banks: Vec<Arc<sound_player::SoundBank>>,
+    current: usize,
+    // A channel being frozen, rendering in the background, and the
+    // freezes the synth has turned down so far, to spot new ones.
+    freezing: Option<JoinHandle<Result<(usize, sound_player::Frozen), String>>>,
+    freeze_failures: usize,
+    // Why the last load or export failed, if it did.
+    error: Option<String>,
+    comparer: Comparer,
+    archive_loader: ArchiveLoader,
+    // An archive being opened, and the files in it to choose from.
+    archive_entries: Option<(String, Vec<String>)>,
+    comparison: Option<Result<compare::Comparison, String>>,
+    // Parts of the bank nothing refers to, when asked for.
+    unreferenced: Option<Vec<coverage::Region>>,
+    gaps: Option<Vec<gaps::Gap>>,
+    // Instruments sharing sample data, when asked for.
+    duplicates: Option<Vec<duplicates::Duplicate>>,
+    // Hex dump of the bank, when open.
+    hex_view: Option<hexview::HexView>,
+    show_help: bool,
+    tour: tour::Tour,
+    stats: stats::Stats,
+    show_stats: bool,
+    trace: trace::TraceView,
+    show_trace: bool,
+    tuner: tuner::TunerView,
+    show_tuner: bool,
+    script: script::ScriptConsole,
+    show_script: bool,
+    timeline: timeline::Timeline,
+    show_timeline: bool,
+}
+
+impl PlayerApp {
+    // The first source is the bank asked for. If it can't be loaded,
+    // we start with an empty one and show the error, so the user can
+    // fix the file and reload. The others are just extras, and are
+    // dropped if they can't be loaded.
+    fn new(
+        sources: Vec<Source>,
+        comparer: Comparer,
+        archive_loader: ArchiveLoader,
+        commands: Sender<cpal_wrapper::Timed<sound_player::Command>>,
+        audio: Audio,
+        variant: &'static driver::Variant,
+        annotations: Arc<annotations::Annotations>,
+    ) -> PlayerApp {
+        let mut banks = Vec::new();
+        let mut loaded = Vec::new();
+        let mut error = None;
+        for (idx, source) in sources.into_iter().enumerate() {
+            let bank = match (source.loader)() {
+                Ok(bank) => bank,
+                Err(err) if idx == 0 => {
+                    error = Some(format!("Couldn't load bank: {}", err));
+                    sound_player::SoundBank::empty(variant)
+                }
+                Err(_) => continue,
+            };
+            banks.push(Arc::new(bank));
+            loaded.push(source);
+        }
+        let bank = banks[0].clone();
+        let mut synth = sound_player::Synth::new(bank.clone());
+        synth.restore_master_volume();
+        let published = synth.publish_status();
+        let mixer = sound_player::Mixer::new(synth.settings());
+        let browser = browser::Browser::new(bank, &loaded[0].name, annotations.clone());
+        PlayerApp {
+            synth: Arc::new(Mutex::new(synth)),
+            published,
+            mixer,
+            browser,
+            commands,
+            audio,
+            show_audio: false,
+            sources: loaded,
+            banks,
+            current: 0,
+            freezing: None,
+            freeze_failures: 0,
+            error,
+            comparer,
+            archive_loader,
+            archive_entries: None,
+            comparison: None,
+            unreferenced: None,
+            gaps: None,
+            duplicates: None,
+            hex_view: None,
+            show_help: false,
+            tour: tour::Tour::new(),
+            stats: stats::Stats::load(),
+            show_stats: false,
+            trace: trace::TraceView::new(),
+            show_trace: false,
+            tuner: tuner::TunerView::new(),
+            show_tuner: false,
+            script: script::ScriptConsole::new(),
+            show_script: false,
+            timeline: timeline::Timeline::new(),
+            show_timeline: false,
+        }
+    }
+
+    // (Re)open the audio device with the current preset. The old
+    // stream is closed first, as some backends only allow one.
+    fn start_audio(&mut self) {
+        self.audio.output = None;
+        match cpal_wrapper::sound_init(
+            self.synth.clone(),
+            self.audio.commands.clone(),
+            self.audio.num_outputs,
+            self.audio.preset,
+        ) {
+            Ok(output) => self.audio.output = Some(output),
+            Err(err) => self.error = Some(format!("Couldn't open audio device: {}", err)),
+        }
+    }
+
+    fn audio_ui(&mut self, ui: &mut egui::Ui) {
+        let mut preset = self.audio.preset;
+        ui.horizontal(|ui| {
+            ui.label("Preset");
+            egui::ComboBox::from_id_source("AudioPreset")
+                .selected_text(preset.name)
+                .show_ui(ui, |ui| {
+                    for option in cpal_wrapper::PRESETS {
+                        ui.selectable_value(&mut preset, option, option.name);
+                    }
+                });
+        });
+        if preset != self.audio.preset {
+            self.audio.preset = preset;
+            self.start_audio();
+        }
+        match &self.audio.output {
+            Some(output) => ui.label(&output.description),
+            None => ui.colored_label(Color32::RED, "No audio device open"),
+        };
+    }
+
+    // Re-read the current bank, e.g. after editing it externally.
+    // The synth switches over as channels finish with the old data.
+    // If it fails, we keep the old bank.
+    fn reload(&mut self) {
+        let source = &self.sources[self.current];
+        let bank = match (source.loader)() {
+            Ok(bank) => Arc::new(bank),
+            Err(err) => {
+                self.error = Some(format!("Couldn't load bank: {}", err));
+                return;
+            }
+        };
+        self.error = None;
+        let annotations = self.browser.annotations().clone();
+        self.browser = browser::Browser::new(bank.clone(), &source.name, annotations);
+        self.banks[self.current] = bank.clone();
+        let command = sound_player::Command::SetBank(bank);
+        self.commands
+            .send(cpal_wrapper::Timed::now(command))
+            .unwrap();
+    }
+
+    // Switch the synth and the browser over to another of the banks.
+    fn select_bank(&mut self, idx: usize) {
+        self.current = idx;
+        let bank = self.banks[idx].clone();
+        let annotations = self.browser.annotations().clone();
+        self.browser = browser::Browser::new(bank.clone(), &self.sources[idx].name, annotations);
+        self.tour.bank_changed();
+        let command = sound_player::Command::SetBank(bank);
+        self.commands
+            .send(cpal_wrapper::Timed::now(command))
+            .unwrap();
+    }
+
+    fn bank_ui(&mut self, ui: &mut egui::Ui) {
+        let mut selected = self.current;
+        egui::ComboBox::from_id_source("Bank")
+            .selected_text(&self.sources[selected].name)
+            .show_ui(ui, |ui| {
+                for (idx, source) in self.sources.iter().enumerate() {
+                    ui.selectable_value(&mut selected, idx, &source.name);
+                }
+            });
+        if selected != self.current {
+            self.select_bank(selected);
+        }
+    }
+
+    // Pick an archive, and the bank file in it, if there's a choice.
+    fn open_archive(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Archive", &["zip", "lha", "lzh"])
+            .pick_file()
+        else {
+            return;
+        };
+        let path = path.to_string_lossy().to_string();
+        let names = std::fs::read(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| archive::names(&data));
+        match names {
+            Ok(names) if names.len() == 1 => self.open_archive_entry(path, names[0].clone()),
+            Ok(names) => self.archive_entries = Some((path, names)),
+            Err(err) => self.error = Some(format!("Couldn't open {}: {}", path, err)),
+        }
+    }
+
+    // Load a bank out of an archive, and switch to it. It's added to
+    // the banks to choose from, and reloads from the archive.
+    fn open_archive_entry(&mut self, path: String, entry: String) {
+        let loader = self.archive_loader.clone();
+        let source = Source {
+            name: Path::new(&entry)
+                .file_stem()
+                .map_or(entry.clone(), |stem| stem.to_string_lossy().to_string()),
+            loader: Box::new(move || loader(&path, &entry)),
+        };
+        match (source.loader)() {
+            Ok(bank) => {
+                self.error = None;
+                self.sources.push(source);
+                self.banks.push(Arc::new(bank));
+                self.select_bank(self.banks.len() - 1);
+            }
+            Err(err) => self.error = Some(format!("Couldn't load bank: {}", err)),
+        }
+    }
+
+    // Write the bank, with any changes, out in the game's format.
+    fn save_bank(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .set_file_name(&format!("{}.bin", self.sources[self.current].name))
+            .save_file()
+        else {
+            return;
+        };
+        let result = self
+            .browser
+            .bank()
+            .to_bytes()
+            .and_then(|data| std::fs::write(&path, data).map_err(|err| err.to_string()));
+        if let Err(err) = result {
+            self.error = Some(format!("Couldn't save {}: {}", path.display(), err));
+        }
+    }
+
+    // Render a channel the user's asked to freeze in the background,
+    // one at a time, and hand it to the synth when it's done. The
+    // synth swaps the channel over if it's still playing what was
+    // rendered.
+    fn poll_freeze(&mut self, status: &sound_player::Status) {
+        if self
+            .freezing
+            .as_ref()
+            .is_some_and(|handle| handle.is_finished())
+        {
+            let result = self.freezing.take().unwrap().join().unwrap();
+            match result {
+                Ok((idx, frozen)) => {
+                    let command = sound_player::Command::Freeze(idx, frozen);
+                    self.commands
+                        .send(cpal_wrapper::Timed::now(command))
+                        .unwrap();
+                }
+                Err(err) => self.error = Some(format!("Couldn't freeze channel: {}", err)),
+            }
+        }
+        if self.freezing.is_some() {
+            return;
+        }
+        let Some(idx) = self.mixer.take_freeze_request() else {
+            return;
+        };
+        match self.mixer.freezer(status, idx) {
+            Ok(freezer) => self.freezing = Some(thread::spawn(move || freezer.render())),
+            Err(err) => self.error = Some(format!("Couldn't freeze channel: {}", err)),
+        }
+    }
+
+    // Pick a .wav file, and play it through the synth's output.
+    fn preview(&mut self) {
+        let Some(path) = rfd::FileDialog::new()
+            .add_filter("Wave", &["wav"])
+            .pick_file()
+        else {
+            return;
+        };
+        match preview::Clip::load(&path) {
+            Ok(clip) => {
+                let command = sound_player::Command::Preview(Arc::new(clip));
+                self.commands
+                    .send(cpal_wrapper::Timed::now(command))
+                    .unwrap();
+            }
+            Err(err) => self.error = Some(format!("Couldn't load preview: {}", err)),
+        }
+    }
+}
+
+impl App for PlayerApp {
+    fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+        // Only hold what's published while copying it, so the audio
+        // thread doesn't have to skip publishing for long.
+        let status = {
+            let mut published = self.published.lock().unwrap();
+            self.trace.collect(&mut published);
+            self.tuner.collect(&published);
+            published.take_status()
+        };
+        CentralPanel::default().show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                if ui.button("Reload bank").clicked() {
+                    self.reload();
+                }
+                if ui.button("Open archive...").clicked() {
+                    self.open_archive();
+                }
+                if ui.button("Compare intro and game banks").clicked() {
+                    self.comparison = Some((self.comparer)());
+                }
+                if ui.button("Hex view").clicked() {
+                    self.hex_view = match self.hex_view {
+                        Some(_) => None,
+                        None => Some(hexview::HexView::new(self.browser.bank().clone())),
+                    };
+                }
+                if ui.button("Find unreferenced data").clicked() {
+                    self.unreferenced = Some(coverage::unreferenced(self.browser.bank()));
+                }
+                if ui.button("Find unused sequences").clicked() {
+                    self.gaps = Some(gaps::find(self.browser.bank()));
+                }
+                if ui.button("Find duplicate samples").clicked() {
+                    self.duplicates = Some(duplicates::find(self.browser.bank()));
+                }
+                if ui.button("Preview exported file").clicked() {
+                    self.preview();
+                }
+                if ui.button("Save bank as...").clicked() {
+                    self.save_bank();
+                }
+                if ui.button("Export metadata").clicked() {
+                    let file_name = format!("{}.json", self.sources[self.current].name);
+                    if let Err(err) = export::save_metadata(
+                        self.browser.bank(),
+                        self.browser.annotations(),
+                        &file_name,
+                    ) {
+                        self.error = Some(format!("Couldn't export metadata: {}", err));
+                    }
+                }
+                if ui.button("Export report").clicked() {
+                    let title = &self.sources[self.current].name;
+                    if let Err(err) =
+                        report::save_html(self.browser.bank(), self.browser.annotations(), title)
+                    {
+                        self.error = Some(format!("Couldn't export report: {}", err));
+                    }
+                }
+                if ui.button("Audio settings").clicked() {
+                    self.show_audio = !self.show_audio;
+                }
+                if ui.button("Help").clicked() {
+                    self.show_help = !self.show_help;
+                }
+                if ui.button("Tour").clicked() {
+                    self.tour.start();
+                }
+                if ui.button("Usage statistics").clicked() {
+                    self.show_stats = !self.show_stats;
+                }
+                if ui.button("Trace").clicked() {
+                    self.show_trace = !self.show_trace;
+                }
+                if ui.button("Tuner").clicked() {
+                    self.show_tuner = !self.show_tuner;
+                }
+                if ui.button("Timeline").clicked() {
+                    self.show_timeline = !self.show_timeline;
+                }
+                if ui.button("Script").clicked() {
+                    self.show_script = !self.show_script;
+                }
+            });
+            if let Some(err) = &self.error {
+                ui.colored_label(Color32::RED, err);
+            }
+
+            if self.sources.len() > 1 {
+                self.bank_ui(ui);
+            }
+            for command in self.mixer.ui(ui, &status) {
+                self.commands
+                    .send(cpal_wrapper::Timed::now(command))
+                    .unwrap();
+            }
+            let is_recording = self.mixer.is_recording();
+            self.stats
+                .listen(&self.sources[self.current].name, status.is_playing());
+
+            let commands = egui::ScrollArea::vertical()
+                .auto_shrink([false, false])
+                .show(ui, |ui| self.browser.ui(ui))
+                .inner;
+            for command in commands {
+                self.tour.observe(&command, is_recording);
+                self.stats
+                    .observe(&self.sources[self.current].name, &command);
+                // Bank changes, from importing samples or adding
+                // sequences, always go to the live synth, ahead of
+                // the commands using the new bank.
+                if let sound_player::Command::SetBank(bank) = &command {
+                    self.banks[self.current] = bank.clone();
+                    self.commands
+                        .send(cpal_wrapper::Timed::now(command))
+                        .unwrap();
+                } else if is_recording {
+                    self.mixer
+                        .record_command(self.browser.bank().clone(), command);
+                } else {
+                    self.commands
+                        .send(cpal_wrapper::Timed::now(command))
+                        .unwrap();
+                }
+            }
+        });
+        let mut open = self.archive_entries.is_some();
+        let mut picked = None;
+        if let Some((path, names)) = &self.archive_entries {
+            egui::Window::new("Open from archive")
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| {
+                    ui.label(path);
+                    for name in names {
+                        if ui.button(name).clicked() {
+                            picked = Some((path.clone(), name.clone()));
+                        }
+                    }
+                });
+        }
+        if let Some((path, name)) = picked {
+            open = false;
+            self.open_archive_entry(path, name);
+        }
+        if !open {
+            self.archive_entries = None;
+        }
+        let mut open = self.comparison.is_some();
+        if let Some(comparison) = &self.comparison {
+            egui::Window::new("Bank comparison")
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| match comparison {
+                    Ok(comparison) => comparison.ui(ui),
+                    Err(err) => {
+                        ui.colored_label(Color32::RED, err);
+                    }
+                });
+        }
+        if !open {
+            self.comparison = None;
+        }
+        self.poll_freeze(&status);
+        if status.freeze_failures() != self.freeze_failures {
+            self.freeze_failures = status.freeze_failures();
+            self.error = Some(
+                "Couldn't freeze channel: The channel changed while it was being frozen"
+                    .to_string(),
+            );
+        }
+        if let Some(idx) = self.browser.take_compare_quality() {
+            let stem = format!("{}_sequence_{:02x}", self.sources[self.current].name, idx);
+            self.mixer.record_qualities(
+                self.browser.bank().clone(),
+                sound_player::Command::Sequence(idx),
+                stem,
+            );
+        }
+        if let Some(owner) = self.browser.take_show_bytes() {
+            let bank = self.browser.bank();
+            let hex_view = self
+                .hex_view
+                .get_or_insert_with(|| hexview::HexView::new(bank.clone()));
+            // The bank may have changed since the view was opened.
+            if !Arc::ptr_eq(hex_view.bank(), bank) {
+                *hex_view = hexview::HexView::new(bank.clone());
+            }
+            hex_view.select(owner);
+        }
+        let mut open = self.hex_view.is_some();
+        if let Some(hex_view) = &mut self.hex_view {
+            egui::Window::new("Hex view")
+                .open(&mut open)
+                .show(ctx, |ui| hex_view.ui(ui));
+        }
+        if !open {
+            self.hex_view = None;
+        }
+        let mut open = self.unreferenced.is_some();
+        if let Some(regions) = &self.unreferenced {
+            egui::Window::new("Unreferenced data")
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| coverage::ui(ui, regions));
+        }
+        if !open {
+            self.unreferenced = None;
+        }
+        let mut open = self.gaps.is_some();
+        if let Some(found) = &self.gaps {
+            egui::Window::new("Unused sequences")
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| gaps::ui(ui, found));
+        }
+        if !open {
+            self.gaps = None;
+        }
+        let mut open = self.duplicates.is_some();
+        if let Some(dups) = &self.duplicates {
+            egui::Window::new("Duplicate samples")
+                .open(&mut open)
+                .vscroll(true)
+                .show(ctx, |ui| duplicates::ui(ui, dups));
+        }
+        if !open {
+            self.duplicates = None;
+        }
+        let mut show_audio = self.show_audio;
+        egui::Window::new("Audio settings")
+            .open(&mut show_audio)
+            .show(ctx, |ui| self.audio_ui(ui));
+        self.show_audio = show_audio;
+        let variant = self.browser.bank().variant;
+        egui::Window::new("Help")
+            .open(&mut self.show_help)
+            .vscroll(true)
+            .show(ctx, |ui| help::ui(ui, variant));
+        let name = &self.sources[self.current].name;
+        let num_sequences = self.browser.bank().sequences.len();
+        egui::Window::new("Usage statistics")
+            .open(&mut self.show_stats)
+            .vscroll(true)
+            .show(ctx, |ui| self.stats.ui(ui, name, num_sequences));
+        egui::Window::new("Trace")
+            .open(&mut self.show_trace)
+            .default_height(400.0)
+            .show(ctx, |ui| self.trace.ui(ui, &self.commands));
+        egui::Window::new("Tuner")
+            .open(&mut self.show_tuner)
+            .show(ctx, |ui| self.tuner.ui(ui, &self.commands));
+        egui::Window::new("Timeline")
+            .open(&mut self.show_timeline)
+            .default_width(700.0)
+            .show(ctx, |ui| {
+                self.timeline.ui(ui, &status, self.browser.annotations())
+            });
+        egui::Window::new("Script")
+            .open(&mut self.show_script)
+            .show(ctx, |ui| {
+                self.script.ui(
+                    ui,
+                    self.browser.bank(),
+                    self.browser.annotations(),
+                    self.mixer.max_passes(),
+                    &self.commands,
+                )
+            });
+        self.tour.ui(ctx);
+        // Cheap way of ensuring GUI catches the sounds finishing,
+        // without having the sound-players hold a reference to the
+        // GUI.
+        ctx.request_repaint_after(Duration::from_millis(100));
+    }
+}
+
+// Open the player on the bank the loader gives, with the standard
+// banks available to switch to.
+pub fn run(args: Args, loader: Loader, annotations: Arc<annotations::Annotations>) {
+    let name = crate::bank_name(&args);
+    // Overrides for the main bank don't make sense for the others.
+    let compare_args = Args {
+        entry: None,
+        sequences: None,
+        instruments: None,
+        ..args.clone()
+    };
+
+    // Load the standard banks too, so we can switch between them.
+    let mut sources = vec![Source {
+        name: name.clone(),
+        loader,
+    }];
+    for bank in [Bank::Intro, Bank::Game] {
+        if config_name(&bank) != name {
+            let extra_args = compare_args.clone();
+            sources.push(Source {
+                name: config_name(&bank),
+                loader: Box::new(move || load_from_config(&extra_args, &bank)),
+            });
+        }
+    }
+
+    let options = NativeOptions::default();
+    let archive_args = compare_args.clone();
+    let archive_loader = Arc::new(move |archive_name: &str, entry: &str| {
+        load_from_archive(&archive_args, archive_name, entry)
+    });
+    let comparer = Box::new(move || {
+        let intro = load_from_config(&compare_args, &Bank::Intro)?;
+        let game = load_from_config(&compare_args, &Bank::Game)?;
+        Ok(compare::Comparison::new(["intro", "game"], [&intro, &game]))
+    });
+    let (sender, receiver) = mpsc::channel();
+    let audio = Audio {
+        output: None,
+        commands: Arc::new(Mutex::new(receiver)),
+        num_outputs: args.outputs,
+        preset: args.audio_preset,
+    };
+    let mut app = PlayerApp::new(
+        sources,
+        comparer,
+        archive_loader,
+        sender,
+        audio,
+        args.driver,
+        annotations,
+    );
+    {
+        let mut synth = app.synth.lock().unwrap();
+        synth.set_max_passes(args.passes);
+        if let Some(port) = crate::midi_port(&args) {
+            synth.set_midi_out(port);
+        }
+        // The mixer starts from the synth's settings, so picks up
+        // the pass limit.
+        app.mixer = sound_player::Mixer::new(synth.settings());
+    }
+    if let Some(link) = args.open {
+        app.browser.focus(link.item, link.play);
+    }
+    if args.tour {
+        app.tour.start();
+    }
+    app.start_audio();
+
+    eframe::run_native(
+        "Speedball II Sound Player",
+        options,
+        Box::new(|_cc| Box::new(app)),
+    )
+    .unwrap();
+}
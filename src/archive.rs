@@ -0,0 +1,442 @@
+//
+// Speedball 2 Sound player
+//
+// archive.rs: Just enough .zip and .lha reading to pull a bank file
+// out of the archives that Amiga data usually circulates in.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::io::Read;
+
+use flate2::read::DeflateDecoder;
+
+////////////////////////////////////////////////////////////////////////
+// Utilities
+//
+
+// Truncated archives are reported rather than read past the end.
+fn bytes(data: &[u8], addr: usize, len: usize) -> Result<&[u8], String> {
+    data.get(addr..)
+        .and_then(|d| d.get(..len))
+        .ok_or_else(|| format!("Archive truncated at 0x{:x}", addr))
+}
+
+// Both formats are little-endian, unlike everything Amiga-side.
+
+fn le_word(data: &[u8], addr: usize) -> Result<usize, String> {
+    let b = bytes(data, addr, 2)?;
+    Ok(b[0] as usize | (b[1] as usize) << 8)
+}
+
+fn le_long(data: &[u8], addr: usize) -> Result<usize, String> {
+    Ok(le_word(data, addr)? | le_word(data, addr + 2)? << 16)
+}
+
+// Archive entry, with enough information to extract it.
+struct Entry {
+    name: String,
+    method: String,
+    data_offset: usize,
+    packed_size: usize,
+    size: usize,
+}
+
+fn entries(archive: &[u8]) -> Result<Vec<Entry>, String> {
+    if archive.starts_with(b"PK") {
+        zip_entries(archive)
+    } else {
+        lha_entries(archive)
+    }
+}
+
+// The files in an archive, with their directories.
+#[cfg(feature = "gui")]
+pub fn names(archive: &[u8]) -> Result<Vec<String>, String> {
+    Ok(entries(archive)?.into_iter().map(|e| e.name).collect())
+}
+
+// Extract the named file from an archive. Directory components are
+// ignored, and names are matched case-insensitively, as the Amiga
+// would.
+pub fn extract(archive: &[u8], name: &str) -> Result<Vec<u8>, String> {
+    let base_name = |s: &str| s.rsplit(['/', '\\']).next().unwrap().to_lowercase();
+    let wanted = base_name(name);
+    let entries = entries(archive)?;
+    let entry = entries
+        .iter()
+        .find(|e| base_name(&e.name) == wanted)
+        .ok_or_else(|| {
+            let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+            format!("No '{}' in archive (contains: {})", name, names.join(", "))
+        })?;
+
+    let packed = bytes(archive, entry.data_offset, entry.packed_size)
+        .map_err(|_| format!("'{}' runs off the end of the archive", entry.name))?;
+    let data = match entry.method.as_str() {
+        "stored" | "-lh0-" => packed.to_vec(),
+        "deflate" => {
+            // The header's size can't be trusted to reserve memory
+            // with, or to stop the output growing without bound.
+            let mut data = Vec::with_capacity(entry.size.min(packed.len()));
+            DeflateDecoder::new(packed)
+                .take(entry.size as u64 + 1)
+                .read_to_end(&mut data)
+                .map_err(|e| format!("Couldn't inflate '{}': {}", entry.name, e))?;
+            data
+        }
+        "-lh4-" => lh_decode(packed, entry.size, 12, 4)
+            .map_err(|e| format!("Couldn't unpack '{}': {}", entry.name, e))?,
+        "-lh5-" => lh_decode(packed, entry.size, 13, 4)
+            .map_err(|e| format!("Couldn't unpack '{}': {}", entry.name, e))?,
+        "-lh6-" => lh_decode(packed, entry.size, 15, 5)
+            .map_err(|e| format!("Couldn't unpack '{}': {}", entry.name, e))?,
+        "-lh7-" => lh_decode(packed, entry.size, 16, 5)
+            .map_err(|e| format!("Couldn't unpack '{}': {}", entry.name, e))?,
+        method => return Err(format!("Unsupported compression method '{}'", method)),
+    };
+
+    if data.len() != entry.size {
+        return Err(format!(
+            "'{}' unpacked to {} bytes, expected {}",
+            entry.name,
+            data.len(),
+            entry.size
+        ));
+    }
+    Ok(data)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Zip
+//
+
+fn zip_entries(archive: &[u8]) -> Result<Vec<Entry>, String> {
+    // Find the end-of-central-directory record, searching backwards
+    // past any comment.
+    const EOCD_SIZE: usize = 22;
+    let eocd = (0..=archive.len().saturating_sub(EOCD_SIZE))
+        .rev()
+        .find(|&addr| archive[addr..].starts_with(b"PK\x05\x06"))
+        .ok_or("Zip central directory not found")?;
+
+    let num_entries = le_word(archive, eocd + 10)?;
+    let mut addr = le_long(archive, eocd + 16)?;
+    let mut entries = Vec::new();
+    for _ in 0..num_entries {
+        if bytes(archive, addr, 4)? != b"PK\x01\x02" {
+            return Err(format!("Bad zip directory entry at 0x{:x}", addr));
+        }
+        let method = match le_word(archive, addr + 10)? {
+            0 => "stored".to_string(),
+            8 => "deflate".to_string(),
+            other => format!("zip method {}", other),
+        };
+        let packed_size = le_long(archive, addr + 20)?;
+        let size = le_long(archive, addr + 24)?;
+        let name_len = le_word(archive, addr + 28)?;
+        let extra_len = le_word(archive, addr + 30)?;
+        let comment_len = le_word(archive, addr + 32)?;
+        let local_addr = le_long(archive, addr + 42)?;
+        let name = String::from_utf8_lossy(bytes(archive, addr + 46, name_len)?).to_string();
+
+        // The local header has its own copy of the variable-length
+        // fields, which needn't match the central directory's.
+        let local_name_len = le_word(archive, local_addr + 26)?;
+        let local_extra_len = le_word(archive, local_addr + 28)?;
+        let data_offset = local_addr + 30 + local_name_len + local_extra_len;
+
+        if !name.ends_with('/') {
+            entries.push(Entry {
+                name,
+                method,
+                data_offset,
+                packed_size,
+                size,
+            });
+        }
+        addr += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+////////////////////////////////////////////////////////////////////////
+// LhA
+//
+
+fn lha_entries(archive: &[u8]) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut addr = 0;
+    // A zero header size marks the end of the archive.
+    while addr < archive.len() && archive[addr] != 0 {
+        let header = &archive[addr..];
+        if header.len() < 22 || header[2] != b'-' || header[6] != b'-' {
+            return Err(format!("Bad LhA header at 0x{:x}", addr));
+        }
+        let method = String::from_utf8_lossy(&header[2..7]).to_string();
+        let mut packed_size = le_long(header, 7)?;
+        let size = le_long(header, 11)?;
+        let level = header[20];
+
+        let (mut name, header_len, mut ext_addr) = match level {
+            0 | 1 => {
+                let name_len = header[21] as usize;
+                let name = String::from_utf8_lossy(bytes(header, 22, name_len)?).to_string();
+                let base_len = header[0] as usize + 2;
+                // Level 1 headers end in the size of the first
+                // extended header.
+                let ext_addr = if level == 1 { Some(base_len - 2) } else { None };
+                (name, base_len, ext_addr)
+            }
+            2 => (String::new(), le_word(header, 0)?, Some(24)),
+            _ => return Err(format!("Unsupported LhA header level {}", level)),
+        };
+        if header_len < 22 {
+            return Err(format!("Bad LhA header at 0x{:x}", addr));
+        }
+
+        // Walk the extended headers, picking up the file name and
+        // directory.
+        let mut dir = String::new();
+        let mut ext_len_total = 0;
+        while let Some(ext) = ext_addr {
+            let ext_len = le_word(header, ext)?;
+            if ext_len == 0 {
+                break;
+            }
+            // Each extended header has at least its type and the next
+            // header's size.
+            if ext_len < 3 {
+                return Err(format!("Bad LhA extended header at 0x{:x}", addr));
+            }
+            let start = if level == 1 {
+                header_len + ext_len_total
+            } else {
+                ext + 2
+            };
+            let body = bytes(header, start, ext_len - 2)?;
+            match body[0] {
+                0x01 => name = String::from_utf8_lossy(&body[1..]).to_string(),
+                0x02 => {
+                    // Path components are separated with 0xff.
+                    let path = body[1..]
+                        .iter()
+                        .map(|&b| if b == 0xff { b'/' } else { b })
+                        .collect::<Vec<_>>();
+                    dir = String::from_utf8_lossy(&path).to_string();
+                }
+                _ => (),
+            }
+            ext_len_total += ext_len;
+            ext_addr = Some(start + ext_len - 2);
+        }
+
+        // Level 1 counts the extended headers as part of the packed
+        // data.
+        let data_offset = if level == 1 {
+            packed_size = packed_size
+                .checked_sub(ext_len_total)
+                .ok_or_else(|| format!("Bad LhA header at 0x{:x}", addr))?;
+            addr + header_len + ext_len_total
+        } else {
+            addr + header_len
+        };
+
+        if !method.starts_with("-lhd") {
+            entries.push(Entry {
+                name: dir + &name.replace('\\', "/"),
+                method,
+                data_offset,
+                packed_size,
+                size,
+            });
+        }
+        addr = data_offset + packed_size;
+    }
+    Ok(entries)
+}
+
+// MSB-first bit reader, as LhA packs its bits.
+struct BitReader<'a> {
+    data: &'a [u8],
+    bit_pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn bit(&mut self) -> usize {
+        // Reading off the end yields zeros, which is what the original
+        // decoder does too.
+        let byte = self.data.get(self.bit_pos / 8).copied().unwrap_or(0);
+        self.bit_pos += 1;
+        (byte >> (7 - (self.bit_pos - 1) % 8)) as usize & 1
+    }
+
+    fn bits(&mut self, n: usize) -> usize {
+        (0..n).fold(0, |acc, _| acc << 1 | self.bit())
+    }
+
+    // Valid data never needs more bits than there are, so having read
+    // past the end means the data is truncated or corrupt.
+    fn overrun(&self) -> bool {
+        self.bit_pos > self.data.len() * 8
+    }
+}
+
+// Canonical Huffman table, built from code lengths.
+enum Huffman {
+    Single(usize),
+    // Number of codes of each length, followed by symbols sorted by
+    // code.
+    Codes(Vec<usize>, Vec<usize>),
+}
+
+impl Huffman {
+    const MAX_LEN: usize = 16;
+
+    fn new(lens: &[usize]) -> Result<Huffman, String> {
+        let mut counts = vec![0; Huffman::MAX_LEN + 1];
+        for &len in lens.iter() {
+            if len > Huffman::MAX_LEN {
+                return Err(format!("Huffman code length {} is too long", len));
+            }
+            counts[len] += 1;
+        }
+        counts[0] = 0;
+        let mut symbols = (0..lens.len())
+            .filter(|&s| lens[s] != 0)
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|&s| lens[s]);
+        Ok(Huffman::Codes(counts, symbols))
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> usize {
+        match self {
+            Huffman::Single(sym) => *sym,
+            Huffman::Codes(counts, symbols) => {
+                let mut code = 0;
+                let mut first = 0;
+                let mut index = 0;
+                for count in counts.iter().skip(1) {
+                    code |= bits.bit();
+                    if code.wrapping_sub(first) < *count {
+                        return symbols[index + code.wrapping_sub(first)];
+                    }
+                    index += count;
+                    first = (first + count) << 1;
+                    code <<= 1;
+                }
+                // Corrupt data. Return something harmless.
+                0
+            }
+        }
+    }
+}
+
+// Read the lengths for the pre-table and the position table.
+fn read_pt_len(
+    bits: &mut BitReader,
+    num: usize,
+    nbits: usize,
+    special: Option<usize>,
+) -> Result<Huffman, String> {
+    let n = bits.bits(nbits);
+    if n == 0 {
+        return Ok(Huffman::Single(bits.bits(nbits)));
+    }
+    let mut lens = vec![0; num];
+    let mut i = 0;
+    while i < n.min(num) {
+        let mut len = bits.bits(3);
+        if len == 7 {
+            while bits.bit() == 1 {
+                len += 1;
+            }
+        }
+        lens[i] = len;
+        i += 1;
+        if Some(i) == special {
+            i += bits.bits(2);
+        }
+    }
+    Huffman::new(&lens)
+}
+
+// Read the lengths for the literal/length table, which are themselves
+// encoded with the pre-table.
+fn read_c_len(bits: &mut BitReader, pt: &Huffman) -> Result<Huffman, String> {
+    const NC: usize = 510;
+    const CBIT: usize = 9;
+    let n = bits.bits(CBIT);
+    if n == 0 {
+        return Ok(Huffman::Single(bits.bits(CBIT)));
+    }
+    let mut lens = vec![0; NC];
+    let mut i = 0;
+    while i < n.min(NC) {
+        match pt.decode(bits) {
+            0 => i += 1,
+            1 => i += bits.bits(4) + 3,
+            2 => i += bits.bits(CBIT) + 20,
+            len => {
+                lens[i] = len - 2;
+                i += 1;
+            }
+        }
+    }
+    Huffman::new(&lens)
+}
+
+// Decoder for the -lh4- to -lh7- methods, which only differ in
+// dictionary size.
+fn lh_decode(packed: &[u8], size: usize, dict_bits: usize, pbit: usize) -> Result<Vec<u8>, String> {
+    const MIN_MATCH: usize = 3;
+    let num_positions = dict_bits + 1;
+    let mut bits = BitReader {
+        data: packed,
+        bit_pos: 0,
+    };
+    // Don't trust the header's size for more than the packed data
+    // could need without compression.
+    let mut out = Vec::with_capacity(size.min(packed.len()));
+
+    while out.len() < size {
+        let mut block_size = bits.bits(16);
+        if block_size == 0 {
+            return Err("Empty block".to_string());
+        }
+        let pt = read_pt_len(&mut bits, 19, 5, Some(3))?;
+        let c = read_c_len(&mut bits, &pt)?;
+        let p = read_pt_len(&mut bits, num_positions, pbit, None)?;
+
+        while block_size > 0 && out.len() < size {
+            if bits.overrun() {
+                return Err("Data truncated".to_string());
+            }
+            block_size -= 1;
+            let code = c.decode(&mut bits);
+            if code < 256 {
+                out.push(code as u8);
+                continue;
+            }
+            let len = code - 256 + MIN_MATCH;
+            let dist = match p.decode(&mut bits) {
+                0 => 0,
+                pos => (1 << (pos - 1)) + bits.bits(pos - 1),
+            };
+            for _ in 0..len {
+                // The dictionary starts out full of spaces.
+                let byte = if dist < out.len() {
+                    out[out.len() - dist - 1]
+                } else {
+                    b' '
+                };
+                out.push(byte);
+            }
+        }
+    }
+    if bits.overrun() {
+        return Err("Data truncated".to_string());
+    }
+    out.truncate(size);
+    Ok(out)
+}
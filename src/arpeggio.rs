@@ -0,0 +1,235 @@
+//
+// Speedball 2 Sound player
+//
+// arpeggio.rs: Find arpeggiated chords - runs of short notes cycling
+// round a few pitches, which is how Amiga music fakes chords on one
+// channel - and name them, to speed up transcribing the harmony.
+//
+// Note names count note 0 as C. Where the music really sits depends
+// on each instrument's sample, so the names give the shape of the
+// harmony, but may all be off by the same interval.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+use crate::disasm::NOTE_NAMES;
+use crate::driver::Op;
+use crate::sound_player::SoundBank;
+
+// Notes this many frames long or shorter count as arpeggio notes.
+// The fastest notes in the game's music are six frames.
+const MAX_NOTE_FRAMES: usize = 6;
+// Chords have between these many different pitches.
+const MIN_PITCHES: usize = 2;
+const MAX_PITCHES: usize = 4;
+// Each pitch must come round at least this many times.
+const MIN_CYCLES: usize = 2;
+// Give up following a sequence after this many commands, as it may
+// loop forever.
+const MAX_STEPS: usize = 0x4000;
+
+// Chord shapes, as semitones above the root.
+const CHORDS: [(&str, &[usize]); 12] = [
+    ("major", &[0, 4, 7]),
+    ("minor", &[0, 3, 7]),
+    ("diminished", &[0, 3, 6]),
+    ("augmented", &[0, 4, 8]),
+    ("sus2", &[0, 2, 7]),
+    ("sus4", &[0, 5, 7]),
+    ("7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("m7", &[0, 3, 7, 10]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("5", &[0, 7]),
+    ("octaves", &[0]),
+];
+
+// A note or rest as played, in time order.
+struct Event {
+    addr: usize,
+    // In semitones, after transposition, or None for a rest.
+    semitone: Option<isize>,
+    frames: usize,
+}
+
+// Play through a sequence without making any sound, following calls,
+// loops and jumps, to get the notes in order.
+fn events(bank: &SoundBank, start: usize) -> Vec<Event> {
+    let data = &bank.data;
+    let mut events = Vec::new();
+    let mut addr = start;
+    let mut frames_per_beat = 0;
+    let mut note_len = 0;
+    let mut transposition = 0isize;
+    let mut stack: Vec<(u8, usize)> = Vec::new();
+    for _ in 0..MAX_STEPS {
+        let Some(&code) = data.get(addr) else {
+            break;
+        };
+        let operand = data.get(addr + 1).copied().unwrap_or(0);
+        if code < 0x80 {
+            events.push(Event {
+                addr,
+                semitone: Some((code as isize * 4 + transposition).div_euclid(4)),
+                frames: note_len,
+            });
+            addr += 1;
+            continue;
+        }
+        let Some(op) = (bank.variant.decode)(code) else {
+            break;
+        };
+        let next = addr + 1 + op.operand_len();
+        addr = next;
+        match op {
+            Op::NoteLen => note_len = operand as usize * frames_per_beat,
+            Op::Tempo if operand != 0 => frames_per_beat = 750 / operand as usize,
+            Op::Rest => events.push(Event {
+                addr: next - 1,
+                semitone: None,
+                frames: note_len,
+            }),
+            Op::AddTransposition if operand == 0 => transposition = 0,
+            Op::AddTransposition => transposition += operand as i8 as isize,
+            Op::SetTransposition => transposition = operand as i8 as isize,
+            Op::Call => match bank.sequences.get(operand as usize) {
+                Some(&target) => {
+                    stack.push((0, next));
+                    addr = target;
+                }
+                None => break,
+            },
+            Op::Return => match stack.pop() {
+                Some((_, ret_addr)) => addr = ret_addr,
+                None => break,
+            },
+            Op::For => stack.push((operand, next)),
+            Op::Next => match stack.last_mut() {
+                Some((0, _)) => {
+                    stack.pop();
+                }
+                Some((count, loop_addr)) => {
+                    *count -= 1;
+                    addr = *loop_addr;
+                }
+                None => break,
+            },
+            Op::Jump => match bank.sequences.get(operand as usize) {
+                Some(&target) => addr = target,
+                None => break,
+            },
+            Op::Restart | Op::Stop => break,
+            _ => (),
+        }
+    }
+    events
+}
+
+// Name a set of pitch classes as a chord, e.g. "A minor (A C E)", if
+// it's one we know.
+fn chord_name(pitch_classes: &[usize]) -> Option<String> {
+    let mut classes = pitch_classes.to_vec();
+    classes.sort();
+    classes.dedup();
+    let notes = classes
+        .iter()
+        .map(|&pc| NOTE_NAMES[pc % 12])
+        .collect::<Vec<_>>()
+        .join(" ");
+    for &root in classes.iter() {
+        let mut intervals = classes
+            .iter()
+            .map(|&pc| (pc + 12 - root) % 12)
+            .collect::<Vec<_>>();
+        intervals.sort();
+        if let Some((name, _)) = CHORDS.iter().find(|(_, shape)| *shape == intervals) {
+            return Some(format!("{} {} ({})", NOTE_NAMES[root], name, notes));
+        }
+    }
+    None
+}
+
+#[derive(Clone, Debug)]
+pub struct Arpeggio {
+    // Address of the first note.
+    pub addr: usize,
+    // Number of notes played.
+    pub notes: usize,
+    pub chord: String,
+}
+
+impl fmt::Display for Arpeggio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:06x}: {} ({} notes)",
+            self.addr, self.chord, self.notes
+        )
+    }
+}
+
+fn pitch_classes(run: &[Event]) -> Vec<usize> {
+    let mut classes = run
+        .iter()
+        .filter_map(|event| event.semitone)
+        .map(|semitone| semitone.rem_euclid(12) as usize)
+        .collect::<Vec<_>>();
+    classes.sort();
+    classes.dedup();
+    classes
+}
+
+// Does a run of short notes look like a chord, rather than a trill
+// or a fast scale?
+fn is_arpeggio(run: &[Event]) -> bool {
+    let mut pitches = run.iter().filter_map(|e| e.semitone).collect::<Vec<_>>();
+    pitches.sort();
+    pitches.dedup();
+    (MIN_PITCHES..=MAX_PITCHES).contains(&pitches.len())
+        && run.len() >= pitches.len() * MIN_CYCLES
+        // A plain trill between neighbouring notes isn't a chord.
+        && !(pitches.len() == 2 && pitches[1] - pitches[0] <= 2)
+}
+
+// The arpeggios played by a sequence, in the order they're played.
+// A passage played several times appears each time.
+pub fn find(bank: &SoundBank, addr: usize) -> Vec<Arpeggio> {
+    let events = events(bank, addr);
+    let mut arpeggios = Vec::new();
+    let mut start = 0;
+    while start < events.len() {
+        // Extend the run while notes are short and the number of
+        // distinct pitches stays chord-sized.
+        let mut end = start;
+        let mut pitches: Vec<isize> = Vec::new();
+        while let Some(event) = events.get(end) {
+            let Some(semitone) = event.semitone else {
+                break;
+            };
+            if event.frames > MAX_NOTE_FRAMES {
+                break;
+            }
+            if !pitches.contains(&semitone) {
+                if pitches.len() == MAX_PITCHES {
+                    break;
+                }
+                pitches.push(semitone);
+            }
+            end += 1;
+        }
+        let run = &events[start..end];
+        if is_arpeggio(run) {
+            if let Some(chord) = chord_name(&pitch_classes(run)) {
+                arpeggios.push(Arpeggio {
+                    addr: run[0].addr,
+                    notes: run.len(),
+                    chord,
+                });
+            }
+        }
+        start = end.max(start + 1);
+    }
+    arpeggios
+}
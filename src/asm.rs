@@ -0,0 +1,96 @@
+//
+// Speedball 2 Sound player
+//
+// asm.rs: Turn a sequence listing, as produced by disasm.rs, back into
+// byte code, so that sequences can be edited or written from scratch.
+//
+// Each line is a note ("C#3") or a command with its operand, if it has
+// one ("Tempo 188", "Call 0x12"). Command names are matched ignoring
+// case. Anything after a ';' is a comment, and a leading hex address
+// and raw bytes, as in the listing, are ignored, so a listing can be
+// pasted straight back in.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use crate::disasm::parse_note;
+use crate::driver::{Op, Variant};
+
+// Hex with "0x", or decimal, which may be negative for signed
+// operands.
+fn parse_operand(s: &str) -> Result<u8, String> {
+    let value = match s.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("Bad operand '{}'", s))?;
+    if !(-128..=255).contains(&value) {
+        return Err(format!("Operand '{}' doesn't fit in a byte", s));
+    }
+    Ok(value as u8)
+}
+
+fn parse_op(variant: &Variant, name: &str) -> Result<u8, String> {
+    let op = Op::ALL
+        .into_iter()
+        .find(|op| format!("{:?}", op).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown note or command '{}'", name))?;
+    variant
+        .encode(op)
+        .ok_or_else(|| format!("The {} driver has no {:?} command", variant.name, op))
+}
+
+fn assemble_line(variant: &Variant, line: &str, out: &mut Vec<u8>) -> Result<(), String> {
+    let code = line.split(';').next().unwrap();
+    let mut tokens = code.split_whitespace().collect::<Vec<_>>();
+    // Skip the address and bytes columns of a pasted listing. No
+    // command is named in hex digits, but some notes are, so the
+    // last token is always kept.
+    let is_hex = |token: &str| token.chars().all(|c| c.is_ascii_hexdigit());
+    if tokens.len() > 1 && is_hex(tokens[0]) {
+        tokens.remove(0);
+    }
+    while tokens.len() > 1 && tokens[0].len() == 2 && is_hex(tokens[0]) {
+        tokens.remove(0);
+    }
+    let Some((&first, operands)) = tokens.split_first() else {
+        return Ok(());
+    };
+
+    if let Some(note) = parse_note(first) {
+        if !operands.is_empty() {
+            return Err(format!("Note '{}' takes no operand", first));
+        }
+        out.push(note as u8);
+        return Ok(());
+    }
+
+    let code = parse_op(variant, first)?;
+    let op = (variant.decode)(code).unwrap();
+    if operands.len() != op.operand_len() {
+        return Err(format!(
+            "{:?} takes {} operand(s), not {}",
+            op,
+            op.operand_len(),
+            operands.len()
+        ));
+    }
+    out.push(code);
+    for operand in operands {
+        out.push(parse_operand(operand)?);
+    }
+    Ok(())
+}
+
+// Assemble a whole listing, reporting the first line that's wrong.
+pub fn assemble(variant: &Variant, text: &str) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        assemble_line(variant, line, &mut out)
+            .map_err(|err| format!("Line {}: {}", idx + 1, err))?;
+    }
+    if out.is_empty() {
+        return Err("Nothing to assemble".to_string());
+    }
+    Ok(out)
+}
@@ -0,0 +1,140 @@
+//
+// Speedball 2 Sound player
+//
+// automation.rs: Hand-drawn pitch and volume curves, applied when
+// auditioning instruments, to mimic the sweeps sequences produce.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use egui::plot::{Line, Plot, PlotPoints, Points};
+#[cfg(feature = "gui")]
+use egui::{DragValue, Ui};
+
+const NUM_POINTS: usize = 16;
+// Range of the pitch lane, in semitones either way.
+#[cfg(feature = "gui")]
+const MAX_BEND: f32 = 24.0;
+
+#[derive(Clone)]
+pub struct Automation {
+    #[cfg(feature = "gui")]
+    pub enabled: bool,
+    length_s: f32,
+    // Pitch offsets, in semitones.
+    pitch: [f32; NUM_POINTS],
+    // Volume multipliers.
+    volume: [f32; NUM_POINTS],
+}
+
+impl Automation {
+    #[cfg(feature = "gui")]
+    pub fn new() -> Automation {
+        Automation {
+            enabled: false,
+            length_s: 2.0,
+            pitch: [0.0; NUM_POINTS],
+            volume: [1.0; NUM_POINTS],
+        }
+    }
+
+    // Linearly interpolate between points, for t in 0..1. The last
+    // value is held.
+    fn interpolate(points: &[f32], t: f32) -> f32 {
+        let pos = t.clamp(0.0, 1.0) * (points.len() - 1) as f32;
+        let idx = pos as usize;
+        if idx + 1 >= points.len() {
+            return points[points.len() - 1];
+        }
+        let x = pos.fract();
+        points[idx] * (1.0 - x) + points[idx + 1] * x
+    }
+
+    // Returns the pitch offset in semitones and volume multiplier at
+    // the given time.
+    pub fn at(&self, time_s: f32) -> (f32, f32) {
+        let t = time_s / self.length_s;
+        (
+            Automation::interpolate(&self.pitch, t),
+            Automation::interpolate(&self.volume, t),
+        )
+    }
+
+    // Display a lane, and let the user draw on it by clicking or
+    // dragging: The nearest point gets moved to the pointer.
+    #[cfg(feature = "gui")]
+    fn lane_ui(ui: &mut Ui, name: &str, points: &mut [f32], length_s: f32, min: f32, max: f32) {
+        let step = length_s as f64 / (points.len() - 1) as f64;
+        let coords = points
+            .iter()
+            .enumerate()
+            .map(|(idx, y)| [idx as f64 * step, *y as f64])
+            .collect::<Vec<_>>();
+        Plot::new(name)
+            .view_aspect(6.0)
+            .allow_drag(false)
+            .allow_zoom(false)
+            .allow_scroll(false)
+            .allow_boxed_zoom(false)
+            .allow_double_click_reset(false)
+            .include_x(0.0)
+            .include_x(length_s)
+            .include_y(min)
+            .include_y(max)
+            .show(ui, |plot_ui| {
+                plot_ui.line(Line::new(PlotPoints::new(coords.clone())).name(name));
+                plot_ui.points(Points::new(PlotPoints::new(coords)).radius(3.0));
+
+                let drawing = plot_ui.ctx().input(|i| i.pointer.primary_down());
+                if plot_ui.plot_hovered() && drawing {
+                    if let Some(pos) = plot_ui.pointer_coordinate() {
+                        let idx = (pos.x / step).round().clamp(0.0, (points.len() - 1) as f64);
+                        points[idx as usize] = (pos.y as f32).clamp(min, max);
+                    }
+                }
+            });
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.checkbox(
+                &mut self.enabled,
+                "Apply automation when playing instruments",
+            );
+            ui.label("over");
+            ui.add(
+                DragValue::new(&mut self.length_s)
+                    .speed(0.1)
+                    .clamp_range(0.1..=30.0),
+            );
+            ui.label("seconds");
+            if ui.button("Reset").clicked() {
+                *self = Automation {
+                    enabled: self.enabled,
+                    length_s: self.length_s,
+                    ..Automation::new()
+                };
+            }
+        });
+        ui.label("Pitch (semitones)");
+        Automation::lane_ui(
+            ui,
+            "Pitch automation",
+            &mut self.pitch,
+            self.length_s,
+            -MAX_BEND,
+            MAX_BEND,
+        );
+        ui.label("Volume");
+        Automation::lane_ui(
+            ui,
+            "Volume automation",
+            &mut self.volume,
+            self.length_s,
+            0.0,
+            1.0,
+        );
+    }
+}
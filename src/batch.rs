@@ -0,0 +1,423 @@
+//
+// Speedball 2 Sound player
+//
+// batch.rs: Render everything in a bank - sounds, sequences and
+// instruments - to .wav files in one go. Each job is independent, so
+// they're spread over several threads, each with its own copy of the
+// synth.
+//
+// A manifest in the output directory records a hash of everything
+// that went into each file, so that re-running an export only
+// renders the files whose inputs have changed.
+//
+// Optionally, the notes each file plays are also written to a .mid
+// file of the same name, with the same timing, and looping music can
+// be cut after exactly once through, using duration.rs's estimate.
+//
+// Also optionally, a build manifest describes what was produced, for
+// pipelines that package the results: every file, with its hash,
+// size and duration, the item it came from, and the settings used.
+// It has no timestamps, so runs with the same results give the same
+// manifest, and can be diffed.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use sha1::{Digest, Sha1};
+
+use crate::annotations::Annotations;
+use crate::cpal_wrapper::{self, Tail, WavFormat};
+use crate::duration;
+use crate::json::{self, Value};
+use crate::sound_data::SOUNDS;
+use crate::sound_player::{self, Command, Synth, DEFAULT_PITCH};
+
+const MANIFEST: &str = "manifest.json";
+
+// How to render every job.
+pub struct Settings {
+    pub max_time_s: f32,
+    pub tail: Tail,
+    // Write a MIDI file alongside each wav.
+    pub midi: bool,
+    // Cut looping music after once through, rather than at max_time_s.
+    pub auto_length: bool,
+    // Where to write the build manifest, if anywhere.
+    pub build_manifest: Option<PathBuf>,
+    // Render with Synth::bypass_processing, to 32-bit float.
+    pub measurement: bool,
+}
+
+pub struct Job {
+    // File name, without the extension.
+    pub name: String,
+    pub command: Command,
+    // What it renders, for the build manifest: "sound", "sequence" or
+    // "instrument", its index, and its name from the annotations.
+    pub kind: &'static str,
+    pub index: usize,
+    pub title: Option<String>,
+}
+
+// File name for an item, with its name from the annotations, if it
+// has one, made safe for use in a path.
+fn file_name(kind: &str, idx: usize, name: Option<&String>) -> String {
+    match name {
+        Some(name) => {
+            let name = name
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '-' => c.to_ascii_lowercase(),
+                    _ => '_',
+                })
+                .collect::<String>();
+            format!("{}_{:02x}_{}", kind, idx, name)
+        }
+        None => format!("{}_{:02x}", kind, idx),
+    }
+}
+
+// Everything worth rendering from the synth's bank.
+pub fn jobs(synth: &Synth, annotations: &Annotations) -> Vec<Job> {
+    let bank = synth.bank();
+    let job = |kind, index, title: Option<&String>, command| Job {
+        name: file_name(kind, index, title),
+        command,
+        kind,
+        index,
+        title: title.cloned(),
+    };
+    let sounds = (0..SOUNDS.len()).map(|idx| {
+        job(
+            "sound",
+            idx,
+            annotations.sounds.get(&idx),
+            Command::Sound(idx),
+        )
+    });
+    // Skip the empty sequence.
+    let sequences = (1..bank.sequences.len()).map(|idx| {
+        job(
+            "sequence",
+            idx,
+            annotations.sequences.get(&idx),
+            Command::Sequence(idx),
+        )
+    });
+    let instruments = (0..bank.instruments.len()).map(|idx| {
+        let command = Command::Instrument {
+            instr: idx,
+            effect: 0,
+            automation: None,
+            pitch: DEFAULT_PITCH,
+        };
+        job(
+            "instrument",
+            idx,
+            annotations.instruments.get(&idx),
+            command,
+        )
+    });
+    sounds.chain(sequences).chain(instruments).collect()
+}
+
+// Default number of jobs to run at once: one per core.
+pub fn default_concurrency() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// Maps file names to the hash of their inputs.
+type Manifest = HashMap<String, String>;
+
+// A missing or unreadable manifest just means rendering everything.
+fn load_manifest(dir: &Path) -> Manifest {
+    let path = dir.join(MANIFEST);
+    let Ok(text) = std::fs::read_to_string(&path) else {
+        return Manifest::new();
+    };
+    let files = match json::parse(&text) {
+        Ok(doc) => match doc.get("files") {
+            Some(Value::Object(files)) => files.clone(),
+            _ => Vec::new(),
+        },
+        Err(err) => {
+            eprintln!("{}: {}, rendering everything", path.display(), err);
+            Vec::new()
+        }
+    };
+    files
+        .into_iter()
+        .filter_map(|(file, hash)| Some((file, hash.as_str()?.to_string())))
+        .collect()
+}
+
+fn save_manifest(dir: &Path, manifest: &Manifest) -> Result<(), String> {
+    let path = dir.join(MANIFEST);
+    // Sort, so the file diffs nicely.
+    let mut files = manifest
+        .iter()
+        .map(|(file, hash)| (file.clone(), Value::from(hash.as_str())))
+        .collect::<Vec<_>>();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    let doc = json::object(vec![("files", Value::Object(files))]);
+    std::fs::write(&path, format!("{}\n", doc))
+        .map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// Hash of everything that goes into a file: the bank, the version of
+// the interpreter, the render settings and which item it is.
+fn job_hash(
+    bank_hash: &[u8],
+    file: &str,
+    max_time_s: f32,
+    settings: &Settings,
+    passes: usize,
+) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(bank_hash);
+    hasher.update(sound_player::INTERPRETER_VERSION.to_be_bytes());
+    hasher.update(max_time_s.to_be_bytes());
+    hasher.update(settings.tail.to_string().as_bytes());
+    // Only when set, so existing manifests stay valid.
+    if passes != 0 {
+        hasher.update((passes as u64).to_be_bytes());
+    }
+    if settings.midi {
+        hasher.update(b"midi");
+    }
+    if settings.measurement {
+        hasher.update(b"measurement");
+    }
+    hasher.update(file.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Hash and size of a file, for the build manifest.
+fn file_fields(path: &Path) -> Result<Vec<(&'static str, Value)>, String> {
+    let data = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(vec![
+        ("sha1", hex(&Sha1::digest(&data)).into()),
+        ("bytes", data.len().into()),
+    ])
+}
+
+// Length of a wav file, in seconds.
+fn wav_seconds(path: &Path) -> Result<f64, String> {
+    let err = |err: std::io::Error| format!("{}: {}", path.display(), err);
+    let mut file = File::open(path).map_err(err)?;
+    let (header, data) = wav::read(&mut file).map_err(err)?;
+    let samples = match data {
+        wav::BitDepth::Sixteen(samples) => samples.len(),
+        wav::BitDepth::ThirtyTwoFloat(samples) => samples.len(),
+        _ => return Err(format!("{}: not a 16-bit or float wav", path.display())),
+    };
+    Ok(samples as f64 / (header.channel_count as f64 * header.sampling_rate as f64))
+}
+
+// What the build manifest lists for each job. Jobs whose files are
+// missing, having failed, are listed as such instead.
+struct Output {
+    file: String,
+    kind: &'static str,
+    index: usize,
+    title: Option<String>,
+    max_time_s: f32,
+}
+
+fn output_entry(dir: &Path, output: &Output, settings: &Settings) -> Result<Value, String> {
+    let path = dir.join(&output.file);
+    let mut fields = vec![
+        ("file", output.file.as_str().into()),
+        ("kind", output.kind.into()),
+        ("index", output.index.into()),
+    ];
+    if let Some(title) = &output.title {
+        fields.push(("name", title.as_str().into()));
+    }
+    fields.extend(file_fields(&path)?);
+    fields.push(("duration_s", wav_seconds(&path)?.into()));
+    fields.push(("max_time_s", (output.max_time_s as f64).into()));
+    if settings.midi {
+        let midi_path = path.with_extension("mid");
+        let midi_file = midi_path.file_name().unwrap().to_string_lossy().to_string();
+        let mut midi = vec![("file", midi_file.into())];
+        midi.extend(file_fields(&midi_path)?);
+        fields.push(("midi", json::object(midi)));
+    }
+    Ok(json::object(fields))
+}
+
+fn save_build_manifest(
+    path: &Path,
+    dir: &Path,
+    synth: &Synth,
+    outputs: &[Output],
+    settings: &Settings,
+    bank_hash: &[u8],
+) -> Result<(), String> {
+    let bank = synth.bank();
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+    for output in outputs {
+        match output_entry(dir, output, settings) {
+            Ok(entry) => files.push(entry),
+            Err(_) => missing.push(Value::from(output.file.as_str())),
+        }
+    }
+    let doc = json::object(vec![
+        ("version", env!("CARGO_PKG_VERSION").into()),
+        (
+            "interpreter_version",
+            (sound_player::INTERPRETER_VERSION as usize).into(),
+        ),
+        (
+            "bank",
+            json::object(vec![
+                ("sha1", hex(bank_hash).into()),
+                ("driver", bank.variant.name.into()),
+                ("sequences", bank.sequences.len().into()),
+                ("instruments", bank.instruments.len().into()),
+            ]),
+        ),
+        (
+            "settings",
+            json::object(vec![
+                ("sample_rate", (cpal_wrapper::SAMPLING_RATE as usize).into()),
+                ("max_time_s", (settings.max_time_s as f64).into()),
+                ("tail", settings.tail.to_string().into()),
+                ("midi", settings.midi.into()),
+                ("auto_length", settings.auto_length.into()),
+                ("measurement", settings.measurement.into()),
+                ("passes", synth.max_passes().into()),
+            ]),
+        ),
+        ("complete", missing.is_empty().into()),
+        ("missing", Value::Array(missing)),
+        ("files", Value::Array(files)),
+    ]);
+    std::fs::write(path, format!("{}\n", doc)).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+pub fn render_job(
+    synth: &Synth,
+    command: Command,
+    path: &Path,
+    max_time_s: f32,
+    settings: &Settings,
+) -> Result<(), String> {
+    let mut synth = synth.with_command(command);
+    let format = if settings.measurement {
+        synth.bypass_processing();
+        WavFormat::Float32
+    } else {
+        WavFormat::Pcm16
+    };
+    if settings.midi {
+        synth.start_midi_recording();
+    }
+    cpal_wrapper::export_wav(&mut synth, path, true, max_time_s, settings.tail, format)?;
+    if let Some(recording) = synth.take_midi_recording() {
+        let midi_path = path.with_extension("mid");
+        std::fs::write(&midi_path, recording.to_smf())
+            .map_err(|err| format!("Couldn't write '{}': {}", midi_path.display(), err))?;
+    }
+    Ok(())
+}
+
+// Render the jobs into the directory, running at most `concurrency`
+// at once, and skipping files that are already up to date. A failed
+// job doesn't stop the others, but its error is returned at the end.
+pub fn run(
+    synth: &Synth,
+    jobs: Vec<Job>,
+    dir: &Path,
+    settings: &Settings,
+    concurrency: usize,
+) -> Result<(), String> {
+    std::fs::create_dir_all(dir).map_err(|err| format!("{}: {}", dir.display(), err))?;
+
+    let old_manifest = load_manifest(dir);
+    let bank_hash = Sha1::digest(&synth.bank().data[..]);
+    let mut skipped = 0;
+    let mut todo = Vec::new();
+    let mut outputs = Vec::new();
+    for job in jobs {
+        let file = format!("{}.wav", job.name);
+        let max_time_s = if settings.auto_length {
+            duration::record_seconds(
+                synth.bank(),
+                &job.command,
+                synth.max_passes(),
+                settings.max_time_s,
+            )
+        } else {
+            settings.max_time_s
+        };
+        let hash = job_hash(&bank_hash, &file, max_time_s, settings, synth.max_passes());
+        let path = dir.join(&file);
+        let outputs_exist =
+            path.exists() && (!settings.midi || path.with_extension("mid").exists());
+        let stale = old_manifest.get(&file) != Some(&hash) || !outputs_exist;
+        outputs.push(Output {
+            file: file.clone(),
+            kind: job.kind,
+            index: job.index,
+            title: job.title,
+            max_time_s,
+        });
+        if stale {
+            todo.push((file, hash, job.command, max_time_s));
+        } else {
+            skipped += 1;
+        }
+    }
+    if skipped > 0 {
+        eprintln!("Skipping {} unchanged files", skipped);
+    }
+
+    let queue = Mutex::new(todo.into_iter());
+    let manifest = Mutex::new(old_manifest);
+    let errors = Mutex::new(Vec::new());
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| loop {
+                // Don't hold the lock while rendering.
+                let job = queue.lock().unwrap().next();
+                let Some((file, hash, command, max_time_s)) = job else {
+                    break;
+                };
+                let path = dir.join(&file);
+                match render_job(synth, command, &path, max_time_s, settings) {
+                    Ok(()) => {
+                        eprintln!("Wrote {}", path.display());
+                        manifest.lock().unwrap().insert(file, hash);
+                    }
+                    Err(err) => {
+                        // Make sure it's redone next time.
+                        manifest.lock().unwrap().remove(&file);
+                        errors.lock().unwrap().push(err);
+                    }
+                }
+            });
+        }
+    });
+
+    save_manifest(dir, &manifest.into_inner().unwrap())?;
+    if let Some(path) = &settings.build_manifest {
+        save_build_manifest(path, dir, synth, &outputs, settings, &bank_hash)?;
+    }
+    match errors.into_inner().unwrap().into_iter().next() {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
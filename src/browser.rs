@@ -0,0 +1,922 @@
+//
+// Speedball 2 Sound player
+//
+// browser.rs: UI for browsing and playing the contents of a sound
+// bank. This is drawn without holding the synth's lock, and asks the
+// synth to play things by returning Commands.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use egui::plot::{Line, Plot, PlotPoints, VLine};
+use egui::{Align, Button, CollapsingHeader, Color32, DragValue, Grid, Label, RichText, Sense, Ui};
+
+use crate::annotations::Annotations;
+use crate::arpeggio::{self, Arpeggio};
+use crate::asm;
+use crate::automation::Automation;
+use crate::classify::{self, Tag};
+use crate::coverage::{self, Owner, Warning};
+use crate::disasm;
+use crate::driver;
+use crate::duplicates::{self, Duplicate, Relation};
+use crate::duration::{self, Estimate};
+use crate::link::{Item, Link};
+use crate::midi_import::Importer;
+use crate::piano_roll;
+use crate::pitches;
+use crate::quirks::{self, VolumeLaw, PRESETS};
+use crate::sampler;
+use crate::sound_data::*;
+use crate::sound_player::{Command, Instrument, SoundBank, DEFAULT_PITCH};
+
+// Volume step offered when switching to a decibel volume law, giving
+// a 48 dB range.
+const DEFAULT_DB_PER_STEP: f32 = 0.75;
+
+// Bytes to a row when listing a sequence's bytes alone.
+const BYTES_PER_ROW: usize = 16;
+
+// Effect 0 is the "no effect" effect.
+fn effect_name(idx: usize) -> String {
+    if idx == 0 {
+        "No effect".to_string()
+    } else {
+        format!("Effect {:02x}", idx)
+    }
+}
+
+// Bytes written like "d0 01 8c 04 30 ac".
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    s.split_whitespace()
+        .map(|byte| u8::from_str_radix(byte, 16).map_err(|_| format!("Bad byte '{}'", byte)))
+        .collect()
+}
+
+fn find_arpeggios(bank: &SoundBank) -> Vec<Vec<Arpeggio>> {
+    bank.sequences
+        .iter()
+        .map(|&addr| arpeggio::find(bank, addr))
+        .collect()
+}
+
+fn find_durations(bank: &SoundBank) -> Vec<Option<Estimate>> {
+    (0..bank.sequences.len())
+        .map(|idx| duration::estimate(bank, idx))
+        .collect()
+}
+
+// Flag sequences that never end by themselves.
+fn loop_marker(ui: &mut Ui, estimate: Estimate) {
+    let Some(repeat) = estimate.repeat else {
+        return;
+    };
+    if estimate.is_stuck() {
+        ui.colored_label(Color32::RED, "Stuck in a loop")
+            .on_hover_text(format!("It {} without playing anything", repeat));
+    } else {
+        ui.colored_label(Color32::YELLOW, "Loops forever")
+            .on_hover_text(format!("It {} after each pass", repeat));
+    }
+}
+
+fn play_button(ui: &mut Ui) -> bool {
+    ui.add(Button::new("Play").fill(Color32::DARK_RED))
+        .clicked()
+}
+
+fn link_button(ui: &mut Ui, bank_name: &str, item: Item) {
+    if ui.button("Copy link").clicked() {
+        ui.output_mut(|o| o.copied_text = Link::new(bank_name, item).to_string());
+    }
+}
+
+pub struct Browser {
+    bank: Arc<SoundBank>,
+    // Effect to use when auditioning each instrument.
+    instr_effects: Vec<usize>,
+    automation: Automation,
+    // Guessed purpose of each sequence.
+    tags: Vec<Tag>,
+    // Chords each sequence arpeggiates.
+    arpeggios: Vec<Vec<Arpeggio>>,
+    // How long each sequence plays for.
+    durations: Vec<Option<Estimate>>,
+    // Name used in links to the bank's items.
+    name: String,
+    annotations: Arc<Annotations>,
+    // Item to bring into view on the next draw, from a link, and
+    // whether to play it too.
+    focus: Option<Item>,
+    play_focus: bool,
+    // Look for smoother loops when exporting samples.
+    optimise_loops: bool,
+    // Why the last sample export or import failed, if it did.
+    export_error: Option<String>,
+    // Structure to show in the hex view.
+    show_bytes: Option<Owner>,
+    // Instruments sharing sample data, for marking in the plots.
+    duplicates: Vec<Duplicate>,
+    // Samples in odd places, suggesting the tables are misread.
+    warnings: Vec<Warning>,
+    // Sequence to record with each quality setting.
+    compare_quality: Option<usize>,
+    // Hex bytes for a sequence to add to the bank, and why it
+    // couldn't be added, if it couldn't.
+    new_sequence: String,
+    sequence_error: Option<String>,
+    // A sequence listing being written or edited, to assemble and
+    // add to the bank.
+    new_listing: String,
+    // Open the listing editor on the next draw.
+    new_listing_opened: Option<bool>,
+    // Show listings as their bytes alone, without decoding them.
+    bytes_only: bool,
+    // Where in each sequence to start playing from, in bytes, and
+    // whether to work out the state it would have there.
+    start_offsets: HashMap<usize, usize>,
+    infer_start: bool,
+    // A MIDI file being turned into sequences.
+    importer: Importer,
+    // Why the last pitch table load or save failed, if it did.
+    pitch_error: Option<String>,
+    // Why the last change of quirks profile failed, if it did.
+    quirks_error: Option<String>,
+    // Why the annotations couldn't be saved, if they couldn't.
+    annotations_error: Option<String>,
+}
+
+impl Browser {
+    pub fn new(bank: Arc<SoundBank>, name: &str, annotations: Arc<Annotations>) -> Browser {
+        Browser {
+            instr_effects: vec![0; bank.instruments.len()],
+            automation: Automation::new(),
+            tags: classify::classify(&bank),
+            arpeggios: find_arpeggios(&bank),
+            durations: find_durations(&bank),
+            duplicates: duplicates::find(&bank),
+            warnings: coverage::sample_warnings(&bank),
+            bank,
+            name: name.to_string(),
+            annotations,
+            focus: None,
+            play_focus: false,
+            optimise_loops: false,
+            export_error: None,
+            show_bytes: None,
+            compare_quality: None,
+            new_sequence: String::new(),
+            sequence_error: None,
+            new_listing: String::new(),
+            new_listing_opened: None,
+            bytes_only: false,
+            start_offsets: HashMap::new(),
+            infer_start: true,
+            importer: Importer::new(),
+            pitch_error: None,
+            quirks_error: None,
+            annotations_error: None,
+        }
+    }
+
+    // What the user asked to see in the hex view, if anything.
+    pub fn take_show_bytes(&mut self) -> Option<Owner> {
+        self.show_bytes.take()
+    }
+
+    // The sequence the user asked to compare quality settings on, if
+    // any.
+    pub fn take_compare_quality(&mut self) -> Option<usize> {
+        self.compare_quality.take()
+    }
+
+    pub fn bank(&self) -> &Arc<SoundBank> {
+        &self.bank
+    }
+
+    // The annotations, including any changes made here.
+    pub fn annotations(&self) -> &Arc<Annotations> {
+        &self.annotations
+    }
+
+    // Open the headers leading to an item, scroll to it, and
+    // optionally play it.
+    pub fn focus(&mut self, item: Item, play: bool) {
+        self.focus = Some(item);
+        self.play_focus = play;
+    }
+
+    // Force open a header containing the focused item.
+    fn open_if(&self, contains: impl Fn(Item) -> bool) -> Option<bool> {
+        self.focus.filter(|&item| contains(item)).map(|_| true)
+    }
+
+    // Scroll to the focused item, and play it if asked.
+    fn reveal(
+        &self,
+        header: &egui::Response,
+        item: Item,
+        command: impl FnOnce() -> Command,
+        commands: &mut Vec<Command>,
+    ) {
+        if self.focus == Some(item) {
+            header.scroll_to_me(Some(Align::TOP));
+            if self.play_focus {
+                commands.push(command());
+            }
+        }
+    }
+
+    fn instrument_command(&self, idx: usize) -> Command {
+        let automation = if self.automation.enabled {
+            Some(self.automation.clone())
+        } else {
+            None
+        };
+        Command::Instrument {
+            instr: idx,
+            effect: self.instr_effects[idx],
+            automation,
+            pitch: self.audition_note(idx) * 4,
+        }
+    }
+
+    // The note an instrument's played at, counting C0 as 0.
+    fn audition_note(&self, idx: usize) -> usize {
+        self.annotations
+            .pitches
+            .get(&idx)
+            .copied()
+            .unwrap_or(DEFAULT_PITCH / 4)
+    }
+
+    // Keep the annotations, with their pitches, in the file they came
+    // from, or in one chosen now if there isn't one.
+    fn save_annotations(&mut self) {
+        let path = match &self.annotations.path {
+            Some(path) => path.clone(),
+            None => {
+                let Some(path) = rfd::FileDialog::new()
+                    .add_filter("JSON", &["json"])
+                    .set_file_name("names.json")
+                    .save_file()
+                else {
+                    return;
+                };
+                path
+            }
+        };
+        self.annotations_error = Arc::make_mut(&mut self.annotations).save(&path).err();
+    }
+
+    // The note to play an instrument at, and whether it's been
+    // changed from the default.
+    fn audition_pitch_ui(&mut self, ui: &mut Ui, idx: usize) {
+        ui.label("at");
+        let mut note = self.audition_note(idx);
+        let response = ui
+            .add(
+                DragValue::new(&mut note)
+                    .clamp_range(0..=0x7f)
+                    .speed(0.1)
+                    .custom_formatter(|note, _| disasm::note_name(note as usize))
+                    .custom_parser(|text| disasm::parse_note(text).map(|note| note as f64)),
+            )
+            .on_hover_text("The note Play uses, saved with the names");
+        if response.changed() {
+            Arc::make_mut(&mut self.annotations)
+                .pitches
+                .insert(idx, note);
+        }
+        let mut changed = response.drag_released() || (response.changed() && !response.dragged());
+        if self.annotations.pitches.contains_key(&idx) && ui.small_button("Default").clicked() {
+            Arc::make_mut(&mut self.annotations).pitches.remove(&idx);
+            changed = true;
+        }
+        if changed {
+            self.save_annotations();
+        }
+    }
+
+    fn instrument_plot_ui(&self, ui: &mut Ui, instrument: &Instrument, idx: usize) {
+        // This looks expensive, but only excecuted if the header is
+        // opened, so I don't care too much.
+        let sample =
+            &self.bank.data[instrument.sample_addr..][..instrument.sample_len as usize * 2];
+        let points = PlotPoints::new(
+            sample
+                .iter()
+                .enumerate()
+                .map(|(x, y)| [x as f64, *y as i8 as f64])
+                .collect::<Vec<_>>(),
+        );
+        let repeat_point = instrument.loop_offset;
+        let shared = duplicates::involving(&self.duplicates, idx);
+        // Highlight the parts of the sample other instruments use.
+        let overlaps = shared
+            .iter()
+            .filter_map(|(other, relation)| match relation {
+                Relation::Overlap(range) => {
+                    let start = range.start - instrument.sample_addr;
+                    let points = (start..start + range.len())
+                        .map(|x| [x as f64, sample[x] as i8 as f64])
+                        .collect::<Vec<_>>();
+                    Some(
+                        Line::new(PlotPoints::new(points))
+                            .color(Color32::YELLOW)
+                            .name(format!("Shared with instrument {:02x}", other)),
+                    )
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        // Disallow scrolling because it's inside a wider scrolling
+        // frame and you probably didn't mean to scroll.
+        Plot::new(format!("Sound {}", idx))
+            .view_aspect(10.0)
+            .allow_scroll(false)
+            .show(ui, |plot_ui| {
+                plot_ui.line(Line::new(points));
+                for overlap in overlaps {
+                    plot_ui.line(overlap);
+                }
+                if repeat_point != 0 {
+                    plot_ui.vline(VLine::new(repeat_point as f64));
+                }
+            });
+        for (other, relation) in shared {
+            ui.label(format!("{} instrument {:02x}", relation, other));
+        }
+    }
+
+    // Save the raw sample for use in other samplers.
+    fn export_sample(&mut self, instrument: &Instrument, idx: usize) {
+        let file_name = rfd::FileDialog::new()
+            .add_filter("Wave", &["wav"])
+            .set_file_name(&format!("{}_instrument_{:02x}.wav", self.name, idx))
+            .save_file();
+        if let Some(path) = file_name {
+            self.export_error =
+                sampler::save_instrument(&self.bank, instrument, self.optimise_loops, &path).err();
+        }
+    }
+
+    // Replace the sample with one from a file, switching the synth
+    // over to the modified bank.
+    fn import_sample(&mut self, idx: usize) -> Option<Command> {
+        let path = rfd::FileDialog::new()
+            .add_filter("Wave", &["wav"])
+            .pick_file()?;
+        match sampler::import_instrument(&self.bank, idx, &path) {
+            Ok(bank) => {
+                self.export_error = None;
+                self.bank = Arc::new(bank);
+                self.duplicates = duplicates::find(&self.bank);
+                self.warnings = coverage::sample_warnings(&self.bank);
+                Some(Command::SetBank(self.bank.clone()))
+            }
+            Err(err) => {
+                self.export_error = Some(err);
+                None
+            }
+        }
+    }
+
+    // Play the bank with a different period table, switching the
+    // synth over to it.
+    fn set_pitches(&mut self, pitches: PitchTable, commands: &mut Vec<Command>) {
+        self.bank = Arc::new(self.bank.with_pitches(pitches));
+        commands.push(Command::SetBank(self.bank.clone()));
+    }
+
+    fn pitches_ui(&mut self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        ui.horizontal(|ui| {
+            match pitches::differences(&self.bank.pitches) {
+                0 => ui.label("Speedball 2's own table"),
+                n => ui.label(format!("{} periods differ from Speedball 2's", n)),
+            };
+            if ui.button("Load...").clicked() {
+                let path = rfd::FileDialog::new()
+                    .add_filter("Text", &["txt"])
+                    .pick_file();
+                if let Some(path) = path {
+                    match pitches::load(&path.to_string_lossy()) {
+                        Ok(pitches) => {
+                            self.pitch_error = None;
+                            self.set_pitches(pitches, commands);
+                        }
+                        Err(err) => self.pitch_error = Some(err),
+                    }
+                }
+            }
+            if ui.button("Save...").clicked() {
+                let path = rfd::FileDialog::new()
+                    .add_filter("Text", &["txt"])
+                    .set_file_name(&format!("{}_pitches.txt", self.name))
+                    .save_file();
+                if let Some(path) = path {
+                    self.pitch_error = std::fs::write(&path, pitches::to_text(&self.bank.pitches))
+                        .map_err(|err| format!("{}: {}", path.display(), err))
+                        .err();
+                }
+            }
+            if ui.button("Reset").clicked() {
+                self.set_pitches(PITCHES, commands);
+            }
+        });
+        if let Some(err) = &self.pitch_error {
+            ui.colored_label(Color32::RED, err);
+        }
+        let mut pitches = self.bank.pitches;
+        Grid::new("Pitches").striped(true).show(ui, |ui| {
+            for (octave, periods) in pitches.chunks_mut(OCTAVE_SIZE).enumerate() {
+                for (semitone, periods) in periods.chunks_mut(4).enumerate() {
+                    ui.label(format!("Octave {}, semitone {}", octave, semitone));
+                    for period in periods.iter_mut() {
+                        ui.add(
+                            DragValue::new(period)
+                                .clamp_range(1..=u16::MAX)
+                                .hexadecimal(4, false, true),
+                        );
+                    }
+                    ui.end_row();
+                }
+            }
+        });
+        if pitches != self.bank.pitches {
+            self.set_pitches(pitches, commands);
+        }
+    }
+
+    fn quirks_ui(&mut self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        let mut quirks = self.bank.quirks;
+        Grid::new("Quirks").show(ui, |ui| {
+            ui.label("Preset");
+            egui::ComboBox::from_id_source("Quirks preset")
+                .selected_text(quirks.preset_name().unwrap_or("custom"))
+                .show_ui(ui, |ui| {
+                    for preset in PRESETS.iter() {
+                        ui.selectable_value(&mut quirks, preset.quirks, preset.title);
+                    }
+                });
+            ui.end_row();
+            ui.label("Driver");
+            egui::ComboBox::from_id_source("Quirks driver")
+                .selected_text(quirks.variant.title)
+                .show_ui(ui, |ui| {
+                    for variant in driver::VARIANTS {
+                        let selected = std::ptr::eq(quirks.variant, variant);
+                        if ui.selectable_label(selected, variant.title).clicked() {
+                            quirks.variant = variant;
+                        }
+                    }
+                });
+            ui.end_row();
+            ui.label("Frames per second");
+            ui.add(
+                DragValue::new(&mut quirks.frame_rate)
+                    .clamp_range(quirks::MIN_FRAME_RATE..=quirks::MAX_FRAME_RATE)
+                    .speed(0.1),
+            );
+            ui.end_row();
+            ui.label("Clock (Hz)");
+            let mut clock_hz = quirks.clock_hz().round();
+            if ui
+                .add(
+                    DragValue::new(&mut clock_hz)
+                        .clamp_range(1_000_000.0..=10_000_000.0)
+                        .speed(100.0),
+                )
+                .changed()
+            {
+                quirks.clock_interval_s = 1.0 / clock_hz;
+            }
+            ui.end_row();
+            ui.label("Effect table entries");
+            ui.add(DragValue::new(&mut quirks.num_effects).clamp_range(0..=EFFECTS.len()));
+            ui.end_row();
+            ui.label("Volume");
+            ui.horizontal(|ui| {
+                let mut linear = quirks.volume_law == VolumeLaw::Linear;
+                ui.radio_value(&mut linear, true, "linear");
+                ui.radio_value(&mut linear, false, "dB per step");
+                quirks.volume_law = match (linear, quirks.volume_law) {
+                    (true, _) => VolumeLaw::Linear,
+                    (false, VolumeLaw::Decibels(step)) => VolumeLaw::Decibels(step),
+                    (false, VolumeLaw::Linear) => VolumeLaw::Decibels(DEFAULT_DB_PER_STEP),
+                };
+                if let VolumeLaw::Decibels(step) = &mut quirks.volume_law {
+                    ui.add(DragValue::new(step).clamp_range(0.01..=6.0).speed(0.01));
+                }
+            });
+            ui.end_row();
+            ui.label("Unused commands");
+            ui.checkbox(&mut quirks.unused_as_nops, "Skip as no-ops")
+                .on_hover_text(
+                    "Play on past the command numbers nothing uses, rather than stopping",
+                );
+            ui.end_row();
+        });
+        if ui
+            .button("Copy as JSON")
+            .on_hover_text("For the \"quirks\" section of a --names file")
+            .clicked()
+        {
+            ui.output_mut(|o| o.copied_text = format!("\"quirks\": {}", quirks.to_json()));
+        }
+        if let Some(err) = &self.quirks_error {
+            ui.colored_label(Color32::RED, err);
+        }
+        if quirks != self.bank.quirks {
+            match self.bank.with_quirks(quirks) {
+                Ok(bank) => {
+                    self.quirks_error = None;
+                    self.bank = Arc::new(bank);
+                    // A different driver revision reads the tables
+                    // differently.
+                    self.instr_effects.resize(self.bank.instruments.len(), 0);
+                    self.tags = classify::classify(&self.bank);
+                    self.arpeggios = find_arpeggios(&self.bank);
+                    self.durations = find_durations(&self.bank);
+                    self.duplicates = duplicates::find(&self.bank);
+                    self.warnings = coverage::sample_warnings(&self.bank);
+                    commands.push(Command::SetBank(self.bank.clone()));
+                }
+                Err(err) => self.quirks_error = Some(err),
+            }
+        }
+    }
+
+    fn instruments_ui(&mut self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        CollapsingHeader::new("Automation")
+            .default_open(false)
+            .show(ui, |ui| self.automation.ui(ui));
+        ui.checkbox(
+            &mut self.optimise_loops,
+            "Optimise loop points when exporting samples",
+        );
+        if let Some(err) = &self.export_error {
+            ui.colored_label(Color32::RED, err);
+        }
+        // Saving audition pitches is shared by every instrument.
+        if let Some(err) = &self.annotations_error {
+            ui.colored_label(Color32::RED, err);
+        }
+        let bank = self.bank.clone();
+        for (idx, instrument) in bank.instruments.iter().enumerate() {
+            let item = Item::Instrument(idx);
+            let response = CollapsingHeader::new(self.annotations.instrument_label(idx))
+                .default_open(false)
+                .open(self.open_if(|i| i == item))
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        if play_button(ui) {
+                            commands.push(self.instrument_command(idx));
+                        }
+                        link_button(ui, &self.name, item);
+                        if ui.button("Export sample").clicked() {
+                            self.export_sample(instrument, idx);
+                        }
+                        if ui.button("Bytes").clicked() {
+                            self.show_bytes = Some(Owner::Sample(idx));
+                        }
+                        if ui.button("Import sample").clicked() {
+                            if let Some(command) = self.import_sample(idx) {
+                                commands.push(command);
+                            }
+                        }
+                        let effect_idx = &mut self.instr_effects[idx];
+                        egui::ComboBox::from_id_source(format!("Effect {}", idx))
+                            .selected_text(effect_name(*effect_idx))
+                            .show_ui(ui, |ui| {
+                                for i in 0..EFFECTS.len() {
+                                    ui.selectable_value(effect_idx, i, effect_name(i));
+                                }
+                            });
+                        self.audition_pitch_ui(ui, idx);
+                        ui.label(format!("{:?}", instrument));
+                    });
+                    coverage::claims_ui(ui, &self.bank, idx);
+                    for warning in self.warnings.iter().filter(|w| w.instrument == idx) {
+                        ui.colored_label(Color32::YELLOW, &warning.problem);
+                    }
+                    self.instrument_plot_ui(ui, instrument, idx);
+                });
+            self.reveal(
+                &response.header_response,
+                item,
+                || self.instrument_command(idx),
+                commands,
+            );
+        }
+    }
+
+    // Add the typed-in sequence to the bank, switching the synth over
+    // to the modified bank, and play it.
+    fn add_sequence(&mut self, bytes: Result<Vec<u8>, String>, commands: &mut Vec<Command>) {
+        let added = bytes.and_then(|bytes| self.add_sequences(&[&bytes], commands));
+        match added {
+            Ok(indices) => {
+                self.sequence_error = None;
+                commands.push(Command::Sequence(indices[0]));
+            }
+            Err(err) => self.sequence_error = Some(err),
+        }
+    }
+
+    // Add sequences to the bank, switching the synth over to the
+    // modified bank, and return their indices.
+    fn add_sequences(
+        &mut self,
+        sequences: &[&[u8]],
+        commands: &mut Vec<Command>,
+    ) -> Result<Vec<usize>, String> {
+        let (bank, indices) = self.bank.with_sequences(sequences)?;
+        self.bank = Arc::new(bank);
+        self.tags = classify::classify(&self.bank);
+        self.arpeggios = find_arpeggios(&self.bank);
+        self.durations = find_durations(&self.bank);
+        self.warnings = coverage::sample_warnings(&self.bank);
+        commands.push(Command::SetBank(self.bank.clone()));
+        Ok(indices)
+    }
+
+    // Add the imported MIDI file's parts to the bank, and play them
+    // together.
+    fn import_midi_ui(&mut self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        let Some(parts) = self.importer.ui(ui, &self.bank) else {
+            return;
+        };
+        let bytes = parts
+            .iter()
+            .flatten()
+            .map(Vec::as_slice)
+            .collect::<Vec<_>>();
+        match self.add_sequences(&bytes, commands) {
+            Ok(indices) => {
+                self.sequence_error = None;
+                let mut indices = indices.into_iter();
+                let sequences = parts.map(|part| match part {
+                    Some(_) => indices.next().unwrap(),
+                    None => 0,
+                });
+                commands.push(Command::Song(sequences));
+            }
+            Err(err) => self.sequence_error = Some(err),
+        }
+    }
+
+    // The sequence's commands, with any arpeggios noted where they
+    // start.
+    fn listing_ui(&mut self, ui: &mut Ui, idx: usize, addr: usize, commands: &mut Vec<Command>) {
+        let listing = disasm::listing(&self.bank, addr);
+        ui.horizontal(|ui| {
+            if ui.button("Edit a copy").clicked() {
+                self.new_listing = listing
+                    .iter()
+                    .map(|line| format!("{}{}\n", "  ".repeat(line.depth), line.text))
+                    .collect();
+                self.new_listing_opened = Some(true);
+            }
+            ui.checkbox(&mut self.bytes_only, "Bytes only");
+            let offset = self.start_offsets.entry(idx).or_default();
+            if ui.button("Play from offset").clicked() {
+                commands.push(Command::SequenceFrom {
+                    idx,
+                    offset: *offset,
+                    infer: self.infer_start,
+                });
+            }
+            ui.add(
+                DragValue::new(offset)
+                    .hexadecimal(2, false, false)
+                    .prefix("0x"),
+            );
+            ui.checkbox(&mut self.infer_start, "Infer state")
+                .on_hover_text(
+                    "Run the sequence silently up to the offset, so the tempo, instrument \
+                     and so on are set as they would be there",
+                );
+        });
+        if self.bytes_only {
+            let bytes = driver::sequence_bytes(self.bank.variant, &self.bank.data, addr);
+            for (row, chunk) in bytes.chunks(BYTES_PER_ROW).enumerate() {
+                let text = format!(
+                    "{:06x}  {}",
+                    addr + row * BYTES_PER_ROW,
+                    disasm::hex_bytes(chunk)
+                );
+                ui.label(RichText::new(text).monospace());
+            }
+            return;
+        }
+        let bytes_width = disasm::MAX_COMMAND_BYTES * 3 - 1;
+        for line in listing {
+            let mut text = format!(
+                "{:06x}  {:bytes_width$}  {}{}",
+                line.addr,
+                disasm::hex_bytes(&line.bytes),
+                "  ".repeat(line.depth),
+                line.text
+            );
+            // A passage played several times is only noted once.
+            if let Some(arpeggio) = self.arpeggios[idx].iter().find(|a| a.addr == line.addr) {
+                text += &format!("  ; {} arpeggio", arpeggio.chord);
+            }
+            let clicked = ui
+                .add(Label::new(RichText::new(text).monospace()).sense(Sense::click()))
+                .on_hover_text("Click to play from here")
+                .clicked();
+            if clicked {
+                let offset = line.addr - addr;
+                self.start_offsets.insert(idx, offset);
+                commands.push(Command::SequenceFrom {
+                    idx,
+                    offset,
+                    infer: self.infer_start,
+                });
+            }
+        }
+    }
+
+    fn sequences_ui(&mut self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        ui.horizontal(|ui| {
+            ui.label("New sequence (hex bytes):");
+            ui.text_edit_singleline(&mut self.new_sequence);
+            if ui.button("Add and play").clicked() {
+                self.add_sequence(parse_hex(&self.new_sequence), commands);
+            }
+        });
+        CollapsingHeader::new("New sequence (listing)")
+            .open(self.new_listing_opened.take())
+            .show(ui, |ui| {
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.new_listing)
+                        .code_editor()
+                        .desired_rows(8),
+                );
+                if ui.button("Assemble, add and play").clicked() {
+                    let bytes = asm::assemble(self.bank.variant, &self.new_listing);
+                    self.add_sequence(bytes, commands);
+                }
+            });
+        CollapsingHeader::new("Import MIDI file").show(ui, |ui| self.import_midi_ui(ui, commands));
+        if let Some(err) = &self.sequence_error {
+            ui.colored_label(Color32::RED, err);
+        }
+        let bank = self.bank.clone();
+        for tag in Tag::ALL {
+            // Skip first element, the empty sequence.
+            let sequences = bank
+                .sequences
+                .iter()
+                .enumerate()
+                .skip(1)
+                .filter(|(idx, _)| self.tags[*idx] == tag)
+                .collect::<Vec<_>>();
+            CollapsingHeader::new(format!("{} ({})", tag, sequences.len()))
+                .default_open(true)
+                .open(self.open_if(|i| match i {
+                    Item::Sequence(idx) => self.tags.get(idx) == Some(&tag),
+                    _ => false,
+                }))
+                .show(ui, |ui| {
+                    for (idx, addr) in sequences {
+                        let item = Item::Sequence(idx);
+                        let response = CollapsingHeader::new(self.annotations.sequence_label(idx))
+                            .default_open(true)
+                            .open(self.open_if(|i| i == item))
+                            .show(ui, |ui| {
+                                ui.horizontal(|ui| {
+                                    if play_button(ui) {
+                                        commands.push(Command::Sequence(idx));
+                                    }
+                                    link_button(ui, &self.name, item);
+                                    if ui.button("Bytes").clicked() {
+                                        self.show_bytes = Some(Owner::Sequence(idx));
+                                    }
+                                    if ui.button("Compare quality").clicked() {
+                                        self.compare_quality = Some(idx);
+                                    }
+                                    ui.label(format!("0x{:06x}", addr));
+                                    ui.label(match self.durations[idx] {
+                                        Some(estimate) => format!("≈ {}", estimate),
+                                        None => "duration unknown".to_string(),
+                                    });
+                                    if let Some(estimate) = self.durations[idx] {
+                                        loop_marker(ui, estimate);
+                                    }
+                                });
+                                CollapsingHeader::new("Listing")
+                                    .id_source(("Listing", idx))
+                                    .show(ui, |ui| self.listing_ui(ui, idx, *addr, commands));
+                                CollapsingHeader::new("Piano roll")
+                                    .id_source(("PianoRoll", idx))
+                                    .show(ui, |ui| {
+                                        piano_roll::ui(ui, &bank, idx, &self.annotations)
+                                    });
+                                let arpeggios = &self.arpeggios[idx];
+                                if !arpeggios.is_empty() {
+                                    CollapsingHeader::new(format!(
+                                        "Arpeggiated chords ({})",
+                                        arpeggios.len()
+                                    ))
+                                    .id_source(("Arpeggios", idx))
+                                    .show(ui, |ui| {
+                                        for arpeggio in arpeggios {
+                                            ui.label(arpeggio.to_string());
+                                        }
+                                    });
+                                }
+                            });
+                        self.reveal(
+                            &response.header_response,
+                            item,
+                            || Command::Sequence(idx),
+                            commands,
+                        );
+                    }
+                });
+        }
+    }
+
+    fn sounds_ui(&self, ui: &mut Ui, commands: &mut Vec<Command>) {
+        for (idx, sound) in SOUNDS.iter().enumerate() {
+            let item = Item::Sound(idx);
+            let response = CollapsingHeader::new(self.annotations.sound_label(idx))
+                .default_open(true)
+                .open(self.open_if(|i| i == item))
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        if play_button(ui) {
+                            commands.push(Command::Sound(idx));
+                        }
+                        link_button(ui, &self.name, item);
+                        ui.label(format!("{:?}", sound));
+                    });
+                });
+            self.reveal(
+                &response.header_response,
+                item,
+                || Command::Sound(idx),
+                commands,
+            );
+        }
+    }
+
+    // Draw the browser, returning what the user asked to play.
+    pub fn ui(&mut self, ui: &mut Ui) -> Vec<Command> {
+        let mut commands = Vec::new();
+
+        ui.label(format!("Driver: {}", self.bank.driver));
+        if let Some(reason) = &self.bank.sandboxed {
+            ui.colored_label(
+                Color32::RED,
+                format!("Sandboxed, as the bank failed validation: {}", reason),
+            );
+        }
+        if !self.warnings.is_empty() {
+            CollapsingHeader::new(
+                RichText::new(format!(
+                    "{} sample warnings - are the table sizes right?",
+                    self.warnings.len()
+                ))
+                .color(Color32::YELLOW),
+            )
+            .default_open(false)
+            .show(ui, |ui| {
+                for warning in self.warnings.iter() {
+                    ui.label(warning.to_string());
+                }
+            });
+        }
+
+        CollapsingHeader::new("Instruments")
+            .default_open(false)
+            .open(self.open_if(|i| matches!(i, Item::Instrument(_))))
+            .show(ui, |ui| self.instruments_ui(ui, &mut commands));
+        CollapsingHeader::new("Sequences")
+            .default_open(false)
+            .open(self.open_if(|i| matches!(i, Item::Sequence(_))))
+            .show(ui, |ui| self.sequences_ui(ui, &mut commands));
+        CollapsingHeader::new("Sounds")
+            .default_open(true)
+            .open(self.open_if(|i| matches!(i, Item::Sound(_))))
+            .show(ui, |ui| self.sounds_ui(ui, &mut commands));
+        CollapsingHeader::new("Driver quirks")
+            .default_open(false)
+            .show(ui, |ui| self.quirks_ui(ui, &mut commands));
+        CollapsingHeader::new("Pitch table")
+            .default_open(false)
+            .show(ui, |ui| self.pitches_ui(ui, &mut commands));
+
+        // Only jump to the item once, so the user can then browse
+        // elsewhere.
+        self.focus = None;
+
+        commands
+    }
+}
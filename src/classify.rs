@@ -0,0 +1,169 @@
+//
+// Speedball 2 Sound player
+//
+// classify.rs: Guess what each sequence is for - music, a short
+// jingle, or part of a sound effect - to make the sequence browser
+// easier to find things in.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+use crate::driver::Op;
+use crate::sound_data::SOUNDS;
+use crate::sound_player::SoundBank;
+
+// Same guard as the driver detection uses.
+const MAX_SEQUENCE_LEN: usize = 0x4000;
+
+// Thresholds, in notes played, found by eye from the Speedball II
+// data.
+const MUSIC_NOTES: usize = 64;
+const LOOPING_MUSIC_NOTES: usize = 16;
+const JINGLE_NOTES: usize = 8;
+const WIDE_JINGLE_NOTES: usize = 4;
+// Sounds using this many channels are usually tunes.
+const WIDE_SOUND_CHANNELS: usize = 3;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Tag {
+    Music,
+    Jingle,
+    Sfx,
+}
+
+impl Tag {
+    #[cfg(feature = "gui")]
+    pub const ALL: [Tag; 3] = [Tag::Music, Tag::Jingle, Tag::Sfx];
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tag::Music => write!(f, "Music"),
+            Tag::Jingle => write!(f, "Jingles"),
+            Tag::Sfx => write!(f, "Sound effect components"),
+        }
+    }
+}
+
+// What a static walk through a sequence finds.
+#[derive(Default)]
+struct Summary {
+    notes: usize,
+    calls: Vec<usize>,
+    jump: Option<usize>,
+    restarts: bool,
+}
+
+fn summarise(bank: &SoundBank, addr: usize) -> Summary {
+    let data = &bank.data;
+    let mut summary = Summary::default();
+    let end = data.len().min(addr + MAX_SEQUENCE_LEN);
+    let mut addr = addr;
+    while addr < end {
+        let code = data[addr];
+        if code < 0x80 {
+            summary.notes += 1;
+            addr += 1;
+            continue;
+        }
+        let Some(op) = (bank.variant.decode)(code) else {
+            break;
+        };
+        let operand = data.get(addr + 1).copied().unwrap_or(0) as usize;
+        match op {
+            Op::Call => summary.calls.push(operand),
+            Op::Jump => summary.jump = Some(operand),
+            Op::Restart => summary.restarts = true,
+            _ => (),
+        }
+        if op.is_terminator() {
+            break;
+        }
+        addr += 1 + op.operand_len();
+    }
+    summary
+}
+
+// Notes played by a sequence, counting subroutines each time they're
+// called, and following jumps, but not round loops. Results are
+// memoised, as some sequences make hundreds of calls.
+fn played_notes(
+    summaries: &[Summary],
+    memo: &mut [Option<usize>],
+    active: &mut [bool],
+    idx: usize,
+) -> usize {
+    if idx >= summaries.len() || active[idx] {
+        return 0;
+    }
+    if let Some(notes) = memo[idx] {
+        return notes;
+    }
+    active[idx] = true;
+    let summary = &summaries[idx];
+    let called = summary
+        .calls
+        .iter()
+        .chain(summary.jump.iter())
+        .map(|&target| played_notes(summaries, memo, active, target))
+        .sum::<usize>();
+    active[idx] = false;
+    let notes = summary.notes + called;
+    memo[idx] = Some(notes);
+    notes
+}
+
+// Does the sequence go on forever, either restarting or jumping
+// round in a loop?
+fn loops(summaries: &[Summary], idx: usize) -> bool {
+    let mut visited = vec![false; summaries.len()];
+    let mut idx = idx;
+    while let Some(summary) = summaries.get(idx) {
+        if summary.restarts || visited[idx] {
+            return true;
+        }
+        visited[idx] = true;
+        match summary.jump {
+            Some(target) => idx = target,
+            None => return false,
+        }
+    }
+    false
+}
+
+// Tag each sequence in the bank.
+pub fn classify(bank: &SoundBank) -> Vec<Tag> {
+    let summaries = bank
+        .sequences
+        .iter()
+        .map(|&addr| summarise(bank, addr))
+        .collect::<Vec<_>>();
+
+    let mut memo = vec![None; summaries.len()];
+    let mut active = vec![false; summaries.len()];
+    (0..summaries.len())
+        .map(|idx| {
+            let notes = played_notes(&summaries, &mut memo, &mut active, idx);
+            let wide = SOUNDS.iter().any(|sound| {
+                sound.sequences.contains(&idx)
+                    && sound.sequences.iter().filter(|&&seq| seq != 0).count()
+                        >= WIDE_SOUND_CHANNELS
+            });
+            if idx == 0 {
+                // The empty sequence.
+                Tag::Sfx
+            } else if notes >= MUSIC_NOTES
+                || (notes >= LOOPING_MUSIC_NOTES && loops(&summaries, idx))
+            {
+                Tag::Music
+            } else if notes >= JINGLE_NOTES || (wide && notes >= WIDE_JINGLE_NOTES) {
+                Tag::Jingle
+            } else {
+                Tag::Sfx
+            }
+        })
+        .collect()
+}
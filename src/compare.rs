@@ -0,0 +1,158 @@
+//
+// Speedball 2 Sound player
+//
+// compare.rs: Compare two banks (typically intro and game), to see
+// which instruments and sequences they share.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use egui::{CollapsingHeader, Grid, Ui};
+
+use crate::driver;
+use crate::sound_player::{Instrument, SoundBank};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Match {
+    // Same contents at the same index in the other bank.
+    Identical,
+    // Same contents, but at a different index.
+    Shifted(usize),
+    // Nothing like it in the other bank.
+    Unique,
+}
+
+// The parts of an instrument that matter, ignoring where its sample
+// happens to live.
+fn instrument_key<'a>(bank: &'a SoundBank, instr: &Instrument) -> (bool, u16, usize, &'a [u8]) {
+    let sample = &bank.data[instr.sample_addr..][..instr.sample_len as usize * 2];
+    (
+        instr.is_one_shot,
+        instr.loop_offset,
+        instr.base_octave,
+        sample,
+    )
+}
+
+fn instrument_keys(bank: &SoundBank) -> Vec<(bool, u16, usize, &[u8])> {
+    bank.instruments
+        .iter()
+        .map(|instr| instrument_key(bank, instr))
+        .collect()
+}
+
+// The empty sequence 0 is given no bytes, and is skipped in the
+// display.
+fn sequence_keys(bank: &SoundBank) -> Vec<&[u8]> {
+    bank.sequences
+        .iter()
+        .enumerate()
+        .map(|(idx, &addr)| match idx {
+            0 => &[],
+            _ => driver::sequence_bytes(bank.variant, &bank.data, addr),
+        })
+        .collect()
+}
+
+fn find_matches<T: Eq>(ours: &[T], theirs: &[T]) -> Vec<Match> {
+    ours.iter()
+        .enumerate()
+        .map(|(idx, key)| {
+            if theirs.get(idx) == Some(key) {
+                Match::Identical
+            } else if let Some(other) = theirs.iter().position(|k| k == key) {
+                Match::Shifted(other)
+            } else {
+                Match::Unique
+            }
+        })
+        .collect()
+}
+
+pub struct Comparison {
+    names: [String; 2],
+    instruments: [Vec<Match>; 2],
+    sequences: [Vec<Match>; 2],
+}
+
+impl Comparison {
+    pub fn new(names: [&str; 2], banks: [&SoundBank; 2]) -> Comparison {
+        let instrs = banks.map(instrument_keys);
+        let seqs = banks.map(sequence_keys);
+        Comparison {
+            names: names.map(str::to_string),
+            instruments: [
+                find_matches(&instrs[0], &instrs[1]),
+                find_matches(&instrs[1], &instrs[0]),
+            ],
+            sequences: [
+                find_matches(&seqs[0], &seqs[1]),
+                find_matches(&seqs[1], &seqs[0]),
+            ],
+        }
+    }
+
+    fn summary(matches: &[Match]) -> String {
+        let identical = matches.iter().filter(|m| **m == Match::Identical).count();
+        let shifted = matches
+            .iter()
+            .filter(|m| matches!(m, Match::Shifted(_)))
+            .count();
+        let unique = matches.iter().filter(|m| **m == Match::Unique).count();
+        format!(
+            "{} identical, {} shifted, {} unique",
+            identical, shifted, unique
+        )
+    }
+
+    fn matches_ui(ui: &mut Ui, id: &str, kind: &str, other: &str, matches: &[Match], skip: usize) {
+        Grid::new(id).striped(true).show(ui, |ui| {
+            for (idx, m) in matches.iter().enumerate().skip(skip) {
+                ui.label(format!("{} {:02x}", kind, idx));
+                ui.label(match m {
+                    Match::Identical => "Identical".to_string(),
+                    Match::Shifted(other_idx) => format!("Same as {} {:02x}", other, other_idx),
+                    Match::Unique => "Unique".to_string(),
+                });
+                ui.end_row();
+            }
+        });
+    }
+
+    pub fn ui(&self, ui: &mut Ui) {
+        for side in 0..2 {
+            let (name, other) = (&self.names[side], &self.names[1 - side]);
+            CollapsingHeader::new(format!(
+                "{} instruments ({})",
+                name,
+                Comparison::summary(&self.instruments[side])
+            ))
+            .show(ui, |ui| {
+                Comparison::matches_ui(
+                    ui,
+                    &format!("{} instruments", name),
+                    "Instrument",
+                    other,
+                    &self.instruments[side],
+                    0,
+                )
+            });
+            // Skip the empty sequence.
+            CollapsingHeader::new(format!(
+                "{} sequences ({})",
+                name,
+                Comparison::summary(self.sequences[side].get(1..).unwrap_or(&[]))
+            ))
+            .show(ui, |ui| {
+                Comparison::matches_ui(
+                    ui,
+                    &format!("{} sequences", name),
+                    "Sequence",
+                    other,
+                    &self.sequences[side],
+                    1,
+                )
+            });
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//
+// Speedball 2 Sound player
+//
+// control.rs: Headless mode, where we act as a sound server driven by
+// newline-delimited JSON on stdin, so that an emulator or remake can
+// use us for its audio. Each request gets a one-line JSON reply on
+// stdout.
+//
+// Requests look like:
+//
+//   {"cmd": "play_sound", "sound": "0x14"}
+//   {"cmd": "set_music", "sound": 5}
+//   {"cmd": "play_sequence", "sequence": 3}
+//   {"cmd": "play_instrument", "instrument": 2, "effect": 1}
+//   {"cmd": "stop", "channel": 2}
+//   {"cmd": "stop_all"}
+//   {"cmd": "status"}
+//   {"cmd": "quit"}
+//
+// Numbers may be given as JSON numbers, or strings like "0x14".
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::io::{BufRead, Write};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::cpal_wrapper::Timed;
+use crate::json::{self, Value};
+use crate::sound_data::{EFFECTS, SOUNDS};
+use crate::sound_player::{Command, SoundBank, Synth, DEFAULT_PITCH};
+
+enum Request {
+    Command(Command),
+    Status,
+    Quit,
+}
+
+// Fetch an index field, checking it's in range.
+fn index(request: &Value, key: &str, limit: usize) -> Result<usize, String> {
+    let value = request
+        .get(key)
+        .ok_or_else(|| format!("Missing field '{}'", key))?;
+    let idx = value
+        .as_usize()
+        .ok_or_else(|| format!("Bad value for '{}': {}", key, value))?;
+    if idx >= limit {
+        return Err(format!(
+            "'{}' out of range: 0x{:x} >= 0x{:x}",
+            key, idx, limit
+        ));
+    }
+    Ok(idx)
+}
+
+fn parse_request(bank: &SoundBank, num_channels: usize, line: &str) -> Result<Request, String> {
+    let request = json::parse(line)?;
+    let cmd = request
+        .get("cmd")
+        .and_then(Value::as_str)
+        .ok_or("Missing 'cmd'")?;
+    let command = match cmd {
+        "play_sound" => Command::Sound(index(&request, "sound", SOUNDS.len())?),
+        "set_music" => Command::Music(index(&request, "sound", SOUNDS.len())?),
+        // Skip the empty sequence 0.
+        "play_sequence" => match index(&request, "sequence", bank.sequences.len())? {
+            0 => return Err("Sequence 0 is empty".to_string()),
+            idx => Command::Sequence(idx),
+        },
+        "play_instrument" => Command::Instrument {
+            instr: index(&request, "instrument", bank.instruments.len())?,
+            effect: match request.get("effect") {
+                Some(_) => index(&request, "effect", EFFECTS.len())?,
+                None => 0,
+            },
+            automation: None,
+            pitch: DEFAULT_PITCH,
+        },
+        "stop" => Command::Stop(index(&request, "channel", num_channels)?),
+        "stop_all" => Command::StopAll,
+        "status" => return Ok(Request::Status),
+        "quit" => return Ok(Request::Quit),
+        _ => return Err(format!("Unknown cmd '{}'", cmd)),
+    };
+    Ok(Request::Command(command))
+}
+
+fn status(synth: &Mutex<Synth>) -> Value {
+    let synth = synth.lock().unwrap();
+    let active = synth
+        .channels
+        .iter()
+        .map(|channel| channel.is_active())
+        .collect::<Vec<_>>();
+    json::object(vec![("ok", true.into()), ("active", active.into())])
+}
+
+// Serve requests from stdin until it's closed or we're told to quit.
+pub fn run(bank: Arc<SoundBank>, synth: Arc<Mutex<Synth>>, commands: Sender<Timed<Command>>) {
+    let num_channels = synth.lock().unwrap().channels.len();
+    let mut stdout = std::io::stdout();
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line.unwrap();
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut quit = false;
+        let reply = match parse_request(&bank, num_channels, &line) {
+            Ok(Request::Command(command)) => {
+                commands.send(Timed::now(command)).unwrap();
+                json::object(vec![("ok", true.into())])
+            }
+            Ok(Request::Status) => status(&synth),
+            Ok(Request::Quit) => {
+                quit = true;
+                json::object(vec![("ok", true.into())])
+            }
+            Err(err) => json::object(vec![("ok", false.into()), ("error", err.into())]),
+        };
+        writeln!(stdout, "{}", reply).unwrap();
+        stdout.flush().unwrap();
+        if quit {
+            break;
+        }
+    }
+}
@@ -0,0 +1,292 @@
+//
+// Speedball 2 Sound player
+//
+// coverage.rs: Work out what each byte of a bank belongs to, and so
+// find the parts that nothing refers to. Whatever's left over may be
+// unused music, cut sound effects, or just padding.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+use std::ops::Range;
+
+#[cfg(feature = "gui")]
+use egui::{pos2, vec2, Color32, Grid, Rect, Sense, Ui};
+
+use crate::driver;
+use crate::sound_player::{long, SoundBank};
+
+// The structures making up a bank.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Owner {
+    Header,
+    SequenceTable,
+    // An instrument's record in the instrument table.
+    Instrument(usize),
+    Sequence(usize),
+    Sample(usize),
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Owner::Header => write!(f, "Header"),
+            Owner::SequenceTable => write!(f, "Sequence table"),
+            Owner::Instrument(idx) => write!(f, "Instrument {:02x} record", idx),
+            Owner::Sequence(idx) => write!(f, "Sequence {:02x}", idx),
+            Owner::Sample(idx) => write!(f, "Instrument {:02x} sample", idx),
+        }
+    }
+}
+
+// The bytes a structure occupies.
+pub fn span(bank: &SoundBank, owner: Owner) -> Range<usize> {
+    let data = &bank.data;
+    let (start, len) = match owner {
+        Owner::Header => (0, 8),
+        Owner::SequenceTable => (long(data, 0) as usize, bank.sequences.len() * 4),
+        Owner::Instrument(idx) => {
+            let size = bank.variant.instrument_size;
+            (long(data, 4) as usize + idx * size, size)
+        }
+        Owner::Sequence(idx) => {
+            let addr = bank.sequences[idx];
+            (addr, driver::sequence_bytes(bank.variant, data, addr).len())
+        }
+        Owner::Sample(idx) => {
+            let instrument = &bank.instruments[idx];
+            (instrument.sample_addr, instrument.sample_len as usize * 2)
+        }
+    };
+    let end = data.len().min(start.saturating_add(len));
+    start.min(end)..end
+}
+
+// Everything but the samples, skipping the empty sequence.
+fn non_samples(bank: &SoundBank) -> impl Iterator<Item = Owner> {
+    [Owner::Header, Owner::SequenceTable]
+        .into_iter()
+        .chain((0..bank.instruments.len()).map(Owner::Instrument))
+        .chain((1..bank.sequences.len()).map(Owner::Sequence))
+}
+
+// What each byte belongs to, if anything. Where structures overlap,
+// the later one in the list wins.
+pub fn owners(bank: &SoundBank) -> Vec<Option<Owner>> {
+    let mut owners = vec![None; bank.data.len()];
+    if bank.data.len() < 8 {
+        return owners;
+    }
+    let structures = non_samples(bank).chain((0..bank.instruments.len()).map(Owner::Sample));
+    for owner in structures {
+        for byte in owners[span(bank, owner)].iter_mut() {
+            *byte = Some(owner);
+        }
+    }
+    owners
+}
+
+// Something odd about an instrument's sample. With the wrong table
+// sizes, the instrument table runs on into other data, and the
+// "samples" of the extra entries land in odd places.
+#[derive(Clone, Debug)]
+pub struct Warning {
+    pub instrument: usize,
+    pub problem: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Instrument {:02x} sample: {}",
+            self.instrument, self.problem
+        )
+    }
+}
+
+// Samples should be word-aligned, as the Amiga's DMA can only fetch
+// whole words, and shouldn't overlap the tables or sequence code.
+pub fn sample_warnings(bank: &SoundBank) -> Vec<Warning> {
+    if bank.data.len() < 8 {
+        return Vec::new();
+    }
+    let structures = non_samples(bank)
+        .map(|owner| (owner, span(bank, owner)))
+        .filter(|(_, range)| !range.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut warnings = Vec::new();
+    for (idx, instrument) in bank.instruments.iter().enumerate() {
+        if instrument.sample_addr & 1 != 0 {
+            warnings.push(Warning {
+                instrument: idx,
+                problem: format!("starts at odd address 0x{:x}", instrument.sample_addr),
+            });
+        }
+        let sample = span(bank, Owner::Sample(idx));
+        for (owner, range) in structures.iter() {
+            if sample.start < range.end && range.start < sample.end {
+                warnings.push(Warning {
+                    instrument: idx,
+                    problem: format!(
+                        "overlaps {} at 0x{:x}",
+                        owner,
+                        sample.start.max(range.start)
+                    ),
+                });
+            }
+        }
+    }
+    warnings
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Guess {
+    // Nothing but zeros.
+    Padding,
+    // Decodes as a sequence of this many bytes.
+    Sequence(usize),
+    // Anything else, such as a sample.
+    Data,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub start: usize,
+    pub len: usize,
+    pub guess: Guess,
+}
+
+impl fmt::Display for Guess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Guess::Padding => write!(f, "Padding"),
+            Guess::Sequence(len) => write!(f, "Could be a sequence (0x{:x} bytes)", len),
+            Guess::Data => write!(f, "Data, maybe a sample"),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "0x{:06x}: 0x{:x} bytes, {}",
+            self.start, self.len, self.guess
+        )
+    }
+}
+
+fn guess(bank: &SoundBank, start: usize, len: usize) -> Guess {
+    let bytes = &bank.data[start..][..len];
+    if bytes.iter().all(|&b| b == 0) {
+        return Guess::Padding;
+    }
+    // Notes are < 0x80, so runs of small values can look like
+    // sequences, but they have to end within the region.
+    match driver::sequence_bytes(bank.variant, &bank.data, start).len() {
+        0 => Guess::Data,
+        seq_len if seq_len <= len => Guess::Sequence(seq_len),
+        _ => Guess::Data,
+    }
+}
+
+// Every region of the bank that isn't a table, a sequence or a
+// sample.
+pub fn unreferenced(bank: &SoundBank) -> Vec<Region> {
+    if bank.data.len() < 8 {
+        return Vec::new();
+    }
+    let used = owners(bank).iter().map(Option::is_some).collect::<Vec<_>>();
+
+    let mut regions = Vec::new();
+    let mut idx = 0;
+    while idx < used.len() {
+        if used[idx] {
+            idx += 1;
+            continue;
+        }
+        let start = idx;
+        while idx < used.len() && !used[idx] {
+            idx += 1;
+        }
+        let len = idx - start;
+        regions.push(Region {
+            start,
+            len,
+            guess: guess(bank, start, len),
+        });
+    }
+    regions
+}
+
+#[cfg(feature = "gui")]
+pub fn ui(ui: &mut Ui, regions: &[Region]) {
+    if regions.is_empty() {
+        ui.label("Every byte is accounted for.");
+        return;
+    }
+    let total = regions.iter().map(|r| r.len).sum::<usize>();
+    ui.label(format!(
+        "{} regions, 0x{:x} bytes in total",
+        regions.len(),
+        total
+    ));
+    Grid::new("Unreferenced").striped(true).show(ui, |ui| {
+        for region in regions {
+            ui.label(format!("0x{:06x}", region.start));
+            ui.label(format!("0x{:x} bytes", region.len));
+            ui.label(region.guess.to_string());
+            ui.end_row();
+        }
+    });
+}
+
+// Where in the bank an instrument's record and sample lie, drawn as a
+// strip the width of the bank, with the parts of the sample that
+// overlap other structures in red.
+#[cfg(feature = "gui")]
+pub fn claims_ui(ui: &mut Ui, bank: &SoundBank, idx: usize) {
+    const HEIGHT: f32 = 12.0;
+    const MIN_WIDTH: f32 = 2.0;
+    let len = bank.data.len();
+    let (rect, response) =
+        ui.allocate_exact_size(vec2(ui.available_width(), HEIGHT), Sense::hover());
+    if len == 0 {
+        return;
+    }
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::from_gray(60));
+    let draw = |range: Range<usize>, colour: Color32| {
+        let x = |addr: usize| rect.left() + rect.width() * addr as f32 / len as f32;
+        let left = x(range.start);
+        let right = x(range.end).max(left + MIN_WIDTH);
+        painter.rect_filled(
+            Rect::from_min_max(pos2(left, rect.top()), pos2(right, rect.bottom())),
+            0.0,
+            colour,
+        );
+    };
+    let record = span(bank, Owner::Instrument(idx));
+    let sample = span(bank, Owner::Sample(idx));
+    draw(record.clone(), Color32::from_rgb(200, 110, 40));
+    draw(sample.clone(), Color32::from_rgb(80, 180, 90));
+    for owner in non_samples(bank) {
+        let range = span(bank, owner);
+        if sample.start < range.end && range.start < sample.end {
+            draw(
+                sample.start.max(range.start)..sample.end.min(range.end),
+                Color32::RED,
+            );
+        }
+    }
+    response.on_hover_text(format!(
+        "Record: 0x{:x} bytes at 0x{:06x}\nSample: 0x{:x} bytes at 0x{:06x}",
+        record.len(),
+        record.start,
+        sample.len(),
+        sample.start
+    ));
+}
@@ -6,59 +6,365 @@
 // (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
 //
 
+#[cfg(feature = "export")]
 use std::fs::File;
+#[cfg(feature = "export")]
+use std::path::Path;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
+#[cfg(feature = "audio")]
+use std::sync::mpsc::Receiver;
+#[cfg(feature = "audio")]
 use std::sync::{Arc, Mutex};
+#[cfg(feature = "audio")]
+use std::time::Instant;
 
+#[cfg(feature = "audio")]
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Sample, SampleFormat, Stream};
+#[cfg(feature = "audio")]
+use cpal::{SampleFormat, Stream};
 
+#[cfg(feature = "gui")]
 use rfd::FileDialog;
 
+#[cfg(feature = "export")]
 use wav::{bit_depth::BitDepth, header, Header};
 
+#[cfg(feature = "audio")]
+use crate::alloc_count;
+
+// The sample formats we can produce, converting as cpal does, so
+// that the synth doesn't need cpal to render.
+#[cfg(any(feature = "audio", feature = "export"))]
+pub trait OutputSample: Copy {
+    fn from_f32(x: f32) -> Self;
+    // And back, for checking levels.
+    #[cfg(feature = "export")]
+    fn to_f32(self) -> f32;
+}
+
+#[cfg(any(feature = "audio", feature = "export"))]
+impl OutputSample for f32 {
+    fn from_f32(x: f32) -> f32 {
+        x
+    }
+
+    #[cfg(feature = "export")]
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+
+#[cfg(any(feature = "audio", feature = "export"))]
+impl OutputSample for i16 {
+    fn from_f32(x: f32) -> i16 {
+        (x * 32_768.0) as i16
+    }
+
+    #[cfg(feature = "export")]
+    fn to_f32(self) -> f32 {
+        self as f32 / 32_768.0
+    }
+}
+
+#[cfg(any(feature = "audio", feature = "export"))]
+impl OutputSample for u16 {
+    fn from_f32(x: f32) -> u16 {
+        (i16::from_f32(x) as u16).wrapping_add(0x8000)
+    }
+
+    #[cfg(feature = "export")]
+    fn to_f32(self) -> f32 {
+        (self.wrapping_sub(0x8000) as i16).to_f32()
+    }
+}
+
 pub trait SoundSource {
-    fn fill_buffer<T: Sample + cpal::FromSample<f32> + std::ops::Add<Output = T>>(
-        &mut self,
-        num_channels: u16,
-        sample_rate: u32,
-        data: &mut [T],
-    );
+    // Requests to change what's playing, applied from the audio
+    // thread.
+    type Command;
+
+    fn apply(&mut self, command: Self::Command);
+
+    // Called before the audio stream starts, with its format, so that
+    // buffers can be allocated up front rather than in the callback.
+    #[cfg(feature = "audio")]
+    fn prepare(&mut self, _num_channels: u16, _sample_rate: u32) {}
+
+    #[cfg(any(feature = "audio", feature = "export"))]
+    fn fill_buffer<T: OutputSample>(&mut self, num_channels: u16, sample_rate: u32, data: &mut [T]);
 
     // Once the stream ends, this should return true, although
     // fill_buffer should continue to work.
+    #[cfg(feature = "export")]
     fn stream_done(&self) -> bool;
 }
 
-// Given a sound source, play it to speakers.
-pub fn sound_init<S>(source: Arc<Mutex<S>>) -> Stream
+// A command, stamped with when it was sent, so that it can be
+// applied at the matching point in the audio stream.
+#[cfg(feature = "audio")]
+pub struct Timed<C> {
+    sent: Instant,
+    command: C,
+}
+
+#[cfg(feature = "audio")]
+impl<C> Timed<C> {
+    pub fn now(command: C) -> Timed<C> {
+        Timed {
+            sent: Instant::now(),
+            command,
+        }
+    }
+}
+
+// Most commands applied in one audio callback. More than this are
+// held over to the next.
+#[cfg(feature = "audio")]
+const MAX_COMMANDS_PER_CALLBACK: usize = 64;
+
+// Fill a buffer, applying each command at the offset into the buffer
+// matching how long after the previous callback it was sent. This
+// delays everything by a buffer's length, but keeps the spacing
+// between triggers intact, rather than snapping them all to buffer
+// boundaries.
+//
+// Nothing here may allocate: commands are gathered into `pending`,
+// whose capacity is fixed, and any that don't fit wait for the next
+// callback. Debug builds check this.
+#[cfg(feature = "audio")]
+fn fill_timed<S, T>(
+    source: &Mutex<S>,
+    commands: &Receiver<Timed<S::Command>>,
+    pending: &mut Vec<(usize, usize, S::Command)>,
+    last_callback: &mut Instant,
+    num_channels: u16,
+    sample_rate: u32,
+    data: &mut [T],
+) where
+    S: SoundSource,
+    T: OutputSample,
+{
+    let allocations = alloc_count::allocations();
+    let now = Instant::now();
+    let num_frames = data.len() / num_channels as usize;
+    let space = pending.capacity() - pending.len();
+    let timed = commands
+        .try_iter()
+        .take(space)
+        .enumerate()
+        .map(|(idx, timed)| {
+            let delay = timed.sent.saturating_duration_since(*last_callback);
+            let offset = (delay.as_secs_f64() * sample_rate as f64) as usize;
+            (offset.min(num_frames), idx, timed.command)
+        });
+    pending.extend(timed);
+    // Commands sent together stay in order. A stable sort might
+    // allocate.
+    pending.sort_unstable_by_key(|(offset, idx, _)| (*offset, *idx));
+    *last_callback = now;
+
+    let mut source = source.lock().unwrap();
+    let mut pos = 0;
+    for (offset, _, command) in pending.drain(..) {
+        if offset > pos {
+            let range = pos * num_channels as usize..offset * num_channels as usize;
+            source.fill_buffer(num_channels, sample_rate, &mut data[range]);
+            pos = offset;
+        }
+        source.apply(command);
+    }
+    source.fill_buffer(
+        num_channels,
+        sample_rate,
+        &mut data[pos * num_channels as usize..],
+    );
+    debug_assert_eq!(
+        alloc_count::allocations(),
+        allocations,
+        "The audio callback allocated"
+    );
+}
+
+// Settings for opening the audio device. Getting low latency without
+// glitches needs different settings on each platform, so there are a
+// few presets to choose from.
+#[derive(Debug, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    // Frames per callback, or None for the device's default.
+    pub buffer_frames: Option<u32>,
+    // Preferred sample rate, or None for the highest supported.
+    pub sample_rate: Option<u32>,
+}
+
+// Whatever the device gives us by default, as before presets.
+const DEFAULT_PRESET: Preset = Preset {
+    name: "default",
+    buffer_frames: None,
+    sample_rate: None,
+};
+
+// Large buffers, for slow machines or flaky drivers.
+const SAFE_PRESET: Preset = Preset {
+    name: "safe",
+    buffer_frames: Some(2048),
+    sample_rate: Some(44_100),
+};
+
+// ALSA, usually via PulseAudio or PipeWire's plugin, which run at
+// 48kHz and cope with fairly small buffers.
+#[cfg(target_os = "linux")]
+const LOW_LATENCY_PRESET: Preset = Preset {
+    name: "low-latency",
+    buffer_frames: Some(256),
+    sample_rate: Some(48_000),
+};
+
+// CoreAudio is happy with small buffers.
+#[cfg(target_os = "macos")]
+const LOW_LATENCY_PRESET: Preset = Preset {
+    name: "low-latency",
+    buffer_frames: Some(128),
+    sample_rate: Some(48_000),
+};
+
+// WASAPI in shared mode works in 10ms periods.
+#[cfg(target_os = "windows")]
+const LOW_LATENCY_PRESET: Preset = Preset {
+    name: "low-latency",
+    buffer_frames: Some(480),
+    sample_rate: Some(48_000),
+};
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+const LOW_LATENCY_PRESET: Preset = Preset {
+    name: "low-latency",
+    buffer_frames: Some(512),
+    sample_rate: Some(48_000),
+};
+
+pub const PRESETS: [&Preset; 3] = [&DEFAULT_PRESET, &LOW_LATENCY_PRESET, &SAFE_PRESET];
+
+pub fn parse_preset(name: &str) -> Result<&'static Preset, String> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.name == name)
+        .copied()
+        .ok_or_else(|| {
+            let names = PRESETS.iter().map(|p| p.name).collect::<Vec<_>>();
+            format!(
+                "Unknown audio preset '{}', expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+// Commands for the audio thread. The receiver is shared so that the
+// stream can be rebuilt with different settings.
+#[cfg(feature = "audio")]
+pub type Commands<C> = Arc<Mutex<Receiver<Timed<C>>>>;
+
+// A running output stream, which plays until dropped.
+#[cfg(feature = "audio")]
+pub struct Output {
+    _stream: Stream,
+    // What we actually got from the device, for display.
+    #[cfg(feature = "gui")]
+    pub description: String,
+}
+
+// Given a sound source, play it to speakers, applying commands sent
+// to it as we go. If a number of output channels is given, we look
+// for a device config with exactly that many. The preset's settings
+// are used where the device supports them.
+#[cfg(feature = "audio")]
+pub fn sound_init<S>(
+    source: Arc<Mutex<S>>,
+    commands: Commands<S::Command>,
+    num_outputs: Option<u16>,
+    preset: &Preset,
+) -> Result<Output, String>
 where
     S: SoundSource + Send + 'static,
+    S::Command: Send,
 {
     let host = cpal::default_host();
     let device = host
         .default_output_device()
-        .expect("no output device available");
-    let mut supported_configs_range = device
+        .ok_or("No output device available")?;
+    let supported_configs = device
         .supported_output_configs()
-        .expect("error while querying configs");
-    let supported_config = supported_configs_range
-        .next()
-        .expect("no supported config?!")
-        .with_max_sample_rate();
+        .map_err(|err| format!("Error while querying configs: {}", err))?
+        .filter(|config| num_outputs.is_none_or(|n| config.channels() == n))
+        .collect::<Vec<_>>();
+    // Prefer a config supporting the preset's sample rate.
+    let supported_config = preset
+        .sample_rate
+        .map(cpal::SampleRate)
+        .and_then(|rate| {
+            supported_configs
+                .iter()
+                .find(|config| config.min_sample_rate() <= rate && rate <= config.max_sample_rate())
+                .map(|config| config.clone().with_sample_rate(rate))
+        })
+        .or_else(|| {
+            supported_configs
+                .first()
+                .map(|config| config.clone().with_max_sample_rate())
+        })
+        .ok_or_else(|| match num_outputs {
+            Some(n) => format!("No output config with {} channels", n),
+            None => "No supported output config".to_string(),
+        })?;
+
     let err_fn = |err| eprintln!("an error occurred on the output audio stream: {}", err);
     let sample_format = supported_config.sample_format();
     let num_channels = supported_config.channels();
     let sample_rate = supported_config.sample_rate().0;
-    let config = supported_config.into();
+    let buffer_size = match (preset.buffer_frames, supported_config.buffer_size()) {
+        (Some(frames), cpal::SupportedBufferSize::Range { min, max }) => {
+            cpal::BufferSize::Fixed(frames.clamp(*min, *max))
+        }
+        (Some(frames), cpal::SupportedBufferSize::Unknown) => cpal::BufferSize::Fixed(frames),
+        (None, _) => cpal::BufferSize::Default,
+    };
+    #[cfg(feature = "gui")]
+    let description = match buffer_size {
+        cpal::BufferSize::Fixed(frames) => format!(
+            "{} Hz, {} channels, {} frame buffer ({:.1} ms)",
+            sample_rate,
+            num_channels,
+            frames,
+            frames as f32 * 1000.0 / sample_rate as f32
+        ),
+        cpal::BufferSize::Default => format!(
+            "{} Hz, {} channels, default buffer",
+            sample_rate, num_channels
+        ),
+    };
+    let config = cpal::StreamConfig {
+        buffer_size,
+        ..supported_config.into()
+    };
+    let mut last_callback = Instant::now();
+    let mut pending = Vec::with_capacity(MAX_COMMANDS_PER_CALLBACK);
+    source.lock().unwrap().prepare(num_channels, sample_rate);
 
     let stream = match sample_format {
         SampleFormat::F32 => device.build_output_stream(
             &config,
             move |data: &mut [f32], _info: &cpal::OutputCallbackInfo| {
-                source
-                    .lock()
-                    .unwrap()
-                    .fill_buffer::<f32>(num_channels, sample_rate, data);
+                fill_timed(
+                    &source,
+                    &commands.lock().unwrap(),
+                    &mut pending,
+                    &mut last_callback,
+                    num_channels,
+                    sample_rate,
+                    data,
+                );
             },
             err_fn,
             None,
@@ -66,10 +372,15 @@ where
         SampleFormat::I16 => device.build_output_stream(
             &config,
             move |data: &mut [i16], _info: &cpal::OutputCallbackInfo| {
-                source
-                    .lock()
-                    .unwrap()
-                    .fill_buffer::<i16>(num_channels, sample_rate, data);
+                fill_timed(
+                    &source,
+                    &commands.lock().unwrap(),
+                    &mut pending,
+                    &mut last_callback,
+                    num_channels,
+                    sample_rate,
+                    data,
+                );
             },
             err_fn,
             None,
@@ -77,24 +388,171 @@ where
         SampleFormat::U16 => device.build_output_stream(
             &config,
             move |data: &mut [u16], _info: &cpal::OutputCallbackInfo| {
-                source
-                    .lock()
-                    .unwrap()
-                    .fill_buffer::<u16>(num_channels, sample_rate, data);
+                fill_timed(
+                    &source,
+                    &commands.lock().unwrap(),
+                    &mut pending,
+                    &mut last_callback,
+                    num_channels,
+                    sample_rate,
+                    data,
+                );
             },
             err_fn,
             None,
         ),
-        sample_format => panic!("Unsupported sample format '{sample_format}'"),
+        sample_format => return Err(format!("Unsupported sample format '{sample_format}'")),
     }
-    .expect("couldn't build output stream");
+    .map_err(|err| format!("Couldn't build output stream: {}", err))?;
 
-    stream.play().expect("couldn't play");
     stream
+        .play()
+        .map_err(|err| format!("Couldn't play: {}", err))?;
+    Ok(Output {
+        _stream: stream,
+        #[cfg(feature = "gui")]
+        description,
+    })
+}
+
+// Everyone loves CD quality. :p
+#[cfg(feature = "export")]
+pub const SAMPLING_RATE: u32 = 44_100;
+
+// What to do once a sound source says it's finished.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tail {
+    // Stop straight away.
+    Cut,
+    // Carry on until the output goes quiet, for sounds that decay.
+    Silence,
+    // Carry on for this many more seconds.
+    Seconds(f32),
+}
+
+impl Tail {
+    // "cut", "silence", or a number of seconds.
+    pub fn parse(s: &str) -> Result<Tail, String> {
+        match s {
+            "cut" => Ok(Tail::Cut),
+            "silence" => Ok(Tail::Silence),
+            _ => s
+                .parse()
+                .map(Tail::Seconds)
+                .map_err(|_| format!("Bad tail '{}', expected cut, silence or seconds", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Tail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Tail::Cut => write!(f, "cut"),
+            Tail::Silence => write!(f, "silence"),
+            Tail::Seconds(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+// Samples this close to zero count as silence (8 in 16 bits).
+#[cfg(feature = "export")]
+const SILENCE_LEVEL: f32 = 8.0 / 32_768.0;
+
+// How exported audio is stored: 16-bit PCM, as usual, or 32-bit
+// float, for measurement exports, which mustn't be quantised or
+// clipped.
+#[cfg(feature = "export")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WavFormat {
+    Pcm16,
+    Float32,
+}
+
+// Run a sound source until it finishes, plus the tail, or for at
+// most the given time, returning the interleaved samples.
+#[cfg(feature = "export")]
+pub fn render<Source: SoundSource, T: OutputSample>(
+    source: &mut Source,
+    stereo: bool,
+    max_time_s: f32,
+    tail: Tail,
+) -> Vec<T> {
+    let num_channels = if stereo { 2 } else { 1 };
+    let max_samples = (max_time_s * SAMPLING_RATE as f32 * num_channels as f32) as usize;
+    // Choose a size that isn't too much overhead, but means we
+    // don't chuck in too much unnecesary silence.`
+    const BATCH_SIZE: usize = 441;
+    let batch = BATCH_SIZE * num_channels as usize;
+    let mut data: Vec<T> = Vec::new();
+    // Samples rendered since the source finished.
+    let mut tail_samples = 0;
+    while data.len() < max_samples {
+        if !source.stream_done() {
+            let tail_done = match tail {
+                Tail::Cut => true,
+                Tail::Silence => data
+                    .iter()
+                    .rev()
+                    .take(batch)
+                    .all(|x| x.to_f32().abs() <= SILENCE_LEVEL),
+                Tail::Seconds(s) => {
+                    tail_samples >= (s * SAMPLING_RATE as f32 * num_channels as f32) as usize
+                }
+            };
+            if tail_done {
+                break;
+            }
+            tail_samples += batch;
+        }
+        let old_len = data.len();
+        data.resize(old_len + batch, T::from_f32(0.0));
+        source.fill_buffer(num_channels, SAMPLING_RATE, &mut data[old_len..]);
+    }
+    data
+}
+
+#[cfg(feature = "export")]
+fn save_wav(name: &Path, stereo: bool, data: BitDepth) -> Result<(), String> {
+    let num_channels = if stereo { 2 } else { 1 };
+    let (format, bits_per_sample) = match data {
+        BitDepth::ThirtyTwoFloat(_) => (header::WAV_FORMAT_IEEE_FLOAT, 32),
+        _ => (header::WAV_FORMAT_PCM, 16),
+    };
+    let header = Header::new(format, num_channels, SAMPLING_RATE, bits_per_sample);
+    let mut out_file = File::create(name)
+        .map_err(|err| format!("Couldn't create file '{}': {}", name.display(), err))?;
+    wav::write(header, &data, &mut out_file)
+        .map_err(|err| format!("Couldn't write '{}': {}", name.display(), err))
+}
+
+// Render a sound source, as render() does, to a .wav file of the
+// given format.
+#[cfg(feature = "export")]
+pub fn export_wav<Source: SoundSource>(
+    source: &mut Source,
+    name: &Path,
+    stereo: bool,
+    max_time_s: f32,
+    tail: Tail,
+    format: WavFormat,
+) -> Result<(), String> {
+    let data = match format {
+        WavFormat::Pcm16 => BitDepth::Sixteen(render(source, stereo, max_time_s, tail)),
+        WavFormat::Float32 => BitDepth::ThirtyTwoFloat(render(source, stereo, max_time_s, tail)),
+    };
+    save_wav(name, stereo, data)
 }
 
 // Given a sound source, and a config, write it to a .wav file.
-pub fn write_wav<Source>(source: &mut Source, stereo: bool, max_time_s: f32)
+// Returns the file's name, if one was chosen.
+#[cfg(feature = "gui")]
+pub fn write_wav<Source>(
+    source: &mut Source,
+    stereo: bool,
+    max_time_s: f32,
+    tail: Tail,
+    format: WavFormat,
+) -> Option<PathBuf>
 where
     Source: SoundSource + Send + 'static,
 {
@@ -103,31 +561,8 @@ where
         .set_file_name("speedball2.wav")
         .save_file();
 
-    if let Some(name) = file_name {
-        let num_channels = if stereo { 2 } else { 1 };
-        // Everyone loves CD quality. :p
-        const SAMPLING_RATE: u32 = 44_100;
-        const BITS_PER_SAMPLE: u16 = 16;
-        let header = Header::new(
-            header::WAV_FORMAT_PCM,
-            num_channels,
-            SAMPLING_RATE,
-            BITS_PER_SAMPLE,
-        );
-        let max_samples = (max_time_s * SAMPLING_RATE as f32 * num_channels as f32) as usize;
-        // Choose a size that isn't too much overhead, but means we
-        // don't chuck in too much unnecesary silence.`
-        const BATCH_SIZE: usize = 441;
-        let batch = BATCH_SIZE * num_channels as usize;
-        let mut data: Vec<i16> = Vec::new();
-        while data.len() < max_samples && source.stream_done() {
-            let old_len = data.len();
-            data.resize(old_len + batch, 0);
-            source.fill_buffer(num_channels, SAMPLING_RATE, &mut data[old_len..]);
-        }
-        let mut out_file =
-            File::create(&name).expect(&format!("Couldn't create file '{}'", name.display()));
-        wav::write(header, &BitDepth::Sixteen(data), &mut out_file)
-            .expect("Couldn't write wav file");
-    }
+    let name = file_name?;
+    export_wav(source, &name, stereo, max_time_s, tail, format)
+        .unwrap_or_else(|err| panic!("{}", err));
+    Some(name)
 }
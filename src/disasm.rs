@@ -0,0 +1,134 @@
+//
+// Speedball 2 Sound player
+//
+// disasm.rs: Turn a sequence's byte code into a readable listing, one
+// command per line, with notes named and loop bodies indented.
+// Commands are named as in the help and the JSON export. Each line
+// keeps the bytes it was decoded from, to check against other
+// disassemblies.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use crate::driver::{self, Op};
+#[cfg(feature = "gui")]
+use crate::sound_player::SoundBank;
+
+pub const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+// e.g. "C#3", counting note 0 as C0.
+#[cfg(feature = "gui")]
+pub fn note_name(note: usize) -> String {
+    format!("{}{}", NOTE_NAMES[note % 12], note / 12)
+}
+
+// And back, e.g. "C#3" to 37, ignoring case. Only notes a sequence
+// can play are accepted.
+pub fn parse_note(s: &str) -> Option<usize> {
+    let split = s.find(|c: char| c.is_ascii_digit())?;
+    let (name, octave) = s.split_at(split);
+    let idx = NOTE_NAMES
+        .iter()
+        .position(|n| n.eq_ignore_ascii_case(name))?;
+    let note = octave.parse::<usize>().ok()? * 12 + idx;
+    (note < 0x80).then_some(note)
+}
+
+#[cfg(feature = "gui")]
+pub struct Line {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    // Depth of For loops the command is in.
+    pub depth: usize,
+    pub text: String,
+}
+
+// The most bytes a command takes, for lining up listings.
+#[cfg(feature = "gui")]
+pub const MAX_COMMAND_BYTES: usize = 2;
+
+// e.g. "8c bc".
+#[cfg(feature = "gui")]
+pub fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(feature = "gui")]
+fn operand_text(op: Op, operand: u8) -> String {
+    match op {
+        Op::Call | Op::Jump | Op::Instrument | Op::Effect => format!("0x{:02x}", operand),
+        Op::AddTransposition | Op::SetTransposition => format!("{}", operand as i8),
+        _ => format!("{}", operand),
+    }
+}
+
+#[cfg(feature = "gui")]
+pub fn command_text(op: Op, operand: Option<u8>) -> String {
+    match operand {
+        Some(operand) if op.operand_len() == 1 => {
+            format!("{:?} {}", op, operand_text(op, operand))
+        }
+        _ => format!("{:?}", op),
+    }
+}
+
+// The single command at an address, e.g. to show where a sequence
+// has got to.
+#[cfg(feature = "gui")]
+pub fn command_at(bank: &SoundBank, addr: usize) -> String {
+    match bank.data.get(addr) {
+        None => "(past the end of the bank)".to_string(),
+        Some(&code) if code < 0x80 => note_name(code as usize),
+        Some(&code) => match (bank.variant.decode)(code) {
+            Some(op) => command_text(op, bank.data.get(addr + 1).copied()),
+            None => format!("Unknown command 0x{:02x}", code),
+        },
+    }
+}
+
+// Decode the sequence at the given address, up to the command that
+// ends it.
+#[cfg(feature = "gui")]
+pub fn listing(bank: &SoundBank, addr: usize) -> Vec<Line> {
+    let bytes = driver::sequence_bytes(bank.variant, &bank.data, addr);
+    let mut lines = Vec::new();
+    let mut depth = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let code = bytes[offset];
+        if code < 0x80 {
+            lines.push(Line {
+                addr: addr + offset,
+                bytes: vec![code],
+                depth,
+                text: note_name(code as usize),
+            });
+            offset += 1;
+            continue;
+        }
+        // sequence_bytes() stops at anything it can't decode.
+        let op = (bank.variant.decode)(code).unwrap();
+        if op == Op::Next {
+            depth = depth.saturating_sub(1);
+        }
+        let len = 1 + op.operand_len();
+        lines.push(Line {
+            addr: addr + offset,
+            bytes: bytes[offset..(offset + len).min(bytes.len())].to_vec(),
+            depth,
+            text: command_text(op, bytes.get(offset + 1).copied()),
+        });
+        if op == Op::For {
+            depth += 1;
+        }
+        offset += len;
+    }
+    lines
+}
@@ -0,0 +1,342 @@
+//
+// Speedball 2 Sound player
+//
+// driver.rs: Knowledge about the sound driver's data formats, used to
+// work out which revision of the driver some data was built for.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+use crate::scanner;
+use crate::sound_player::Instrument;
+
+// The operations the sequence byte code can perform, independent of
+// how a particular driver revision numbers them.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Op {
+    Volume,
+    Restart,
+    NoteLen,
+    Rest,
+    Tempo,
+    Effect,
+    EffectLoops,
+    Stop,
+    Call,
+    Return,
+    AddTransposition,
+    SetTransposition,
+    For,
+    Next,
+    Instrument,
+    Jump,
+}
+
+impl Op {
+    pub const ALL: [Op; 16] = [
+        Op::Volume,
+        Op::Restart,
+        Op::NoteLen,
+        Op::Rest,
+        Op::Tempo,
+        Op::Effect,
+        Op::EffectLoops,
+        Op::Stop,
+        Op::Call,
+        Op::Return,
+        Op::AddTransposition,
+        Op::SetTransposition,
+        Op::For,
+        Op::Next,
+        Op::Instrument,
+        Op::Jump,
+    ];
+
+    // Number of operand bytes following the command byte.
+    pub fn operand_len(self) -> usize {
+        match self {
+            Op::Restart | Op::Rest | Op::Stop | Op::Return | Op::Next => 0,
+            _ => 1,
+        }
+    }
+
+    // Commands after which execution doesn't fall through to the
+    // next byte: restart, stop, return and jump.
+    pub fn is_terminator(self) -> bool {
+        matches!(self, Op::Restart | Op::Stop | Op::Return | Op::Jump)
+    }
+
+    // What the operand byte means, if there is one.
+    #[cfg(feature = "gui")]
+    pub fn operand_name(self) -> &'static str {
+        match self {
+            Op::Volume => "volume (0-64)",
+            Op::NoteLen => "beats",
+            Op::Tempo => "bpm",
+            Op::Effect => "effect",
+            Op::EffectLoops => "flags",
+            Op::Call | Op::Jump => "sequence",
+            Op::AddTransposition | Op::SetTransposition => "signed quarter-semitones",
+            Op::For => "count",
+            Op::Instrument => "instrument",
+            Op::Restart | Op::Rest | Op::Stop | Op::Return | Op::Next => "",
+        }
+    }
+
+    // What the interpreter does with it, for the built-in help.
+    #[cfg(feature = "gui")]
+    pub fn description(self) -> &'static str {
+        match self {
+            Op::Volume => "Set the channel volume.",
+            Op::Restart => {
+                "Go back to the start of the sequence, if repeats are enabled, otherwise end."
+            }
+            Op::NoteLen => "Set the length of following notes and rests, in beats.",
+            Op::Rest => "Wait one note length. Stops the sample if it loops from the start.",
+            Op::Tempo => "Set the tempo. A beat lasts 750/bpm frames.",
+            Op::Effect => "Select a tremolo/vibrato effect from the built-in table.",
+            Op::EffectLoops => "Bit 0 makes the tremolo loop, bit 1 the vibrato.",
+            Op::Stop => "End the sequence.",
+            Op::Call => "Run another sequence, coming back here when it returns.",
+            Op::Return => "Return from a call. Ends the sequence if there was no call.",
+            Op::AddTransposition => "Add to the transposition. Zero resets it instead.",
+            Op::SetTransposition => "Set the transposition applied to following notes.",
+            Op::For => "Start a loop, whose body runs count + 1 times.",
+            Op::Next => "End of a loop body.",
+            Op::Instrument => "Select the instrument used by following notes.",
+            Op::Jump => "Carry on from the start of another sequence, without returning.",
+        }
+    }
+}
+
+// The things that can vary between revisions of the driver: how
+// commands are numbered, and the instrument record layout.
+pub struct Variant {
+    // As used on the command line.
+    pub name: &'static str,
+    // For display.
+    pub title: &'static str,
+    // Decode a command byte (notes, < 0x80, are common to all).
+    pub decode: fn(u8) -> Option<Op>,
+    // Command numbers that fall in the driver's jump table but that
+    // nothing uses, so aren't decoded.
+    pub unused_codes: &'static [u8],
+    pub instrument_size: usize,
+    pub read_instrument: fn(&[u8]) -> Result<Instrument, String>,
+    pub write_instrument: fn(&Instrument, &mut [u8]),
+}
+
+impl Variant {
+    pub fn operand_len(&self, code: u8) -> Option<usize> {
+        if code < 0x80 {
+            return Some(0);
+        }
+        (self.decode)(code).map(Op::operand_len)
+    }
+
+    // The command byte for an op, if this revision has it.
+    pub fn encode(&self, op: Op) -> Option<u8> {
+        (0x80..=0xff).find(|&code| (self.decode)(code) == Some(op))
+    }
+
+    pub fn is_terminator(&self, code: u8) -> bool {
+        code >= 0x80 && (self.decode)(code).is_some_and(Op::is_terminator)
+    }
+}
+
+impl fmt::Debug for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// Commands index a jump table, so are multiples of 4 from 0x80. The
+// gaps, 0x84, 0x98, 0xa0, 0xa4, 0xc8 and 0xcc, aren't used by any of
+// the game's sequences, and what the driver does with them (and how
+// many operand bytes they take) would need its code, which we don't
+// have, so they're left undecoded rather than guessed at. The
+// "unused_as_nops" quirk plays on past them anyway.
+const SPEEDBALL2_UNUSED: [u8; 6] = [0x84, 0x98, 0xa0, 0xa4, 0xc8, 0xcc];
+
+fn decode_speedball2(code: u8) -> Option<Op> {
+    Some(match code {
+        0x80 => Op::Volume,
+        0x88 => Op::Restart,
+        0x8c => Op::NoteLen,
+        0x90 => Op::Rest,
+        0x94 => Op::Tempo,
+        0x9c => Op::Effect,
+        0xa8 => Op::EffectLoops,
+        0xac => Op::Stop,
+        0xb0 => Op::Call,
+        0xb4 => Op::Return,
+        0xb8 => Op::AddTransposition,
+        0xbc => Op::SetTransposition,
+        0xc0 => Op::For,
+        0xc4 => Op::Next,
+        0xd0 => Op::Instrument,
+        0xd4 => Op::Jump,
+        _ => return None,
+    })
+}
+
+pub const SPEEDBALL2: Variant = Variant {
+    name: "speedball2",
+    title: "Speedball II",
+    decode: decode_speedball2,
+    unused_codes: &SPEEDBALL2_UNUSED,
+    instrument_size: Instrument::SIZE,
+    read_instrument: Instrument::new,
+    write_instrument: Instrument::write,
+};
+
+// All the driver revisions we know about. Other Bitmap Brothers games
+// I've looked at use different music players entirely, so so far
+// this is a list of one, but new revisions can be added here.
+pub const VARIANTS: [&Variant; 1] = [&SPEEDBALL2];
+
+pub fn parse_variant(name: &str) -> Result<&'static Variant, String> {
+    VARIANTS
+        .iter()
+        .find(|variant| variant.name == name)
+        .copied()
+        .ok_or_else(|| {
+            let names = VARIANTS.iter().map(|v| v.name).collect::<Vec<_>>();
+            format!(
+                "Unknown driver '{}', expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })
+}
+
+// Is this a command byte some driver revision understands?
+pub fn is_known_code(code: u8) -> bool {
+    VARIANTS
+        .iter()
+        .any(|variant| variant.operand_len(code).is_some())
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DriverVersion {
+    // The data fits the driver revision we've been asked to use.
+    Known(&'static str),
+    // Doesn't look like anything we know. We play it with the chosen
+    // interpreter anyway, and hope.
+    Unknown,
+}
+
+impl fmt::Display for DriverVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverVersion::Known(name) => write!(f, "{}", name),
+            DriverVersion::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Detection {
+    pub version: DriverVersion,
+    // Sequences that decode using the known command set.
+    pub clean_sequences: usize,
+    pub total_sequences: usize,
+    // Instrument records that look like the known 14-byte layout.
+    pub plausible_instruments: usize,
+    pub total_instruments: usize,
+    pub unknown_codes: Vec<u8>,
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}/{} sequences decode, {}/{} instruments plausible",
+            self.version,
+            self.clean_sequences,
+            self.total_sequences,
+            self.plausible_instruments,
+            self.total_instruments
+        )?;
+        if !self.unknown_codes.is_empty() {
+            let codes = self
+                .unknown_codes
+                .iter()
+                .map(|c| format!("{:02x}", c))
+                .collect::<Vec<_>>();
+            write!(f, ", unknown codes {}", codes.join(" "))?;
+        }
+        write!(f, ")")
+    }
+}
+
+// Sequences longer than this are assumed to have gone off into the
+// weeds.
+const MAX_SEQUENCE_LEN: usize = 0x4000;
+
+// Statically walk a sequence, returning its length in bytes, or the
+// first code we don't understand, if any.
+fn walk_sequence(variant: &Variant, data: &[u8], addr: usize) -> Result<usize, Option<u8>> {
+    let start = addr;
+    let end = data.len().min(addr + MAX_SEQUENCE_LEN);
+    let mut addr = addr;
+    while addr < end {
+        let code = data[addr];
+        let len = variant.operand_len(code).ok_or(Some(code))?;
+        if variant.is_terminator(code) {
+            return Ok((addr + 1 + len).min(data.len()) - start);
+        }
+        addr += 1 + len;
+    }
+    Err(None)
+}
+
+// The bytes making up a sequence, as far as we can tell.
+pub fn sequence_bytes<'a>(variant: &Variant, data: &'a [u8], addr: usize) -> &'a [u8] {
+    match walk_sequence(variant, data, addr) {
+        Ok(len) => &data[addr..][..len],
+        Err(_) => &[],
+    }
+}
+
+// Check whether the data fits the given driver revision, based on
+// whether the sequences only use commands it knows about and the
+// instrument table has the layout we expect.
+pub fn detect(
+    variant: &'static Variant,
+    data: &[u8],
+    sequences: &[usize],
+    num_instruments: usize,
+) -> Detection {
+    let mut clean_sequences = 0;
+    let mut unknown_codes = Vec::new();
+    // Skip the empty sequence.
+    for &addr in sequences.iter().skip(1) {
+        match walk_sequence(variant, data, addr) {
+            Ok(_) => clean_sequences += 1,
+            Err(Some(code)) if !unknown_codes.contains(&code) => unknown_codes.push(code),
+            Err(_) => (),
+        }
+    }
+    unknown_codes.sort();
+
+    let total_sequences = sequences.len().saturating_sub(1);
+    let plausible_instruments = scanner::count_instruments(data).min(num_instruments);
+    let version = if clean_sequences == total_sequences && plausible_instruments == num_instruments
+    {
+        DriverVersion::Known(variant.title)
+    } else {
+        DriverVersion::Unknown
+    };
+
+    Detection {
+        version,
+        clean_sequences,
+        total_sequences,
+        plausible_instruments,
+        total_instruments: num_instruments,
+        unknown_codes,
+    }
+}
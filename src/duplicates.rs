@@ -0,0 +1,129 @@
+//
+// Speedball 2 Sound player
+//
+// duplicates.rs: Find instruments whose samples are the same data,
+// either by pointing at the same bytes or at identical copies, or
+// whose samples overlap. Sharing is how one sample gets used at
+// several pitches or loop points, while identical copies are wasted
+// space.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+
+use egui::{Grid, Ui};
+
+use crate::sound_player::{Instrument, SoundBank};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Relation {
+    // Exactly the same bytes of the bank.
+    Shared,
+    // The same data, stored twice.
+    Identical,
+    // Partly the same bytes of the bank, given as bank addresses.
+    Overlap(Range<usize>),
+}
+
+impl fmt::Display for Relation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Relation::Shared => write!(f, "Same sample as"),
+            Relation::Identical => write!(f, "Identical sample data to"),
+            Relation::Overlap(range) => write!(
+                f,
+                "Sample overlaps at 0x{:06x}-0x{:06x} with",
+                range.start,
+                range.end - 1
+            ),
+        }
+    }
+}
+
+// A pair of instruments with related samples, with the lower index
+// first.
+#[derive(Clone, Debug)]
+pub struct Duplicate {
+    pub instruments: (usize, usize),
+    pub relation: Relation,
+}
+
+fn sample_range(instrument: &Instrument) -> Range<usize> {
+    instrument.sample_addr..instrument.sample_addr + instrument.sample_len as usize * 2
+}
+
+pub fn find(bank: &SoundBank) -> Vec<Duplicate> {
+    let ranges = bank
+        .instruments
+        .iter()
+        .map(sample_range)
+        .collect::<Vec<_>>();
+
+    // Group by contents to find copies.
+    let mut by_contents: HashMap<&[u8], usize> = HashMap::new();
+    let mut duplicates = Vec::new();
+    for (idx, range) in ranges.iter().enumerate() {
+        if range.is_empty() {
+            continue;
+        }
+        let first = *by_contents.entry(&bank.data[range.clone()]).or_insert(idx);
+        if first != idx && ranges[first] != *range {
+            duplicates.push(Duplicate {
+                instruments: (first, idx),
+                relation: Relation::Identical,
+            });
+        }
+    }
+
+    for (a, range_a) in ranges.iter().enumerate() {
+        for (b, range_b) in ranges.iter().enumerate().skip(a + 1) {
+            let start = range_a.start.max(range_b.start);
+            let end = range_a.end.min(range_b.end);
+            if start >= end {
+                continue;
+            }
+            let relation = if range_a == range_b {
+                Relation::Shared
+            } else {
+                Relation::Overlap(start..end)
+            };
+            duplicates.push(Duplicate {
+                instruments: (a, b),
+                relation,
+            });
+        }
+    }
+    duplicates.sort_by_key(|d| d.instruments);
+    duplicates
+}
+
+// The duplicates involving an instrument, with the other instrument.
+pub fn involving(duplicates: &[Duplicate], idx: usize) -> Vec<(usize, &Relation)> {
+    duplicates
+        .iter()
+        .filter_map(|d| match d.instruments {
+            (a, b) if a == idx => Some((b, &d.relation)),
+            (a, b) if b == idx => Some((a, &d.relation)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn ui(ui: &mut Ui, duplicates: &[Duplicate]) {
+    if duplicates.is_empty() {
+        ui.label("Every instrument has its own sample.");
+        return;
+    }
+    Grid::new("Duplicates").striped(true).show(ui, |ui| {
+        for duplicate in duplicates {
+            let (a, b) = duplicate.instruments;
+            ui.label(format!("Instrument {:02x}", a));
+            ui.label(duplicate.relation.to_string());
+            ui.label(format!("instrument {:02x}", b));
+            ui.end_row();
+        }
+    });
+}
@@ -0,0 +1,262 @@
+//
+// Speedball 2 Sound player
+//
+// duration.rs: Estimate how long a sequence plays for without playing
+// it, by walking its commands and adding up the note lengths at the
+// tempo in force, going round For loops, into Calls and along Jumps.
+//
+// Looping music is measured once through: up to the Restart, or the
+// Jump back to somewhere it's already been. Such sequences never end
+// by themselves, so are marked in the browser, and exports stop them
+// after one pass rather than running on to the time limit.
+//
+// The same walk lists the notes played and when, for the piano roll.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+use crate::driver::Op;
+use crate::sound_data::SOUNDS;
+use crate::sound_player::{Command, SoundBank, MAX_STACK_DEPTH};
+
+// Give up on sequences that run this many commands, as they're
+// probably stuck.
+const MAX_COMMANDS: usize = 1_000_000;
+
+// How a sequence goes round again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Repeat {
+    // Command 0x88, back to its own start.
+    Restart,
+    // Command 0xd4, to a sequence it's already been through.
+    Jump(u8),
+}
+
+impl fmt::Display for Repeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repeat::Restart => write!(f, "restarts"),
+            Repeat::Jump(idx) => write!(f, "jumps back to sequence {:02x}", idx),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Estimate {
+    pub frames: usize,
+    // Of the bank's driver, to turn frames into time.
+    pub frame_rate: f32,
+    // Repeats forever, if allowed to. `frames` is one time through.
+    pub repeat: Option<Repeat>,
+    // Where repeats go back to, in frames from the start.
+    pub loop_frame: usize,
+}
+
+impl Estimate {
+    pub fn seconds(&self) -> f32 {
+        self.frames as f32 / self.frame_rate
+    }
+
+    pub fn loops(&self) -> bool {
+        self.repeat.is_some()
+    }
+
+    // Goes round without any time passing, so the interpreter has to
+    // give up on it.
+    pub fn is_stuck(&self) -> bool {
+        self.loops() && self.frames == 0
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let seconds = self.seconds();
+        write!(f, "{}:{:05.2}", (seconds / 60.0) as usize, seconds % 60.0)?;
+        if self.loops() {
+            write!(f, ", looping")?;
+        }
+        Ok(())
+    }
+}
+
+// A note played, as found walking a sequence. Scripts only count
+// them; the piano roll reads the fields.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+#[derive(Clone, Copy, Debug)]
+pub struct Note {
+    // When it starts, and how long until the next note or rest, in
+    // frames.
+    pub frame: usize,
+    pub frames: usize,
+    // In quarter semitones, transposition included, counting note 0
+    // as 0.
+    pub pitch: isize,
+    pub instrument: usize,
+}
+
+// Walk the sequence as the interpreter would, returning None if it
+// goes wrong in a way that would make the interpreter give up.
+pub fn estimate(bank: &SoundBank, idx: usize) -> Option<Estimate> {
+    walk(bank, idx, |_| ())
+}
+
+// The notes of one pass through the sequence, and how long it is.
+pub fn notes(bank: &SoundBank, idx: usize) -> (Vec<Note>, Option<Estimate>) {
+    let mut notes = Vec::new();
+    let estimate = walk(bank, idx, |note| notes.push(note));
+    (notes, estimate)
+}
+
+fn walk(bank: &SoundBank, idx: usize, mut on_note: impl FnMut(Note)) -> Option<Estimate> {
+    let data = &bank.data;
+    let frame_rate = bank.quirks.frame_rate;
+    let start = *bank.sequences.get(idx)?;
+    let mut addr = start;
+    let mut frames_per_beat = 0;
+    let mut note_len = 0;
+    let mut frames = 0;
+    let mut transposition = 0;
+    let mut instrument = 0;
+    // Loop counts (0 for calls) and where to go back to.
+    let mut stack: Vec<(u8, usize)> = Vec::new();
+    // Places jumped to, and when they were first reached.
+    let mut jump_targets = vec![(start, 0)];
+
+    for _ in 0..MAX_COMMANDS {
+        let code = *data.get(addr)?;
+        addr += 1;
+        // A note or rest that's had no length still takes a frame.
+        if code < 0x80 {
+            on_note(Note {
+                frame: frames,
+                frames: note_len.max(1),
+                pitch: code as isize * 4 + transposition,
+                instrument,
+            });
+            frames += note_len.max(1);
+            continue;
+        }
+        let Some(op) = (bank.variant.decode)(code) else {
+            if bank.quirks.skips(code) {
+                continue;
+            }
+            return None;
+        };
+        let operand = if op.operand_len() > 0 {
+            addr += 1;
+            data.get(addr - 1).copied().unwrap_or(0)
+        } else {
+            0
+        };
+        match op {
+            Op::Rest => frames += note_len.max(1),
+            // The length is fixed at the tempo when it's set.
+            Op::NoteLen => note_len = operand as usize * frames_per_beat,
+            Op::Tempo => frames_per_beat = 750usize.checked_div(operand as usize)?,
+            Op::Restart => {
+                return Some(Estimate {
+                    frames,
+                    frame_rate,
+                    repeat: Some(Repeat::Restart),
+                    loop_frame: 0,
+                })
+            }
+            Op::Stop => {
+                return Some(Estimate {
+                    frames,
+                    frame_rate,
+                    repeat: None,
+                    loop_frame: 0,
+                })
+            }
+            Op::Call => {
+                if stack.len() >= MAX_STACK_DEPTH {
+                    return None;
+                }
+                stack.push((0, addr));
+                addr = *bank.sequences.get(operand as usize)?;
+            }
+            Op::Return => match stack.pop() {
+                Some((0, ret_addr)) => addr = ret_addr,
+                Some(_) => return None,
+                None => {
+                    return Some(Estimate {
+                        frames,
+                        frame_rate,
+                        repeat: None,
+                        loop_frame: 0,
+                    })
+                }
+            },
+            Op::For => {
+                if stack.len() >= MAX_STACK_DEPTH {
+                    return None;
+                }
+                stack.push((operand, addr));
+            }
+            Op::Next => {
+                let (count, loop_addr) = stack.last_mut()?;
+                if *count == 0 {
+                    stack.pop();
+                } else {
+                    *count -= 1;
+                    addr = *loop_addr;
+                }
+            }
+            Op::Jump => {
+                let target = *bank.sequences.get(operand as usize)?;
+                if let Some(&(_, loop_frame)) = jump_targets.iter().find(|(t, _)| *t == target) {
+                    return Some(Estimate {
+                        frames,
+                        frame_rate,
+                        repeat: Some(Repeat::Jump(operand)),
+                        loop_frame,
+                    });
+                }
+                jump_targets.push((target, frames));
+                addr = target;
+            }
+            Op::AddTransposition if operand == 0 => transposition = 0,
+            Op::AddTransposition => transposition += operand as i8 as isize,
+            Op::SetTransposition => transposition = operand as i8 as isize,
+            Op::Instrument => instrument = operand as usize,
+            Op::Volume | Op::Effect | Op::EffectLoops => (),
+        }
+    }
+    None
+}
+
+// How long to record a command for, so that looping music comes out
+// once through rather than cut off at an arbitrary point: as long as
+// its longest sequence, if any of them loop. Anything else, or music
+// that stops itself after a number of passes (or is stuck, and
+// bailed out of), stops recording by itself, so gets the full
+// max_time_s.
+pub fn record_seconds(bank: &SoundBank, command: &Command, passes: usize, max_time_s: f32) -> f32 {
+    let sequences = match command {
+        Command::Sequence(idx) => vec![*idx],
+        #[cfg(feature = "gui")]
+        Command::SequenceFrom { idx, .. } => vec![*idx],
+        Command::Sound(idx) | Command::Music(idx) => SOUNDS[*idx].sequences.to_vec(),
+        #[cfg(feature = "gui")]
+        Command::Song(sequences) => sequences.to_vec(),
+        _ => Vec::new(),
+    };
+    let estimates = sequences
+        .into_iter()
+        .filter(|&idx| idx != 0)
+        .map(|idx| estimate(bank, idx))
+        .collect::<Option<Vec<_>>>();
+    match estimates {
+        Some(estimates) if passes == 0 && estimates.iter().any(|e| e.loops() && !e.is_stuck()) => {
+            estimates
+                .iter()
+                .map(Estimate::seconds)
+                .fold(0.0, f32::max)
+                .min(max_time_s)
+        }
+        _ => max_time_s,
+    }
+}
@@ -0,0 +1,121 @@
+//
+// Speedball 2 Sound player
+//
+// export.rs: Dump what we know about a bank's structure - sequences,
+// instruments and the sounds table - as JSON, for other tools.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use rfd::FileDialog;
+
+use crate::annotations::Annotations;
+use crate::driver;
+use crate::json::{self, Value};
+use crate::sound_data::SOUNDS;
+use crate::sound_player::{Instrument, SoundBank};
+
+// Decode a sequence into one object per note or command.
+fn ops(bank: &SoundBank, addr: usize) -> Value {
+    let bytes = driver::sequence_bytes(bank.variant, &bank.data, addr);
+    let mut ops = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let code = bytes[offset];
+        let mut fields = vec![("addr", (addr + offset).into())];
+        let len = if code < 0x80 {
+            fields.push(("note", (code as usize).into()));
+            0
+        } else {
+            // sequence_bytes only returns sequences that decode.
+            let op = (bank.variant.decode)(code).unwrap();
+            fields.push(("op", format!("{:?}", op).into()));
+            if op.operand_len() > 0 {
+                let operand = bytes.get(offset + 1).copied().unwrap_or(0);
+                fields.push(("operand", (operand as usize).into()));
+            }
+            op.operand_len()
+        };
+        ops.push(json::object(fields));
+        offset += 1 + len;
+    }
+    Value::Array(ops)
+}
+
+// Add the name field, if there is one.
+fn named(mut fields: Vec<(&str, Value)>, name: Option<&String>) -> Value {
+    if let Some(name) = name {
+        fields.insert(1, ("name", name.as_str().into()));
+    }
+    json::object(fields)
+}
+
+fn instrument(idx: usize, instr: &Instrument, annotations: &Annotations) -> Value {
+    let fields = vec![
+        ("index", idx.into()),
+        ("is_one_shot", instr.is_one_shot.into()),
+        ("loop_offset", (instr.loop_offset as usize).into()),
+        ("sample_len", (instr.sample_len as usize).into()),
+        ("sample_addr", instr.sample_addr.into()),
+        ("base_octave", instr.base_octave.into()),
+    ];
+    named(fields, annotations.instruments.get(&idx))
+}
+
+pub fn metadata(bank: &SoundBank, annotations: &Annotations) -> Value {
+    let sequences = bank
+        .sequences
+        .iter()
+        .enumerate()
+        .map(|(idx, &addr)| {
+            let fields = vec![
+                ("index", idx.into()),
+                ("addr", addr.into()),
+                ("ops", ops(bank, addr)),
+            ];
+            named(fields, annotations.sequences.get(&idx))
+        })
+        .collect::<Vec<_>>();
+    let instruments = bank
+        .instruments
+        .iter()
+        .enumerate()
+        .map(|(idx, instr)| instrument(idx, instr, annotations))
+        .collect::<Vec<_>>();
+    let sounds = SOUNDS
+        .iter()
+        .enumerate()
+        .map(|(idx, sound)| {
+            let fields = vec![
+                ("index", idx.into()),
+                ("sequences", sound.sequences.to_vec().into()),
+            ];
+            named(fields, annotations.sounds.get(&idx))
+        })
+        .collect::<Vec<_>>();
+    json::object(vec![
+        ("driver", bank.variant.name.into()),
+        ("sequences", sequences.into()),
+        ("instruments", instruments.into()),
+        ("sounds", sounds.into()),
+    ])
+}
+
+// Ask where to save the metadata, and write it there.
+#[cfg(feature = "gui")]
+pub fn save_metadata(
+    bank: &SoundBank,
+    annotations: &Annotations,
+    default_name: &str,
+) -> Result<(), String> {
+    let file_name = FileDialog::new()
+        .add_filter("JSON", &["json"])
+        .set_file_name(default_name)
+        .save_file();
+    match file_name {
+        Some(name) => std::fs::write(&name, format!("{}\n", metadata(bank, annotations)))
+            .map_err(|err| format!("{}: {}", name.display(), err)),
+        None => Ok(()),
+    }
+}
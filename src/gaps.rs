@@ -0,0 +1,165 @@
+//
+// Speedball 2 Sound player
+//
+// gaps.rs: Compare the sounds table with the sequences, to find
+// sequences that no sound plays and no other sequence calls or jumps
+// to, and sounds that play sequences with nothing in them. Either is
+// a hint of leftover content: music that was cut, or a sound whose
+// sequence was emptied out late on.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+#[cfg(feature = "gui")]
+use egui::{Grid, Ui};
+
+use crate::driver;
+use crate::report::{self, Refs};
+use crate::sound_data::SOUNDS;
+use crate::sound_player::SoundBank;
+
+// Why a sound's sequence is empty.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Emptiness {
+    // The bank's table doesn't go that far.
+    Missing,
+    // Doesn't decode with the bank's driver.
+    Undecodable,
+    // Decodes, but plays no notes, even through what it calls.
+    NoNotes,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Gap {
+    // A sequence nothing refers to.
+    Orphan(usize),
+    // A sound channel playing an empty sequence.
+    EmptySequence {
+        sound: usize,
+        channel: usize,
+        sequence: usize,
+        why: Emptiness,
+    },
+}
+
+impl fmt::Display for Emptiness {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Emptiness::Missing => write!(f, "which isn't in the bank"),
+            Emptiness::Undecodable => write!(f, "which doesn't decode"),
+            Emptiness::NoNotes => write!(f, "which plays no notes"),
+        }
+    }
+}
+
+impl fmt::Display for Gap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gap::Orphan(idx) => write!(
+                f,
+                "Sequence {:02x} isn't in any sound, or called or jumped to",
+                idx
+            ),
+            Gap::EmptySequence {
+                sound,
+                channel,
+                sequence,
+                why,
+            } => write!(
+                f,
+                "Sound {:02x} channel {} plays sequence {:02x}, {}",
+                sound, channel, sequence, why
+            ),
+        }
+    }
+}
+
+// Whether a sequence plays any notes, following calls and jumps.
+fn has_notes(all_refs: &[Refs], idx: usize, seen: &mut [bool]) -> bool {
+    if seen[idx] {
+        return false;
+    }
+    seen[idx] = true;
+    all_refs[idx].notes > 0
+        || all_refs[idx]
+            .calls
+            .iter()
+            .chain(all_refs[idx].jumps.iter())
+            .filter(|&&target| target < all_refs.len())
+            .any(|&target| has_notes(all_refs, target, seen))
+}
+
+fn emptiness(bank: &SoundBank, all_refs: &[Refs], idx: usize) -> Option<Emptiness> {
+    let Some(&addr) = bank.sequences.get(idx) else {
+        return Some(Emptiness::Missing);
+    };
+    if driver::sequence_bytes(bank.variant, &bank.data, addr).is_empty() {
+        return Some(Emptiness::Undecodable);
+    }
+    let mut seen = vec![false; bank.sequences.len()];
+    (!has_notes(all_refs, idx, &mut seen)).then_some(Emptiness::NoNotes)
+}
+
+pub fn find(bank: &SoundBank) -> Vec<Gap> {
+    let all_refs = bank
+        .sequences
+        .iter()
+        .map(|&addr| report::refs(bank, addr))
+        .collect::<Vec<_>>();
+
+    // Anything referred to counts, even from another orphan, as it
+    // may be part of the same piece.
+    let mut referenced = vec![false; bank.sequences.len()];
+    let targets = SOUNDS
+        .iter()
+        .flat_map(|sound| sound.sequences.iter())
+        .chain(
+            all_refs
+                .iter()
+                .flat_map(|refs| refs.calls.iter().chain(refs.jumps.iter())),
+        );
+    for &target in targets {
+        if let Some(flag) = referenced.get_mut(target) {
+            *flag = true;
+        }
+    }
+    // Skip the empty sequence.
+    let mut gaps = (1..bank.sequences.len())
+        .filter(|&idx| !referenced[idx])
+        .map(Gap::Orphan)
+        .collect::<Vec<_>>();
+
+    for (sound, entry) in SOUNDS.iter().enumerate() {
+        for (channel, &sequence) in entry.sequences.iter().enumerate() {
+            // 0 is how the table says "leave this channel alone".
+            if sequence == 0 {
+                continue;
+            }
+            if let Some(why) = emptiness(bank, &all_refs, sequence) {
+                gaps.push(Gap::EmptySequence {
+                    sound,
+                    channel,
+                    sequence,
+                    why,
+                });
+            }
+        }
+    }
+    gaps
+}
+
+#[cfg(feature = "gui")]
+pub fn ui(ui: &mut Ui, gaps: &[Gap]) {
+    if gaps.is_empty() {
+        ui.label("Every sequence is used, and every sound plays something.");
+        return;
+    }
+    Grid::new("Gaps").striped(true).show(ui, |ui| {
+        for gap in gaps {
+            ui.label(gap.to_string());
+            ui.end_row();
+        }
+    });
+}
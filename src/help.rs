@@ -0,0 +1,116 @@
+//
+// Speedball 2 Sound player
+//
+// help.rs: A built-in reference to the bank format and sequence
+// commands, so listings can be understood without reading the
+// source. The command table comes from the driver description, so it
+// matches what the player and exports decode.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use egui::{CollapsingHeader, Grid, RichText, Ui};
+
+use crate::driver::{Op, Variant};
+use crate::sound_player::Instrument;
+
+fn fields_ui(ui: &mut Ui, id: &str, fields: &[(&str, &str, &str)]) {
+    Grid::new(id).striped(true).show(ui, |ui| {
+        ui.strong("Offset");
+        ui.strong("Size");
+        ui.strong("Meaning");
+        ui.end_row();
+        for (offset, size, meaning) in fields {
+            ui.label(RichText::new(*offset).monospace());
+            ui.label(*size);
+            ui.label(*meaning);
+            ui.end_row();
+        }
+    });
+}
+
+fn commands_ui(ui: &mut Ui, variant: &Variant) {
+    ui.label(
+        "A sequence is a list of bytes run one at a time. Bytes below 0x80 are \
+         notes, in semitones, played with the current instrument for the current \
+         note length, plus the transposition. The rest are commands, most \
+         followed by a one-byte operand:",
+    );
+    Grid::new("HelpCommands").striped(true).show(ui, |ui| {
+        ui.strong("Code");
+        ui.strong("Command");
+        ui.strong("Operand");
+        ui.strong("Meaning");
+        ui.end_row();
+        for op in Op::ALL {
+            let code = variant
+                .encode(op)
+                .map_or_else(|| "--".to_string(), |code| format!("{:02x}", code));
+            ui.label(RichText::new(code).monospace());
+            ui.label(format!("{:?}", op));
+            ui.label(op.operand_name());
+            ui.label(op.description());
+            ui.end_row();
+        }
+    });
+    ui.label(
+        "Restart, Stop, Return and Jump end a sequence's bytes, as execution \
+         doesn't fall through them.",
+    );
+}
+
+pub fn ui(ui: &mut Ui, variant: &Variant) {
+    ui.label(format!("Driver: {}", variant.title));
+    CollapsingHeader::new("Bank layout")
+        .default_open(true)
+        .show(ui, |ui| {
+            ui.label("All values are big-endian, and addresses are offsets into the bank.");
+            fields_ui(
+                ui,
+                "HelpBank",
+                &[
+                    ("0x00", "4", "Address of the sequence table"),
+                    ("0x04", "4", "Address of the instrument table"),
+                ],
+            );
+            ui.label(
+                "The sequence table is a list of 4-byte sequence addresses, and the \
+                 instrument table a list of instrument records. The bank doesn't say \
+                 how many entries each has, hence --sequences and --instruments. \
+                 Sequence 0 is empty.",
+            );
+        });
+    CollapsingHeader::new(format!("Instrument records ({} bytes)", Instrument::SIZE)).show(
+        ui,
+        |ui| {
+            fields_ui(
+                ui,
+                "HelpInstrument",
+                &[
+                    (
+                        "0x00",
+                        "2",
+                        "1 if the sample plays once, otherwise it loops",
+                    ),
+                    (
+                        "0x02",
+                        "2",
+                        "Loop start, in bytes from the start of the sample",
+                    ),
+                    ("0x04", "2", "Sample length, in words"),
+                    ("0x06", "4", "Sample address"),
+                    ("0x0a", "4", "Base octave"),
+                ],
+            );
+            ui.label("Samples are signed 8-bit.");
+        },
+    );
+    CollapsingHeader::new("Sequence commands").show(ui, |ui| commands_ui(ui, variant));
+    CollapsingHeader::new("Sounds").show(ui, |ui| {
+        ui.label(
+            "Sounds are what the game asks for. Each starts up to four sequences, one \
+             per channel, with 0 leaving the channel alone. The table is built into the \
+             game's code rather than the bank, so the player has its own copy.",
+        );
+    });
+}
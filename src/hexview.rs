@@ -0,0 +1,197 @@
+//
+// Speedball 2 Sound player
+//
+// hexview.rs: A hex dump of the bank, where hovering over a byte says
+// which structure it belongs to, and structures picked in the browser
+// are highlighted and scrolled to. Above it, a map of the whole bank
+// coloured by what each part is, which can be clicked to jump there.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use egui::{pos2, vec2, Color32, Rect, RichText, ScrollArea, Sense, Stroke, Ui};
+
+use crate::coverage::{self, Owner};
+use crate::sound_player::SoundBank;
+
+const BYTES_PER_ROW: usize = 16;
+const MAP_HEIGHT: f32 = 24.0;
+
+// The kinds of data shown in the memory map, with their colours.
+const KINDS: [(&str, Color32); 5] = [
+    ("Tables", Color32::from_rgb(200, 200, 80)),
+    ("Instrument records", Color32::from_rgb(200, 110, 40)),
+    ("Sequence code", Color32::from_rgb(80, 160, 220)),
+    ("Sample data", Color32::from_rgb(80, 180, 90)),
+    ("Unknown", Color32::from_gray(60)),
+];
+
+fn kind(owner: Option<Owner>) -> usize {
+    match owner {
+        Some(Owner::Header | Owner::SequenceTable) => 0,
+        Some(Owner::Instrument(_)) => 1,
+        Some(Owner::Sequence(_)) => 2,
+        Some(Owner::Sample(_)) => 3,
+        None => 4,
+    }
+}
+
+pub struct HexView {
+    bank: Arc<SoundBank>,
+    owners: Vec<Option<Owner>>,
+    selection: Option<Range<usize>>,
+    // Scroll to the selection on the next draw.
+    scroll_pending: bool,
+}
+
+impl HexView {
+    pub fn new(bank: Arc<SoundBank>) -> HexView {
+        HexView {
+            owners: coverage::owners(&bank),
+            bank,
+            selection: None,
+            scroll_pending: false,
+        }
+    }
+
+    pub fn bank(&self) -> &Arc<SoundBank> {
+        &self.bank
+    }
+
+    pub fn select(&mut self, owner: Owner) {
+        self.selection = Some(coverage::span(&self.bank, owner));
+        self.scroll_pending = true;
+    }
+
+    // Select whatever's at the address, and scroll to it.
+    fn jump(&mut self, addr: usize) {
+        self.selection = Some(match self.owners[addr] {
+            Some(owner) => coverage::span(&self.bank, owner),
+            None => addr..addr + 1,
+        });
+        self.scroll_pending = true;
+    }
+
+    // One column per pixel, coloured by the commonest kind of data in
+    // the bytes it covers.
+    fn map_ui(&mut self, ui: &mut Ui) {
+        let len = self.owners.len();
+        let (rect, response) =
+            ui.allocate_exact_size(vec2(ui.available_width(), MAP_HEIGHT), Sense::click());
+        if len == 0 {
+            return;
+        }
+        let width = rect.width().max(1.0) as usize;
+        let x_to_addr = |x: f32| {
+            let frac = ((x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            ((frac * len as f32) as usize).min(len - 1)
+        };
+        let addr_to_x = |addr: usize| rect.left() + rect.width() * addr as f32 / len as f32;
+
+        let painter = ui.painter_at(rect);
+        for column in 0..width {
+            let start = column * len / width;
+            let end = ((column + 1) * len / width).max(start + 1).min(len);
+            let mut counts = [0; KINDS.len()];
+            for owner in &self.owners[start..end] {
+                counts[kind(*owner)] += 1;
+            }
+            let commonest = (0..KINDS.len()).max_by_key(|&k| counts[k]).unwrap();
+            let x = rect.left() + column as f32;
+            painter.rect_filled(
+                Rect::from_min_max(pos2(x, rect.top()), pos2(x + 1.0, rect.bottom())),
+                0.0,
+                KINDS[commonest].1,
+            );
+        }
+        if let Some(sel) = &self.selection {
+            let sel_rect = Rect::from_min_max(
+                pos2(addr_to_x(sel.start), rect.top()),
+                pos2(
+                    addr_to_x(sel.end).max(addr_to_x(sel.start) + 2.0),
+                    rect.bottom(),
+                ),
+            );
+            painter.rect_stroke(sel_rect, 0.0, Stroke::new(2.0, Color32::WHITE));
+        }
+
+        if let Some(pos) = response.hover_pos() {
+            let addr = x_to_addr(pos.x);
+            let text = match self.owners[addr] {
+                Some(owner) => format!("0x{:06x}: {}", addr, owner),
+                None => format!("0x{:06x}: Unreferenced", addr),
+            };
+            response.clone().on_hover_text(text);
+        }
+        if response.clicked() {
+            if let Some(pos) = response.interact_pointer_pos() {
+                self.jump(x_to_addr(pos.x));
+            }
+        }
+
+        ui.horizontal(|ui| {
+            for (name, colour) in KINDS {
+                ui.colored_label(colour, "\u{25a0}");
+                ui.label(name);
+            }
+        });
+    }
+
+    fn byte_ui(&self, ui: &mut Ui, addr: usize) {
+        let mut text = RichText::new(format!("{:02x}", self.bank.data[addr])).monospace();
+        if self
+            .selection
+            .as_ref()
+            .is_some_and(|sel| sel.contains(&addr))
+        {
+            text = text.background_color(Color32::DARK_BLUE);
+        } else if self.owners[addr].is_none() {
+            text = text.color(Color32::DARK_GRAY);
+        }
+        let response = ui.label(text);
+        response.on_hover_ui(|ui| {
+            match self.owners[addr] {
+                Some(owner) => ui.label(format!("0x{:06x}: {}", addr, owner)),
+                None => ui.label(format!("0x{:06x}: Unreferenced", addr)),
+            };
+        });
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui) {
+        self.map_ui(ui);
+        if let Some(sel) = &self.selection {
+            ui.label(format!(
+                "Selected 0x{:06x}-0x{:06x}",
+                sel.start,
+                sel.end.saturating_sub(1)
+            ));
+        }
+
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        let row_pitch = row_height + ui.spacing().item_spacing.y;
+        let num_rows = self.bank.data.len().div_ceil(BYTES_PER_ROW);
+        let mut scroll_area = ScrollArea::vertical().auto_shrink([false, false]);
+        if self.scroll_pending {
+            if let Some(sel) = &self.selection {
+                let row = sel.start / BYTES_PER_ROW;
+                scroll_area = scroll_area.vertical_scroll_offset(row as f32 * row_pitch);
+            }
+            self.scroll_pending = false;
+        }
+        scroll_area.show_rows(ui, row_height, num_rows, |ui, rows| {
+            for row in rows {
+                ui.horizontal(|ui| {
+                    let start = row * BYTES_PER_ROW;
+                    let end = self.bank.data.len().min(start + BYTES_PER_ROW);
+                    ui.label(RichText::new(format!("{:06x}", start)).monospace());
+                    for addr in start..end {
+                        self.byte_ui(ui, addr);
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,170 @@
+//
+// Speedball 2 Sound player
+//
+// hunk.rs: Split an AmigaDOS executable into its hunks, so the sound
+// bank can be found in the game's program file. The bank's addresses
+// are relative to its own start, so it doesn't need relocating, we
+// just have to get the hunk's data out from between the headers.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use crate::sound_player::{long, word};
+
+// Hunk types. The top two bits hold memory flags.
+const HUNK_NAME: u32 = 0x3e8;
+const HUNK_CODE: u32 = 0x3e9;
+const HUNK_DATA: u32 = 0x3ea;
+const HUNK_BSS: u32 = 0x3eb;
+const HUNK_RELOC32: u32 = 0x3ec;
+const HUNK_SYMBOL: u32 = 0x3f0;
+const HUNK_DEBUG: u32 = 0x3f1;
+const HUNK_END: u32 = 0x3f2;
+const HUNK_HEADER: u32 = 0x3f3;
+const HUNK_RELOC32SHORT: u32 = 0x3fc;
+const TYPE_MASK: u32 = 0x3fff_ffff;
+
+pub struct Hunk {
+    // Index of the hunk, and "code" or "data".
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn long(&mut self) -> Result<u32, String> {
+        if self.pos + 4 > self.data.len() {
+            return Err(format!("Truncated at 0x{:x}", self.pos));
+        }
+        let value = long(self.data, self.pos);
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        Ok(word(self.bytes(2)?, 0))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| format!("Truncated at 0x{:x}", self.pos))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn skip_longs(&mut self, num: u32) -> Result<(), String> {
+        self.bytes(num as usize * 4).map(|_| ())
+    }
+
+    // Relocation tables are lists of (count, hunk, offsets...),
+    // ended by a zero count.
+    fn skip_relocs(&mut self) -> Result<(), String> {
+        loop {
+            let num = self.long()?;
+            if num == 0 {
+                return Ok(());
+            }
+            self.skip_longs(num + 1)?;
+        }
+    }
+
+    // As skip_relocs, but with 16-bit entries, padded to a long at
+    // the end.
+    fn skip_short_relocs(&mut self) -> Result<(), String> {
+        let start = self.pos;
+        loop {
+            let num = self.word()?;
+            if num == 0 {
+                break;
+            }
+            self.bytes((num as usize + 1) * 2)?;
+        }
+        if !(self.pos - start).is_multiple_of(4) {
+            self.word()?;
+        }
+        Ok(())
+    }
+
+    // Symbol tables are lists of (name length, name, value), ended
+    // by a zero length.
+    fn skip_symbols(&mut self) -> Result<(), String> {
+        loop {
+            let num = self.long()? & 0xff_ffff;
+            if num == 0 {
+                return Ok(());
+            }
+            self.skip_longs(num + 1)?;
+        }
+    }
+}
+
+// The code and data hunks of an executable, in order. BSS hunks have
+// no contents, so are skipped.
+pub fn hunks(data: &[u8]) -> Result<Vec<Hunk>, String> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.long()? != HUNK_HEADER {
+        return Err("Not an AmigaDOS executable".to_string());
+    }
+    // Resident library names, which should be empty.
+    loop {
+        let num = reader.long()?;
+        if num == 0 {
+            break;
+        }
+        reader.skip_longs(num)?;
+    }
+    let _table_size = reader.long()?;
+    let first = reader.long()?;
+    let last = reader.long()?;
+    if last < first {
+        return Err(format!("Bad hunk range {}-{}", first, last));
+    }
+    for _ in first..=last {
+        // Both memory flag bits set means an extra attributes word.
+        if reader.long()? >> 30 == 3 {
+            reader.long()?;
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let mut idx = first;
+    // Allow for padding on the end.
+    while reader.pos + 4 <= data.len() {
+        let kind = reader.long()? & TYPE_MASK;
+        match kind {
+            HUNK_CODE | HUNK_DATA => {
+                let len = reader.long()? as usize * 4;
+                let kind_name = if kind == HUNK_CODE { "code" } else { "data" };
+                hunks.push(Hunk {
+                    name: format!("hunk {} ({})", idx, kind_name),
+                    data: reader.bytes(len)?.to_vec(),
+                });
+            }
+            HUNK_BSS => {
+                reader.long()?;
+            }
+            HUNK_RELOC32 => reader.skip_relocs()?,
+            HUNK_RELOC32SHORT => reader.skip_short_relocs()?,
+            HUNK_SYMBOL => reader.skip_symbols()?,
+            HUNK_NAME | HUNK_DEBUG => {
+                let num = reader.long()?;
+                reader.skip_longs(num)?;
+            }
+            HUNK_END => idx += 1,
+            _ => {
+                return Err(format!(
+                    "Unknown hunk type 0x{:x} at 0x{:x}",
+                    kind,
+                    reader.pos - 4
+                ))
+            }
+        }
+    }
+    Ok(hunks)
+}
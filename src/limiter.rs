@@ -0,0 +1,96 @@
+//
+// Speedball 2 Sound player
+//
+// limiter.rs: A limiter for the master output, to stop the mix
+// clipping when several channels play loud samples at once. Peaks
+// nearing full scale are turned down, starting gently over a soft
+// knee, reacting quickly and recovering slowly so that it doesn't
+// pump. Anything that gets through before it reacts is soft-clipped,
+// rather than hitting the hard limit of the output format.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+// Gain reduction starts half the knee below the threshold, in dB
+// from full scale, and holds peaks at the threshold above the knee.
+const THRESHOLD_DB: f32 = -3.0;
+const KNEE_DB: f32 = 6.0;
+const ATTACK_S: f32 = 0.001;
+const RELEASE_S: f32 = 0.1;
+// Soft clipping takes over above this level, approaching full scale.
+const CLIP_START: f32 = 0.9;
+
+#[derive(Clone)]
+pub struct Limiter {
+    // Gain being applied, in dB (never positive).
+    gain_db: f32,
+    // The most reduction since the meter last looked, in dB.
+    max_reduction_db: f32,
+    // What the smoothing coefficients were worked out for.
+    sample_rate: u32,
+    attack: f32,
+    release: f32,
+}
+
+// The gain wanted for a peak level, in dB: none below the knee, then
+// curving round to holding the peak at the threshold.
+fn target_db(peak: f32) -> f32 {
+    let over = 20.0 * peak.max(1e-9).log10() - THRESHOLD_DB;
+    if over <= -KNEE_DB / 2.0 {
+        0.0
+    } else if over < KNEE_DB / 2.0 {
+        let into_knee = over + KNEE_DB / 2.0;
+        -into_knee * into_knee / (2.0 * KNEE_DB)
+    } else {
+        -over
+    }
+}
+
+// Pass samples through below CLIP_START, and curve anything above it
+// smoothly towards full scale.
+pub fn soft_clip(x: f32) -> f32 {
+    let level = x.abs();
+    if level <= CLIP_START {
+        return x;
+    }
+    let room = 1.0 - CLIP_START;
+    x.signum() * (CLIP_START + room * ((level - CLIP_START) / room).tanh())
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter {
+            gain_db: 0.0,
+            max_reduction_db: 0.0,
+            sample_rate: 0,
+            attack: 0.0,
+            release: 0.0,
+        }
+    }
+
+    // The gain to apply to the next frame, given its peak level
+    // across the outputs, so that every output is turned down alike.
+    pub fn gain(&mut self, sample_rate: u32, peak: f32) -> f32 {
+        if sample_rate != self.sample_rate {
+            let coeff = |s: f32| (-1.0 / (s * sample_rate as f32)).exp();
+            self.sample_rate = sample_rate;
+            self.attack = coeff(ATTACK_S);
+            self.release = coeff(RELEASE_S);
+        }
+        let target = target_db(peak);
+        let coeff = if target < self.gain_db {
+            self.attack
+        } else {
+            self.release
+        };
+        self.gain_db = target + coeff * (self.gain_db - target);
+        self.max_reduction_db = self.max_reduction_db.max(-self.gain_db);
+        10f32.powf(self.gain_db / 20.0)
+    }
+
+    // The most gain reduction since last asked, in dB, for a meter.
+    #[cfg(feature = "gui")]
+    pub fn take_reduction_db(&mut self) -> f32 {
+        std::mem::take(&mut self.max_reduction_db)
+    }
+}
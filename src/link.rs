@@ -0,0 +1,85 @@
+//
+// Speedball 2 Sound player
+//
+// link.rs: Links to items in a bank, like "sb2://game/seq/0x12", for
+// cross-referencing from notes. Adding "?play" plays the item when
+// the link is opened.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+const SCHEME: &str = "sb2://";
+const PLAY: &str = "?play";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Item {
+    Instrument(usize),
+    Sequence(usize),
+    Sound(usize),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Link {
+    // Name of the bank, e.g. "intro" or "game".
+    pub bank: String,
+    pub item: Item,
+    pub play: bool,
+}
+
+impl Link {
+    #[cfg(feature = "gui")]
+    pub fn new(bank: &str, item: Item) -> Link {
+        Link {
+            bank: bank.to_string(),
+            item,
+            play: false,
+        }
+    }
+
+    pub fn parse(s: &str) -> Result<Link, String> {
+        let bad = || format!("Bad link '{}', expected e.g. {}game/seq/0x12", s, SCHEME);
+
+        let rest = s.strip_prefix(SCHEME).ok_or_else(bad)?;
+        let (rest, play) = match rest.strip_suffix(PLAY) {
+            Some(rest) => (rest, true),
+            None => (rest, false),
+        };
+        let parts = rest.split('/').collect::<Vec<_>>();
+        let [bank, kind, idx] = parts[..] else {
+            return Err(bad());
+        };
+        let idx = match idx.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => idx.parse(),
+        }
+        .map_err(|_| bad())?;
+        let item = match kind {
+            "instr" => Item::Instrument(idx),
+            "seq" => Item::Sequence(idx),
+            "sound" => Item::Sound(idx),
+            _ => return Err(bad()),
+        };
+        Ok(Link {
+            bank: bank.to_string(),
+            item,
+            play,
+        })
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (kind, idx) = match self.item {
+            Item::Instrument(idx) => ("instr", idx),
+            Item::Sequence(idx) => ("seq", idx),
+            Item::Sound(idx) => ("sound", idx),
+        };
+        write!(f, "{}{}/{}/0x{:02x}", SCHEME, self.bank, kind, idx)?;
+        if self.play {
+            write!(f, "{}", PLAY)?;
+        }
+        Ok(())
+    }
+}
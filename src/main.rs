@@ -6,17 +6,84 @@
 // (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
 //
 
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+#[cfg(feature = "export")]
+use std::path::Path;
+#[cfg(feature = "audio")]
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+#[cfg(feature = "audio")]
+use std::sync::Mutex;
 
 use clap::{Parser, ValueEnum};
 
-use eframe::{App, Frame, NativeOptions};
-use egui::{CentralPanel, Context};
-
+mod adf;
+#[cfg(feature = "audio")]
+mod alloc_count;
+mod annotations;
+#[cfg(feature = "gui")]
+mod app;
+mod archive;
+#[cfg(feature = "gui")]
+mod arpeggio;
+mod asm;
+mod automation;
+#[cfg(feature = "export")]
+mod batch;
+#[cfg(feature = "gui")]
+mod browser;
+mod classify;
+#[cfg(feature = "gui")]
+mod compare;
+#[cfg(feature = "audio")]
+mod control;
+mod coverage;
 mod cpal_wrapper;
+mod disasm;
+mod driver;
+#[cfg(feature = "gui")]
+mod duplicates;
+#[cfg(feature = "export")]
+mod duration;
+mod export;
+mod gaps;
+#[cfg(feature = "gui")]
+mod help;
+#[cfg(feature = "gui")]
+mod hexview;
+mod hunk;
+mod json;
+#[cfg(any(feature = "audio", feature = "export"))]
+mod limiter;
+mod link;
+mod midi;
+#[cfg(feature = "gui")]
+mod midi_import;
+#[cfg(feature = "gui")]
+mod piano_roll;
+mod pitches;
+#[cfg(feature = "gui")]
+mod preview;
+mod quirks;
+mod report;
+#[cfg(feature = "gui")]
+mod sampler;
+mod scanner;
+#[cfg(feature = "export")]
+mod script;
+#[cfg(feature = "audio")]
+mod soak;
 mod sound_data;
 mod sound_player;
+#[cfg(feature = "gui")]
+mod stats;
+#[cfg(any(feature = "audio", feature = "export"))]
+mod stretch;
+#[cfg(feature = "gui")]
+mod timeline;
+#[cfg(feature = "gui")]
+mod tour;
+mod trace;
+mod tuner;
 
 #[derive(Clone, Debug, Parser, ValueEnum)]
 enum Bank {
@@ -27,75 +94,680 @@ enum Bank {
 }
 
 /// Player of Speedball II sounds
-#[derive(Parser, Debug)]
+#[derive(Clone, Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// The sound bank to load
-    #[arg(value_enum)]
-    bank: Bank,
+    #[arg(value_enum, required_unless_present_any = ["file", "adf", "exe", "dump", "scan", "open"])]
+    bank: Option<Bank>,
+    /// Find the bank in an Amiga disk image (.adf). Picks the bank
+    /// that looks like the one named, if given
+    #[arg(long)]
+    adf: Option<String>,
+    /// Find the bank in the game's executable. Picks the bank that
+    /// looks like the one named, if given
+    #[arg(long)]
+    exe: Option<String>,
+    /// Find the bank in a memory dump, such as a WinUAE chip RAM
+    /// save. Picks the bank that looks like the one named, if given
+    #[arg(long)]
+    dump: Option<String>,
+    /// Address the memory dump starts at (0 for chip RAM)
+    #[arg(long, value_parser = parse_num, default_value = "0")]
+    dump_base: usize,
+    /// Load the bank from a .zip or .lha archive instead of data/
+    #[arg(long)]
+    archive: Option<String>,
+    /// File within the archive to load (defaults to the bank's usual
+    /// file name)
+    #[arg(long)]
+    entry: Option<String>,
+    /// Memory-map the bank file rather than reading it in. The file
+    /// mustn't be changed or truncated while the player runs
+    #[arg(long)]
+    mmap: bool,
+    /// Raw MIDI device to send MIDI clock to, e.g. /dev/snd/midiC1D0
+    #[arg(long)]
+    midi_out: Option<String>,
+    /// If the bank fails validation, load and play it anyway, with
+    /// the tables trimmed to fit the data
+    #[arg(long)]
+    sandbox: bool,
+    /// Search a disk image for sound banks, and list what's found
+    #[arg(long)]
+    scan: Option<String>,
+    /// Load a bank from any file, such as a disk image (see --scan)
+    #[arg(long, visible_alias = "image")]
+    file: Option<String>,
+    /// Offset of the bank within the file
+    #[arg(long, value_parser = parse_num, default_value = "0")]
+    offset: usize,
+    /// Number of sequences in the bank (detected if not given)
+    #[arg(long, value_parser = parse_num)]
+    sequences: Option<usize>,
+    /// Number of instruments in the bank (detected if not given)
+    #[arg(long, value_parser = parse_num)]
+    instruments: Option<usize>,
+    /// Sound driver revision the bank was built for
+    #[arg(long, value_parser = driver::parse_variant, default_value = "speedball2")]
+    driver: &'static driver::Variant,
+    /// Open the audio device with this many output channels, so
+    /// that synth channels can be routed to separate outputs
+    #[arg(long)]
+    outputs: Option<u16>,
+    /// Audio device settings: "default", "low-latency" (tuned for
+    /// this platform), or "safe" (large buffers)
+    #[arg(long, value_parser = cpal_wrapper::parse_preset, default_value = "default")]
+    audio_preset: &'static cpal_wrapper::Preset,
+    /// Run without a GUI, taking JSON commands on stdin (see
+    /// control.rs)
+    #[arg(long)]
+    headless: bool,
+    /// Open at an item, given a link like sb2://game/seq/0x12 (add
+    /// "?play" to play it). Picks the bank if none is given
+    #[arg(long, value_parser = link::Link::parse)]
+    open: Option<link::Link>,
+    /// Write the bank's sequences, instruments and sounds table to a
+    /// JSON file, and exit
+    #[arg(long)]
+    export_metadata: Option<String>,
+    /// Write a "sound design sheet" for the bank - its instruments,
+    /// sequences and sounds, cross-referenced - as an HTML page, and
+    /// exit
+    #[arg(long)]
+    report: Option<String>,
+    /// Render every sound, sequence and instrument to .wav files in
+    /// this directory, and exit
+    #[arg(long)]
+    export_dir: Option<String>,
+    /// When exporting, run looping music on to --max-time, rather
+    /// than stopping it after once through
+    #[arg(long)]
+    full_length: bool,
+    /// When exporting, also write the notes played to a .mid file
+    /// next to each .wav
+    #[arg(long)]
+    export_midi: bool,
+    /// When exporting, also write a JSON manifest of the files
+    /// produced, with their hashes, durations, sources and the
+    /// settings used, for build pipelines
+    #[arg(long, requires = "export_dir")]
+    export_manifest: Option<String>,
+    /// When exporting, render just what the machine would play - no
+    /// filters, interpolation, mixer levels or panning - to 32-bit
+    /// float files, for comparing with emulator captures
+    #[arg(long)]
+    measurement: bool,
+    /// Run a script (see script.rs) on the bank, and exit
+    #[arg(long)]
+    script: Option<String>,
+    /// Number of files to render at once when exporting (defaults to
+    /// the number of cores)
+    #[arg(long)]
+    jobs: Option<usize>,
+    /// Longest time to render each file for, in seconds
+    #[arg(long, default_value = "60")]
+    max_time: f32,
+    /// Play looping music this many times through, then stop (0
+    /// repeats forever)
+    #[arg(long, default_value = "0")]
+    passes: usize,
+    /// What to render after a sound finishes when exporting: "cut",
+    /// "silence" (until it goes quiet), or a number of seconds
+    #[arg(long, value_parser = cpal_wrapper::Tail::parse, default_value = "cut")]
+    tail: cpal_wrapper::Tail,
+    /// List the parts of the bank that nothing refers to, and exit
+    #[arg(long)]
+    unreferenced: bool,
+    /// List sequences nothing plays or calls, and sounds that play
+    /// empty sequences, and exit
+    #[arg(long)]
+    gaps: bool,
+    /// Assemble a sequence listing (see asm.rs), add it to the bank
+    /// as a new sequence, write the bank to --bank-out, and exit
+    #[arg(long, requires = "bank_out")]
+    assemble: Option<String>,
+    /// Where to write the bank with the --assemble'd sequence added
+    #[arg(long)]
+    bank_out: Option<String>,
+    /// Soak test: play random material for this many hours without a
+    /// GUI, logging memory and CPU use every minute
+    #[arg(long)]
+    soak: Option<f32>,
+    /// JSON file giving names to instruments, sequences and sounds
+    /// (see annotations.rs)
+    #[arg(long, value_parser = annotations::Annotations::load)]
+    names: Option<annotations::Annotations>,
+    /// Text file of note periods to play the bank with, for games
+    /// whose driver has a different table (see pitches.rs)
+    #[arg(long, value_parser = pitches::load)]
+    pitches: Option<sound_data::PitchTable>,
+    /// Show the guided tour, even if it's been seen before
+    #[arg(long)]
+    tour: bool,
+}
+
+// Accept hex for offsets, since that's what everything else uses.
+fn parse_num(s: &str) -> Result<usize, String> {
+    match s.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
 }
 
 struct Config {
     file: &'static str,
+    // The file's contents, if built into the executable.
+    embedded: Option<&'static [u8]>,
     num_sequences: usize,
     num_instruments: usize,
 }
 
+// With the "embed-banks" feature, the standard banks are built into
+// the executable, so it can be shared on its own.
+#[cfg(feature = "embed-banks")]
+const INTRO_DATA: Option<&[u8]> = Some(include_bytes!("../data/intro.bin"));
+#[cfg(not(feature = "embed-banks"))]
+const INTRO_DATA: Option<&[u8]> = None;
+
+#[cfg(feature = "embed-banks")]
+const GAME_DATA: Option<&[u8]> = Some(include_bytes!("../data/main.bin"));
+#[cfg(not(feature = "embed-banks"))]
+const GAME_DATA: Option<&[u8]> = None;
+
 const INTRO_CONF: Config = Config {
     file: "data/intro.bin",
+    embedded: INTRO_DATA,
     num_sequences: 27,
     num_instruments: 40,
 };
 
 const GAME_CONF: Config = Config {
     file: "data/main.bin",
+    embedded: GAME_DATA,
     num_sequences: 78,
     num_instruments: 43,
 };
 
-struct PlayerApp {
-    synth: Arc<Mutex<sound_player::Synth>>,
-}
+// Reloads the bank from wherever it came from.
+type Loader = Box<dyn Fn() -> Result<sound_player::SoundBank, String>>;
 
-impl PlayerApp {
-    fn new(bank: sound_player::SoundBank) -> PlayerApp {
-        let bank = Arc::new(bank);
-        let synth = Arc::new(Mutex::new(sound_player::Synth::new(bank)));
-        PlayerApp { synth }
+fn scan(image_name: &str) {
+    let image = std::fs::read(image_name).unwrap();
+    for hit in scanner::scan(&image) {
+        println!(
+            "0x{:06x}: {} sequences, {} instruments (open with --file {} --offset 0x{:x})",
+            hit.offset, hit.num_sequences, hit.num_instruments, image_name, hit.offset
+        );
     }
 }
 
-impl App for PlayerApp {
-    fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
-        CentralPanel::default().show(ctx, |ui| {
-            let mut synth = self.synth.lock().unwrap();
-            synth.ui(ui);
-        });
-        // Cheap way of ensuring GUI catches the sounds finishing,
-        // without having the sound-players hold a reference to the
-        // GUI.
-        ctx.request_repaint_after(Duration::from_millis(100));
-    }
+// Load a bank from an offset in an arbitrary file, working out the
+// table sizes ourselves unless told otherwise.
+fn load_from_file(args: &Args, file_name: &str) -> Result<sound_player::SoundBank, String> {
+    let data: sound_player::BankData = if args.mmap {
+        sound_player::BankData::map(file_name, args.offset)?
+    } else {
+        read(file_name)?
+            .get(args.offset..)
+            .ok_or_else(|| {
+                format!(
+                    "Offset 0x{:x} is past the end of {}",
+                    args.offset, file_name
+                )
+            })?
+            .to_vec()
+            .into()
+    };
+    sound_player::SoundBank::detect(
+        data,
+        args.driver,
+        args.sequences,
+        args.instruments,
+        args.sandbox,
+    )
 }
 
-fn main() {
-    let args = Args::parse();
+fn read(file_name: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(file_name).map_err(|err| format!("{}: {}", file_name, err))
+}
 
-    let conf = match args.bank {
+fn config(bank: &Bank) -> Config {
+    match bank {
         Bank::Intro => INTRO_CONF,
         Bank::Game => GAME_CONF,
+    }
+}
+
+// Find a bank in an Amiga disk image. If it has a file system we can
+// read we look in each file, and we always fall back to scanning the
+// raw image.
+fn load_from_adf(args: &Args, adf_name: &str) -> Result<sound_player::SoundBank, String> {
+    let image = read(adf_name)?;
+    let mut blobs = match adf::files(&image) {
+        Ok(files) => files.into_iter().map(|f| (f.path, f.data)).collect(),
+        Err(err) => {
+            eprintln!("{}: {}, scanning the raw image", adf_name, err);
+            Vec::new()
+        }
     };
+    blobs.push(("raw image".to_string(), image));
+    load_from_blobs(args, adf_name, blobs)
+}
 
-    let data = std::fs::read(conf.file).unwrap();
-    let sound_bank = sound_player::SoundBank::new(data, conf.num_sequences, conf.num_instruments);
-    let options = NativeOptions::default();
-    let app = PlayerApp::new(sound_bank);
-    let _stream = cpal_wrapper::sound_init(app.synth.clone());
+// Find a bank in the game's executable. We look in each hunk, and
+// fall back to scanning the whole file, e.g. if it isn't an
+// executable after all.
+fn load_from_exe(args: &Args, exe_name: &str) -> Result<sound_player::SoundBank, String> {
+    let exe = read(exe_name)?;
+    let mut blobs = match hunk::hunks(&exe) {
+        Ok(hunks) => hunks.into_iter().map(|h| (h.name, h.data)).collect(),
+        Err(err) => {
+            eprintln!("{}: {}, scanning the whole file", exe_name, err);
+            Vec::new()
+        }
+    };
+    blobs.push(("whole file".to_string(), exe));
+    load_from_blobs(args, exe_name, blobs)
+}
 
-    eframe::run_native(
-        "Speedball II Sound Player",
-        options,
-        Box::new(|_cc| Box::new(app)),
+// Scan named chunks of data from a file for banks, and load the best.
+fn load_from_blobs(
+    args: &Args,
+    file_name: &str,
+    blobs: Vec<(String, Vec<u8>)>,
+) -> Result<sound_player::SoundBank, String> {
+    let hits = blobs
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, (_, data))| scanner::scan(data).into_iter().map(move |hit| (idx, hit)))
+        .collect::<Vec<_>>();
+    let (idx, hit) =
+        best_hit(args, &hits).ok_or_else(|| format!("No sound banks found in {}", file_name))?;
+
+    let (name, data) = &blobs[*idx];
+    eprintln!("Using bank at 0x{:x} in {}", hit.offset, name);
+    sound_player::SoundBank::detect(
+        data[hit.offset..].to_vec().into(),
+        args.driver,
+        args.sequences,
+        args.instruments,
+        args.sandbox,
     )
-    .unwrap();
+}
+
+// Find a bank in a memory dump, such as a WinUAE chip RAM save. Once
+// loaded, the bank's pointers may have been relocated to absolute
+// addresses, so we look for banks either way.
+fn load_from_dump(args: &Args, dump_name: &str) -> Result<sound_player::SoundBank, String> {
+    let image = read(dump_name)?;
+    let mut hits = scanner::scan_relocated(&image, args.dump_base)
+        .into_iter()
+        .map(|hit| (true, hit))
+        .collect::<Vec<_>>();
+    hits.extend(scanner::scan(&image).into_iter().map(|hit| (false, hit)));
+    let (relocated, hit) =
+        best_hit(args, &hits).ok_or_else(|| format!("No sound banks found in {}", dump_name))?;
+    eprintln!(
+        "Using {} bank at 0x{:x} in {}",
+        if *relocated {
+            "relocated"
+        } else {
+            "unrelocated"
+        },
+        hit.offset,
+        dump_name
+    );
+    let data = if *relocated {
+        scanner::unrelocate(&image, args.dump_base, hit)
+    } else {
+        image[hit.offset..].to_vec()
+    };
+    sound_player::SoundBank::detect(
+        data.into(),
+        args.driver,
+        args.sequences.or(Some(hit.num_sequences)),
+        args.instruments.or(Some(hit.num_instruments)),
+        args.sandbox,
+    )
+}
+
+// Prefer a bank the same shape as the one asked for, and otherwise
+// take the first.
+fn best_hit<'a, T>(args: &Args, hits: &'a [(T, scanner::Hit)]) -> Option<&'a (T, scanner::Hit)> {
+    let wanted = args.bank.as_ref().map(config);
+    hits.iter()
+        .find(|(_, hit)| {
+            wanted.as_ref().is_some_and(|conf| {
+                hit.num_sequences == conf.num_sequences
+                    && hit.num_instruments == conf.num_instruments
+            })
+        })
+        .or_else(|| hits.first())
+}
+
+fn load_from_config(args: &Args, bank: &Bank) -> Result<sound_player::SoundBank, String> {
+    let conf = config(bank);
+
+    let data = match (&args.archive, conf.embedded) {
+        (Some(archive_name), _) => {
+            let archive = read(archive_name)?;
+            let entry = args
+                .entry
+                .as_deref()
+                .unwrap_or_else(|| conf.file.rsplit('/').next().unwrap());
+            archive::extract(&archive, entry)?.into()
+        }
+        (None, Some(embedded)) => embedded.to_vec().into(),
+        (None, None) if args.mmap => sound_player::BankData::map(conf.file, 0)?,
+        (None, None) => read(conf.file)?.into(),
+    };
+    sound_player::SoundBank::new_or_sandboxed(
+        data,
+        args.driver,
+        args.sequences.unwrap_or(conf.num_sequences),
+        args.instruments.unwrap_or(conf.num_instruments),
+        args.sandbox,
+    )
+}
+
+// Load a bank picked from inside an archive in the GUI. Files with a
+// standard bank's usual name get its table sizes, and others have
+// theirs worked out.
+#[cfg(feature = "gui")]
+fn load_from_archive(
+    args: &Args,
+    archive_name: &str,
+    entry: &str,
+) -> Result<sound_player::SoundBank, String> {
+    let archive = read(archive_name)?;
+    let data = archive::extract(&archive, entry)?.into();
+    let base_name = |s: &str| s.rsplit(['/', '\\']).next().unwrap().to_lowercase();
+    let conf = [Bank::Intro, Bank::Game]
+        .iter()
+        .map(config)
+        .find(|conf| base_name(conf.file) == base_name(entry));
+    match conf {
+        Some(conf) => sound_player::SoundBank::new_or_sandboxed(
+            data,
+            args.driver,
+            args.sequences.unwrap_or(conf.num_sequences),
+            args.instruments.unwrap_or(conf.num_instruments),
+            args.sandbox,
+        ),
+        None => sound_player::SoundBank::detect(
+            data,
+            args.driver,
+            args.sequences,
+            args.instruments,
+            args.sandbox,
+        ),
+    }
+}
+
+// As given on the command line.
+fn config_name(bank: &Bank) -> String {
+    bank.to_possible_value()
+        .map_or_else(String::new, |v| v.get_name().to_string())
+}
+
+// Name of the bank in links. Banks loaded from elsewhere can't be
+// found again from just a link, but the links still serve as
+// references.
+fn bank_name(args: &Args) -> String {
+    match (&args.file, &args.adf, &args.exe, &args.dump, &args.bank) {
+        (Some(_), _, _, _, _) => "file".to_string(),
+        (None, Some(_), _, _, _) => "adf".to_string(),
+        (None, None, Some(_), _, _) => "exe".to_string(),
+        (None, None, None, Some(_), _) => "dump".to_string(),
+        (None, None, None, None, Some(bank)) => config_name(bank),
+        (None, None, None, None, None) => unreachable!("clap requires a bank"),
+    }
+}
+
+// Render every sound, sequence and instrument to .wav files.
+#[cfg(feature = "export")]
+fn export_dir(args: &Args, loader: &Loader, annotations: &annotations::Annotations) {
+    let dir = args.export_dir.as_ref().unwrap();
+    let bank = Arc::new(loader().unwrap_or_else(|err| panic!("{}", err)));
+    let mut synth = sound_player::Synth::new(bank);
+    synth.set_max_passes(args.passes);
+    let work = batch::jobs(&synth, annotations);
+    let jobs = args.jobs.unwrap_or_else(batch::default_concurrency);
+    let dir = Path::new(dir);
+    batch::run(
+        &synth,
+        work,
+        dir,
+        &batch::Settings {
+            max_time_s: args.max_time,
+            tail: args.tail,
+            midi: args.export_midi,
+            auto_length: !args.full_length,
+            build_manifest: args.export_manifest.clone().map(Into::into),
+            measurement: args.measurement,
+        },
+        jobs,
+    )
+    .unwrap_or_else(|err| panic!("{}", err));
+}
+
+// Run a script file on the bank, with the export settings given.
+#[cfg(feature = "export")]
+fn run_script(args: &Args, loader: &Loader, annotations: &annotations::Annotations) {
+    let file_name = args.script.as_ref().unwrap();
+    let text =
+        std::fs::read_to_string(file_name).unwrap_or_else(|err| panic!("{}: {}", file_name, err));
+    let script =
+        script::Script::parse(&text).unwrap_or_else(|err| panic!("{}: {}", file_name, err));
+    let bank = Arc::new(loader().unwrap_or_else(|err| panic!("{}", err)));
+    let settings = batch::Settings {
+        max_time_s: args.max_time,
+        tail: args.tail,
+        midi: args.export_midi,
+        auto_length: !args.full_length,
+        build_manifest: None,
+        measurement: args.measurement,
+    };
+    let cancel = std::sync::atomic::AtomicBool::new(false);
+    script::run(
+        &script,
+        bank,
+        annotations,
+        settings,
+        args.passes,
+        &mut script::Console,
+        &cancel,
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("{}: {}", file_name, err);
+        std::process::exit(1);
+    });
+}
+
+// Load the bank, and play a synth for it to the sound card, while
+// something other than the GUI drives it.
+#[cfg(feature = "audio")]
+fn play_with<F>(args: &Args, loader: &Loader, drive: F)
+where
+    F: FnOnce(
+        Arc<sound_player::SoundBank>,
+        Arc<Mutex<sound_player::Synth>>,
+        Sender<cpal_wrapper::Timed<sound_player::Command>>,
+    ),
+{
+    let bank = Arc::new(loader().unwrap_or_else(|err| panic!("{}", err)));
+    let mut synth = sound_player::Synth::new(bank.clone());
+    synth.set_max_passes(args.passes);
+    if let Some(port) = midi_port(args) {
+        synth.set_midi_out(port);
+    }
+    let synth = Arc::new(Mutex::new(synth));
+    let (sender, receiver) = mpsc::channel();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let _output =
+        cpal_wrapper::sound_init(synth.clone(), receiver, args.outputs, args.audio_preset)
+            .unwrap_or_else(|err| panic!("{}", err));
+    drive(bank, synth, sender);
+}
+
+// The MIDI device asked for, if any.
+#[cfg(feature = "audio")]
+fn midi_port(args: &Args) -> Option<Arc<midi::Port>> {
+    let name = args.midi_out.as_ref()?;
+    Some(Arc::new(
+        midi::Port::open(name).unwrap_or_else(|err| panic!("{}", err)),
+    ))
+}
+
+// For modes this build left out.
+#[cfg(not(all(feature = "gui", feature = "audio", feature = "export")))]
+fn without(what: &str, feature: &str) {
+    eprintln!(
+        "{} needs the \"{}\" feature, which this build doesn't have",
+        what, feature
+    );
+    std::process::exit(2);
+}
+
+fn main() {
+    let mut args = Args::parse();
+
+    // A link names the bank, if we weren't given one.
+    if let Some(link) = &args.open {
+        let has_file = [&args.file, &args.adf, &args.exe, &args.dump]
+            .iter()
+            .any(|name| name.is_some());
+        if args.bank.is_none() && !has_file {
+            args.bank = Some(Bank::from_str(&link.bank, true).unwrap_or_else(|err| {
+                eprintln!("Bad bank in link '{}': {}", link, err);
+                std::process::exit(2)
+            }));
+        }
+    }
+
+    if let Some(image_name) = &args.scan {
+        scan(image_name);
+        return;
+    }
+
+    let annotations = Arc::new(args.names.clone().unwrap_or_default());
+    let loader_args = args.clone();
+    let loader: Loader = Box::new(move || {
+        let args = &loader_args;
+        match (&args.file, &args.adf, &args.exe, &args.dump, &args.bank) {
+            (Some(file_name), _, _, _, _) => load_from_file(args, file_name),
+            (None, Some(adf_name), _, _, _) => load_from_adf(args, adf_name),
+            (None, None, Some(exe_name), _, _) => load_from_exe(args, exe_name),
+            (None, None, None, Some(dump_name), _) => load_from_dump(args, dump_name),
+            (None, None, None, None, Some(bank)) => load_from_config(args, bank),
+            (None, None, None, None, None) => unreachable!("clap requires a bank"),
+        }
+        .map(|bank| match args.pitches {
+            Some(pitches) => sound_player::SoundBank { pitches, ..bank },
+            None => bank,
+        })
+        .and_then(
+            |bank| match args.names.as_ref().and_then(|names| names.quirks) {
+                Some(quirks) => bank.with_quirks(quirks),
+                None => Ok(bank),
+            },
+        )
+        .inspect(|bank| {
+            if let Some(reason) = &bank.sandboxed {
+                eprintln!("Warning: {}, playing in a sandbox", reason);
+            }
+            for warning in coverage::sample_warnings(bank) {
+                eprintln!("Warning: {}", warning);
+            }
+        })
+    });
+
+    if let Some(file_name) = &args.export_metadata {
+        let bank = loader().unwrap_or_else(|err| panic!("{}", err));
+        std::fs::write(
+            file_name,
+            format!("{}\n", export::metadata(&bank, &annotations)),
+        )
+        .unwrap_or_else(|err| panic!("{}: {}", file_name, err));
+        return;
+    }
+
+    if let Some(file_name) = &args.report {
+        let bank = Arc::new(loader().unwrap_or_else(|err| panic!("{}", err)));
+        let html = report::html(&bank, &annotations, &bank_name(&args));
+        std::fs::write(file_name, html).unwrap_or_else(|err| panic!("{}: {}", file_name, err));
+        return;
+    }
+
+    if args.unreferenced {
+        let bank = loader().unwrap_or_else(|err| panic!("{}", err));
+        for region in coverage::unreferenced(&bank) {
+            println!("{}", region);
+        }
+        return;
+    }
+
+    if args.gaps {
+        let bank = loader().unwrap_or_else(|err| panic!("{}", err));
+        for gap in gaps::find(&bank) {
+            println!("{}", gap);
+        }
+        return;
+    }
+
+    if let (Some(listing_name), Some(out_name)) = (&args.assemble, &args.bank_out) {
+        let bank = loader().unwrap_or_else(|err| panic!("{}", err));
+        let listing = std::fs::read_to_string(listing_name)
+            .unwrap_or_else(|err| panic!("{}: {}", listing_name, err));
+        let bytes = asm::assemble(bank.variant, &listing)
+            .unwrap_or_else(|err| panic!("{}: {}", listing_name, err));
+        let (bank, idx) = bank
+            .with_sequence(&bytes)
+            .unwrap_or_else(|err| panic!("{}", err));
+        let data = bank.to_bytes().unwrap_or_else(|err| panic!("{}", err));
+        std::fs::write(out_name, data).unwrap_or_else(|err| panic!("{}: {}", out_name, err));
+        println!("Added sequence 0x{:02x} ({} bytes)", idx, bytes.len());
+        return;
+    }
+
+    if args.export_dir.is_some() {
+        #[cfg(feature = "export")]
+        export_dir(&args, &loader, &annotations);
+        #[cfg(not(feature = "export"))]
+        without("--export-dir", "export");
+        return;
+    }
+
+    if args.script.is_some() {
+        #[cfg(feature = "export")]
+        run_script(&args, &loader, &annotations);
+        #[cfg(not(feature = "export"))]
+        without("--script", "export");
+        return;
+    }
+
+    if args.soak.is_some() {
+        #[cfg(feature = "audio")]
+        play_with(&args, &loader, |bank, synth, sender| {
+            soak::run(bank, synth, sender, args.soak.unwrap())
+        });
+        #[cfg(not(feature = "audio"))]
+        without("--soak", "audio");
+        return;
+    }
+
+    if args.headless {
+        #[cfg(feature = "audio")]
+        play_with(&args, &loader, control::run);
+        #[cfg(not(feature = "audio"))]
+        without("--headless", "audio");
+        return;
+    }
+
+    #[cfg(feature = "gui")]
+    app::run(args, loader, annotations);
+    #[cfg(not(feature = "gui"))]
+    without("The player's window", "gui");
 }
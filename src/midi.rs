@@ -0,0 +1,202 @@
+//
+// Speedball 2 Sound player
+//
+// midi.rs: Send MIDI to other gear and software as the music plays:
+// a clock to sync to, and the notes themselves. Notes can also be
+// recorded to a Standard MIDI File alongside a .wav export.
+//
+// Rather than pulling in a MIDI library, messages are written as raw
+// bytes to a MIDI device file, such as ALSA's /dev/snd/midiC1D0. With
+// the snd-virmidi module loaded, its ports appear to other programs
+// as ordinary MIDI inputs, so a DAW can listen to us.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fs::File;
+#[cfg(feature = "audio")]
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Mutex;
+
+// Channel messages, ORed with the channel number.
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+
+// System real-time messages.
+const CLOCK: u8 = 0xf8;
+const START: u8 = 0xfa;
+const STOP: u8 = 0xfc;
+
+// MIDI clock runs at this many pulses per quarter note.
+const PULSES_PER_QUARTER: f32 = 24.0;
+
+// Used as the quarter note length in files if we never find out the
+// tempo, making the file 60 bpm.
+#[cfg(feature = "export")]
+const DEFAULT_FRAMES_PER_QUARTER: usize = 50;
+
+// Velocity is from 0.0 to 1.0.
+pub fn note_on(channel: u8, note: u8, velocity: f32) -> [u8; 3] {
+    let velocity = (velocity.clamp(0.0, 1.0) * 127.0).round().max(1.0) as u8;
+    [NOTE_ON | channel, note, velocity]
+}
+
+pub fn note_off(channel: u8, note: u8) -> [u8; 3] {
+    [NOTE_OFF | channel, note, 0]
+}
+
+pub struct Port {
+    #[cfg(feature = "gui")]
+    pub name: String,
+    file: Mutex<File>,
+}
+
+impl Port {
+    #[cfg(feature = "audio")]
+    pub fn open(name: &str) -> Result<Port, String> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(name)
+            .map_err(|err| format!("Couldn't open MIDI device {}: {}", name, err))?;
+        Ok(Port {
+            #[cfg(feature = "gui")]
+            name: name.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    // Errors are dropped, as the music should carry on playing if the
+    // other end goes away.
+    pub fn send(&self, message: &[u8]) {
+        let _ = self.file.lock().unwrap().write_all(message);
+    }
+}
+
+// MIDI clock following a sequence's tempo. Clocks are only sent on
+// frame boundaries, so come in bursts of a 50th of a second, which
+// most receivers smooth out.
+#[derive(Clone, Default)]
+pub struct Clock {
+    // Pulses owed but not yet sent.
+    pulses: f32,
+    running: bool,
+}
+
+impl Clock {
+    // Called once a frame, with the length of a quarter note in
+    // frames, or None if the music isn't playing.
+    pub fn frame(&mut self, port: &Port, frames_per_quarter: Option<f32>) {
+        let Some(frames_per_quarter) = frames_per_quarter.filter(|&f| f > 0.0) else {
+            if self.running {
+                port.send(&[STOP]);
+                self.running = false;
+            }
+            return;
+        };
+        if !self.running {
+            port.send(&[START]);
+            self.running = true;
+            self.pulses = 0.0;
+        }
+        self.pulses += PULSES_PER_QUARTER / frames_per_quarter;
+        while self.pulses >= 1.0 {
+            port.send(&[CLOCK]);
+            self.pulses -= 1.0;
+        }
+    }
+}
+
+// Notes timestamped by frame, for writing to a file.
+#[derive(Clone)]
+pub struct Recording {
+    // Length of a frame, as the bank's driver steps.
+    #[cfg(feature = "export")]
+    frame_us: usize,
+    frame: usize,
+    events: Vec<(usize, [u8; 3])>,
+    frames_per_quarter: Option<usize>,
+}
+
+// Variable-length quantity, as used for delta times.
+#[cfg(feature = "export")]
+fn push_varlen(out: &mut Vec<u8>, value: usize) {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    let mut value = value >> 7;
+    while value != 0 {
+        bytes.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+impl Recording {
+    #[cfg(feature = "export")]
+    pub fn new(frame_rate: f32) -> Recording {
+        Recording {
+            frame_us: (1_000_000.0 / frame_rate).round() as usize,
+            frame: 0,
+            events: Vec::new(),
+            frames_per_quarter: None,
+        }
+    }
+
+    pub fn push(&mut self, message: [u8; 3]) {
+        self.events.push((self.frame, message));
+    }
+
+    // Called at the end of each frame, with the length of a quarter
+    // note in frames, if known. The first one known is used for the
+    // whole file.
+    pub fn end_frame(&mut self, frames_per_quarter: Option<usize>) {
+        self.frame += 1;
+        if self.frames_per_quarter.is_none() {
+            self.frames_per_quarter = frames_per_quarter.filter(|&f| f > 0);
+        }
+    }
+
+    // A format 0 Standard MIDI File. Each tick is a frame, so the
+    // timing matches the audio exactly, and the tempo is set so that
+    // quarter notes line up with the music's beats.
+    #[cfg(feature = "export")]
+    pub fn to_smf(&self) -> Vec<u8> {
+        let frames_per_quarter = self
+            .frames_per_quarter
+            .unwrap_or(DEFAULT_FRAMES_PER_QUARTER);
+        let us_per_quarter = (frames_per_quarter * self.frame_us).to_be_bytes();
+
+        let mut track = vec![0x00, 0xff, 0x51, 0x03];
+        track.extend_from_slice(&us_per_quarter[us_per_quarter.len() - 3..]);
+        let mut last_frame = 0;
+        // Notes still sounding at the end are stopped there.
+        let mut sounding: Vec<[u8; 3]> = Vec::new();
+        for (frame, message) in self.events.iter() {
+            push_varlen(&mut track, frame - last_frame);
+            track.extend_from_slice(message);
+            last_frame = *frame;
+            let key = |m: &[u8; 3]| (m[0] & 0x0f, m[1]);
+            sounding.retain(|m| key(m) != key(message));
+            if message[0] & 0xf0 == NOTE_ON {
+                sounding.push(*message);
+            }
+        }
+        for message in sounding {
+            push_varlen(&mut track, self.frame.saturating_sub(last_frame));
+            track.extend_from_slice(&note_off(message[0] & 0x0f, message[1]));
+            last_frame = self.frame;
+        }
+        track.extend_from_slice(&[0x00, 0xff, 0x2f, 0x00]);
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"MThd");
+        out.extend_from_slice(&6u32.to_be_bytes());
+        // Format 0, one track, ticks per quarter note.
+        out.extend_from_slice(&0u16.to_be_bytes());
+        out.extend_from_slice(&1u16.to_be_bytes());
+        out.extend_from_slice(&(frames_per_quarter as u16).to_be_bytes());
+        out.extend_from_slice(b"MTrk");
+        out.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        out.extend(track);
+        out
+    }
+}
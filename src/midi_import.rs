@@ -0,0 +1,638 @@
+//
+// Speedball 2 Sound player
+//
+// midi_import.rs: The reverse of MIDI export. Read a Standard MIDI
+// File, and turn chosen parts of it into sequences the driver can
+// play, so a tune can be heard on the game's instruments.
+//
+// The driver's model is simpler than MIDI's, so the file is bent to
+// fit:
+//
+//  * Each channel plays one note at a time. Where a part has chords,
+//    the highest note starting at once wins, and a new note cuts off
+//    the one before.
+//  * Times are quantised to a grid of steps, a driver beat each, and
+//    the whole song plays at the file's first tempo, rounded to a
+//    whole number of frames a step.
+//  * A note lasts at most 255 steps, the longest NoteLen.
+//  * MIDI notes map to driver notes as in MIDI export, an octave
+//    apart, with notes out of range dropped.
+//
+// A part is the notes on one MIDI channel of one track, so format 0
+// and format 1 files both split up sensibly.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::path::Path;
+
+use egui::{Color32, DragValue, Grid, Ui};
+use rfd::FileDialog;
+
+use crate::driver::{Op, Variant};
+use crate::sound_player::SoundBank;
+
+// MIDI's default tempo, 120 bpm, in microseconds a quarter note.
+const DEFAULT_US_PER_QUARTER: u32 = 500_000;
+// A beat lasts 750/bpm frames, and the tempo is a byte, so the
+// frames a step can be from 3 (250 bpm) to 250 (3 bpm).
+const MIN_FRAMES_PER_STEP: usize = 3;
+const MAX_FRAMES_PER_STEP: usize = 250;
+const MAX_NOTE_LEN: usize = 255;
+// Driver notes are an octave below MIDI's, as in midi.rs.
+const NOTE_OFFSET: i32 = 12;
+const MAX_VOLUME: u8 = 64;
+
+#[derive(Clone, Copy, Debug)]
+struct Note {
+    // In ticks.
+    start: u32,
+    end: u32,
+    key: u8,
+    velocity: u8,
+}
+
+#[derive(Clone, Debug)]
+pub struct Part {
+    pub track: usize,
+    pub channel: u8,
+    // The track's name, if it has one.
+    pub name: String,
+    notes: Vec<Note>,
+}
+
+impl Part {
+    pub fn num_notes(&self) -> usize {
+        self.notes.len()
+    }
+
+    pub fn label(&self) -> String {
+        let name = if self.name.is_empty() {
+            format!("Track {}", self.track)
+        } else {
+            self.name.clone()
+        };
+        format!(
+            "{}, ch {} ({} notes)",
+            name,
+            self.channel + 1,
+            self.notes.len()
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Song {
+    ticks_per_quarter: u16,
+    us_per_quarter: u32,
+    pub parts: Vec<Part>,
+}
+
+////////////////////////////////////////////////////////////////////////
+// Reading the file
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or("Truncated MIDI file")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn word(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn long(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Variable-length quantity, as used for delta times.
+    fn varlen(&mut self) -> Result<u32, String> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("Bad variable-length number in MIDI file".to_string())
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+type Sounding = Vec<(u8, u8, u32, u8)>;
+
+// End the note on the channel, if it's on.
+fn note_off(sounding: &mut Sounding, notes: &mut Vec<(u8, Note)>, channel: u8, key: u8, tick: u32) {
+    if let Some(idx) = sounding
+        .iter()
+        .position(|&(c, k, _, _)| c == channel && k == key)
+    {
+        let (_, _, start, velocity) = sounding.remove(idx);
+        notes.push((
+            channel,
+            Note {
+                start,
+                end: tick,
+                key,
+                velocity,
+            },
+        ));
+    }
+}
+
+// Add a track's notes to the parts, one part per channel used.
+fn read_track(
+    data: &[u8],
+    track: usize,
+    parts: &mut Vec<Part>,
+    tempo: &mut Option<u32>,
+) -> Result<(), String> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut name = String::new();
+    let mut notes: Vec<(u8, Note)> = Vec::new();
+    // Notes on, by channel and key: when they started and how hard.
+    let mut sounding: Sounding = Vec::new();
+    let mut tick = 0u32;
+    let mut running_status = None;
+
+    while !reader.is_done() {
+        tick = tick.saturating_add(reader.varlen()?);
+        let mut status = reader.byte()?;
+        match status {
+            0xff => {
+                let kind = reader.byte()?;
+                let len = reader.varlen()? as usize;
+                let body = reader.bytes(len)?;
+                match kind {
+                    0x03 if name.is_empty() => name = String::from_utf8_lossy(body).to_string(),
+                    0x51 if tempo.is_none() && len == 3 => {
+                        *tempo = Some(u32::from_be_bytes([0, body[0], body[1], body[2]]))
+                    }
+                    0x2f => break,
+                    _ => (),
+                }
+                continue;
+            }
+            0xf0 | 0xf7 => {
+                let len = reader.varlen()? as usize;
+                reader.bytes(len)?;
+                continue;
+            }
+            _ => (),
+        }
+        // Data bytes carry on with the last status.
+        if status < 0x80 {
+            status = running_status.ok_or("MIDI data byte without a status")?;
+            reader.pos -= 1;
+        }
+        running_status = Some(status);
+        let channel = status & 0x0f;
+        match status & 0xf0 {
+            0x80 | 0x90 => {
+                let key = reader.byte()? & 0x7f;
+                let velocity = reader.byte()? & 0x7f;
+                note_off(&mut sounding, &mut notes, channel, key, tick);
+                if status & 0xf0 == 0x90 && velocity != 0 {
+                    sounding.push((channel, key, tick, velocity));
+                }
+            }
+            0xa0 | 0xb0 | 0xe0 => {
+                reader.bytes(2)?;
+            }
+            0xc0 | 0xd0 => {
+                reader.byte()?;
+            }
+            _ => return Err(format!("Unknown MIDI status 0x{:02x}", status)),
+        }
+    }
+    // Anything still on stops at the end of the track.
+    for (channel, key, _, _) in sounding.clone() {
+        note_off(&mut sounding, &mut notes, channel, key, tick);
+    }
+
+    notes.sort_by_key(|(_, note)| note.start);
+    for channel in 0..16 {
+        let channel_notes = notes
+            .iter()
+            .filter(|(c, _)| *c == channel)
+            .map(|(_, note)| *note)
+            .collect::<Vec<_>>();
+        if !channel_notes.is_empty() {
+            parts.push(Part {
+                track,
+                channel,
+                name: name.clone(),
+                notes: channel_notes,
+            });
+        }
+    }
+    Ok(())
+}
+
+impl Song {
+    pub fn parse(data: &[u8]) -> Result<Song, String> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.bytes(4).ok() != Some(b"MThd".as_slice()) {
+            return Err("Not a Standard MIDI File".to_string());
+        }
+        let header_len = reader.long()? as usize;
+        let _format = reader.word()?;
+        let num_tracks = reader.word()? as usize;
+        let division = reader.word()?;
+        reader.bytes(header_len.saturating_sub(6))?;
+        if division & 0x8000 != 0 || division == 0 {
+            return Err("MIDI files timed in SMPTE frames aren't supported".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut tempo = None;
+        let mut track = 0;
+        while track < num_tracks && !reader.is_done() {
+            let kind = reader.bytes(4)?;
+            let len = reader.long()? as usize;
+            let body = reader.bytes(len)?;
+            // Other chunk types are to be skipped.
+            if kind == b"MTrk" {
+                read_track(body, track, &mut parts, &mut tempo)?;
+                track += 1;
+            }
+        }
+        if parts.is_empty() {
+            return Err("No notes in MIDI file".to_string());
+        }
+        Ok(Song {
+            ticks_per_quarter: division,
+            us_per_quarter: tempo.unwrap_or(DEFAULT_US_PER_QUARTER),
+            parts,
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Song, String> {
+        let data = std::fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Song::parse(&data).map_err(|err| format!("{}: {}", path.display(), err))
+    }
+
+    // The file's own tempo.
+    pub fn bpm(&self) -> f32 {
+        60e6 / self.us_per_quarter as f32
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Turning parts into sequences
+
+#[derive(Clone, Copy, Debug)]
+pub struct Settings {
+    // The grid notes are quantised to.
+    pub steps_per_quarter: usize,
+    // Of the bank's driver.
+    pub frame_rate: f32,
+    // Set each note's volume from its velocity, rather than playing
+    // everything at full volume.
+    pub velocity: bool,
+    // Play the song forever, restarting at the end.
+    pub repeat: bool,
+}
+
+impl Settings {
+    // Frames each step lasts, as close to the file's tempo as the
+    // driver allows.
+    pub fn frames_per_step(&self, song: &Song) -> usize {
+        let quarter_s = song.us_per_quarter as f32 / 1e6;
+        let frames = self.frame_rate * quarter_s / self.steps_per_quarter as f32;
+        (frames.round() as usize).clamp(MIN_FRAMES_PER_STEP, MAX_FRAMES_PER_STEP)
+    }
+
+    // The tempo the song comes out at.
+    pub fn bpm(&self, song: &Song) -> f32 {
+        60.0 * self.frame_rate / (self.frames_per_step(song) * self.steps_per_quarter) as f32
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Mapping {
+    // Index into the song's parts.
+    pub part: usize,
+    pub instrument: usize,
+    // In semitones.
+    pub transpose: i32,
+}
+
+// A part's notes on the grid, one at a time: start and end steps,
+// key and velocity.
+fn monophonic(song: &Song, part: &Part, steps_per_quarter: usize) -> Vec<(usize, usize, u8, u8)> {
+    let ticks_per_step = song.ticks_per_quarter as f64 / steps_per_quarter as f64;
+    let step = |tick: u32| (tick as f64 / ticks_per_step).round() as usize;
+    let mut notes = part.notes.clone();
+    // Highest first, where notes start together.
+    notes.sort_by_key(|note| (note.start, std::cmp::Reverse(note.key)));
+    let mut out: Vec<(usize, usize, u8, u8)> = Vec::new();
+    for note in notes {
+        let start = step(note.start);
+        let end = step(note.end).max(start + 1);
+        if let Some(last) = out.last_mut() {
+            if start <= last.0 {
+                continue;
+            }
+            last.1 = last.1.min(start);
+        }
+        out.push((start, end, note.key, note.velocity));
+    }
+    out
+}
+
+// The length of the song, in steps, so that parts can be padded to
+// restart together.
+fn song_steps(song: &Song, steps_per_quarter: usize) -> usize {
+    song.parts
+        .iter()
+        .flat_map(|part| monophonic(song, part, steps_per_quarter))
+        .map(|(_, end, _, _)| end)
+        .max()
+        .unwrap_or(0)
+}
+
+struct Emitter<'a> {
+    variant: &'a Variant,
+    out: Vec<u8>,
+    note_len: Option<usize>,
+}
+
+impl Emitter<'_> {
+    fn op(&mut self, op: Op, operand: Option<u8>) -> Result<(), String> {
+        let code = self
+            .variant
+            .encode(op)
+            .ok_or_else(|| format!("The {} driver has no {:?} command", self.variant.name, op))?;
+        self.out.push(code);
+        self.out.extend(operand);
+        Ok(())
+    }
+
+    fn set_len(&mut self, len: usize) -> Result<(), String> {
+        if self.note_len != Some(len) {
+            self.op(Op::NoteLen, Some(len as u8))?;
+            self.note_len = Some(len);
+        }
+        Ok(())
+    }
+
+    fn rest(&mut self, mut steps: usize) -> Result<(), String> {
+        while steps > 0 {
+            let len = steps.min(MAX_NOTE_LEN);
+            self.set_len(len)?;
+            self.op(Op::Rest, None)?;
+            steps -= len;
+        }
+        Ok(())
+    }
+}
+
+// A sequence playing one part of the song.
+pub fn compile(
+    variant: &Variant,
+    song: &Song,
+    mapping: &Mapping,
+    settings: &Settings,
+) -> Result<Vec<u8>, String> {
+    let part = song
+        .parts
+        .get(mapping.part)
+        .ok_or_else(|| format!("No part {}", mapping.part))?;
+    let instrument = u8::try_from(mapping.instrument).map_err(|_| "Instrument out of range")?;
+    let bpm = 750 / settings.frames_per_step(song);
+
+    let mut emitter = Emitter {
+        variant,
+        out: Vec::new(),
+        note_len: None,
+    };
+    emitter.op(Op::Tempo, Some(bpm as u8))?;
+    emitter.op(Op::Instrument, Some(instrument))?;
+    emitter.op(Op::Volume, Some(MAX_VOLUME))?;
+    let mut volume = MAX_VOLUME;
+    let mut pos = 0;
+    for (start, end, key, velocity) in monophonic(song, part, settings.steps_per_quarter) {
+        let code = key as i32 - NOTE_OFFSET + mapping.transpose;
+        if !(0..0x80).contains(&code) {
+            continue;
+        }
+        emitter.rest(start - pos)?;
+        if settings.velocity {
+            let note_volume = ((velocity as u32 * MAX_VOLUME as u32 + 63) / 127) as u8;
+            if note_volume != volume {
+                emitter.op(Op::Volume, Some(note_volume))?;
+                volume = note_volume;
+            }
+        }
+        let len = (end - start).min(MAX_NOTE_LEN);
+        emitter.set_len(len)?;
+        emitter.out.push(code as u8);
+        pos = start + len;
+    }
+    if settings.repeat {
+        // Pad to the end of the song, so the parts restart together.
+        let steps = song_steps(song, settings.steps_per_quarter);
+        emitter.rest(steps.saturating_sub(pos))?;
+        emitter.op(Op::Restart, None)?;
+    } else {
+        emitter.op(Op::Stop, None)?;
+    }
+    Ok(emitter.out)
+}
+
+////////////////////////////////////////////////////////////////////////
+// UI
+
+// Grids offered, in steps a quarter note.
+const GRIDS: [(usize, &str); 6] = [
+    (1, "quarter notes"),
+    (2, "eighths"),
+    (3, "eighth triplets"),
+    (4, "sixteenths"),
+    (6, "sixteenth triplets"),
+    (8, "32nds"),
+];
+
+pub struct Importer {
+    song: Option<Song>,
+    file_name: String,
+    // What each of the four channels plays, if anything.
+    mappings: [Option<Mapping>; 4],
+    settings: Settings,
+    error: Option<String>,
+}
+
+impl Importer {
+    pub fn new() -> Importer {
+        Importer {
+            song: None,
+            file_name: String::new(),
+            mappings: [None; 4],
+            settings: Settings {
+                steps_per_quarter: 4,
+                frame_rate: 50.0,
+                velocity: true,
+                repeat: false,
+            },
+            error: None,
+        }
+    }
+
+    fn load(&mut self, bank: &SoundBank) {
+        let Some(path) = FileDialog::new()
+            .add_filter("MIDI", &["mid", "midi"])
+            .pick_file()
+        else {
+            return;
+        };
+        match Song::load(&path) {
+            Ok(song) => {
+                // Start with the busiest parts, on the first
+                // instrument with a sample.
+                let mut parts = (0..song.parts.len()).collect::<Vec<_>>();
+                parts.sort_by_key(|&idx| std::cmp::Reverse(song.parts[idx].num_notes()));
+                let instrument = bank
+                    .instruments
+                    .iter()
+                    .position(|instr| instr.sample_len > 0)
+                    .unwrap_or(0);
+                self.mappings = [0, 1, 2, 3].map(|idx| {
+                    parts.get(idx).map(|&part| Mapping {
+                        part,
+                        instrument,
+                        transpose: 0,
+                    })
+                });
+                self.file_name = path
+                    .file_name()
+                    .map_or_else(String::new, |n| n.to_string_lossy().to_string());
+                self.song = Some(song);
+                self.error = None;
+            }
+            Err(err) => self.error = Some(err),
+        }
+    }
+
+    // Returns the sequences to add for each channel, when asked to
+    // play.
+    pub fn ui(&mut self, ui: &mut Ui, bank: &SoundBank) -> Option<[Option<Vec<u8>>; 4]> {
+        self.settings.frame_rate = bank.quirks.frame_rate;
+        ui.horizontal(|ui| {
+            if ui.button("Load MIDI file...").clicked() {
+                self.load(bank);
+            }
+            if let Some(song) = &self.song {
+                ui.label(format!(
+                    "{}: {} parts, {:.1} bpm",
+                    self.file_name,
+                    song.parts.len(),
+                    song.bpm()
+                ));
+            }
+        });
+        if let Some(err) = &self.error {
+            ui.colored_label(Color32::RED, err);
+        }
+        let song = self.song.as_ref()?;
+
+        Grid::new("MidiImport").show(ui, |ui| {
+            for (idx, mapping) in self.mappings.iter_mut().enumerate() {
+                ui.label(format!("Ch {}", idx));
+                egui::ComboBox::from_id_source(("MidiPart", idx))
+                    .selected_text(
+                        mapping.map_or("nothing".to_string(), |m| song.parts[m.part].label()),
+                    )
+                    .width(240.0)
+                    .show_ui(ui, |ui| {
+                        if ui.selectable_label(mapping.is_none(), "nothing").clicked() {
+                            *mapping = None;
+                        }
+                        for (part_idx, part) in song.parts.iter().enumerate() {
+                            let selected = mapping.is_some_and(|m| m.part == part_idx);
+                            if ui.selectable_label(selected, part.label()).clicked() {
+                                *mapping = Some(Mapping {
+                                    part: part_idx,
+                                    ..mapping.unwrap_or(Mapping {
+                                        part: 0,
+                                        instrument: 0,
+                                        transpose: 0,
+                                    })
+                                });
+                            }
+                        }
+                    });
+                if let Some(mapping) = mapping {
+                    ui.label("Instrument");
+                    ui.add(
+                        DragValue::new(&mut mapping.instrument)
+                            .clamp_range(0..=bank.instruments.len().saturating_sub(1)),
+                    );
+                    ui.label("Transpose");
+                    ui.add(
+                        DragValue::new(&mut mapping.transpose)
+                            .clamp_range(-48..=48)
+                            .suffix(" st"),
+                    );
+                }
+                ui.end_row();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            ui.label("Grid");
+            let steps = &mut self.settings.steps_per_quarter;
+            egui::ComboBox::from_id_source("MidiGrid")
+                .selected_text(GRIDS.iter().find(|g| g.0 == *steps).map_or("", |g| g.1))
+                .show_ui(ui, |ui| {
+                    for (value, name) in GRIDS {
+                        ui.selectable_value(steps, value, name);
+                    }
+                });
+            ui.checkbox(&mut self.settings.velocity, "Volume from velocity");
+            ui.checkbox(&mut self.settings.repeat, "Loop");
+        });
+        ui.label(format!(
+            "Plays at {:.1} bpm, {} frames a step",
+            self.settings.bpm(song),
+            self.settings.frames_per_step(song)
+        ));
+
+        if !ui.button("Add to bank and play").clicked() {
+            return None;
+        }
+        self.error = None;
+        let mut sequences = [None, None, None, None];
+        for (sequence, mapping) in sequences.iter_mut().zip(self.mappings.iter()) {
+            if let Some(mapping) = mapping {
+                match compile(bank.variant, song, mapping, &self.settings) {
+                    Ok(bytes) => *sequence = Some(bytes),
+                    Err(err) => {
+                        self.error = Some(err);
+                        return None;
+                    }
+                }
+            }
+        }
+        Some(sequences)
+    }
+}
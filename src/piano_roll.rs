@@ -0,0 +1,86 @@
+//
+// Speedball 2 Sound player
+//
+// piano_roll.rs: Plot a sequence's notes, pitch against time, once
+// through, with each instrument in its own colour, so the shape of
+// the music can be seen at a glance. The notes are found by walking
+// the sequence (see duration.rs), not by playing it.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use egui::ecolor::Hsva;
+use egui::plot::{uniform_grid_spacer, Legend, Line, Plot, PlotPoints};
+use egui::{Color32, Ui};
+
+use crate::annotations::Annotations;
+use crate::disasm;
+use crate::duration;
+use crate::sound_player::SoundBank;
+
+// Screen width of a note's bar.
+const NOTE_WIDTH: f32 = 4.0;
+
+// Hues spread round the wheel by the golden ratio, so instruments
+// next to each other get colours far apart.
+pub fn instrument_colour(idx: usize) -> Color32 {
+    let hue = (idx as f32 * 0.618_034).fract();
+    Hsva::new(hue, 0.75, 0.9, 1.0).into()
+}
+
+// Semitones to a note name, for whole notes only.
+fn note_label(semitones: f64) -> String {
+    if semitones < 0.0 || semitones.fract() != 0.0 {
+        return String::new();
+    }
+    disasm::note_name(semitones as usize)
+}
+
+pub fn ui(ui: &mut Ui, bank: &SoundBank, idx: usize, annotations: &Annotations) {
+    let (notes, estimate) = duration::notes(bank, idx);
+    if notes.is_empty() {
+        ui.label("No notes");
+        return;
+    }
+    if estimate.is_none() {
+        ui.colored_label(
+            Color32::YELLOW,
+            "The sequence goes wrong after these notes, and stops",
+        );
+    }
+    let frame_rate = bank.quirks.frame_rate as f64;
+    let seconds = |frame: usize| frame as f64 / frame_rate;
+    // Disallow scrolling, as in the sample plots, since it's inside
+    // the browser's scrolling frame.
+    Plot::new(("PianoRoll", idx))
+        .view_aspect(4.0)
+        .allow_scroll(false)
+        .legend(Legend::default())
+        .x_axis_formatter(|x, _| format!("{:.1}s", x))
+        // Grid lines a semitone apart, labelled at each C.
+        .y_grid_spacer(uniform_grid_spacer(|_| [1.0, 12.0, 120.0]))
+        .y_axis_formatter(|y, _| note_label(y))
+        .label_formatter(|name, value| {
+            format!(
+                "{}\n{} at {:.2}s",
+                name,
+                note_label(value.y.round()),
+                value.x
+            )
+        })
+        .show(ui, |plot_ui| {
+            for note in notes.iter() {
+                let pitch = note.pitch as f64 / 4.0;
+                let points = vec![
+                    [seconds(note.frame), pitch],
+                    [seconds(note.frame + note.frames), pitch],
+                ];
+                plot_ui.line(
+                    Line::new(PlotPoints::new(points))
+                        .color(instrument_colour(note.instrument))
+                        .width(NOTE_WIDTH)
+                        .name(annotations.instrument_label(note.instrument)),
+                );
+            }
+        });
+}
@@ -0,0 +1,89 @@
+//
+// Speedball 2 Sound player
+//
+// pitches.rs: Load and save alternative note-to-period tables. Other
+// games using this driver family ship slightly different tables, and
+// their music is only in tune with the right one. The file is just
+// the table's periods, in hex (with "0x" or "$") or decimal, separated
+// by whitespace or commas, e.g.:
+//
+//   # Quarter semi-tones, from the lowest note up.
+//   0xD5C8, 0xD2B7, 0xCFB2, ...
+//
+// so a table can be pasted straight from a disassembly. Anything from
+// a '#' or ';' to the end of the line is a comment.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use std::fmt::Write;
+
+#[cfg(feature = "gui")]
+use crate::sound_data::OCTAVE_SIZE;
+use crate::sound_data::{PitchTable, PITCHES};
+
+fn parse_period(word: &str) -> Result<u16, String> {
+    let hex = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_prefix('$'));
+    let period = match hex {
+        Some(digits) => u16::from_str_radix(digits, 16),
+        None => word.parse(),
+    }
+    .map_err(|_| format!("Bad period '{}'", word))?;
+    // A zero period would play infinitely fast.
+    if period == 0 {
+        return Err("Periods can't be zero".to_string());
+    }
+    Ok(period)
+}
+
+pub fn parse(text: &str) -> Result<PitchTable, String> {
+    let periods = text
+        .lines()
+        .map(|line| line.split(['#', ';']).next().unwrap())
+        .flat_map(|line| line.split([' ', '\t', ',']))
+        .filter(|word| !word.is_empty())
+        .map(parse_period)
+        .collect::<Result<Vec<_>, _>>()?;
+    let len = periods.len();
+    periods
+        .try_into()
+        .map_err(|_| format!("Table has {} periods, should have {}", len, PITCHES.len()))
+}
+
+pub fn load(file_name: &str) -> Result<PitchTable, String> {
+    let text =
+        std::fs::read_to_string(file_name).map_err(|err| format!("{}: {}", file_name, err))?;
+    parse(&text).map_err(|err| format!("{}: {}", file_name, err))
+}
+
+// The table as text that parse() reads back, an octave per
+// paragraph like sound_data.rs.
+#[cfg(feature = "gui")]
+pub fn to_text(pitches: &PitchTable) -> String {
+    let mut text = "# Periods in quarter semi-tones, from the lowest note up.\n".to_string();
+    for (octave, periods) in pitches.chunks(OCTAVE_SIZE).enumerate() {
+        let _ = writeln!(text, "\n# Octave {}", octave);
+        for row in periods.chunks(8) {
+            let row = row
+                .iter()
+                .map(|period| format!("0x{:04X}", period))
+                .collect::<Vec<_>>();
+            let _ = writeln!(text, "{}", row.join(", "));
+        }
+    }
+    text
+}
+
+// How many periods differ from the game's own table.
+#[cfg(feature = "gui")]
+pub fn differences(pitches: &PitchTable) -> usize {
+    pitches
+        .iter()
+        .zip(PITCHES.iter())
+        .filter(|(a, b)| a != b)
+        .count()
+}
@@ -0,0 +1,161 @@
+//
+// Speedball 2 Sound player
+//
+// preview.rs: Play back previously exported .wav files through the
+// synth's output, so they can be compared with the live synth
+// without switching programs. Only WAV is supported, as that's all
+// we export.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "export")]
+use std::fs::File;
+#[cfg(feature = "export")]
+use std::path::Path;
+use std::sync::Arc;
+
+#[cfg(feature = "gui")]
+use egui::{Slider, Ui};
+
+#[cfg(feature = "export")]
+use wav::BitDepth;
+
+pub struct Clip {
+    name: String,
+    sample_rate: u32,
+    num_channels: usize,
+    // Interleaved, scaled to +/-1.0.
+    samples: Vec<f32>,
+}
+
+impl Clip {
+    #[cfg(feature = "export")]
+    pub fn load(path: &Path) -> Result<Clip, String> {
+        let err = |e: std::io::Error| format!("{}: {}", path.display(), e);
+        let mut file = File::open(path).map_err(err)?;
+        let (header, data) = wav::read(&mut file).map_err(err)?;
+        let samples = match data {
+            BitDepth::Eight(v) => v.iter().map(|&x| (x as f32 - 128.0) / 128.0).collect(),
+            BitDepth::Sixteen(v) => v.iter().map(|&x| x as f32 / 32768.0).collect(),
+            BitDepth::TwentyFour(v) => v.iter().map(|&x| x as f32 / 8388608.0).collect(),
+            BitDepth::ThirtyTwoFloat(v) => v,
+            BitDepth::Empty => Vec::new(),
+        };
+        if header.channel_count == 0 || header.sampling_rate == 0 {
+            return Err(format!("{}: bad header", path.display()));
+        }
+        Ok(Clip {
+            name: path
+                .file_name()
+                .map_or_else(String::new, |n| n.to_string_lossy().to_string()),
+            sample_rate: header.sampling_rate,
+            num_channels: header.channel_count as usize,
+            samples,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // The channels averaged together.
+    pub fn mono(&self) -> Vec<f32> {
+        self.samples
+            .chunks(self.num_channels)
+            .map(|frame| frame.iter().sum::<f32>() / self.num_channels as f32)
+            .collect()
+    }
+
+    fn frames(&self) -> usize {
+        self.samples.len() / self.num_channels
+    }
+
+    fn len_s(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate as f64
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Preview {
+    clip: Option<Arc<Clip>>,
+    // Position in the clip, in its frames.
+    pos: f64,
+    playing: bool,
+}
+
+impl Preview {
+    // Start playing a new clip from the beginning.
+    pub fn set_clip(&mut self, clip: Arc<Clip>) {
+        self.clip = Some(clip);
+        self.pos = 0.0;
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    // Add the clip into the output buffer. Resampling is nearest
+    // neighbour, which is fine for checking what an export sounds
+    // like. Stereo clips go to the first two outputs.
+    pub fn render(&mut self, sample_rate: u32, out: &mut [f32], num_outputs: usize) {
+        let Some(clip) = &self.clip else {
+            return;
+        };
+        if !self.playing {
+            return;
+        }
+        let step = clip.sample_rate as f64 / sample_rate as f64;
+        for dsts in out.chunks_mut(num_outputs) {
+            let frame = self.pos as usize;
+            if frame >= clip.frames() {
+                self.playing = false;
+                self.pos = 0.0;
+                return;
+            }
+            let src = &clip.samples[frame * clip.num_channels..][..clip.num_channels];
+            for (idx, dst) in dsts.iter_mut().enumerate() {
+                *dst += if num_outputs == 1 {
+                    src.iter().sum::<f32>() / src.len() as f32
+                } else if idx < 2 {
+                    src[idx.min(src.len() - 1)]
+                } else {
+                    0.0
+                };
+            }
+            self.pos += step;
+        }
+    }
+
+    // Play or pause, from the given position.
+    #[cfg(feature = "gui")]
+    pub fn seek(&mut self, playing: bool, pos_s: f64) {
+        self.playing = playing;
+        if let Some(clip) = &self.clip {
+            self.pos = pos_s * clip.sample_rate as f64;
+        }
+    }
+
+    // Draws the controls for a copy published by the audio thread, so
+    // returns whether to play, and from where, when they're changed.
+    #[cfg(feature = "gui")]
+    pub fn ui(&self, ui: &mut Ui) -> Option<(bool, f64)> {
+        let clip = self.clip.as_ref()?;
+        ui.horizontal(|ui| {
+            ui.label(format!("Preview {}", clip.name));
+            let mut playing = self.playing;
+            let label = if playing { "Pause" } else { "Play" };
+            let clicked = ui.button(label).clicked();
+            if clicked {
+                playing = !playing;
+            }
+            let mut pos_s = self.pos / clip.sample_rate as f64;
+            let moved = ui
+                .add(Slider::new(&mut pos_s, 0.0..=clip.len_s()).suffix(" s"))
+                .changed();
+            (clicked || moved).then_some((playing, pos_s))
+        })
+        .inner
+    }
+}
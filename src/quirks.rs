@@ -0,0 +1,308 @@
+//
+// Speedball 2 Sound player
+//
+// quirks.rs: The things besides the command set that differ between
+// games and versions using this driver family: how often the driver
+// runs (PAL or NTSC vertical blank), Paula's clock, how many entries
+// the effect table has, how volume bytes map to gain, and whether to
+// play on past the command numbers nothing uses. Together
+// with the driver revision, they make up a bank's "quirks profile".
+//
+// Profiles come from presets, or the "quirks" section of the names
+// file (see annotations.rs), which looks like:
+//
+//   "quirks": {
+//     "preset": "speedball2-ntsc",
+//     "frame_rate": 60,
+//     "clock_hz": 3579545,
+//     "effects": 23,
+//     "volume": "linear",
+//     "unused_as_nops": false
+//   }
+//
+// where every field is optional, and overrides the preset (or the
+// driver revision's own profile, without one). Banks without a profile
+// get their driver revision's.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::fmt;
+
+use crate::driver::{self, Variant};
+#[cfg(feature = "gui")]
+use crate::json;
+use crate::json::Value;
+use crate::sound_data::EFFECTS;
+
+// The driver is stepped at least this often, so mixing buffers can be
+// sized up front.
+pub const MIN_FRAME_RATE: f32 = 25.0;
+pub const MAX_FRAME_RATE: f32 = 120.0;
+
+// Seconds per tick of Paula's clock.
+const PAL_CLOCK_INTERVAL_S: f32 = 0.281937e-6;
+const NTSC_CLOCK_INTERVAL_S: f32 = 0.279365e-6;
+
+// How a volume byte, 0 to 64, becomes a gain.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VolumeLaw {
+    // Straight to Paula, as Speedball 2 does.
+    Linear,
+    // Through a table, for an even fade: each step down from the top
+    // is this many dB quieter, and 0 is silent.
+    Decibels(f32),
+}
+
+impl VolumeLaw {
+    pub fn gain(self, volume: u8) -> f32 {
+        let volume = volume.min(64);
+        match self {
+            VolumeLaw::Linear => volume as f32 / 64.0,
+            VolumeLaw::Decibels(_) if volume == 0 => 0.0,
+            VolumeLaw::Decibels(step) => 10f32.powf(-step * (64 - volume) as f32 / 20.0),
+        }
+    }
+
+    fn parse(name: &str) -> Result<VolumeLaw, String> {
+        if name == "linear" {
+            return Ok(VolumeLaw::Linear);
+        }
+        name.strip_suffix("dB")
+            .and_then(|step| step.trim().parse().ok())
+            .filter(|step: &f32| *step > 0.0)
+            .map(VolumeLaw::Decibels)
+            .ok_or_else(|| {
+                format!(
+                    "Bad volume law '{}', expected 'linear' or e.g. '0.75dB'",
+                    name
+                )
+            })
+    }
+}
+
+impl fmt::Display for VolumeLaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VolumeLaw::Linear => write!(f, "linear"),
+            VolumeLaw::Decibels(step) => write!(f, "{}dB", step),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quirks {
+    pub variant: &'static Variant,
+    // Driver steps a second.
+    pub frame_rate: f32,
+    // Seconds per tick of Paula's clock, the unit of sample periods.
+    pub clock_interval_s: f32,
+    // Entries in the effect table. Effect commands past the end stop
+    // the sequence, as unknown ones do.
+    pub num_effects: usize,
+    pub volume_law: VolumeLaw,
+    // Take the driver revision's unused command numbers to be no-ops
+    // without operands, rather than stopping the sequence. A guess,
+    // for exploring data that uses them.
+    pub unused_as_nops: bool,
+}
+
+pub struct Preset {
+    pub name: &'static str,
+    #[cfg(feature = "gui")]
+    pub title: &'static str,
+    pub quirks: Quirks,
+}
+
+pub const SPEEDBALL2_PAL: Quirks = Quirks {
+    variant: &driver::SPEEDBALL2,
+    frame_rate: 50.0,
+    clock_interval_s: PAL_CLOCK_INTERVAL_S,
+    num_effects: EFFECTS.len(),
+    volume_law: VolumeLaw::Linear,
+    unused_as_nops: false,
+};
+
+pub const PRESETS: [Preset; 2] = [
+    Preset {
+        name: "speedball2-pal",
+        #[cfg(feature = "gui")]
+        title: "Speedball II (PAL)",
+        quirks: SPEEDBALL2_PAL,
+    },
+    Preset {
+        name: "speedball2-ntsc",
+        #[cfg(feature = "gui")]
+        title: "Speedball II (NTSC)",
+        quirks: Quirks {
+            frame_rate: 60.0,
+            clock_interval_s: NTSC_CLOCK_INTERVAL_S,
+            ..SPEEDBALL2_PAL
+        },
+    },
+];
+
+fn number(section: &Value, key: &str) -> Result<Option<f64>, String> {
+    match section.get(key) {
+        None => Ok(None),
+        Some(Value::Number(n)) => Ok(Some(*n)),
+        Some(_) => Err(format!("'{}' in 'quirks' should be a number", key)),
+    }
+}
+
+impl Quirks {
+    // The profile a driver revision's banks get by default: its
+    // first preset.
+    pub fn for_variant(variant: &'static Variant) -> Quirks {
+        PRESETS
+            .iter()
+            .find(|preset| std::ptr::eq(preset.quirks.variant, variant))
+            .map_or(
+                Quirks {
+                    variant,
+                    ..SPEEDBALL2_PAL
+                },
+                |preset| preset.quirks,
+            )
+    }
+
+    pub fn preset(name: &str) -> Result<Quirks, String> {
+        PRESETS
+            .iter()
+            .find(|preset| preset.name == name)
+            .map(|preset| preset.quirks)
+            .ok_or_else(|| {
+                let names = PRESETS.iter().map(|p| p.name).collect::<Vec<_>>();
+                format!(
+                    "Unknown preset '{}', expected one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            })
+    }
+
+    // The preset this profile is, if any.
+    #[cfg(feature = "gui")]
+    pub fn preset_name(&self) -> Option<&'static str> {
+        PRESETS
+            .iter()
+            .find(|preset| preset.quirks == *self)
+            .map(|preset| preset.name)
+    }
+
+    // Read the "quirks" section of a names file.
+    pub fn from_json(section: &Value) -> Result<Quirks, String> {
+        let variant = match section.get("driver") {
+            Some(value) => {
+                let name = value
+                    .as_str()
+                    .ok_or("'driver' in 'quirks' should be a string")?;
+                Some(driver::parse_variant(name)?)
+            }
+            None => None,
+        };
+        let mut quirks = match (section.get("preset"), variant) {
+            (Some(Value::String(name)), _) => Quirks::preset(name)?,
+            (Some(_), _) => return Err("'preset' in 'quirks' should be a string".to_string()),
+            (None, Some(variant)) => Quirks::for_variant(variant),
+            (None, None) => SPEEDBALL2_PAL,
+        };
+        if let Some(variant) = variant {
+            quirks.variant = variant;
+        }
+        if let Some(rate) = number(section, "frame_rate")? {
+            let rate = rate as f32;
+            if !(MIN_FRAME_RATE..=MAX_FRAME_RATE).contains(&rate) {
+                return Err(format!(
+                    "'frame_rate' should be from {} to {}",
+                    MIN_FRAME_RATE, MAX_FRAME_RATE
+                ));
+            }
+            quirks.frame_rate = rate;
+        }
+        if let Some(clock) = number(section, "clock_hz")? {
+            if clock <= 0.0 {
+                return Err("'clock_hz' should be positive".to_string());
+            }
+            quirks.clock_interval_s = 1.0 / clock as f32;
+        }
+        if let Some(value) = section.get("effects") {
+            quirks.num_effects = value
+                .as_usize()
+                .filter(|&n| n <= EFFECTS.len())
+                .ok_or_else(|| format!("'effects' should be at most {}", EFFECTS.len()))?;
+        }
+        if let Some(value) = section.get("volume") {
+            let name = value
+                .as_str()
+                .ok_or("'volume' in 'quirks' should be a string")?;
+            quirks.volume_law = VolumeLaw::parse(name)?;
+        }
+        match section.get("unused_as_nops") {
+            None => (),
+            Some(Value::Bool(b)) => quirks.unused_as_nops = *b,
+            Some(_) => {
+                return Err("'unused_as_nops' in 'quirks' should be true or false".to_string())
+            }
+        }
+        Ok(quirks)
+    }
+
+    // The "quirks" section giving this profile in full.
+    #[cfg(feature = "gui")]
+    pub fn to_json(self) -> Value {
+        let mut fields = Vec::new();
+        if let Some(name) = self.preset_name() {
+            fields.push(("preset", Value::from(name)));
+        }
+        fields.extend([
+            ("driver", Value::from(self.variant.name)),
+            ("frame_rate", Value::from(self.frame_rate as f64)),
+            ("clock_hz", Value::from(self.clock_hz().round() as f64)),
+            ("effects", Value::from(self.num_effects)),
+            ("volume", Value::from(self.volume_law.to_string())),
+            ("unused_as_nops", Value::from(self.unused_as_nops)),
+        ]);
+        json::object(fields)
+    }
+
+    pub fn clock_hz(&self) -> f32 {
+        1.0 / self.clock_interval_s
+    }
+
+    // Should this command, which the driver revision doesn't decode,
+    // be skipped over rather than stop the sequence?
+    pub fn skips(&self, code: u8) -> bool {
+        self.unused_as_nops && self.variant.unused_codes.contains(&code)
+    }
+}
+
+// Variants can't be compared, so are compared by identity.
+impl PartialEq for Quirks {
+    fn eq(&self, other: &Quirks) -> bool {
+        std::ptr::eq(self.variant, other.variant)
+            && self.frame_rate == other.frame_rate
+            && self.clock_interval_s == other.clock_interval_s
+            && self.num_effects == other.num_effects
+            && self.volume_law == other.volume_law
+            && self.unused_as_nops == other.unused_as_nops
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} frames/s, {:.0} Hz clock, {} effects, {} volume",
+            self.variant.title,
+            self.frame_rate,
+            self.clock_hz(),
+            self.num_effects,
+            self.volume_law
+        )?;
+        if self.unused_as_nops {
+            write!(f, ", unused codes skipped")?;
+        }
+        Ok(())
+    }
+}
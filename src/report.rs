@@ -0,0 +1,353 @@
+//
+// Speedball 2 Sound player
+//
+// report.rs: A "sound design sheet" for a bank: a single,
+// self-contained HTML page documenting its instruments (with
+// waveform thumbnails), sequences (with how long they play and their
+// tempo changes), the sounds table, and what refers to what. Meant
+// for dropping straight into a wiki or archive.
+//
+// Durations and tempo maps come from running each sequence through
+// the interpreter, silently and once through, so they match what's
+// heard.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::sync::Arc;
+
+#[cfg(feature = "gui")]
+use rfd::FileDialog;
+
+use crate::annotations::Annotations;
+use crate::classify;
+use crate::cpal_wrapper::SoundSource;
+use crate::driver::{self, Op};
+use crate::gaps;
+use crate::sound_data::SOUNDS;
+use crate::sound_player::{Command, SoundBank, Synth};
+
+// Longest we'll run a sequence for, for ones that never end, in
+// seconds.
+const MAX_SECONDS: f32 = 60.0 * 10.0;
+// Sequences are rendered at a low rate, as only their peak level is
+// wanted.
+const SAMPLE_RATE: u32 = 11_025;
+// Size of the waveform thumbnails, in pixels.
+const THUMB_WIDTH: usize = 200;
+const THUMB_HEIGHT: usize = 40;
+
+const STYLE: &str = "body { font-family: sans-serif; }
+table { border-collapse: collapse; }
+th, td { border: 1px solid #ccc; padding: 2px 6px; vertical-align: top; }
+td.num { font-family: monospace; text-align: right; }
+svg { background: #111; }";
+
+// What running a sequence once through finds.
+struct Playthrough {
+    frames: usize,
+    // Still going when we gave up.
+    endless: bool,
+    // Frame, and frames per driver beat, each time the tempo changes.
+    tempos: Vec<(usize, usize)>,
+    // Loudest output sample, on either side.
+    peak: f32,
+}
+
+fn play_through(bank: &Arc<SoundBank>, idx: usize) -> Playthrough {
+    let mut synth = Synth::new(bank.clone());
+    synth.set_max_passes(1);
+    synth.apply(Command::Sequence(idx));
+    let mut tempos = Vec::new();
+    let max_frames = (MAX_SECONDS * bank.quirks.frame_rate) as usize;
+    let samples_per_frame = (SAMPLE_RATE as f32 / bank.quirks.frame_rate) as usize;
+    let mut buf = vec![0.0; samples_per_frame * 2];
+    let mut peak = 0.0f32;
+    let mut frames = 0;
+    while frames < max_frames && synth.channels[0].beats().is_some() {
+        synth.tick();
+        synth.render(SAMPLE_RATE, &mut buf);
+        peak = buf.iter().fold(peak, |peak, x| peak.max(x.abs()));
+        if let Some(fpb) = synth.channels[0].frames_per_beat() {
+            if tempos.last().map(|&(_, last)| last) != Some(fpb) {
+                tempos.push((frames, fpb));
+            }
+        }
+        frames += 1;
+    }
+    Playthrough {
+        frames,
+        endless: frames == max_frames,
+        tempos,
+        peak,
+    }
+}
+
+// What a sequence refers to directly, and how many notes it has of
+// its own.
+#[derive(Default)]
+pub struct Refs {
+    pub instruments: BTreeSet<usize>,
+    pub calls: BTreeSet<usize>,
+    pub jumps: BTreeSet<usize>,
+    pub notes: usize,
+}
+
+pub fn refs(bank: &SoundBank, addr: usize) -> Refs {
+    let bytes = driver::sequence_bytes(bank.variant, &bank.data, addr);
+    let mut refs = Refs::default();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let code = bytes[offset];
+        if code < 0x80 {
+            refs.notes += 1;
+            offset += 1;
+            continue;
+        }
+        // sequence_bytes only returns sequences that decode.
+        let op = (bank.variant.decode)(code).unwrap();
+        let operand = bytes.get(offset + 1).copied().unwrap_or(0) as usize;
+        let set = match op {
+            Op::Instrument => Some(&mut refs.instruments),
+            Op::Call => Some(&mut refs.calls),
+            Op::Jump => Some(&mut refs.jumps),
+            _ => None,
+        };
+        if let Some(set) = set {
+            set.insert(operand);
+        }
+        offset += 1 + op.operand_len();
+    }
+    refs
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn name_cell(name: Option<&String>) -> String {
+    name.map_or(String::new(), |name| escape(name))
+}
+
+fn time(frames: usize, frame_rate: f32) -> String {
+    let seconds = frames as f32 / frame_rate;
+    format!("{}:{:05.2}", (seconds / 60.0) as usize, seconds % 60.0)
+}
+
+// Links to entries elsewhere in the report.
+fn links(kind: &str, indices: impl IntoIterator<Item = usize>) -> String {
+    indices
+        .into_iter()
+        .map(|idx| format!("<a href=\"#{kind}-{idx:02x}\">{idx:02x}</a>"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Min/max envelope of the sample, with the loop start marked.
+fn thumbnail(samples: &[u8], loop_start: Option<usize>) -> String {
+    let mut svg = format!(
+        "<svg width=\"{THUMB_WIDTH}\" height=\"{THUMB_HEIGHT}\"><path stroke=\"#6c6\" d=\""
+    );
+    if !samples.is_empty() {
+        let scale = THUMB_HEIGHT as f32 / 256.0;
+        let mid = THUMB_HEIGHT as f32 / 2.0;
+        for x in 0..THUMB_WIDTH {
+            let start = x * samples.len() / THUMB_WIDTH;
+            let end = ((x + 1) * samples.len() / THUMB_WIDTH).max(start + 1);
+            let column = samples[start..end.min(samples.len())].iter();
+            let (lo, hi) = column.fold((0i8, 0i8), |(lo, hi), &s| {
+                (lo.min(s as i8), hi.max(s as i8))
+            });
+            let _ = write!(
+                svg,
+                "M{x} {:.1}V{:.1}",
+                mid - hi as f32 * scale,
+                mid - lo as f32 * scale + 0.5
+            );
+        }
+    }
+    svg.push_str("\"/>");
+    if let Some(start) = loop_start.filter(|_| !samples.is_empty()) {
+        let x = start * THUMB_WIDTH / samples.len();
+        let _ = write!(svg, "<path stroke=\"#c66\" d=\"M{x} 0V{THUMB_HEIGHT}\"/>");
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+pub fn html(bank: &Arc<SoundBank>, annotations: &Annotations, title: &str) -> String {
+    let all_refs = bank
+        .sequences
+        .iter()
+        .map(|&addr| refs(bank, addr))
+        .collect::<Vec<_>>();
+    let tags = classify::classify(bank);
+
+    let mut out = String::new();
+    let _ = write!(
+        out,
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>{0} sound design sheet</title><style>{STYLE}</style></head><body>\n\
+         <h1>{0} sound design sheet</h1>\n\
+         <p>Driver: {1}. {2} sequences, {3} instruments, {4} sounds, {5} bytes of data.</p>\n",
+        escape(title),
+        bank.variant.title,
+        bank.sequences.len(),
+        bank.instruments.len(),
+        SOUNDS.len(),
+        bank.data.len()
+    );
+
+    out.push_str(
+        "<h2>Instruments</h2>\n<table><tr><th>#</th><th>Name</th><th>Sample</th>\
+         <th>Bytes</th><th>Loop</th><th>Octave</th><th>Seconds</th><th>Peak</th>\
+         <th>Waveform</th><th>Used by</th></tr>\n",
+    );
+    for (idx, instr) in bank.instruments.iter().enumerate() {
+        let len = instr.sample_len as usize * 2;
+        let samples = bank
+            .data
+            .get(instr.sample_addr..instr.sample_addr + len)
+            .unwrap_or(&[]);
+        let loop_start = (!instr.is_one_shot).then_some(instr.loop_offset as usize);
+        let peak = samples
+            .iter()
+            .map(|&s| (s as i8).unsigned_abs())
+            .max()
+            .unwrap_or(0);
+        let users = all_refs
+            .iter()
+            .enumerate()
+            .filter(|(_, refs)| refs.instruments.contains(&idx))
+            .map(|(seq, _)| seq);
+        let _ = writeln!(
+            out,
+            "<tr id=\"instrument-{idx:02x}\"><td class=\"num\">{idx:02x}</td><td>{}</td>\
+             <td class=\"num\">0x{:06x}</td><td class=\"num\">{len}</td><td>{}</td>\
+             <td class=\"num\">{}</td><td class=\"num\">{:.2}</td><td class=\"num\">{}%</td>\
+             <td>{}</td><td>{}</td></tr>",
+            name_cell(annotations.instruments.get(&idx)),
+            instr.sample_addr,
+            loop_start.map_or("one-shot".to_string(), |start| format!("from {}", start)),
+            instr.base_octave,
+            len as f32 / instr.default_rate(bank),
+            peak as usize * 100 / 128,
+            thumbnail(samples, loop_start),
+            links("sequence", users)
+        );
+    }
+    out.push_str("</table>\n");
+
+    out.push_str(
+        "<h2>Sequences</h2>\n<p>Durations are once through, at the tempos the \
+         sequence sets.</p>\n<table><tr><th>#</th><th>Name</th><th>Address</th>\
+         <th>Kind</th><th>Duration</th><th>Peak</th><th>Tempo changes</th><th>Instruments</th>\
+         <th>Calls</th><th>Jumps to</th><th>Called by</th><th>In sounds</th></tr>\n",
+    );
+    // Skip the empty sequence.
+    let frame_rate = bank.quirks.frame_rate;
+    for (idx, &addr) in bank.sequences.iter().enumerate().skip(1) {
+        let run = play_through(bank, idx);
+        let duration = if run.endless {
+            format!("over {}", time(run.frames, frame_rate))
+        } else {
+            time(run.frames, frame_rate)
+        };
+        let tempos = run
+            .tempos
+            .iter()
+            .map(|&(frame, fpb)| {
+                format!(
+                    "{}: {} frames/beat ({:.0} bpm)",
+                    time(frame, frame_rate),
+                    fpb,
+                    750.0 / fpb as f32
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("<br>");
+        let refs = &all_refs[idx];
+        let callers = all_refs
+            .iter()
+            .enumerate()
+            .filter(|(_, refs)| refs.calls.contains(&idx) || refs.jumps.contains(&idx))
+            .map(|(seq, _)| seq);
+        let sounds = SOUNDS
+            .iter()
+            .enumerate()
+            .filter(|(_, sound)| sound.sequences.contains(&idx))
+            .map(|(sound, _)| sound);
+        let _ = writeln!(
+            out,
+            "<tr id=\"sequence-{idx:02x}\"><td class=\"num\">{idx:02x}</td><td>{}</td>\
+             <td class=\"num\">0x{addr:06x}</td><td>{}</td><td class=\"num\">{duration}</td>\
+             <td class=\"num\">{:.0}%</td><td>{tempos}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            name_cell(annotations.sequences.get(&idx)),
+            tags.get(idx)
+                .map_or(String::new(), |tag| format!("{:?}", tag)),
+            run.peak * 100.0,
+            links("instrument", refs.instruments.iter().copied()),
+            links("sequence", refs.calls.iter().copied()),
+            links("sequence", refs.jumps.iter().copied()),
+            links("sequence", callers),
+            links("sound", sounds)
+        );
+    }
+    out.push_str("</table>\n");
+
+    out.push_str(
+        "<h2>Sounds</h2>\n<table><tr><th>#</th><th>Name</th><th>Channel 0</th>\
+         <th>Channel 1</th><th>Channel 2</th><th>Channel 3</th></tr>\n",
+    );
+    for (idx, sound) in SOUNDS.iter().enumerate() {
+        let channels = sound
+            .sequences
+            .iter()
+            .map(|&seq| match seq {
+                0 => "<td></td>".to_string(),
+                _ => format!("<td>{}</td>", links("sequence", [seq])),
+            })
+            .collect::<String>();
+        let _ = writeln!(
+            out,
+            "<tr id=\"sound-{idx:02x}\"><td class=\"num\">{idx:02x}</td><td>{}</td>{channels}</tr>",
+            name_cell(annotations.sounds.get(&idx))
+        );
+    }
+    out.push_str("</table>\n");
+
+    // Left-over content worth a closer look.
+    let gaps = gaps::find(bank);
+    if !gaps.is_empty() {
+        out.push_str("<h2>Gaps</h2>\n<ul>\n");
+        for gap in gaps {
+            let _ = writeln!(out, "<li>{}</li>", escape(&gap.to_string()));
+        }
+        out.push_str("</ul>\n");
+    }
+    out.push_str("</body></html>\n");
+    out
+}
+
+// Ask where to save the report, and write it there.
+#[cfg(feature = "gui")]
+pub fn save_html(
+    bank: &Arc<SoundBank>,
+    annotations: &Annotations,
+    title: &str,
+) -> Result<(), String> {
+    let file_name = FileDialog::new()
+        .add_filter("HTML", &["html"])
+        .set_file_name(&format!("{}.html", title))
+        .save_file();
+    match file_name {
+        Some(name) => std::fs::write(&name, html(bank, annotations, title))
+            .map_err(|err| format!("{}: {}", name.display(), err)),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,172 @@
+//
+// Speedball 2 Sound player
+//
+// sampler.rs: Export instruments' raw samples as .wav files for
+// modern samplers, with the loop stored in a "smpl" chunk. Loops that
+// were fine on the Amiga can click elsewhere, so there's an option to
+// look for a smoother loop start nearby. Only the exported file is
+// changed, never the bank.
+//
+// Going the other way, a .wav file can be imported to replace an
+// instrument's sample, to hear replacement sounds in context.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::path::Path;
+
+#[cfg(feature = "export")]
+use crate::preview::Clip;
+use crate::sound_player::{Instrument, SoundBank};
+
+// How far either side of the original loop start to search.
+const SEARCH_RADIUS: usize = 256;
+// Number of samples before the loop points that should match.
+const MATCH_LEN: usize = 16;
+// How much to favour starting the loop near a zero crossing.
+const ZERO_CROSSING_WEIGHT: f32 = 4.0;
+// Middle C, as the sample plays at the default pitch.
+const UNITY_NOTE: u32 = 60;
+
+// How badly the loop would click if it started here: the samples
+// leading up to the end of the sample should look like those leading
+// up to the loop start, and the loop should start near zero.
+fn loop_cost(sample: &[i8], start: usize) -> f32 {
+    let end = sample.len();
+    let window = MATCH_LEN.min(start);
+    let mismatch = (1..=window)
+        .map(|k| {
+            let diff = sample[end - k] as f32 - sample[start - k] as f32;
+            diff * diff
+        })
+        .sum::<f32>()
+        / window.max(1) as f32;
+    let level = sample[start] as f32;
+    mismatch + ZERO_CROSSING_WEIGHT * level * level
+}
+
+// Find the best loop start near the original one, keeping the end
+// where it is. Only moves it if that's an improvement.
+pub fn optimise_loop(sample: &[i8], loop_start: usize) -> usize {
+    if sample.len() < 2 {
+        return loop_start;
+    }
+    let last = sample.len() - 2;
+    let loop_start = loop_start.min(last);
+    let (lo, hi) = (
+        loop_start.saturating_sub(SEARCH_RADIUS),
+        (loop_start + SEARCH_RADIUS).min(last),
+    );
+    let mut best = (loop_cost(sample, loop_start), loop_start);
+    for start in lo..=hi {
+        let cost = loop_cost(sample, start);
+        if cost < best.0 {
+            best = (cost, start);
+        }
+    }
+    best.1
+}
+
+fn push_u16(out: &mut Vec<u8>, x: u16) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn push_u32(out: &mut Vec<u8>, x: u32) {
+    out.extend_from_slice(&x.to_le_bytes());
+}
+
+fn push_chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(id);
+    push_u32(out, data.len() as u32);
+    out.extend_from_slice(data);
+    if !data.len().is_multiple_of(2) {
+        out.push(0);
+    }
+}
+
+// Build an 8-bit mono .wav of the sample, with a loop from the given
+// start to the end of the sample, if it loops.
+fn wav_bytes(sample: &[i8], rate: u32, loop_start: Option<usize>) -> Vec<u8> {
+    let mut fmt = Vec::new();
+    push_u16(&mut fmt, 1); // PCM
+    push_u16(&mut fmt, 1); // Mono
+    push_u32(&mut fmt, rate);
+    push_u32(&mut fmt, rate); // Bytes per second
+    push_u16(&mut fmt, 1); // Block align
+    push_u16(&mut fmt, 8); // Bits per sample
+
+    let mut body = b"WAVE".to_vec();
+    push_chunk(&mut body, b"fmt ", &fmt);
+
+    if let Some(start) = loop_start {
+        let mut smpl = Vec::new();
+        push_u32(&mut smpl, 0); // Manufacturer
+        push_u32(&mut smpl, 0); // Product
+        push_u32(&mut smpl, 1_000_000_000 / rate); // Sample period, ns
+        push_u32(&mut smpl, UNITY_NOTE);
+        push_u32(&mut smpl, 0); // Pitch fraction
+        push_u32(&mut smpl, 0); // SMPTE format
+        push_u32(&mut smpl, 0); // SMPTE offset
+        push_u32(&mut smpl, 1); // Number of loops
+        push_u32(&mut smpl, 0); // Sampler data
+        push_u32(&mut smpl, 0); // Cue point ID
+        push_u32(&mut smpl, 0); // Forward loop
+        push_u32(&mut smpl, start as u32);
+        push_u32(&mut smpl, sample.len() as u32 - 1); // Inclusive end
+        push_u32(&mut smpl, 0); // Fraction
+        push_u32(&mut smpl, 0); // Loop forever
+        push_chunk(&mut body, b"smpl", &smpl);
+    }
+
+    // 8-bit .wav data is unsigned.
+    let data = sample.iter().map(|&x| x as u8 ^ 0x80).collect::<Vec<_>>();
+    push_chunk(&mut body, b"data", &data);
+
+    let mut out = Vec::new();
+    push_chunk(&mut out, b"RIFF", &body);
+    out
+}
+
+pub fn save_instrument(
+    bank: &SoundBank,
+    instr: &Instrument,
+    optimise: bool,
+    path: &Path,
+) -> Result<(), String> {
+    let sample = bank.data[instr.sample_addr..][..instr.sample_len as usize * 2]
+        .iter()
+        .map(|&x| x as i8)
+        .collect::<Vec<_>>();
+    let loop_start = if instr.is_one_shot || sample.is_empty() {
+        None
+    } else if optimise {
+        Some(optimise_loop(&sample, instr.loop_offset as usize))
+    } else {
+        Some((instr.loop_offset as usize).min(sample.len() - 1))
+    };
+    let bytes = wav_bytes(&sample, instr.default_rate(bank).round() as u32, loop_start);
+    std::fs::write(path, bytes).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+// Load a .wav file as a replacement sample for an instrument,
+// returning the modified bank. The file is converted to mono and
+// resampled so that it plays at its original pitch at the default
+// pitch.
+#[cfg(feature = "export")]
+pub fn import_instrument(bank: &SoundBank, idx: usize, path: &Path) -> Result<SoundBank, String> {
+    let clip = Clip::load(path)?;
+    let input = clip.mono();
+    let step = clip.sample_rate() as f32 / bank.instruments[idx].default_rate(bank);
+    let len = (input.len() as f32 / step) as usize;
+    let sample = (0..len)
+        .map(|i| {
+            let pos = i as f32 * step;
+            let (left_idx, x) = (pos as usize, pos.fract());
+            let left = input[left_idx];
+            let right = input.get(left_idx + 1).copied().unwrap_or(left);
+            let val = left * (1.0 - x) + right * x;
+            (val * 128.0).round().clamp(-128.0, 127.0) as i8
+        })
+        .collect::<Vec<_>>();
+    bank.with_sample(idx, &sample)
+}
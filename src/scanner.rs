@@ -0,0 +1,177 @@
+//
+// Speedball 2 Sound player
+//
+// scanner.rs: Search disk images (or any other big blob) for data
+// that looks like it belongs to this sound driver.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use crate::driver;
+use crate::sound_player::{long, set_long, word, Instrument};
+
+// Minimum table sizes for a hit to be worth reporting.
+const MIN_SEQUENCES: usize = 4;
+const MIN_INSTRUMENTS: usize = 4;
+// The pitch table only has room for this many octaves.
+const MAX_OCTAVE: usize = 8;
+
+#[derive(Debug)]
+pub struct Hit {
+    pub offset: usize,
+    pub num_sequences: usize,
+    pub num_instruments: usize,
+}
+
+// Read a pointer, converting it to an offset into the bank. In files
+// pointers are relative to the start of the bank, so the bias is 0,
+// but once loaded into memory they may have been relocated to
+// absolute addresses, so the bias is the bank's address.
+pub fn pointer(data: &[u8], addr: usize, bias: usize) -> Option<usize> {
+    (long(data, addr) as usize).checked_sub(bias)
+}
+
+// A bank starts with a pointer to the sequence table, then a pointer
+// to the instrument table.
+fn table_addr(data: &[u8], header_addr: usize, bias: usize) -> Option<usize> {
+    if data.len() < header_addr + 4 {
+        return None;
+    }
+    let addr = pointer(data, header_addr, bias)?;
+    if addr < 8 || addr & 1 != 0 || addr >= data.len() {
+        return None;
+    }
+    Some(addr)
+}
+
+// Count the plausible entries in the sequence table. Entry 0 is the
+// empty sequence, and the table is followed by a zero entry.
+pub fn count_sequences(data: &[u8]) -> usize {
+    count_sequences_biased(data, 0)
+}
+
+fn count_sequences_biased(data: &[u8], bias: usize) -> usize {
+    let Some(table) = table_addr(data, 0, bias) else {
+        return 0;
+    };
+    // Relocation may or may not have turned the empty entry into a
+    // pointer to the start of the bank.
+    if table + 4 > data.len() || (long(data, table) != 0 && pointer(data, table, bias) != Some(0)) {
+        return 0;
+    }
+
+    let mut count = 1;
+    loop {
+        let entry_addr = table + count * 4;
+        if entry_addr + 4 > data.len() {
+            return count;
+        }
+        let Some(seq_addr) = pointer(data, entry_addr, bias) else {
+            return count;
+        };
+        if seq_addr < 8 || seq_addr >= data.len() {
+            return count;
+        }
+        // And it should start with something the driver understands.
+        if !driver::is_known_code(data[seq_addr]) {
+            return count;
+        }
+        count += 1;
+    }
+}
+
+fn plausible_instrument(data: &[u8], addr: usize, bias: usize) -> bool {
+    if addr + Instrument::SIZE > data.len() {
+        return false;
+    }
+    let one_shot_flag = word(data, addr);
+    let loop_offset = word(data, addr + 2) as usize;
+    let sample_len = word(data, addr + 4) as usize * 2;
+    let Some(sample_addr) = pointer(data, addr + 6, bias) else {
+        return false;
+    };
+    let base_octave = long(data, addr + 10) as usize;
+
+    matches!(one_shot_flag, 0 | 1 | 0xffff)
+        && sample_len > 0
+        && loop_offset <= sample_len
+        && base_octave < MAX_OCTAVE
+        && sample_addr >= 8
+        && sample_addr + sample_len <= data.len()
+}
+
+// Count the plausible entries in the instrument table. There's no
+// terminator, so we keep going until the entries stop making sense.
+pub fn count_instruments(data: &[u8]) -> usize {
+    count_instruments_biased(data, 0)
+}
+
+fn count_instruments_biased(data: &[u8], bias: usize) -> usize {
+    let Some(table) = table_addr(data, 4, bias) else {
+        return 0;
+    };
+    (0..)
+        .take_while(|idx| plausible_instrument(data, table + idx * Instrument::SIZE, bias))
+        .count()
+}
+
+// Try every word-aligned offset in the image as the start of a bank.
+pub fn scan(image: &[u8]) -> Vec<Hit> {
+    scan_biased(image, None)
+}
+
+// As scan(), but for memory images where the bank's pointers have
+// been relocated to absolute addresses. The image starts at the
+// given address.
+pub fn scan_relocated(image: &[u8], base: usize) -> Vec<Hit> {
+    scan_biased(image, Some(base))
+}
+
+fn scan_biased(image: &[u8], base: Option<usize>) -> Vec<Hit> {
+    (0..image.len().saturating_sub(8))
+        .step_by(2)
+        .filter_map(|offset| {
+            let data = &image[offset..];
+            let bias = base.map_or(0, |base| base + offset);
+            let num_sequences = count_sequences_biased(data, bias);
+            if num_sequences < MIN_SEQUENCES {
+                return None;
+            }
+            let num_instruments = count_instruments_biased(data, bias);
+            if num_instruments < MIN_INSTRUMENTS {
+                return None;
+            }
+            Some(Hit {
+                offset,
+                num_sequences,
+                num_instruments,
+            })
+        })
+        .collect()
+}
+
+// Copy a bank found by scan_relocated() out of the image, turning its
+// pointers back into offsets from the start of the bank, as in the
+// game's files.
+pub fn unrelocate(image: &[u8], base: usize, hit: &Hit) -> Vec<u8> {
+    let bias = (base + hit.offset) as u32;
+    let mut data = image[hit.offset..].to_vec();
+    let fix = |data: &mut [u8], addr: usize| {
+        let value = long(data, addr);
+        if value != 0 {
+            set_long(data, addr, value.wrapping_sub(bias));
+        }
+    };
+    // Read the tables' addresses before fixing the header.
+    let sequence_table = long(&data, 0).wrapping_sub(bias) as usize;
+    let instrument_table = long(&data, 4).wrapping_sub(bias) as usize;
+    fix(&mut data, 0);
+    fix(&mut data, 4);
+    for idx in 0..hit.num_sequences {
+        fix(&mut data, sequence_table + idx * 4);
+    }
+    for idx in 0..hit.num_instruments {
+        fix(&mut data, instrument_table + idx * Instrument::SIZE + 6);
+    }
+    data
+}
@@ -0,0 +1,723 @@
+//
+// Speedball 2 Sound player
+//
+// script.rs: A small scripting language for automating analyses and
+// exports the GUI doesn't anticipate, run with --script or from the
+// player's script console. A script is a list of commands, one a
+// line, such as:
+//
+//   # Render each looping sequence, and list how long it is.
+//   set max_time 120
+//   for seq in 1..$sequences
+//     let secs = duration sequence $seq
+//     print sequence $seq lasts $secs s
+//     render sequence $seq out/seq_$seq.wav
+//   end
+//
+// Words are split on whitespace, and "$name" or "${name}" in a word is
+// replaced by the variable's value. "#" starts a comment. Commands are:
+//
+//   print WORDS...           Print the words.
+//   let NAME = WORDS...      Set a variable, to the words, or to the
+//                            result of a function (below), or of
+//                            "A + B" (or -, *, /) on numbers.
+//   for NAME in A..B         Run the lines up to "end" for each number
+//   for NAME in KIND           from A up to, but not including, B, or
+//                            for each sequence, instrument or sound.
+//   end
+//   load FILE [OFFSET]       Load a bank from a file.
+//   set SETTING VALUE        "max_time" (seconds), "tail" (as
+//                            --tail), "passes", or "midi" or
+//                            "measurement" (on or off).
+//   render KIND N FILE       Render to a .wav file (and .mid, with
+//                            midi on), as a batch export would.
+//   export DIR               Render the whole bank, as --export-dir.
+//   write FILE WORDS...      Write the words as a line of a new file,
+//   append FILE WORDS...       or the end of an existing one.
+//   play KIND N              Play on the player's synth, from the
+//   stop                       console only.
+//   wait SECONDS             Pause, e.g. to let something play.
+//
+// KIND is "sound", "sequence", "instrument" or "music" (a sound that
+// stops everything else, as the game's music does). Functions are:
+//
+//   duration KIND N          Seconds a render would last.
+//   name KIND N              Its name from the names file, if any.
+//   notes N                  Notes a sequence plays once through.
+//   loops N                  "yes" if a sequence repeats forever.
+//
+// and "$sequences", "$instruments" and "$sounds" are how many the
+// bank has.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "gui")]
+use std::sync::mpsc::Sender;
+#[cfg(feature = "gui")]
+use std::sync::Mutex;
+#[cfg(feature = "gui")]
+use std::thread::{self, JoinHandle};
+
+#[cfg(feature = "gui")]
+use egui::{Color32, ScrollArea, TextEdit, Ui};
+#[cfg(feature = "gui")]
+use rfd::FileDialog;
+
+use crate::annotations::Annotations;
+use crate::batch;
+use crate::cpal_wrapper::Tail;
+#[cfg(feature = "gui")]
+use crate::cpal_wrapper::Timed;
+use crate::duration;
+use crate::sound_data::SOUNDS;
+use crate::sound_player::{Command, SoundBank, Synth, DEFAULT_PITCH};
+
+// Lines, with their line numbers for errors.
+enum Statement {
+    Command(Vec<String>),
+    For {
+        var: String,
+        range: Vec<String>,
+        body: Vec<Line>,
+    },
+}
+
+struct Line {
+    number: usize,
+    statement: Statement,
+}
+
+pub struct Script {
+    lines: Vec<Line>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, String> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.split('#').next().unwrap()))
+            .map(|(number, code)| {
+                let words = code.split_whitespace().map(str::to_string).collect();
+                (number, words)
+            })
+            .filter(|(_, words): &(usize, Vec<String>)| !words.is_empty());
+        let (lines, end) = parse_block(&mut lines)?;
+        match end {
+            Some(number) => Err(format!("Line {}: 'end' without 'for'", number)),
+            None => Ok(Script { lines }),
+        }
+    }
+}
+
+// Lines up to an "end", returning the "end"'s line number, if there
+// was one.
+fn parse_block(
+    lines: &mut impl Iterator<Item = (usize, Vec<String>)>,
+) -> Result<(Vec<Line>, Option<usize>), String> {
+    let mut block = Vec::new();
+    while let Some((number, words)) = lines.next() {
+        let statement = match words[0].as_str() {
+            "end" => return Ok((block, Some(number))),
+            "for" => {
+                if words.len() != 4 || words[2] != "in" {
+                    return Err(format!("Line {}: expected 'for NAME in RANGE'", number));
+                }
+                let (body, end) = parse_block(lines)?;
+                if end.is_none() {
+                    return Err(format!("Line {}: 'for' without 'end'", number));
+                }
+                Statement::For {
+                    var: words[1].clone(),
+                    range: words[3..].to_vec(),
+                    body,
+                }
+            }
+            _ => Statement::Command(words),
+        };
+        block.push(Line { number, statement });
+    }
+    Ok((block, None))
+}
+
+// Hex with "0x", or decimal, as on the command line.
+fn number(word: &str) -> Result<usize, String> {
+    match word.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => word.parse(),
+    }
+    .map_err(|_| format!("Expected a number, not '{}'", word))
+}
+
+fn float(word: &str) -> Result<f64, String> {
+    word.parse()
+        .map_err(|_| format!("Expected a number, not '{}'", word))
+}
+
+// A setting that's switched "on" or "off".
+fn on_off(name: &str, word: &str) -> Result<bool, String> {
+    match word {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("'{}' should be 'on' or 'off'", name)),
+    }
+}
+
+// Numbers as the script would write them.
+fn float_text(value: f64) -> String {
+    if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{:.3}", value)
+    }
+}
+
+fn expect_args(words: &[String], count: usize, usage: &str) -> Result<(), String> {
+    if words.len() != count {
+        return Err(format!("Expected '{}'", usage));
+    }
+    Ok(())
+}
+
+// What the script does besides computing and writing files.
+pub trait Host {
+    fn print(&mut self, text: &str);
+    fn play(&mut self, command: Command) -> Result<(), String>;
+}
+
+// Running from the command line, with nothing to play to.
+pub struct Console;
+
+impl Host for Console {
+    fn print(&mut self, text: &str) {
+        println!("{}", text);
+    }
+
+    fn play(&mut self, _command: Command) -> Result<(), String> {
+        Err("Playing needs the player's script console".to_string())
+    }
+}
+
+struct Runner<'a> {
+    host: &'a mut dyn Host,
+    synth: Synth,
+    annotations: &'a Annotations,
+    settings: batch::Settings,
+    vars: HashMap<String, String>,
+    // Set to stop the script early.
+    cancel: &'a AtomicBool,
+}
+
+impl<'a> Runner<'a> {
+    fn set_bank(&mut self, bank: Arc<SoundBank>) {
+        let passes = self.synth.max_passes();
+        self.synth = Synth::new(bank);
+        self.synth.set_max_passes(passes);
+        let bank = self.synth.bank().clone();
+        for (name, count) in [
+            ("sequences", bank.sequences.len()),
+            ("instruments", bank.instruments.len()),
+            ("sounds", SOUNDS.len()),
+        ] {
+            self.vars.insert(name.to_string(), count.to_string());
+        }
+    }
+
+    // Replace the variables in a word.
+    fn expand(&self, word: &str) -> Result<String, String> {
+        let mut out = String::new();
+        let mut rest = word;
+        while let Some(start) = rest.find('$') {
+            out.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+            let (name, after) = match rest.strip_prefix('{') {
+                Some(braced) => {
+                    let end = braced
+                        .find('}')
+                        .ok_or_else(|| format!("Unclosed '${{' in '{}'", word))?;
+                    (&braced[..end], &braced[end + 1..])
+                }
+                None => {
+                    let end = rest
+                        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                        .unwrap_or(rest.len());
+                    (&rest[..end], &rest[end..])
+                }
+            };
+            let value = self
+                .vars
+                .get(name)
+                .ok_or_else(|| format!("Unknown variable '{}'", name))?;
+            out.push_str(value);
+            rest = after;
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    // A sound, sequence or instrument, checked against the bank.
+    fn command(&self, kind: &str, idx: &str) -> Result<Command, String> {
+        let idx = number(idx)?;
+        let bank = self.synth.bank();
+        let (limit, command) = match kind {
+            "sound" => (SOUNDS.len(), Command::Sound(idx)),
+            "music" => (SOUNDS.len(), Command::Music(idx)),
+            "sequence" => (bank.sequences.len(), Command::Sequence(idx)),
+            "instrument" => (
+                bank.instruments.len(),
+                Command::Instrument {
+                    instr: idx,
+                    effect: 0,
+                    automation: None,
+                    pitch: DEFAULT_PITCH,
+                },
+            ),
+            _ => return Err(format!("Unknown kind '{}'", kind)),
+        };
+        if idx >= limit {
+            return Err(format!("No {} 0x{:x}", kind, idx));
+        }
+        Ok(command)
+    }
+
+    fn sequence(&self, idx: &str) -> Result<usize, String> {
+        match self.command("sequence", idx)? {
+            Command::Sequence(idx) => Ok(idx),
+            _ => unreachable!(),
+        }
+    }
+
+    // The value of a "let".
+    fn value(&self, words: &[String]) -> Result<String, String> {
+        let bank = self.synth.bank();
+        Ok(match words {
+            [f, kind, idx] if f == "duration" => {
+                let command = self.command(kind, idx)?;
+                let seconds = duration::record_seconds(
+                    bank,
+                    &command,
+                    self.synth.max_passes(),
+                    self.settings.max_time_s,
+                );
+                float_text(seconds as f64)
+            }
+            [f, kind, idx] if f == "name" => {
+                let idx = number(idx)?;
+                let names = match kind.as_str() {
+                    "sound" | "music" => &self.annotations.sounds,
+                    "sequence" => &self.annotations.sequences,
+                    "instrument" => &self.annotations.instruments,
+                    _ => return Err(format!("Unknown kind '{}'", kind)),
+                };
+                names.get(&idx).cloned().unwrap_or_default()
+            }
+            [f, idx] if f == "notes" => {
+                let idx = self.sequence(idx)?;
+                duration::notes(bank, idx).0.len().to_string()
+            }
+            [f, idx] if f == "loops" => {
+                let idx = self.sequence(idx)?;
+                let loops = duration::estimate(bank, idx).is_some_and(|e| e.loops());
+                (if loops { "yes" } else { "no" }).to_string()
+            }
+            [a, op, b] if ["+", "-", "*", "/"].contains(&op.as_str()) => {
+                let (a, b) = (float(a)?, float(b)?);
+                float_text(match op.as_str() {
+                    "+" => a + b,
+                    "-" => a - b,
+                    "*" => a * b,
+                    _ if b == 0.0 => return Err("Division by zero".to_string()),
+                    _ => a / b,
+                })
+            }
+            _ => words.join(" "),
+        })
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "max_time" => {
+                let seconds = float(value)? as f32;
+                if seconds <= 0.0 {
+                    return Err("'max_time' should be positive".to_string());
+                }
+                self.settings.max_time_s = seconds;
+            }
+            "tail" => self.settings.tail = Tail::parse(value)?,
+            "passes" => self.synth.set_max_passes(number(value)?),
+            "midi" => self.settings.midi = on_off(name, value)?,
+            "measurement" => self.settings.measurement = on_off(name, value)?,
+            _ => return Err(format!("Unknown setting '{}'", name)),
+        }
+        Ok(())
+    }
+
+    fn write(path: &str, text: &str, append: bool) -> Result<(), String> {
+        OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", text))
+            .map_err(|err| format!("{}: {}", path, err))
+    }
+
+    // Sleep, waking up regularly to see if we've been stopped.
+    fn wait(&self, seconds: f64) {
+        let end = Instant::now() + Duration::from_secs_f64(seconds.max(0.0));
+        while !self.cancel.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            std::thread::sleep((end - now).min(Duration::from_millis(50)));
+        }
+    }
+
+    fn run_command(&mut self, words: &[String]) -> Result<(), String> {
+        let words = words
+            .iter()
+            .map(|word| self.expand(word))
+            .collect::<Result<Vec<_>, _>>()?;
+        match words[0].as_str() {
+            "print" => self.host.print(&words[1..].join(" ")),
+            "let" => {
+                if words.len() < 3 || words[2] != "=" {
+                    return Err("Expected 'let NAME = VALUE'".to_string());
+                }
+                let value = self.value(&words[3..])?;
+                self.vars.insert(words[1].clone(), value);
+            }
+            "load" => {
+                if !(2..=3).contains(&words.len()) {
+                    return Err("Expected 'load FILE [OFFSET]'".to_string());
+                }
+                let offset = words.get(2).map_or(Ok(0), |word| number(word))?;
+                let data =
+                    std::fs::read(&words[1]).map_err(|err| format!("{}: {}", words[1], err))?;
+                let data = data
+                    .get(offset..)
+                    .ok_or_else(|| format!("Offset 0x{:x} is past the end of the file", offset))?;
+                let variant = self.synth.bank().variant;
+                let bank = SoundBank::detect(data.to_vec().into(), variant, None, None, false)?;
+                self.set_bank(Arc::new(bank));
+            }
+            "set" => {
+                expect_args(&words, 3, "set SETTING VALUE")?;
+                self.set(&words[1], &words[2])?;
+            }
+            "render" => {
+                expect_args(&words, 4, "render KIND N FILE")?;
+                let command = self.command(&words[1], &words[2])?;
+                let max_time_s = if self.settings.auto_length {
+                    duration::record_seconds(
+                        self.synth.bank(),
+                        &command,
+                        self.synth.max_passes(),
+                        self.settings.max_time_s,
+                    )
+                } else {
+                    self.settings.max_time_s
+                };
+                let path = Path::new(&words[3]);
+                batch::render_job(&self.synth, command, path, max_time_s, &self.settings)?;
+            }
+            "export" => {
+                expect_args(&words, 2, "export DIR")?;
+                let jobs = batch::jobs(&self.synth, self.annotations);
+                let dir = Path::new(&words[1]);
+                batch::run(
+                    &self.synth,
+                    jobs,
+                    dir,
+                    &self.settings,
+                    batch::default_concurrency(),
+                )?;
+            }
+            "write" | "append" => {
+                if words.len() < 2 {
+                    return Err(format!("Expected '{} FILE WORDS...'", words[0]));
+                }
+                Runner::write(&words[1], &words[2..].join(" "), words[0] == "append")?;
+            }
+            "play" => {
+                expect_args(&words, 3, "play KIND N")?;
+                let command = self.command(&words[1], &words[2])?;
+                self.host.play(command)?;
+            }
+            "stop" => {
+                expect_args(&words, 1, "stop")?;
+                self.host.play(Command::StopAll)?;
+            }
+            "wait" => {
+                expect_args(&words, 2, "wait SECONDS")?;
+                self.wait(float(&words[1])?);
+            }
+            other => return Err(format!("Unknown command '{}'", other)),
+        }
+        Ok(())
+    }
+
+    // The numbers a "for" goes through.
+    fn range(&self, words: &[String]) -> Result<Vec<String>, String> {
+        let word = self.expand(&words[0])?;
+        let bank = self.synth.bank();
+        let (start, end) = match word.as_str() {
+            "sequences" => (0, bank.sequences.len()),
+            "instruments" => (0, bank.instruments.len()),
+            "sounds" => (0, SOUNDS.len()),
+            _ => {
+                let (start, end) = word
+                    .split_once("..")
+                    .ok_or_else(|| format!("Expected 'A..B' or a kind, not '{}'", word))?;
+                (number(start)?, number(end)?)
+            }
+        };
+        Ok((start..end).map(|idx| idx.to_string()).collect())
+    }
+
+    fn run(&mut self, lines: &[Line]) -> Result<(), String> {
+        for line in lines {
+            if self.cancel.load(Ordering::Relaxed) {
+                return Err("Stopped".to_string());
+            }
+            let result = match &line.statement {
+                Statement::Command(words) => self.run_command(words),
+                Statement::For { var, range, body } => {
+                    let values = self
+                        .range(range)
+                        .map_err(|err| format!("Line {}: {}", line.number, err))?;
+                    for value in values {
+                        self.vars.insert(var.clone(), value);
+                        self.run(body)?;
+                    }
+                    Ok(())
+                }
+            };
+            result.map_err(|err| {
+                // Errors from loop bodies already have their line.
+                if err.starts_with("Line ") || err == "Stopped" {
+                    err
+                } else {
+                    format!("Line {}: {}", line.number, err)
+                }
+            })?;
+        }
+        Ok(())
+    }
+}
+
+// Run a script on a bank, with the export settings given.
+pub fn run(
+    script: &Script,
+    bank: Arc<SoundBank>,
+    annotations: &Annotations,
+    settings: batch::Settings,
+    passes: usize,
+    host: &mut dyn Host,
+    cancel: &AtomicBool,
+) -> Result<(), String> {
+    let mut runner = Runner {
+        host,
+        synth: Synth::new(bank.clone()),
+        annotations,
+        settings,
+        vars: HashMap::new(),
+        cancel,
+    };
+    runner.synth.set_max_passes(passes);
+    runner.set_bank(bank);
+    runner.run(&script.lines)
+}
+
+////////////////////////////////////////////////////////////////////////
+// Console
+
+// Lines of output the console keeps.
+#[cfg(feature = "gui")]
+const MAX_OUTPUT: usize = 5000;
+
+// Prints to the console, and plays on the player's synth.
+#[cfg(feature = "gui")]
+struct Player {
+    output: Arc<Mutex<Vec<String>>>,
+    commands: Sender<Timed<Command>>,
+}
+
+#[cfg(feature = "gui")]
+impl Host for Player {
+    fn print(&mut self, text: &str) {
+        let mut output = self.output.lock().unwrap();
+        output.push(text.to_string());
+        let excess = output.len().saturating_sub(MAX_OUTPUT);
+        output.drain(..excess);
+    }
+
+    fn play(&mut self, command: Command) -> Result<(), String> {
+        self.commands
+            .send(Timed::now(command))
+            .map_err(|_| "The player has shut down".to_string())
+    }
+}
+
+#[cfg(feature = "gui")]
+pub struct ScriptConsole {
+    text: String,
+    output: Arc<Mutex<Vec<String>>>,
+    running: Option<JoinHandle<()>>,
+    cancel: Arc<AtomicBool>,
+    error: Option<String>,
+}
+
+#[cfg(feature = "gui")]
+impl ScriptConsole {
+    pub fn new() -> ScriptConsole {
+        ScriptConsole {
+            text: "for seq in 1..$sequences\n  let secs = duration sequence $seq\n  \
+                   print sequence $seq: $secs s\nend\n"
+                .to_string(),
+            output: Arc::new(Mutex::new(Vec::new())),
+            running: None,
+            cancel: Arc::new(AtomicBool::new(false)),
+            error: None,
+        }
+    }
+
+    // Run in the background, so long renders and waits don't hold up
+    // the GUI. Scripts work on their own copy of the synth, with the
+    // player's bank; only "play" and "stop" touch the player's.
+    fn start(
+        &mut self,
+        bank: Arc<SoundBank>,
+        annotations: Arc<Annotations>,
+        passes: usize,
+        commands: Sender<Timed<Command>>,
+    ) {
+        let script = match Script::parse(&self.text) {
+            Ok(script) => script,
+            Err(err) => {
+                self.error = Some(err);
+                return;
+            }
+        };
+        self.error = None;
+        self.output.lock().unwrap().clear();
+        self.cancel.store(false, Ordering::Relaxed);
+        let mut host = Player {
+            output: self.output.clone(),
+            commands,
+        };
+        let cancel = self.cancel.clone();
+        self.running = Some(thread::spawn(move || {
+            let settings = batch::Settings {
+                max_time_s: 60.0,
+                tail: Tail::Cut,
+                midi: false,
+                auto_length: true,
+                build_manifest: None,
+                measurement: false,
+            };
+            let result = run(
+                &script,
+                bank,
+                &annotations,
+                settings,
+                passes,
+                &mut host,
+                &cancel,
+            );
+            if let Err(err) = result {
+                host.print(&format!("Error: {}", err));
+            }
+        }));
+    }
+
+    fn load(&mut self) {
+        let Some(path) = FileDialog::new()
+            .add_filter("Script", &["txt", "sb2s"])
+            .pick_file()
+        else {
+            return;
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => self.text = text,
+            Err(err) => self.error = Some(format!("{}: {}", path.display(), err)),
+        }
+    }
+
+    fn save(&mut self) {
+        let Some(path) = FileDialog::new()
+            .add_filter("Script", &["txt", "sb2s"])
+            .set_file_name("script.txt")
+            .save_file()
+        else {
+            return;
+        };
+        self.error = std::fs::write(&path, &self.text)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+            .err();
+    }
+
+    pub fn ui(
+        &mut self,
+        ui: &mut Ui,
+        bank: &Arc<SoundBank>,
+        annotations: &Arc<Annotations>,
+        passes: usize,
+        commands: &Sender<Timed<Command>>,
+    ) {
+        if self
+            .running
+            .as_ref()
+            .is_some_and(|handle| handle.is_finished())
+        {
+            self.running = None;
+        }
+        ui.horizontal(|ui| {
+            if self.running.is_some() {
+                if ui.button("Stop").clicked() {
+                    self.cancel.store(true, Ordering::Relaxed);
+                }
+                ui.spinner();
+            } else if ui.button("Run").clicked() {
+                self.start(bank.clone(), annotations.clone(), passes, commands.clone());
+            }
+            if ui.button("Load...").clicked() {
+                self.load();
+            }
+            if ui.button("Save...").clicked() {
+                self.save();
+            }
+        });
+        if let Some(err) = &self.error {
+            ui.colored_label(Color32::RED, err);
+        }
+        ui.add(
+            TextEdit::multiline(&mut self.text)
+                .code_editor()
+                .desired_rows(12)
+                .desired_width(f32::INFINITY),
+        );
+        ui.separator();
+        let output = self.output.lock().unwrap();
+        ScrollArea::vertical()
+            .max_height(200.0)
+            .auto_shrink([false, true])
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for line in output.iter() {
+                    ui.monospace(line);
+                }
+            });
+    }
+}
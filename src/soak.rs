@@ -0,0 +1,153 @@
+//
+// Speedball 2 Sound player
+//
+// soak.rs: A soak test, playing random sounds, sequences and
+// instruments through the audio device for hours on end, logging
+// memory and CPU use as it goes, to check for leaks or slowdowns
+// before leaving the player running somewhere unattended.
+//
+// Memory and CPU figures come from /proc, so are only available on
+// Linux. Elsewhere they're logged as "?".
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cpal_wrapper::Timed;
+use crate::sound_data::{EFFECTS, SOUNDS};
+use crate::sound_player::{Command, SoundBank, Synth, DEFAULT_PITCH};
+
+// How often to start something new.
+const CHANGE_INTERVAL: Duration = Duration::from_secs(5);
+// How often to log usage.
+const LOG_INTERVAL: Duration = Duration::from_secs(60);
+// Clock ticks per second for /proc/self/stat times. This is almost
+// always 100, and we'd need libc to ask.
+const CLOCK_TICKS: f64 = 100.0;
+
+// Small xorshift generator, as we don't need anything better.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        let seed = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(1, |d| d.as_nanos() as u64);
+        Rng(seed | 1)
+    }
+
+    fn below(&mut self, limit: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % limit as u64) as usize
+    }
+}
+
+fn random_command(bank: &SoundBank, rng: &mut Rng) -> Command {
+    // Only the sounds whose sequences are all in this bank.
+    let sounds = SOUNDS
+        .iter()
+        .enumerate()
+        .filter(|(_, sound)| sound.sequences.iter().all(|&s| s < bank.sequences.len()))
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    match rng.below(10) {
+        0 => Command::StopAll,
+        1..=3 if !sounds.is_empty() => Command::Sound(sounds[rng.below(sounds.len())]),
+        4 if !sounds.is_empty() => Command::Music(sounds[rng.below(sounds.len())]),
+        // Skip the empty sequence 0.
+        5..=7 if bank.sequences.len() > 1 => {
+            Command::Sequence(1 + rng.below(bank.sequences.len() - 1))
+        }
+        _ if !bank.instruments.is_empty() => Command::Instrument {
+            instr: rng.below(bank.instruments.len()),
+            effect: rng.below(EFFECTS.len()),
+            automation: None,
+            pitch: DEFAULT_PITCH,
+        },
+        _ => Command::StopAll,
+    }
+}
+
+// Resident memory, in kB.
+fn memory_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+// User plus system CPU time used so far, in seconds.
+fn cpu_s() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name may contain spaces, so count from after it.
+    let fields = stat
+        .rsplit(')')
+        .next()?
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let utime: f64 = fields.get(11)?.parse().ok()?;
+    let stime: f64 = fields.get(12)?.parse().ok()?;
+    Some((utime + stime) / CLOCK_TICKS)
+}
+
+fn or_unknown<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "?".to_string(), |v| v.to_string())
+}
+
+// Play random things for the given time, logging usage to stdout.
+pub fn run(
+    bank: Arc<SoundBank>,
+    synth: Arc<Mutex<Synth>>,
+    commands: Sender<Timed<Command>>,
+    hours: f32,
+) {
+    let mut rng = Rng::new();
+    let start = Instant::now();
+    let end = start + Duration::from_secs_f32(hours * 3600.0);
+    let mut next_log = start;
+    let mut last_cpu = (start, cpu_s());
+    let mut played = 0;
+
+    println!("elapsed_s played active_channels rss_kb cpu_percent");
+    while Instant::now() < end {
+        let now = Instant::now();
+        if now >= next_log {
+            let cpu = cpu_s();
+            let percent = match (cpu, last_cpu.1) {
+                (Some(cpu), Some(last)) if now > last_cpu.0 => Some(format!(
+                    "{:.1}",
+                    100.0 * (cpu - last) / (now - last_cpu.0).as_secs_f64()
+                )),
+                _ => None,
+            };
+            let active = synth
+                .lock()
+                .unwrap()
+                .channels
+                .iter()
+                .filter(|channel| channel.is_active())
+                .count();
+            println!(
+                "{} {} {} {} {}",
+                (now - start).as_secs(),
+                played,
+                active,
+                or_unknown(memory_kb()),
+                or_unknown(percent)
+            );
+            last_cpu = (now, cpu);
+            next_log += LOG_INTERVAL;
+        }
+
+        commands
+            .send(Timed::now(random_command(&bank, &mut rng)))
+            .unwrap();
+        played += 1;
+        thread::sleep(CHANGE_INTERVAL);
+    }
+}
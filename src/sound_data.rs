@@ -14,9 +14,13 @@
 // Table is in 4 steps per semi-tone.
 pub const OCTAVE_SIZE: usize = 12 * 4;
 
-// 11 octaves of quarter semi-tones.
+// A note-to-period table, indexed in quarter semi-tones.
+pub type PitchTable = [u16; OCTAVE_SIZE * 11];
+
+// 11 octaves of quarter semi-tones. Banks can override this, see
+// pitches.rs.
 #[rustfmt::skip]
-pub const PITCHES: [u16; OCTAVE_SIZE * 11] = [
+pub const PITCHES: PitchTable = [
     0xD5C8, 0xD2B7, 0xCFB2, 0xCCB7, 0xC9C8, 0xC6E4, 0xC40A, 0xC13A,
     0xBE75, 0xBBBA, 0xB909, 0xB662, 0xB3C4, 0xB131, 0xAEA6, 0xAC25,
     0xA9AD, 0xA73F, 0xA4D9, 0xA27C, 0xA027, 0x9DDC, 0x9B98, 0x995D,
@@ -8,27 +8,113 @@
 //
 
 use std::fmt;
-use std::sync::Arc;
+use std::fs::File;
+use std::ops::Deref;
+#[cfg(feature = "gui")]
+use std::path::PathBuf;
+#[cfg(feature = "gui")]
+use std::sync::Mutex;
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "gui")]
 use std::thread;
 
-use cpal::Sample;
+#[cfg(feature = "gui")]
+use egui::{Button, Checkbox, Color32, DragValue, ProgressBar, RichText, Slider, Ui};
 
-use egui::plot::{Line, Plot, PlotPoints, VLine};
-use egui::{Button, CollapsingHeader, Color32, DragValue, RichText, Ui};
+use memmap2::{Mmap, MmapOptions};
 
+use crate::automation::Automation;
 use crate::cpal_wrapper;
+#[cfg(any(feature = "audio", feature = "export"))]
+use crate::cpal_wrapper::OutputSample;
+#[cfg(feature = "export")]
+use crate::cpal_wrapper::SoundSource;
+#[cfg(feature = "gui")]
+use crate::cpal_wrapper::{Tail, WavFormat};
+#[cfg(feature = "gui")]
+use crate::disasm;
+use crate::driver::{self, Detection, Op, Variant};
+#[cfg(feature = "gui")]
+use crate::duration;
+#[cfg(any(feature = "audio", feature = "export"))]
+use crate::limiter::{self, Limiter};
+use crate::midi;
+#[cfg(feature = "gui")]
+use crate::preview::{Clip, Preview};
+#[cfg(feature = "audio")]
+use crate::quirks;
+use crate::quirks::Quirks;
+use crate::scanner;
 use crate::sound_data::*;
+#[cfg(feature = "gui")]
+use crate::stretch;
+#[cfg(any(feature = "audio", feature = "export"))]
+use crate::stretch::Stretcher;
+#[cfg(feature = "gui")]
+use crate::trace;
+use crate::trace::Trace;
+#[cfg(feature = "gui")]
+use crate::tuner;
+use crate::tuner::{Capture, ReferenceTone};
 
 const MAX_VOLUME: f32 = 64.0;
+// Sequences and effects are stepped once a frame, usually a PAL one,
+// though a bank's quirks can change that.
+#[cfg(feature = "gui")]
+const FRAMES_PER_SECOND: usize = 50;
+// Channels start at this pitch, in quarter semi-tones.
+pub const DEFAULT_PITCH: usize = 48 * 4;
+// Longest sample, in words. Playback works out sample lengths in
+// bytes as u16s, so anything longer would overflow.
+const MAX_SAMPLE_LEN: u16 = 0x7fff;
+// Sequences that run this many commands in one frame without playing
+// a note or rest are assumed to be stuck in a loop.
+const MAX_COMMANDS_PER_FRAME: usize = 0x1000;
+// Range of the tempo control.
+#[cfg(feature = "gui")]
+const MIN_TEMPO_SCALE: f32 = 0.25;
+#[cfg(feature = "gui")]
+const MAX_TEMPO_SCALE: f32 = 4.0;
+// Range of the global transposition, in semitones.
+#[cfg(feature = "gui")]
+const MAX_TRANSPOSE: isize = 24;
+// Gain reduction that fills the limiter's meter, in dB.
+#[cfg(feature = "gui")]
+const METER_RANGE_DB: f32 = 12.0;
+// Range of the master volume, in dB.
+#[cfg(feature = "gui")]
+const MIN_MASTER_DB: f32 = -40.0;
+#[cfg(feature = "gui")]
+const MAX_MASTER_DB: f32 = 12.0;
+// Give up seeking after this many frames (half an hour), in case the
+// position asked for is never reached.
+#[cfg(feature = "gui")]
+const MAX_SEEK_FRAMES: usize = FRAMES_PER_SECOND * 60 * 30;
+// The seek slider covers at least this many driver beats, which is
+// more than the title theme's 2900 or so.
+#[cfg(feature = "gui")]
+const SEEK_RANGE_BEATS: usize = 4096;
+// Bump this when a change to the synth changes what it renders, so
+// that batch exports know to render everything again.
+#[cfg(feature = "export")]
+pub const INTERPRETER_VERSION: u32 = 1;
 
 ////////////////////////////////////////////////////////////////////////
 // Utilities
 
-fn word(data: &[u8], addr: usize) -> u16 {
+pub fn word(data: &[u8], addr: usize) -> u16 {
     (data[addr] as u16) << 8 | (data[addr + 1] as u16)
 }
 
-fn long(data: &[u8], addr: usize) -> u32 {
+pub fn set_word(data: &mut [u8], addr: usize, value: u16) {
+    data[addr..][..2].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn set_long(data: &mut [u8], addr: usize, value: u32) {
+    data[addr..][..4].copy_from_slice(&value.to_be_bytes());
+}
+
+pub fn long(data: &[u8], addr: usize) -> u32 {
     (data[addr] as u32) << 24
         | (data[addr + 1] as u32) << 16
         | (data[addr + 2] as u32) << 8
@@ -40,23 +126,97 @@ fn long(data: &[u8], addr: usize) -> u32 {
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Instrument {
-    is_one_shot: bool,
-    loop_offset: u16,
-    sample_len: u16,
-    sample_addr: usize,
-    base_octave: usize,
+    pub is_one_shot: bool,
+    pub loop_offset: u16,
+    pub sample_len: u16,
+    pub sample_addr: usize,
+    pub base_octave: usize,
 }
 
 impl Instrument {
-    const SIZE: usize = 14;
+    pub const SIZE: usize = 14;
 
-    fn new(data: &[u8]) -> Instrument {
-        Instrument {
+    // Playback rate of the sample at a channel's default pitch, with
+    // no effects, for exporting to other samplers.
+    pub fn default_rate(&self, bank: &SoundBank) -> f32 {
+        let pitches = &bank.pitches;
+        let note = ((self.base_octave + 1) * OCTAVE_SIZE + DEFAULT_PITCH).min(pitches.len() - 1);
+        1.0 / (pitches[note] as f32 * bank.quirks.clock_interval_s)
+    }
+
+    pub fn new(data: &[u8]) -> Result<Instrument, String> {
+        if data.len() < Instrument::SIZE {
+            return Err(format!(
+                "Instrument record needs 0x{:x} bytes, only 0x{:x} left",
+                Instrument::SIZE,
+                data.len()
+            ));
+        }
+        Ok(Instrument {
             is_one_shot: word(data, 0) == 1,
             loop_offset: word(data, 2),
             sample_len: word(data, 4),
             sample_addr: long(data, 6) as usize,
             base_octave: long(data, 10) as usize,
+        })
+    }
+
+    // The reverse of new().
+    pub fn write(&self, data: &mut [u8]) {
+        set_word(data, 0, self.is_one_shot as u16);
+        set_word(data, 2, self.loop_offset);
+        set_word(data, 4, self.sample_len);
+        set_long(data, 6, self.sample_addr as u32);
+        set_long(data, 10, self.base_octave as u32);
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Raw bank data, either read into memory or mapped from the file, so
+// that huge dumps don't need to be copied into RAM.
+
+pub enum BankData {
+    Owned(Vec<u8>),
+    Mapped(Mmap),
+}
+
+impl BankData {
+    pub fn map(file_name: &str, offset: usize) -> Result<BankData, String> {
+        let file = File::open(file_name).map_err(|err| format!("{}: {}", file_name, err))?;
+        // Safety: The mapping is handed out as a &[u8], which is only
+        // sound while the bytes underneath don't change. It's private
+        // and read-only, so nothing we do can write to the file, and
+        // everything that reads the data bounds-checks against the
+        // length at the time of mapping. What we can't prevent is
+        // another process changing the file: pages not yet read may
+        // then show the new contents, and if it's truncated, touching
+        // a page past the new end raises SIGBUS, killing the player
+        // (from the audio thread, if it's playing a sample). So
+        // mapping is only done when asked for with --mmap, which
+        // documents that the file must be left alone while in use.
+        let mmap = unsafe {
+            MmapOptions::new()
+                .offset(offset as u64)
+                .map_copy_read_only(&file)
+        }
+        .map_err(|err| format!("Couldn't map {}: {}", file_name, err))?;
+        Ok(BankData::Mapped(mmap))
+    }
+}
+
+impl From<Vec<u8>> for BankData {
+    fn from(data: Vec<u8>) -> BankData {
+        BankData::Owned(data)
+    }
+}
+
+impl Deref for BankData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            BankData::Owned(data) => data,
+            BankData::Mapped(mmap) => mmap,
         }
     }
 }
@@ -66,12 +226,25 @@ impl Instrument {
 
 pub struct SoundBank {
     // Raw memory data.
-    pub data: Vec<u8>,
+    pub data: BankData,
     // Instrment data scraped into structs.
     pub instruments: Vec<Instrument>,
     // Sequence definitions don't include length, so we just store
     // starting points.
     pub sequences: Vec<usize>,
+    // The driver revision used to interpret the data.
+    pub variant: &'static Variant,
+    // How well the data seems to fit that revision.
+    pub driver: Detection,
+    // Why the bank failed validation, if it was loaded anyway by
+    // sandboxed().
+    pub sandboxed: Option<String>,
+    // Period for each note. Other games using the driver have their
+    // own tables.
+    pub pitches: PitchTable,
+    // Timing, clock, effect table and volume differences between
+    // games and versions, with the variant.
+    pub quirks: Quirks,
 }
 
 // Skip data.
@@ -80,96 +253,436 @@ impl fmt::Debug for SoundBank {
         f.debug_struct("SoundBank")
             .field("sequences", &self.sequences)
             .field("instruments", &self.instruments)
+            .field("variant", &self.variant)
+            .field("driver", &self.driver)
             .finish()
     }
 }
 
+// Check that a table of the given size fits in the data.
+fn check_table(data: &[u8], name: &str, addr: usize, len: usize) -> Result<(), String> {
+    if addr < 8 || addr + len > data.len() {
+        return Err(format!(
+            "Bad {} table offset: 0x{:x} bytes at 0x{:x}, but data is 0x{:x} bytes",
+            name,
+            len,
+            addr,
+            data.len()
+        ));
+    }
+    Ok(())
+}
+
 impl SoundBank {
-    pub fn new(data: Vec<u8>, num_sequences: usize, num_instruments: usize) -> SoundBank {
-        let sequence_table_offset = long(&data, 0) as usize;
+    pub fn new(
+        data: BankData,
+        variant: &'static Variant,
+        num_sequences: usize,
+        num_instruments: usize,
+    ) -> Result<SoundBank, String> {
+        SoundBank::new_or_sandboxed(data, variant, num_sequences, num_instruments, false)
+    }
+
+    // As new(), but if asked, a bank that fails validation is loaded
+    // with sandboxed() instead.
+    pub fn new_or_sandboxed(
+        data: BankData,
+        variant: &'static Variant,
+        num_sequences: usize,
+        num_instruments: usize,
+        sandbox: bool,
+    ) -> Result<SoundBank, String> {
+        match SoundBank::parse(&data, variant, num_sequences, num_instruments) {
+            Ok((sequences, instruments)) => {
+                let driver = driver::detect(variant, &data, &sequences, num_instruments);
+                Ok(SoundBank {
+                    data,
+                    variant,
+                    sequences,
+                    instruments,
+                    driver,
+                    sandboxed: None,
+                    pitches: PITCHES,
+                    quirks: Quirks::for_variant(variant),
+                })
+            }
+            Err(err) if sandbox => Ok(SoundBank::sandboxed(
+                data,
+                variant,
+                num_sequences,
+                num_instruments,
+                err,
+            )),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Read and check the sequence and instrument tables.
+    fn parse(
+        data: &[u8],
+        variant: &'static Variant,
+        num_sequences: usize,
+        num_instruments: usize,
+    ) -> Result<(Vec<usize>, Vec<Instrument>), String> {
+        if data.len() < 8 {
+            return Err(format!("Bank is too short: 0x{:x} bytes", data.len()));
+        }
+
+        let sequence_table_offset = long(data, 0) as usize;
+        check_table(data, "sequence", sequence_table_offset, num_sequences * 4)?;
         let sequences = (0..num_sequences)
-            .map(|idx| long(&data, sequence_table_offset + idx * 4) as usize)
-            .collect();
+            .map(|idx| long(data, sequence_table_offset + idx * 4) as usize)
+            .collect::<Vec<_>>();
+        for (idx, &addr) in sequences.iter().enumerate() {
+            // Sequence 0 is the empty sequence, and can be anything.
+            if idx != 0 && addr >= data.len() {
+                return Err(format!(
+                    "Sequence {:02x} address 0x{:x} is outside the data",
+                    idx, addr
+                ));
+            }
+        }
 
-        let instrument_table_offset = long(&data, 4) as usize;
+        let instrument_table_offset = long(data, 4) as usize;
+        check_table(
+            data,
+            "instrument",
+            instrument_table_offset,
+            num_instruments * variant.instrument_size,
+        )?;
         let instruments = (0..num_instruments)
-            .map(|idx| Instrument::new(&data[(instrument_table_offset + idx * Instrument::SIZE)..]))
-            .collect();
+            .map(|idx| {
+                let addr = instrument_table_offset + idx * variant.instrument_size;
+                (variant.read_instrument)(&data[addr..])
+                    .map_err(|err| format!("Instrument {:02x}: {}", idx, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (idx, instrument) in instruments.iter().enumerate() {
+            let sample_len = instrument.sample_len as usize * 2;
+            if instrument.sample_len > MAX_SAMPLE_LEN {
+                return Err(format!(
+                    "Instrument {:02x} sample is 0x{:x} bytes, but can be at most 0x{:x}",
+                    idx,
+                    sample_len,
+                    MAX_SAMPLE_LEN as usize * 2
+                ));
+            }
+            if instrument.sample_addr + sample_len > data.len() {
+                return Err(format!(
+                    "Instrument {:02x} sample (0x{:x} bytes at 0x{:x}) is outside the data",
+                    idx, sample_len, instrument.sample_addr
+                ));
+            }
+            if instrument.loop_offset as usize > sample_len {
+                return Err(format!(
+                    "Instrument {:02x} loop offset 0x{:x} is past the end of its 0x{:x} byte sample",
+                    idx, instrument.loop_offset, sample_len
+                ));
+            }
+        }
+
+        Ok((sequences, instruments))
+    }
+
+    // Load a bank that failed validation anyway, so that partly
+    // understood data can still be explored and played. Tables are
+    // cut down to what fits in the data, sequences outside it are
+    // pointed at the start of the bank, and samples are trimmed to
+    // fit. Sequences already check their reads as they play.
+    pub fn sandboxed(
+        data: BankData,
+        variant: &'static Variant,
+        num_sequences: usize,
+        num_instruments: usize,
+        reason: String,
+    ) -> SoundBank {
+        let len = data.len();
+        // How many entries of a table fit, given its offset in the
+        // header.
+        let fits = |header_addr: usize, size: usize| {
+            if len < 8 {
+                return (0, 0);
+            }
+            let addr = long(&data, header_addr) as usize;
+            if addr < 8 || addr >= len {
+                return (addr, 0);
+            }
+            (addr, (len - addr) / size)
+        };
+
+        let (sequence_table, max_sequences) = fits(0, 4);
+        let sequences = (0..num_sequences.min(max_sequences))
+            .map(|idx| long(&data, sequence_table + idx * 4) as usize)
+            .map(|addr| if addr < len { addr } else { 0 })
+            .collect::<Vec<_>>();
+
+        let (instrument_table, max_instruments) = fits(4, variant.instrument_size);
+        let instruments = (0..num_instruments.min(max_instruments))
+            .filter_map(|idx| {
+                let addr = instrument_table + idx * variant.instrument_size;
+                (variant.read_instrument)(&data[addr..]).ok()
+            })
+            .map(|mut instrument| {
+                instrument.sample_addr = instrument.sample_addr.min(len);
+                let max_len = ((len - instrument.sample_addr) / 2).min(MAX_SAMPLE_LEN as usize);
+                instrument.sample_len = instrument.sample_len.min(max_len as u16);
+                let sample_bytes = instrument.sample_len * 2;
+                instrument.loop_offset = instrument.loop_offset.min(sample_bytes.saturating_sub(1));
+                // An empty sample would loop on the spot forever.
+                instrument.is_one_shot |= sample_bytes == 0;
+                instrument
+            })
+            .collect::<Vec<_>>();
+
+        let driver = driver::detect(variant, &data, &sequences, instruments.len());
 
         SoundBank {
             data,
+            variant,
             sequences,
             instruments,
+            driver,
+            sandboxed: Some(reason),
+            pitches: PITCHES,
+            quirks: Quirks::for_variant(variant),
         }
     }
 
-    fn instrument_plot_ui(&self, ui: &mut Ui, instrument: &Instrument, idx: usize) {
-        // This looks expensive, but only excecuted if the header is
-        // opened, so I don't care too much.
-        let sample = &self.data[instrument.sample_addr..][..instrument.sample_len as usize * 2];
-        let points = PlotPoints::new(
-            sample
-                .iter()
-                .enumerate()
-                .map(|(x, y)| [x as f64, *y as i8 as f64])
-                .collect::<Vec<_>>(),
-        );
-        let repeat_point = instrument.loop_offset;
-        // Disallow scrolling because it's inside a wider scrolling
-        // frame and you probably didn't mean to scroll.
-        Plot::new(format!("Sound {}", idx))
-            .view_aspect(10.0)
-            .allow_scroll(false)
-            .show(ui, |plot_ui| {
-                plot_ui.line(Line::new(points));
-                if repeat_point != 0 {
-                    plot_ui.vline(VLine::new(repeat_point as f64));
-                }
-            });
+    // Load a bank without knowing the table sizes up front, by
+    // walking the tables until the entries stop looking valid. Sizes
+    // that are given are used as-is.
+    pub fn detect(
+        data: BankData,
+        variant: &'static Variant,
+        num_sequences: Option<usize>,
+        num_instruments: Option<usize>,
+        sandbox: bool,
+    ) -> Result<SoundBank, String> {
+        let num_sequences = num_sequences.unwrap_or_else(|| scanner::count_sequences(&data));
+        let num_instruments = num_instruments.unwrap_or_else(|| scanner::count_instruments(&data));
+        SoundBank::new_or_sandboxed(data, variant, num_sequences, num_instruments, sandbox)
     }
 
-    pub fn ui(&self, ui: &mut Ui, synth: &mut Synth) {
-        CollapsingHeader::new("Instruments")
-            .default_open(false)
-            .show(ui, |ui| {
-                for (idx, instrument) in self.instruments.iter().enumerate() {
-                    CollapsingHeader::new(format!("Instrument {:02x}", idx))
-                        .default_open(false)
-                        .show(ui, |ui| {
-                            ui.horizontal(|ui| {
-                                if ui
-                                    .add(Button::new("Play").fill(Color32::DARK_RED))
-                                    .clicked()
-                                {
-                                    synth.play_instr(instrument);
-                                }
-                                ui.label(&format!("{:?}", instrument));
-                            });
-                            self.instrument_plot_ui(ui, instrument, idx);
-                        });
+    // A copy of the bank with an instrument's sample replaced. The
+    // new sample is added to the end of the data, and the old one
+    // left in place, unused.
+    #[cfg(feature = "gui")]
+    pub fn with_sample(&self, idx: usize, sample: &[i8]) -> Result<SoundBank, String> {
+        // Lengths are stored in words.
+        let sample_len = sample.len().div_ceil(2);
+        if sample_len > MAX_SAMPLE_LEN as usize {
+            return Err(format!(
+                "Sample is 0x{:x} bytes, but can be at most 0x{:x}",
+                sample.len(),
+                MAX_SAMPLE_LEN as usize * 2
+            ));
+        }
+
+        let mut data = self.data.to_vec();
+        // Samples must be word-aligned for the Amiga's DMA.
+        if !data.len().is_multiple_of(2) {
+            data.push(0);
+        }
+        let sample_addr = data.len();
+        data.extend(sample.iter().map(|&x| x as u8));
+        data.resize(sample_addr + sample_len * 2, 0);
+
+        // Keep the loop behaviour, but loop the whole sample.
+        let instrument = Instrument {
+            loop_offset: 0,
+            sample_len: sample_len as u16,
+            sample_addr,
+            ..self.instruments[idx].clone()
+        };
+        let record = long(&data, 4) as usize + idx * self.variant.instrument_size;
+        (self.variant.write_instrument)(&instrument, &mut data[record..]);
+
+        let bank = SoundBank::new(
+            data.into(),
+            self.variant,
+            self.sequences.len(),
+            self.instruments.len(),
+        )?;
+        Ok(SoundBank {
+            pitches: self.pitches,
+            quirks: self.quirks,
+            ..bank
+        })
+    }
+
+    fn copy(&self) -> SoundBank {
+        SoundBank {
+            data: self.data.to_vec().into(),
+            instruments: self.instruments.clone(),
+            sequences: self.sequences.clone(),
+            variant: self.variant,
+            driver: self.driver.clone(),
+            sandboxed: self.sandboxed.clone(),
+            pitches: self.pitches,
+            quirks: self.quirks,
+        }
+    }
+
+    // A copy of the bank that plays notes with a different period
+    // table.
+    #[cfg(feature = "gui")]
+    pub fn with_pitches(&self, pitches: PitchTable) -> SoundBank {
+        SoundBank {
+            pitches,
+            ..self.copy()
+        }
+    }
+
+    // A copy of the bank played with a different quirks profile. A
+    // different driver revision means reading the tables again.
+    pub fn with_quirks(&self, quirks: Quirks) -> Result<SoundBank, String> {
+        let bank = if std::ptr::eq(quirks.variant, self.variant) {
+            self.copy()
+        } else {
+            SoundBank::new_or_sandboxed(
+                self.data.to_vec().into(),
+                quirks.variant,
+                self.sequences.len(),
+                self.instruments.len(),
+                self.sandboxed.is_some(),
+            )?
+        };
+        Ok(SoundBank {
+            pitches: self.pitches,
+            quirks,
+            ..bank
+        })
+    }
+
+    // A copy of the bank with a new sequence added to the end,
+    // returning it and the new sequence's index.
+    pub fn with_sequence(&self, bytes: &[u8]) -> Result<(SoundBank, usize), String> {
+        let (bank, indices) = self.with_sequences(&[bytes])?;
+        Ok((bank, indices[0]))
+    }
+
+    // A copy of the bank with new sequences added to the end, and
+    // their indices. The sequence table can't grow where it is, so a
+    // longer copy is added to the end too, and the old one left in
+    // place, unused.
+    pub fn with_sequences(&self, sequences: &[&[u8]]) -> Result<(SoundBank, Vec<usize>), String> {
+        let mut data = self.data.to_vec();
+        let mut addrs = self.sequences.clone();
+        for bytes in sequences {
+            let decoded = driver::sequence_bytes(self.variant, bytes, 0).len();
+            if decoded == 0 {
+                return Err("Sequence doesn't decode, or doesn't end".to_string());
+            }
+            if decoded != bytes.len() {
+                return Err(format!(
+                    "Sequence ends after 0x{:x} of 0x{:x} bytes",
+                    decoded,
+                    bytes.len()
+                ));
+            }
+            addrs.push(data.len());
+            data.extend_from_slice(bytes);
+        }
+        // The table is read a long at a time, so must be aligned.
+        if !data.len().is_multiple_of(2) {
+            data.push(0);
+        }
+        let table = data.len();
+        for &addr in addrs.iter() {
+            data.extend_from_slice(&(addr as u32).to_be_bytes());
+        }
+        set_long(&mut data, 0, table as u32);
+
+        let bank = SoundBank::new(
+            data.into(),
+            self.variant,
+            addrs.len(),
+            self.instruments.len(),
+        )?;
+        let bank = SoundBank {
+            pitches: self.pitches,
+            quirks: self.quirks,
+            ..bank
+        };
+        Ok((bank, (self.sequences.len()..addrs.len()).collect()))
+    }
+
+    // Write the bank back out in the format the game loads, with
+    // fresh tables. Only the sequences and samples the tables point
+    // at are kept, so anything replaced, or not referenced, is
+    // dropped.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let instrument_size = self.variant.instrument_size;
+        let sequence_table = 8;
+        let instrument_table = sequence_table + self.sequences.len() * 4;
+        let mut data = vec![0; instrument_table + self.instruments.len() * instrument_size];
+        set_long(&mut data, 0, sequence_table as u32);
+        set_long(&mut data, 4, instrument_table as u32);
+
+        // Sequences may share code, so we keep track of where each
+        // old address went. Sequence 0 is the empty sequence, and
+        // just points at 0.
+        let mut moved = std::collections::HashMap::new();
+        for (idx, &addr) in self.sequences.iter().enumerate().skip(1) {
+            let new_addr = match moved.get(&addr) {
+                Some(&new_addr) => new_addr,
+                None => {
+                    let bytes = driver::sequence_bytes(self.variant, &self.data, addr);
+                    if bytes.is_empty() {
+                        return Err(format!("Sequence {:02x} doesn't decode", idx));
+                    }
+                    let new_addr = data.len();
+                    data.extend_from_slice(bytes);
+                    moved.insert(addr, new_addr);
+                    new_addr
                 }
-            });
+            };
+            set_long(&mut data, sequence_table + idx * 4, new_addr as u32);
+        }
 
-        CollapsingHeader::new("Sequences")
-            .default_open(false)
-            .show(ui, |ui| {
-                // Skip first element, the empty sequence.
-                for (idx, addr) in self.sequences.iter().enumerate().skip(1) {
-                    CollapsingHeader::new(format!("Sequence {:02x}", idx))
-                        .default_open(true)
-                        .show(ui, |ui| {
-                            ui.horizontal(|ui| {
-                                if ui
-                                    .add(Button::new("Play").fill(Color32::DARK_RED))
-                                    .clicked()
-                                {
-                                    synth.play_seq(idx);
-                                }
-                                ui.label(&format!("0x{:06x}", addr));
-                            });
-                        });
+        let mut moved = std::collections::HashMap::new();
+        for (idx, instrument) in self.instruments.iter().enumerate() {
+            let len = instrument.sample_len as usize * 2;
+            let key = (instrument.sample_addr, len);
+            let sample_addr = match moved.get(&key) {
+                Some(&new_addr) => new_addr,
+                None => {
+                    // Samples must be word-aligned for the Amiga's DMA.
+                    if !data.len().is_multiple_of(2) {
+                        data.push(0);
+                    }
+                    let new_addr = data.len();
+                    data.extend_from_slice(&self.data[instrument.sample_addr..][..len]);
+                    moved.insert(key, new_addr);
+                    new_addr
                 }
-            });
+            };
+            let moved_instrument = Instrument {
+                sample_addr,
+                ..instrument.clone()
+            };
+            let record = instrument_table + idx * instrument_size;
+            (self.variant.write_instrument)(&moved_instrument, &mut data[record..]);
+        }
+
+        Ok(data)
+    }
+
+    // A bank with nothing in it, for when loading fails.
+    #[cfg(feature = "gui")]
+    pub fn empty(variant: &'static Variant) -> SoundBank {
+        let data = BankData::Owned(Vec::new());
+        let driver = driver::detect(variant, &data, &[], 0);
+        SoundBank {
+            data,
+            variant,
+            sequences: Vec::new(),
+            instruments: Vec::new(),
+            driver,
+            sandboxed: None,
+            pitches: PITCHES,
+            quirks: Quirks::for_variant(variant),
+        }
     }
 }
 
@@ -178,16 +691,205 @@ impl SoundBank {
 // by Amiga hardware and the sound interrupt routine.
 //
 
+// How a looping sample is stopped. Fade is only chosen from the GUI.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StopMode {
+    // Treat it as a one-shot, so it ends when it next reaches the
+    // end of the sample. This is what the game does, but can stop on
+    // a non-zero value, and click.
+    OneShot,
+    // Let the current loop finish, then fade out over a few
+    // milliseconds.
+    Fade,
+}
+
+// Length of the fade when stopping in Fade mode.
+const STOP_FADE_S: f32 = 0.01;
+
+// How samples are read between the points stored. Without the GUI,
+// nothing picks Nearest or Sinc.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Interpolation {
+    Nearest,
+    Linear,
+    // A windowed sinc, band-limited to below the output's Nyquist
+    // frequency, so high notes don't alias.
+    Sinc,
+    // What Paula does: each stored sample is held for a whole number
+    // of clock ticks, aliasing and all.
+    Paula,
+}
+
+impl Interpolation {
+    #[cfg(feature = "gui")]
+    const ALL: [Interpolation; 4] = [
+        Interpolation::Nearest,
+        Interpolation::Linear,
+        Interpolation::Sinc,
+        Interpolation::Paula,
+    ];
+}
+
+// The shortest period Paula's DMA can keep up with. The driver never
+// asks for less, but pitch adjustments can.
+const MIN_PAULA_PERIOD: u16 = 124;
+
+// The sinc kernel reaches this many zero crossings either side of
+// its centre, and is tabulated this finely between them.
+const SINC_HALF_WIDTH: usize = 8;
+const SINC_RESOLUTION: usize = 64;
+const SINC_TABLE_LEN: usize = SINC_HALF_WIDTH * SINC_RESOLUTION + 2;
+// When a note plays back faster than this many stored samples to an
+// output sample, the kernel stops widening, to bound the cost, and
+// lets a little aliasing through.
+const MAX_SINC_STEP: f32 = 8.0;
+
+// The Blackman-windowed sinc, from 0 out to SINC_HALF_WIDTH. Built
+// once, lazily, in a static, so the audio thread never allocates for
+// it.
+fn sinc_table() -> &'static [f32; SINC_TABLE_LEN] {
+    static TABLE: OnceLock<[f32; SINC_TABLE_LEN]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [0.0; SINC_TABLE_LEN];
+        for (idx, value) in table.iter_mut().enumerate() {
+            let x = idx as f64 / SINC_RESOLUTION as f64;
+            let sinc = if idx == 0 {
+                1.0
+            } else {
+                (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+            };
+            let w = std::f64::consts::PI * x / SINC_HALF_WIDTH as f64;
+            let window = (0.42 + 0.5 * w.cos() + 0.08 * (2.0 * w).cos()).max(0.0);
+            *value = (sinc * window) as f32;
+        }
+        table
+    })
+}
+
+fn sinc_kernel(table: &[f32; SINC_TABLE_LEN], x: f32) -> f32 {
+    let pos = x.abs() * SINC_RESOLUTION as f32;
+    let idx = pos as usize;
+    if idx + 1 >= SINC_TABLE_LEN {
+        return 0.0;
+    }
+    let frac = pos - idx as f32;
+    table[idx] * (1.0 - frac) + table[idx + 1] * frac
+}
+
+// A stored sample, as a signed byte, following the loop past the
+// end, or silence for one-shots and before the start.
+fn sample_at(mem: &[u8], instrument: &Instrument, idx: isize) -> f32 {
+    let len = instrument.sample_len as isize * 2;
+    let idx = if idx < 0 {
+        return 0.0;
+    } else if idx < len {
+        idx
+    } else {
+        let loop_offset = instrument.loop_offset as isize;
+        let loop_len = len - loop_offset;
+        if instrument.is_one_shot || loop_len <= 0 {
+            return 0.0;
+        }
+        loop_offset + (idx - len) % loop_len
+    };
+    mem[instrument.sample_addr + idx as usize] as i8 as f32
+}
+
+// The sample at a fractional position, band-limited for playing back
+// `step` stored samples to each output sample.
+fn sinc_sample(mem: &[u8], instrument: &Instrument, phase: f32, step: f32) -> f32 {
+    let table = sinc_table();
+    // Playing back faster, the cut-off has to come down to the
+    // output's Nyquist frequency.
+    let cutoff = 1.0 / step.clamp(1.0, MAX_SINC_STEP);
+    let reach = SINC_HALF_WIDTH as f32 / cutoff;
+    let first = (phase - reach).ceil() as isize;
+    let last = (phase + reach).floor() as isize;
+    let mut sum = 0.0;
+    for idx in first..=last {
+        let weight = sinc_kernel(table, (phase - idx as f32) * cutoff);
+        if weight != 0.0 {
+            sum += weight * sample_at(mem, instrument, idx);
+        }
+    }
+    sum * cutoff
+}
+
+// Paula's output for the next output sample, `ticks` clock ticks long:
+// each stored sample is held for `period` ticks, counted from where
+// the last output sample left off, and the output is the average of
+// what was held over the whole interval. Moves `idx` on to the stored
+// sample that's playing at the end, and returns None when a one-shot
+// runs out, or the value and whether the sample looped.
+fn paula_sample(
+    mem: &[u8],
+    instrument: &Instrument,
+    idx: &mut usize,
+    elapsed: &mut f32,
+    period: u16,
+    ticks: f32,
+) -> Option<(f32, bool)> {
+    let period = period.max(MIN_PAULA_PERIOD) as f32;
+    let len = instrument.sample_len as usize * 2;
+    // An empty sample may start right at the end of the data.
+    if len == 0 {
+        return None;
+    }
+    let mut remaining = ticks;
+    let mut sum = 0.0;
+    let mut looped = false;
+    loop {
+        let held = mem[instrument.sample_addr + *idx] as i8 as f32;
+        // The period may have dropped since the sample started.
+        let left = (period - *elapsed).max(0.0);
+        if remaining < left {
+            *elapsed += remaining;
+            sum += held * remaining;
+            return Some((sum / ticks, looped));
+        }
+        sum += held * left;
+        remaining -= left;
+        *elapsed = 0.0;
+        *idx += 1;
+        if *idx >= len {
+            if instrument.is_one_shot {
+                return None;
+            }
+            *idx = instrument.loop_offset as usize;
+            looped = true;
+        }
+    }
+}
+
 #[derive(Clone)]
 struct SampleChannel {
     bank: Arc<SoundBank>,
     instr: Option<Instrument>,
+    // The instrument last played, even once it's finished, for
+    // previews.
+    last_instr: Option<Instrument>,
     volume: f32,
     volume_adjust: f32,
     pitch: usize,
     pitch_adjust: i16,
+    // Adjustments from automation, in quarter semitones and as a
+    // volume multiplier.
+    pitch_offset: isize,
+    volume_scale: f32,
+    // Transposition set by the user, in semitones, on top of
+    // everything else.
+    transpose: isize,
     phase: f32,
-    lerp: bool,
+    // Clock ticks into the current stored sample, for Paula stepping.
+    paula_elapsed: f32,
+    interpolation: Interpolation,
+    stop_mode: StopMode,
+    // Stopping in Fade mode: whether we've been asked to stop, and
+    // the gain once the fade has started.
+    releasing: bool,
+    fade_gain: Option<f32>,
 }
 
 impl SampleChannel {
@@ -195,26 +897,41 @@ impl SampleChannel {
         SampleChannel {
             bank,
             instr: None,
+            last_instr: None,
             volume: 1.0,
             volume_adjust: 0.0,
-            pitch: 48 * 4,
+            pitch: DEFAULT_PITCH,
             pitch_adjust: 0,
+            pitch_offset: 0,
+            volume_scale: 1.0,
+            transpose: 0,
             phase: 0.0,
-            lerp: true,
+            paula_elapsed: 0.0,
+            interpolation: Interpolation::Linear,
+            stop_mode: StopMode::OneShot,
+            releasing: false,
+            fade_gain: None,
         }
     }
 
     // New sounds are triggered immediately.
     pub fn play(&mut self, instr: &Instrument) {
         self.instr = Some(instr.clone());
+        self.last_instr = Some(instr.clone());
         self.phase = 0.0;
+        self.paula_elapsed = 0.0;
+        self.releasing = false;
+        self.fade_gain = None;
     }
 
     // Running sounds are stopped at a convenient point.
     pub fn stop(&mut self) {
         if let Some(current_instr) = &mut self.instr {
-            // Stop at next loop.
-            current_instr.is_one_shot = true;
+            match self.stop_mode {
+                // Stop at next loop.
+                StopMode::OneShot => current_instr.is_one_shot = true,
+                StopMode::Fade => self.releasing = true,
+            }
         }
     }
 
@@ -233,70 +950,115 @@ impl SampleChannel {
         }
     }
 
-    fn calc_time_step(&self) -> f32 {
+    // The sample period, in ticks of Paula's clock.
+    fn period(&self) -> u16 {
         if let Some(instrument) = &self.instr {
-            // This is PAL. 0.279365 for NTSC.
-            const CLOCK_INTERVAL_S: f32 = 0.281937e-6;
-
             // For some reason, the lowest base is one octave above the
             // lowest note.
             let base_note = (instrument.base_octave + 1) * OCTAVE_SIZE;
-            let period_tick =
-                PITCHES[base_note + self.pitch].wrapping_add_signed(self.pitch_adjust);
-            period_tick as f32 * CLOCK_INTERVAL_S
+            let note = base_note
+                .saturating_add(self.pitch)
+                .saturating_add_signed(self.pitch_offset + self.transpose * 4)
+                .min(self.bank.pitches.len() - 1);
+            self.bank.pitches[note].wrapping_add_signed(self.pitch_adjust)
         } else {
-            0.0
+            0
         }
     }
 
+    fn calc_time_step(&self) -> f32 {
+        self.period() as f32 * self.bank.quirks.clock_interval_s
+    }
+
     fn fill_buffer(&mut self, sample_rate: u32, data: &mut [f32]) {
         // Simple base case.
         for elt in data.iter_mut() {
-            *elt = Sample::EQUILIBRIUM;
+            *elt = 0.0;
         }
 
+        let period = self.period();
         let time_step = self.calc_time_step();
         let step = 1.0 / (time_step * sample_rate as f32);
+        let ticks = self.bank.quirks.clock_hz() / sample_rate as f32;
 
-        let vol = self.volume + self.volume_adjust;
+        let vol = (self.volume + self.volume_adjust) * self.volume_scale;
+        let fade_step = 1.0 / (STOP_FADE_S * sample_rate as f32);
 
         if let Some(instrument) = &mut self.instr {
             let mem = &self.bank.data;
             for elt in data.iter_mut() {
-                self.phase += step;
-                let mut idx_int = self.phase as usize;
-
-                if idx_int >= instrument.sample_len as usize * 2 {
-                    if instrument.is_one_shot {
+                let val = if self.interpolation == Interpolation::Paula {
+                    let mut idx = self.phase as usize;
+                    let Some((val, looped)) = paula_sample(
+                        mem,
+                        instrument,
+                        &mut idx,
+                        &mut self.paula_elapsed,
+                        period,
+                        ticks,
+                    ) else {
                         self.instr = None;
                         break;
-                    } else {
-                        self.phase -= (instrument.sample_len * 2 - instrument.loop_offset) as f32;
-                        idx_int = self.phase as usize;
+                    };
+                    self.phase = idx as f32;
+                    if looped && self.releasing && self.fade_gain.is_none() {
+                        self.fade_gain = Some(1.0);
                     }
-                }
+                    val
+                } else {
+                    self.phase += step;
+                    let mut idx_int = self.phase as usize;
 
-                let val = if self.lerp {
-                    let left = mem[instrument.sample_addr + idx_int] as i8 as f32;
-                    let right_idx = instrument.sample_addr + idx_int + 1;
-                    let right = if right_idx
-                        == instrument.sample_addr + instrument.sample_len as usize * 2
-                    {
+                    if idx_int >= instrument.sample_len as usize * 2 {
                         if instrument.is_one_shot {
-                            0
+                            self.instr = None;
+                            break;
                         } else {
-                            mem[instrument.sample_addr + instrument.loop_offset as usize]
+                            self.phase -= (instrument.sample_len as usize * 2
+                                - instrument.loop_offset as usize)
+                                as f32;
+                            idx_int = self.phase as usize;
+                            if self.releasing && self.fade_gain.is_none() {
+                                self.fade_gain = Some(1.0);
+                            }
                         }
-                    } else {
-                        mem[right_idx]
-                    } as i8 as f32;
-                    let x = self.phase.fract();
-                    left * (1.0 - x) + right * x
-                } else {
-                    mem[instrument.sample_addr + idx_int] as i8 as f32
+                    }
+
+                    match self.interpolation {
+                        Interpolation::Linear => {
+                            let left = mem[instrument.sample_addr + idx_int] as i8 as f32;
+                            let right_idx = instrument.sample_addr + idx_int + 1;
+                            let right = if right_idx
+                                == instrument.sample_addr + instrument.sample_len as usize * 2
+                            {
+                                if instrument.is_one_shot {
+                                    0
+                                } else {
+                                    mem[instrument.sample_addr + instrument.loop_offset as usize]
+                                }
+                            } else {
+                                mem[right_idx]
+                            } as i8 as f32;
+                            let x = self.phase.fract();
+                            left * (1.0 - x) + right * x
+                        }
+                        Interpolation::Sinc => sinc_sample(mem, instrument, self.phase, step),
+                        Interpolation::Nearest | Interpolation::Paula => {
+                            mem[instrument.sample_addr + idx_int] as i8 as f32
+                        }
+                    }
                 };
 
                 *elt = vol * val / 128.0;
+
+                if let Some(gain) = &mut self.fade_gain {
+                    *elt *= *gain;
+                    *gain -= fade_step;
+                    if *gain <= 0.0 {
+                        self.instr = None;
+                        break;
+                    }
+                }
             }
         }
     }
@@ -395,6 +1157,18 @@ impl EffectState {
         self.vol_adjust +=
             EffectState::step(&effect.tremolos, &mut self.tremolos, self.tremolo_loops);
     }
+
+    // Step the effects on by a frame, and apply them to the channel.
+    fn step_frame(&mut self, effect: &Effect, channel: &mut SampleChannel, options: &Options) {
+        if options.tremolo {
+            self.step_tremolo(effect);
+            channel.pitch_adjust = self.period_adjust;
+        }
+        if options.vibrato {
+            self.step_vibrato(effect);
+            channel.volume_adjust = self.vol_adjust as f32 / MAX_VOLUME;
+        }
+    }
 }
 
 ////////////////////////////////////////////////////////////////////////
@@ -402,6 +1176,70 @@ impl EffectState {
 // so.
 //
 
+// The driver keeps For loops and Calls on a small fixed-size stack
+// per channel, rather than a growable one. The depth is configurable
+// up to this, and nesting deeper than allowed stops the sequence
+// rather than corrupting anything.
+pub const MAX_STACK_DEPTH: usize = 16;
+// The game's own music never nests more than one deep.
+const DEFAULT_STACK_DEPTH: usize = 8;
+
+// Jump targets remembered for counting passes. Sequences are created
+// on the audio thread, so this is fixed rather than growing; past it,
+// new targets aren't remembered.
+const MAX_JUMP_TARGETS: usize = 16;
+
+// Each entry is a loop count and the address to go back to. Calls
+// push a count of 0.
+#[derive(Clone)]
+struct LoopStack {
+    entries: [(u8, usize); MAX_STACK_DEPTH],
+    len: usize,
+    // Deepest it's been, to show how close a sequence gets to the
+    // limit.
+    peak: usize,
+    // Where a push failed, making the sequence stop.
+    overflow: Option<usize>,
+}
+
+impl LoopStack {
+    fn new() -> LoopStack {
+        LoopStack {
+            entries: [(0, 0); MAX_STACK_DEPTH],
+            len: 0,
+            peak: 0,
+            overflow: None,
+        }
+    }
+
+    // Push an entry, or record an overflow at the given command
+    // address and return false if already `depth` deep.
+    fn push(&mut self, entry: (u8, usize), depth: usize, addr: usize) -> bool {
+        if self.len >= depth.min(MAX_STACK_DEPTH) {
+            self.overflow = Some(addr);
+            return false;
+        }
+        self.entries[self.len] = entry;
+        self.len += 1;
+        self.peak = self.peak.max(self.len);
+        true
+    }
+
+    fn pop(&mut self) -> Option<(u8, usize)> {
+        self.len = self.len.checked_sub(1)?;
+        Some(self.entries[self.len])
+    }
+
+    fn last_mut(&mut self) -> Option<&mut (u8, usize)> {
+        self.len.checked_sub(1).map(|top| &mut self.entries[top])
+    }
+
+    #[cfg(feature = "gui")]
+    fn iter(&self) -> impl Iterator<Item = &(u8, usize)> {
+        self.entries[..self.len].iter()
+    }
+}
+
 #[derive(Clone)]
 pub struct Sequence {
     addr: usize,
@@ -413,7 +1251,86 @@ pub struct Sequence {
     ttl: usize,
     effect: Effect,
     effect_state: EffectState,
-    loop_stack: Vec<(u8, usize)>,
+    loop_stack: LoopStack,
+    // Position in the driver's beats (the units of note lengths)
+    // since the sequence started, and frames into the current one.
+    beats: usize,
+    beat_frames: usize,
+    // Frames the sequence has run for, as duration.rs counts them.
+    frames: usize,
+    // When single-stepping, how many more commands may be run. None
+    // runs freely.
+    step_budget: Option<usize>,
+    // The breakpoint we're stopped at, or have just run on from.
+    breakpoint: Option<usize>,
+    // Times the sequence has looped back, by Restart or by jumping
+    // to one of the places it's jumped to (or started at) before.
+    passes: usize,
+    jump_targets: [usize; MAX_JUMP_TARGETS],
+    num_jump_targets: usize,
+    // The note sounding, in semitones, for sending as MIDI, and the
+    // number of notes played, to tell repeats of the same note apart.
+    sounding: Option<isize>,
+    notes_played: usize,
+    // Frames owed to the sequence when the tempo is scaled.
+    tempo_phase: f32,
+}
+
+// Places to pause a sequence and start single-stepping: command
+// addresses, and kinds of command, wherever they are.
+#[derive(Clone, Default, PartialEq)]
+pub struct Breakpoints {
+    addrs: Vec<usize>,
+    ops: Vec<Op>,
+}
+
+impl Breakpoints {
+    fn hits(&self, bank: &SoundBank, addr: usize) -> bool {
+        if self.addrs.contains(&addr) {
+            return true;
+        }
+        match bank.data.get(addr) {
+            Some(&code) if code >= 0x80 => {
+                (bank.variant.decode)(code).is_some_and(|op| self.ops.contains(&op))
+            }
+            _ => false,
+        }
+    }
+
+    // `new_addr` is the address being typed in.
+    #[cfg(feature = "gui")]
+    fn ui(&mut self, ui: &mut Ui, new_addr: &mut String) {
+        ui.horizontal(|ui| {
+            ui.label("Break at address");
+            ui.add(egui::TextEdit::singleline(new_addr).desired_width(60.0));
+            let addr = usize::from_str_radix(new_addr.trim_start_matches("0x"), 16);
+            if ui.add_enabled(addr.is_ok(), Button::new("Add")).clicked() {
+                self.addrs.push(addr.unwrap());
+                new_addr.clear();
+            }
+            let mut new_op = None;
+            egui::ComboBox::from_id_source("BreakOp")
+                .selected_text("Break on command")
+                .show_ui(ui, |ui| {
+                    for op in Op::ALL {
+                        if !self.ops.contains(&op) {
+                            ui.selectable_value(&mut new_op, Some(op), format!("{:?}", op));
+                        }
+                    }
+                });
+            self.ops.extend(new_op);
+        });
+        if self.addrs.is_empty() && self.ops.is_empty() {
+            return;
+        }
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Breakpoints (click to remove):");
+            self.addrs
+                .retain(|addr| !ui.button(format!("0x{:06x}", addr)).clicked());
+            self.ops
+                .retain(|op| !ui.button(format!("{:?}", op)).clicked());
+        });
+    }
 }
 
 #[derive(Eq, PartialEq)]
@@ -436,10 +1353,41 @@ impl Sequence {
             ttl: 0,
             effect: no_effect,
             effect_state: EffectState::new(),
-            loop_stack: Vec::new(),
+            loop_stack: LoopStack::new(),
+            beats: 0,
+            beat_frames: 0,
+            frames: 0,
+            step_budget: None,
+            breakpoint: None,
+            passes: 0,
+            jump_targets: [addr; MAX_JUMP_TARGETS],
+            num_jump_targets: 1,
+            sounding: None,
+            notes_played: 0,
+            tempo_phase: 0.0,
         }
     }
 
+    // Count a pass through looping music, returning whether we've
+    // played as many as asked for, and should stop.
+    fn end_of_pass(&mut self, options: &Options) -> bool {
+        self.passes += 1;
+        options.max_passes != 0 && self.passes >= options.max_passes
+    }
+
+    // Waiting to be allowed to run the next command.
+    fn is_held(&self) -> bool {
+        self.ttl == 0 && self.step_budget == Some(0)
+    }
+
+    // Read an operand byte. Past the end of the bank reads as zero,
+    // and the next command fetch then stops the sequence.
+    fn operand(&mut self, bank: &SoundBank) -> u8 {
+        let value = bank.data.get(self.addr).copied().unwrap_or(0);
+        self.addr += 1;
+        value
+    }
+
     // Run a single command in the command sequence. Implements
     // `sound_next_command`.
     fn eval(
@@ -447,116 +1395,126 @@ impl Sequence {
         bank: &SoundBank,
         channel: &mut SampleChannel,
         options: &Options,
+        trace: &mut Trace,
     ) -> EvalResult {
-        let code = bank.data[self.addr];
+        let Some(&code) = bank.data.get(self.addr) else {
+            eprintln!("Ran off the end of the bank. Bailing.");
+            return EvalResult::Stop;
+        };
+        let cmd_addr = self.addr;
         self.addr += 1;
 
         if code < 0x80 {
-            if cfg!(debug) {
-                println!("Note {}", code);
-            }
+            trace.record(cmd_addr, None, code);
 
             // If envelopes were implemented, they would be
-            // reinitialised here.
+            // reinitialised here. The driver has envelopes, but
+            // none of the game's sequences use one, so there's no
+            // envelope data in the bank to implement them from.
 
             // New notes reset tremolo/vibrato state.
             self.effect_state.reset(&self.effect);
-            channel.pitch = (code as usize * 4).wrapping_add_signed(self.transposition);
-            channel.play(&bank.instruments[self.instrument_idx]);
+            let Some(instrument) = bank.instruments.get(self.instrument_idx) else {
+                eprintln!("No instrument {:02x}. Bailing.", self.instrument_idx);
+                return EvalResult::Stop;
+            };
+            channel.pitch = (code as usize * 4).saturating_add_signed(self.transposition);
+            channel.play(instrument);
+            self.sounding = Some((code as isize * 4 + self.transposition).div_euclid(4));
+            self.notes_played += 1;
             self.ttl = self.note_len;
             return EvalResult::Done;
         }
 
-        match code {
-            0x80 => {
+        let op = (bank.variant.decode)(code);
+        if let Some(op) = op {
+            let operand = match op.operand_len() {
+                0 => 0,
+                _ => bank.data.get(self.addr).copied().unwrap_or(0),
+            };
+            trace.record(cmd_addr, Some(op), operand);
+        }
+        match op {
+            Some(Op::Volume) => {
                 // Set volume
-                let volume = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Vol: {}", volume);
-                }
-                channel.volume = volume as f32 / MAX_VOLUME;
+                let volume = self.operand(bank);
+                channel.volume = bank.quirks.volume_law.gain(volume);
             }
-            0x88 => {
+            Some(Op::Restart) => {
                 // Go back to start
-                if cfg!(debug) {
-                    println!("Restart");
-                }
                 if !options.repeats {
                     return EvalResult::Done;
                 }
+                if self.end_of_pass(options) {
+                    return EvalResult::Stop;
+                }
                 self.addr = self.start_addr;
             }
-            0x8c => {
+            Some(Op::NoteLen) => {
                 // Set note length
-                let note_len = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Len: {}", note_len);
-                }
+                let note_len = self.operand(bank);
                 self.note_len = note_len as usize * self.frames_per_beat;
             }
-            0x90 => {
+            Some(Op::Rest) => {
                 // Rest.
-                if cfg!(debug) {
-                    println!("Rest");
-                }
+                self.sounding = None;
                 channel.stop_loop();
                 return EvalResult::Done;
             }
-            0x94 => {
+            Some(Op::Tempo) => {
                 // Set tempo
-                let bpm = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Tempo: {} bpm", bpm);
-                }
+                // Sandboxed data may set a tempo of 0, which we take as
+                // the slowest there is.
+                let bpm = self.operand(bank).max(1);
                 self.frames_per_beat = 750 / bpm as usize;
             }
-            0x9c => {
+            Some(Op::Effect) => {
                 // Set effect
-                let effect = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Effect: {}", effect);
-                }
-                self.effect = EFFECTS[effect as usize];
+                let effect = self.operand(bank);
+                let effects = &EFFECTS[..bank.quirks.num_effects];
+                let Some(&effect) = effects.get(effect as usize) else {
+                    eprintln!("No effect {:02x}. Bailing.", effect);
+                    return EvalResult::Stop;
+                };
+                self.effect = effect;
                 self.effect_state = EffectState::new();
             }
-            0xa8 => {
+            Some(Op::EffectLoops) => {
                 // Effects looping flags
-                let loop_flags = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Loop: {}", loop_flags);
-                }
+                let loop_flags = self.operand(bank);
                 self.effect_state.tremolo_loops = loop_flags & 1 != 0;
                 self.effect_state.vibrato_loops = loop_flags & 2 != 0;
             }
-            0xac => {
+            Some(Op::Stop) => {
                 // Stop
-                if cfg!(debug) {
-                    println!("Stop");
-                }
                 return EvalResult::Stop;
             }
-            0xb0 => {
+            Some(Op::Call) => {
                 // Call
-                let seq_idx = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Call: {}", seq_idx);
+                let seq_idx = self.operand(bank);
+                let Some(&seq_addr) = bank.sequences.get(seq_idx as usize) else {
+                    eprintln!("No sequence {:02x} to call. Bailing.", seq_idx);
+                    return EvalResult::Stop;
+                };
+                if !self
+                    .loop_stack
+                    .push((0, self.addr), options.stack_depth, cmd_addr)
+                {
+                    eprintln!(
+                        "Loop stack overflow at 0x{:06x}, deeper than {}. Bailing.",
+                        cmd_addr, options.stack_depth
+                    );
+                    return EvalResult::Stop;
                 }
-                self.loop_stack.push((0, self.addr));
-                self.addr = bank.sequences[seq_idx as usize];
+                self.addr = seq_addr;
             }
-            0xb4 => {
+            Some(Op::Return) => {
                 // Return
-                if cfg!(debug) {
-                    println!("Return");
-                }
                 if let Some((i, ret_addr)) = self.loop_stack.pop() {
-                    assert_eq!(i, 0, "Return doesn't match call");
+                    if i != 0 {
+                        eprintln!("Return doesn't match call. Bailing.");
+                        return EvalResult::Stop;
+                    }
                     self.addr = ret_addr;
                 } else {
                     // Treat a return on a sequence that we've played
@@ -564,43 +1522,40 @@ impl Sequence {
                     return EvalResult::Stop;
                 }
             }
-            0xb8 => {
+            Some(Op::AddTransposition) => {
                 // Add transposition
-                let transposition = bank.data[self.addr] as i8;
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("TransRel: {}", transposition);
-                }
+                let transposition = self.operand(bank) as i8;
                 if transposition == 0 {
                     self.transposition = 0;
                 } else {
                     self.transposition += transposition as isize;
                 }
             }
-            0xbc => {
+            Some(Op::SetTransposition) => {
                 // Set transposition
-                let transposition = bank.data[self.addr] as i8;
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Trans: {}", transposition);
-                }
+                let transposition = self.operand(bank) as i8;
                 self.transposition = transposition as isize;
             }
-            0xc0 => {
+            Some(Op::For) => {
                 // For loop
-                let count = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("For: {}", count);
+                let count = self.operand(bank);
+                if !self
+                    .loop_stack
+                    .push((count, self.addr), options.stack_depth, cmd_addr)
+                {
+                    eprintln!(
+                        "Loop stack overflow at 0x{:06x}, deeper than {}. Bailing.",
+                        cmd_addr, options.stack_depth
+                    );
+                    return EvalResult::Stop;
                 }
-                self.loop_stack.push((count, self.addr));
             }
-            0xc4 => {
+            Some(Op::Next) => {
                 // Next
-                if cfg!(debug) {
-                    println!("Next");
-                }
-                let (count, loop_addr) = self.loop_stack.last_mut().unwrap();
+                let Some((count, loop_addr)) = self.loop_stack.last_mut() else {
+                    eprintln!("Next without For. Bailing.");
+                    return EvalResult::Stop;
+                };
                 if *count == 0 {
                     self.loop_stack.pop();
                 } else {
@@ -608,26 +1563,36 @@ impl Sequence {
                     self.addr = *loop_addr;
                 }
             }
-            0xd0 => {
+            Some(Op::Instrument) => {
                 // Set instrument
-                let instr_idx = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Instrument: {}", instr_idx);
-                }
+                let instr_idx = self.operand(bank);
                 self.instrument_idx = instr_idx as usize;
             }
-            0xd4 => {
+            Some(Op::Jump) => {
                 // Jump
-                let seq_idx = bank.data[self.addr];
-                self.addr += 1;
-                if cfg!(debug) {
-                    println!("Jump: {}", seq_idx);
+                let seq_idx = self.operand(bank);
+                let Some(&seq_addr) = bank.sequences.get(seq_idx as usize) else {
+                    eprintln!("No sequence {:02x} to jump to. Bailing.", seq_idx);
+                    return EvalResult::Stop;
+                };
+                // Some music loops by jumping back to somewhere it's
+                // been, rather than with Restart.
+                if self.jump_targets[..self.num_jump_targets].contains(&seq_addr) {
+                    if self.end_of_pass(options) {
+                        return EvalResult::Stop;
+                    }
+                } else if self.num_jump_targets < MAX_JUMP_TARGETS {
+                    self.jump_targets[self.num_jump_targets] = seq_addr;
+                    self.num_jump_targets += 1;
                 }
-                self.addr = bank.sequences[seq_idx as usize];
+                self.addr = seq_addr;
             }
-            unknown => {
-                println!("Unknown code: {:02x}. Bailing.", unknown);
+            // Guessed to do nothing, if the quirk is set.
+            None if bank.quirks.skips(code) => (),
+            None => {
+                // Without knowing its operands, we can't carry on
+                // past it.
+                eprintln!("Unknown code {:02x} at 0x{:06x}. Bailing.", code, cmd_addr);
                 return EvalResult::Stop;
             }
         }
@@ -639,14 +1604,48 @@ impl Sequence {
     // Perform a timestep of the sequence, usually synchronised with a
     // vertical blanking interval. Returns whether the sequence
     // continues.
-    fn update(&mut self, bank: &SoundBank, channel: &mut SampleChannel, options: &Options) -> bool {
+    fn update(
+        &mut self,
+        bank: &SoundBank,
+        channel: &mut SampleChannel,
+        options: &Options,
+        breakpoints: &Breakpoints,
+        trace: &mut Trace,
+    ) -> bool {
         if self.ttl > 0 {
             return true;
         }
 
         let mut result = EvalResult::Cont;
+        let mut commands = 0;
         while result == EvalResult::Cont {
-            result = self.eval(bank, channel, options);
+            // A loop that never plays a note would hang the driver,
+            // and us with it.
+            commands += 1;
+            if commands > MAX_COMMANDS_PER_FRAME {
+                eprintln!(
+                    "No note after {} commands. Bailing.",
+                    MAX_COMMANDS_PER_FRAME
+                );
+                result = EvalResult::Stop;
+                break;
+            }
+            if self.breakpoint != Some(self.addr) {
+                self.breakpoint = None;
+            }
+            match &mut self.step_budget {
+                Some(0) => return true,
+                Some(budget) => *budget -= 1,
+                // Stop before the command, unless we've just been
+                // told to run on from here.
+                None if self.breakpoint.is_none() && breakpoints.hits(bank, self.addr) => {
+                    self.breakpoint = Some(self.addr);
+                    self.step_budget = Some(0);
+                    return true;
+                }
+                None => (),
+            }
+            result = self.eval(bank, channel, options, trace);
         }
 
         self.ttl = self.note_len;
@@ -654,7 +1653,12 @@ impl Sequence {
         if result == EvalResult::Done {
             true
         } else {
-            channel.stop_hard();
+            if options.ring_out {
+                // Let the last note finish, as set by the stop mode.
+                channel.stop();
+            } else {
+                channel.stop_hard();
+            }
             false
         }
     }
@@ -664,21 +1668,39 @@ impl Sequence {
         bank: &SoundBank,
         channel: &mut SampleChannel,
         options: &Options,
+        breakpoints: &Breakpoints,
+        tempo_scale: f32,
+        trace: &mut Trace,
     ) -> bool {
-        let running = self.update(bank, channel, options);
-        if running {
-            self.ttl -= 1;
+        // The sequence runs tempo_scale frames' worth each frame, but
+        // effects still run once a frame, so that vibrato and the
+        // like sound the same at any tempo.
+        self.tempo_phase += tempo_scale;
+        let mut running = true;
+        while self.tempo_phase >= 1.0 {
+            self.tempo_phase -= 1.0;
+            running = self.update(bank, channel, options, breakpoints, trace);
+            if !running || self.is_held() {
+                break;
+            }
+            // A note played before any NoteLen has no length.
+            self.ttl = self.ttl.saturating_sub(1);
+            self.frames += 1;
+            self.beat_frames += 1;
+            if self.beat_frames >= self.frames_per_beat {
+                self.beats += 1;
+                self.beat_frames = 0;
+            }
+        }
+        if self.is_held() {
+            // Don't save up frames to rush through after stepping.
+            self.tempo_phase = 0.0;
+        }
+        if running && !self.is_held() {
             // If envelope were implemented, it would go here, and
             // based on the assembly code, an envelope would disable
             // the effects.
-            if options.tremolo {
-                self.effect_state.step_tremolo(&self.effect);
-                channel.pitch_adjust = self.effect_state.period_adjust;
-            }
-            if options.vibrato {
-                self.effect_state.step_vibrato(&self.effect);
-                channel.volume_adjust = self.effect_state.vol_adjust as f32 / MAX_VOLUME;
-            }
+            self.effect_state.step_frame(&self.effect, channel, options);
         }
         running
     }
@@ -688,11 +1710,19 @@ impl Sequence {
 // Sound channel capable of playing a sound.
 //
 
-#[derive(Clone)]
+#[derive(Clone, Copy, PartialEq)]
 pub struct Options {
     tremolo: bool,
     vibrato: bool,
     repeats: bool,
+    // Times to play a repeating sequence before stopping, or 0 to
+    // repeat forever.
+    max_passes: usize,
+    // Entries the loop and call stack holds, up to MAX_STACK_DEPTH.
+    stack_depth: usize,
+    // When a sequence ends, let the last note finish rather than
+    // cutting it off, as the game does.
+    ring_out: bool,
 }
 
 impl Options {
@@ -701,281 +1731,2806 @@ impl Options {
             tremolo: true,
             vibrato: true,
             repeats: true,
+            max_passes: 0,
+            stack_depth: DEFAULT_STACK_DEPTH,
+            ring_out: false,
         }
     }
 
+    #[cfg(feature = "gui")]
     fn ui(&mut self, ui: &mut Ui) {
         ui.checkbox(&mut self.tremolo, "Tremolo");
         ui.checkbox(&mut self.vibrato, "Vibrato");
         ui.checkbox(&mut self.repeats, "Repeats");
+        ui.add_enabled(
+            self.repeats,
+            DragValue::new(&mut self.max_passes)
+                .clamp_range(0..=99)
+                .prefix("Passes: "),
+        )
+        .on_hover_text("Stop after this many times through, or 0 to repeat forever");
+        ui.add(
+            DragValue::new(&mut self.stack_depth)
+                .clamp_range(1..=MAX_STACK_DEPTH)
+                .prefix("Stack: "),
+        )
+        .on_hover_text("Deepest the For loops and Calls may nest");
+        ui.checkbox(&mut self.ring_out, "Ring out");
     }
 }
 
+// When an instrument is played directly, rather than through a
+// sequence, we can still apply an effect to it.
 #[derive(Clone)]
-pub struct SoundChannel {
-    bank: Arc<SoundBank>,
+struct Audition {
+    effect: Effect,
+    effect_state: EffectState,
+    automation: Option<Automation>,
+    frame: usize,
+}
+
+impl Audition {
+    fn step_frame(&mut self, channel: &mut SampleChannel, options: &Options) {
+        self.effect_state.step_frame(&self.effect, channel, options);
+        if let Some(automation) = &self.automation {
+            let time_s = self.frame as f32 / channel.bank.quirks.frame_rate;
+            let (pitch, volume) = automation.at(time_s);
+            channel.pitch_offset = (pitch * 4.0).round() as isize;
+            channel.volume_scale = volume;
+        }
+        self.frame += 1;
+    }
+}
+
+// Which part of a channel's output to hear, split by a crossover
+// filter, e.g. to pick a bass line out from chords arpeggiated on the
+// same channel. Only the mixer selects a band other than Full.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Band {
+    Full,
+    Low,
+    High,
+}
+
+const DEFAULT_CROSSOVER_HZ: f32 = 300.0;
+
+// Two one-pole low-passes in series, with the high band being what
+// they take out, so that the two bands add back up to the original.
+#[derive(Clone)]
+struct Crossover {
+    band: Band,
+    freq_hz: f32,
+    state: [f32; 2],
+}
+
+impl Crossover {
+    fn new() -> Crossover {
+        Crossover {
+            band: Band::Full,
+            freq_hz: DEFAULT_CROSSOVER_HZ,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, sample_rate: u32, data: &mut [f32]) {
+        if self.band == Band::Full {
+            return;
+        }
+        let omega = 2.0 * std::f32::consts::PI / sample_rate as f32;
+        let coeff = 1.0 - (-omega * self.freq_hz).exp();
+        for x in data.iter_mut() {
+            self.state[0] += coeff * (*x - self.state[0]);
+            self.state[1] += coeff * (self.state[0] - self.state[1]);
+            *x = match self.band {
+                Band::Low => self.state[1],
+                _ => *x - self.state[1],
+            };
+        }
+    }
+}
+
+// Channels can be nudged earlier or later by up to this much, to line
+// them up with recordings of real hardware.
+const MAX_OFFSET_MS: f32 = 10.0;
+
+// A fixed delay, for offsetting channels. Negative offsets can't be
+// done directly, so the synth delays every channel by the most
+// negative offset as well, keeping their relative timing.
+#[derive(Clone, Default)]
+struct DelayLine {
+    buf: Vec<f32>,
+    pos: usize,
+}
+
+impl DelayLine {
+    // Room for both extremes of offset, so the audio thread needn't
+    // allocate.
+    fn prepare(&mut self, sample_rate: u32) {
+        let len = (2.0 * MAX_OFFSET_MS * sample_rate as f32 / 1000.0).ceil() as usize + 1;
+        if self.buf.len() != len {
+            self.buf = vec![0.0; len];
+            self.pos = 0;
+        }
+    }
+
+    fn process(&mut self, sample_rate: u32, delay: usize, data: &mut [f32]) {
+        if delay == 0 && self.buf.is_empty() {
+            return;
+        }
+        self.prepare(sample_rate);
+        let len = self.buf.len();
+        let delay = delay.min(len - 1);
+        for x in data.iter_mut() {
+            self.buf[self.pos] = *x;
+            *x = self.buf[(self.pos + len - delay) % len];
+            self.pos = (self.pos + 1) % len;
+        }
+    }
+}
+
+// Longest a channel that doesn't loop can be frozen for.
+#[cfg(feature = "gui")]
+const MAX_FREEZE_S: f32 = 600.0;
+
+// A channel's sequence rendered ahead of time, played back instead of
+// resampling the instruments live, to save CPU. The sequence itself
+// still runs, as it's cheap, so that the bar counter, MIDI and so on
+// carry on as before, and the frozen audio keeps in step with it.
+// Anything that would make the two differ thaws the channel.
+#[derive(Clone)]
+pub struct Frozen {
+    samples: Arc<Vec<f32>>,
+    sample_rate: u32,
+    start_addr: usize,
+    transpose: isize,
+    // Where playback goes back to at the end, for looping sequences.
+    loop_start: Option<usize>,
+    pos: usize,
+}
+
+impl Frozen {
+    // Returns false once a sequence that doesn't loop has played out.
+    fn fill(&mut self, data: &mut [f32]) -> bool {
+        for x in data.iter_mut() {
+            if self.pos >= self.samples.len() {
+                match self.loop_start {
+                    Some(start) => self.pos = start,
+                    None => {
+                        *x = 0.0;
+                        continue;
+                    }
+                }
+            }
+            *x = self.samples[self.pos];
+            self.pos += 1;
+        }
+        self.loop_start.is_some() || self.pos < self.samples.len()
+    }
+}
+
+// A copy of a channel to freeze, made from what the synth last
+// published, so the rendering can be done without holding up the
+// audio.
+#[cfg(feature = "gui")]
+pub struct Freezer {
+    channel: usize,
+    source: SoundChannel,
+    sample_rate: u32,
+}
+
+#[cfg(feature = "gui")]
+impl Freezer {
+    // Render the channel's sequence from the start, once through if it
+    // loops, or until it finishes sounding.
+    pub fn render(self) -> Result<(usize, Frozen), String> {
+        let mut channel = self.source;
+        let (start_addr, _) = channel.position().ok_or("Nothing is playing to freeze")?;
+        let bank = channel.bank.clone();
+        let seq = bank
+            .sequences
+            .iter()
+            .position(|&addr| addr == start_addr)
+            .ok_or("The channel's sequence isn't in the bank")?;
+        let frame_rate = bank.quirks.frame_rate;
+        let samples_per_frame = (self.sample_rate as f32 / frame_rate) as usize;
+        let estimate = duration::estimate(&bank, seq).filter(|e| e.loops() && !e.is_stuck());
+        let max_frames = match estimate {
+            Some(estimate) => estimate.frames,
+            None => (MAX_FREEZE_S * frame_rate) as usize,
+        };
+
+        channel.stepping = false;
+        channel.trace = Trace::new(self.channel);
+        channel.frozen = None;
+        channel.stop_hard();
+        channel.play_seq(seq);
+        let breakpoints = Breakpoints::default();
+        let mut samples = Vec::new();
+        let mut buf = vec![0.0; samples_per_frame];
+        for _ in 0..max_frames {
+            channel.tick(&breakpoints, 1.0);
+            if !channel.is_active() {
+                break;
+            }
+            channel
+                .sample_channel
+                .fill_buffer(self.sample_rate, &mut buf);
+            samples.extend_from_slice(&buf);
+        }
+        let frozen = Frozen {
+            samples: Arc::new(samples),
+            sample_rate: self.sample_rate,
+            start_addr,
+            transpose: channel.sample_channel.transpose,
+            loop_start: estimate.map(|e| e.loop_frame * samples_per_frame),
+            pos: 0,
+        };
+        Ok((self.channel, frozen))
+    }
+}
+
+#[derive(Clone)]
+pub struct SoundChannel {
+    bank: Arc<SoundBank>,
     sample_channel: SampleChannel,
-    samples_remaining: usize,
     sequence: Option<Sequence>,
+    audition: Option<Audition>,
     options: Options,
+    crossover: Crossover,
+    // Run the sequence one command at a time, for debugging.
+    stepping: bool,
+    // Leave the channel out of the mix, or play only soloed
+    // channels. Either way, the channel carries on playing silently.
+    mute: bool,
+    solo: bool,
+    // The MIDI note last sent as sounding, and the sequence's note
+    // count when it was sent.
+    midi_sent: Option<(u8, usize)>,
+    // Address of the command that overflowed the loop stack, if
+    // that's what stopped the last sequence.
+    stack_overflow: Option<usize>,
+    // A newly-loaded bank, which we'll switch to once we're not
+    // playing anything from the old one.
+    pending_bank: Option<Arc<SoundBank>>,
+    // Commands run, when tracing.
+    trace: Trace,
+    // How much later than the other channels to play, in ms, and the
+    // delay that makes it so.
+    offset_ms: f32,
+    delay: DelayLine,
+    // The mixer's gain for the channel, on top of the driver's
+    // volume.
+    level: f32,
+    // Audio rendered ahead of time, when frozen.
+    frozen: Option<Frozen>,
+    // Whether the volume or pitch is being dragged in the GUI, and
+    // whether the last instrument is being played while it is.
+    dragging: bool,
+    drag_preview: bool,
+}
+
+// The mixer settings of a channel, as kept in an A/B snapshot.
+#[cfg(feature = "gui")]
+#[derive(Clone, Copy, PartialEq)]
+struct ChannelMix {
+    level: f32,
+    mute: bool,
+    solo: bool,
+    band: Band,
+    crossover_hz: f32,
+    interpolation: Interpolation,
+    offset_ms: f32,
+}
+
+#[cfg(feature = "gui")]
+impl ChannelMix {
+    fn crossover_ui(&mut self, ui: &mut Ui) {
+        let band = &mut self.band;
+        egui::ComboBox::from_id_source(ui.id().with("Band"))
+            .selected_text(format!("Band: {:?}", band))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(band, Band::Full, "Full");
+                ui.selectable_value(band, Band::Low, "Low");
+                ui.selectable_value(band, Band::High, "High");
+            });
+        if self.band != Band::Full {
+            ui.add(
+                DragValue::new(&mut self.crossover_hz)
+                    .clamp_range(20.0..=10_000.0)
+                    .suffix(" Hz"),
+            );
+        }
+    }
+}
+
+// What the GUI shows of a channel, as last published by the audio
+// thread.
+#[cfg(feature = "gui")]
+#[derive(Clone)]
+struct ChannelStatus {
+    // The bank the channel's playing from, which lags the synth's
+    // while a new one is pending.
+    bank: Arc<SoundBank>,
+    active: bool,
+    frozen: bool,
+    stepping: bool,
+    volume: f32,
+    pitch: usize,
+    stack_overflow: Option<usize>,
+    sequence: Option<Sequence>,
+}
+
+#[cfg(feature = "gui")]
+impl ChannelStatus {
+    // The state of the sequence being single-stepped, as it is before
+    // running the next command.
+    fn debugger_ui(&self, ui: &mut Ui, idx: usize, commands: &mut Vec<Command>) {
+        if !self.stepping {
+            return;
+        }
+        let Some(sequence) = &self.sequence else {
+            ui.label("Stepping: play a sequence to step through it");
+            return;
+        };
+        ui.horizontal(|ui| {
+            let held = sequence.is_held();
+            if ui.add_enabled(held, Button::new("Step")).clicked() {
+                commands.push(Command::Step(idx));
+            }
+            let next = if held {
+                format!(
+                    "Next: 0x{:06x} {}",
+                    sequence.addr,
+                    disasm::command_at(&self.bank, sequence.addr)
+                )
+            } else {
+                "Playing note".to_string()
+            };
+            let mut text = RichText::new(next).monospace();
+            if held && sequence.breakpoint == Some(sequence.addr) {
+                text = text.background_color(Color32::DARK_RED);
+                ui.label("Breakpoint");
+            }
+            ui.label(text);
+        });
+        let loops = sequence
+            .loop_stack
+            .iter()
+            .map(|(count, addr)| match count {
+                0 => format!("return/loop 0x{:06x}", addr),
+                _ => format!("loop 0x{:06x} x{}", addr, count),
+            })
+            .collect::<Vec<_>>();
+        let state = &sequence.effect_state;
+        ui.label(
+            RichText::new(format!(
+                "ttl {}  note len {}  transposition {}  instrument {:02x}\n\
+                 loop stack [{}]\n\
+                 effect adjust: volume {} period {}  loops: tremolo {} vibrato {}",
+                sequence.ttl,
+                sequence.note_len,
+                sequence.transposition,
+                sequence.instrument_idx,
+                loops.join(", "),
+                state.vol_adjust,
+                state.period_adjust,
+                state.tremolo_loops,
+                state.vibrato_loops
+            ))
+            .monospace(),
+        );
+    }
 }
 
 impl SoundChannel {
-    pub fn new(bank: Arc<SoundBank>) -> SoundChannel {
+    // The index identifies the channel in traces.
+    pub fn new(bank: Arc<SoundBank>, index: usize) -> SoundChannel {
         let sample_channel = SampleChannel::new(bank.clone());
         SoundChannel {
             bank,
             sample_channel,
-            samples_remaining: 0,
             sequence: None,
+            audition: None,
             options: Options::new(),
+            crossover: Crossover::new(),
+            stepping: false,
+            mute: false,
+            solo: false,
+            midi_sent: None,
+            stack_overflow: None,
+            pending_bank: None,
+            trace: Trace::new(index),
+            offset_ms: 0.0,
+            delay: DelayLine::default(),
+            level: 1.0,
+            frozen: None,
+            dragging: false,
+            drag_preview: false,
         }
     }
 
-    pub fn play_instr(&mut self, instr: &Instrument) {
+    #[cfg(feature = "gui")]
+    fn mix(&self) -> ChannelMix {
+        ChannelMix {
+            level: self.level,
+            mute: self.mute,
+            solo: self.solo,
+            band: self.crossover.band,
+            crossover_hz: self.crossover.freq_hz,
+            interpolation: self.sample_channel.interpolation,
+            offset_ms: self.offset_ms,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn set_mix(&mut self, mix: &ChannelMix) {
+        self.level = mix.level;
+        self.mute = mix.mute;
+        self.solo = mix.solo;
+        self.crossover.band = mix.band;
+        self.crossover.freq_hz = mix.crossover_hz;
+        self.sample_channel.interpolation = mix.interpolation;
+        self.offset_ms = mix.offset_ms;
+    }
+
+    #[cfg(any(feature = "audio", feature = "export"))]
+    pub fn play_instr(
+        &mut self,
+        instr: &Instrument,
+        effect: &Effect,
+        automation: Option<&Automation>,
+    ) {
+        self.frozen = None;
+        let mut effect_state = EffectState::new();
+        effect_state.reset(effect);
+        self.audition = Some(Audition {
+            effect: *effect,
+            effect_state,
+            automation: automation.cloned(),
+            frame: 0,
+        });
+        self.adopt_pending_bank();
+        self.reset_adjustments();
         self.sample_channel.play(instr);
     }
 
     pub fn play_seq(&mut self, seq: usize) {
-        let addr = self.bank.sequences[seq];
-        self.sequence = Some(Sequence::new(addr));
+        self.adopt_pending_bank();
+        // Sandboxed banks may not have every sequence asked for.
+        let Some(&addr) = self.bank.sequences.get(seq) else {
+            return;
+        };
+        self.frozen = None;
+        let mut sequence = Sequence::new(addr);
+        sequence.step_budget = self.stepping.then_some(0);
+        self.sequence = Some(sequence);
+        self.stack_overflow = None;
+        self.audition = None;
+        self.reset_adjustments();
+    }
+
+    // Play a sequence from a byte offset into it. Inferring the state
+    // runs it silently from the start until it's about to run the
+    // command there, so the tempo, instrument, loops and so on are as
+    // they would be; otherwise it starts there cold, with everything
+    // at its defaults, as for data whose start isn't known.
+    #[cfg(feature = "gui")]
+    pub fn play_seq_from(&mut self, seq: usize, offset: usize, infer: bool) {
+        self.play_seq(seq);
+        let Some(start) = self.sequence.as_ref().map(|s| s.start_addr) else {
+            return;
+        };
+        let target = start + offset;
+        if infer {
+            if self.run_to(target) {
+                return;
+            }
+            eprintln!(
+                "Sequence {:02x} never reaches 0x{:06x}, so starting there cold",
+                seq, target
+            );
+            self.sample_channel.stop_hard();
+            self.play_seq(seq);
+        }
+        if let Some(sequence) = &mut self.sequence {
+            sequence.addr = target;
+        }
+    }
+
+    // Run the sequence silently, a command at a time, as in stepping,
+    // until it's about to run the command at `addr`. Returns whether
+    // it got there.
+    #[cfg(feature = "gui")]
+    fn run_to(&mut self, addr: usize) -> bool {
+        let Some(sequence) = &mut self.sequence else {
+            return false;
+        };
+        let step_budget = sequence.step_budget;
+        sequence.step_budget = Some(0);
+        // Breakpoints are for playing, not seeking.
+        let breakpoints = Breakpoints::default();
+        for _ in 0..MAX_SEEK_FRAMES {
+            if sequence.is_held() {
+                if sequence.addr == addr {
+                    sequence.step_budget = step_budget;
+                    return true;
+                }
+                sequence.step_budget = Some(1);
+            }
+            let running = sequence.step_frame(
+                &self.bank,
+                &mut self.sample_channel,
+                &self.options,
+                &breakpoints,
+                1.0,
+                &mut self.trace,
+            );
+            if !running {
+                break;
+            }
+        }
+        false
+    }
+
+    fn reset_adjustments(&mut self) {
+        self.sample_channel.pitch_adjust = 0;
+        self.sample_channel.volume_adjust = 0.0;
+        self.sample_channel.pitch_offset = 0;
+        self.sample_channel.volume_scale = 1.0;
     }
 
+    #[cfg(feature = "audio")]
     pub fn stop(&mut self) {
+        self.frozen = None;
         self.sample_channel.stop();
         self.sequence = None;
+        self.audition = None;
     }
 
+    #[cfg(any(feature = "audio", feature = "export"))]
     pub fn stop_hard(&mut self) {
+        self.frozen = None;
         self.sample_channel.stop_hard();
         self.sequence = None;
+        self.audition = None;
+    }
+
+    // Switch to a new bank. Anything currently playing carries on
+    // with the old bank's data until it stops, so we never play a
+    // mixture of the two.
+    #[cfg(feature = "gui")]
+    pub fn set_bank(&mut self, bank: Arc<SoundBank>) {
+        self.pending_bank = Some(bank);
+        if !self.is_active() {
+            self.adopt_pending_bank();
+        }
+    }
+
+    fn adopt_pending_bank(&mut self) {
+        if let Some(bank) = self.pending_bank.take() {
+            self.sample_channel.bank = bank.clone();
+            // Its instruments are the old bank's.
+            self.sample_channel.last_instr = None;
+            self.bank = bank;
+        }
     }
 
     pub fn is_active(&self) -> bool {
         self.sequence.is_some() || self.sample_channel.instr.is_some()
     }
 
-    pub fn ui(&mut self, ui: &mut Ui) {
-        ui.horizontal(|ui| {
-            let stop_colour = if self.is_active() {
-                Color32::DARK_RED
-            } else {
-                Color32::DARK_GRAY
-            };
-            if ui.add(Button::new("Stop").fill(stop_colour)).clicked() {
-                self.stop();
+    // Frames per driver beat, once the sequence has set a tempo.
+    pub fn frames_per_beat(&self) -> Option<usize> {
+        self.sequence
+            .as_ref()
+            .map(|sequence| sequence.frames_per_beat)
+            .filter(|&frames| frames > 0)
+    }
+
+    // Driver beats since the sequence started, if one is playing.
+    pub fn beats(&self) -> Option<usize> {
+        self.sequence.as_ref().map(|sequence| sequence.beats)
+    }
+
+    // Where the sequence started, and how many frames it's run for,
+    // if one is playing.
+    #[cfg(feature = "gui")]
+    pub fn position(&self) -> Option<(usize, usize)> {
+        self.sequence
+            .as_ref()
+            .map(|sequence| (sequence.start_addr, sequence.frames))
+    }
+
+    // MIDI note off and on messages for any change in the note the
+    // sequence is playing since last time. Notes are numbered as in
+    // listings, with C0 as MIDI note 12, whatever the instrument's
+    // octave, plus any global transposition.
+    fn midi_note_messages(&mut self, midi_channel: u8, enabled: bool) -> [Option<[u8; 3]>; 2] {
+        let playing = self
+            .sequence
+            .as_ref()
+            .filter(|_| enabled)
+            .and_then(|sequence| {
+                let note = sequence.sounding? + 12 + self.sample_channel.transpose;
+                Some((note.clamp(0, 127) as u8, sequence.notes_played))
+            });
+        if playing == self.midi_sent {
+            return [None, None];
+        }
+        let off = self
+            .midi_sent
+            .map(|(note, _)| midi::note_off(midi_channel, note));
+        let on =
+            playing.map(|(note, _)| midi::note_on(midi_channel, note, self.sample_channel.volume));
+        self.midi_sent = playing;
+        [off, on]
+    }
+
+    // Move the playing sequence to the given driver beat, running its
+    // commands without rendering any audio. Going backwards restarts
+    // it from the beginning.
+    #[cfg(feature = "gui")]
+    pub fn seek(&mut self, beat: usize) {
+        self.frozen = None;
+        let Some(sequence) = &mut self.sequence else {
+            return;
+        };
+        if beat < sequence.beats {
+            let step_budget = sequence.step_budget;
+            *sequence = Sequence::new(sequence.start_addr);
+            sequence.step_budget = step_budget;
+        }
+        // Breakpoints are for playing, not seeking.
+        let breakpoints = Breakpoints::default();
+        let mut running = true;
+        for _ in 0..MAX_SEEK_FRAMES {
+            if sequence.beats >= beat || sequence.is_held() {
+                break;
+            }
+            running = sequence.step_frame(
+                &self.bank,
+                &mut self.sample_channel,
+                &self.options,
+                &breakpoints,
+                1.0,
+                &mut self.trace,
+            );
+            if !running {
+                break;
+            }
+        }
+        if !running {
+            self.sequence = None;
+        }
+    }
+
+    // Keep the last instrument sounding while the volume or pitch is
+    // dragged, so the change can be heard as it's made, unless a
+    // sequence is playing, as that sets them itself. Called every
+    // frame.
+    fn preview_while_dragging(&mut self) {
+        if !self.dragging {
+            if std::mem::take(&mut self.drag_preview) {
+                self.sample_channel.stop();
             }
-            ui.checkbox(&mut self.sample_channel.lerp, "Linear interpolation");
-            ui.label("Volume");
-            ui.add(DragValue::new(&mut self.sample_channel.volume));
-            ui.label("Pitch");
-            ui.add(DragValue::new(&mut self.sample_channel.pitch));
-            ui.checkbox(&mut self.sample_channel.lerp, "Linear interpolation");
+            return;
+        }
+        if self.sequence.is_some() || self.sample_channel.instr.is_some() {
+            return;
+        }
+        // Played out, or not started yet: (re)start it.
+        if let Some(instr) = self.sample_channel.last_instr.clone() {
+            self.frozen = None;
+            self.sample_channel.play(&instr);
+            self.drag_preview = true;
+        }
+    }
 
-            self.options.ui(ui);
-        });
+    // Start or stop single-stepping. A sequence that's playing stops
+    // before its next command.
+    #[cfg(feature = "gui")]
+    fn set_stepping(&mut self, stepping: bool) {
+        self.stepping = stepping;
+        if let Some(sequence) = &mut self.sequence {
+            sequence.step_budget = stepping.then_some(0);
+        }
     }
 
-    fn fill_buffer(&mut self, sample_rate: u32, data: &mut [f32]) {
-        // Not going to try to do sub-sample accuracy.
-        const FRAMES_PER_SECOND: usize = 50;
-        let samples_per_frame = sample_rate as usize / FRAMES_PER_SECOND;
+    // Run the next command, when stopped at one.
+    #[cfg(feature = "gui")]
+    fn step(&mut self) {
+        if let Some(sequence) = self.sequence.as_mut().filter(|s| s.is_held()) {
+            sequence.step_budget = Some(1);
+        }
+    }
 
-        let mut data = data;
-        // Fill buffer until we hit a new frame, repeat.
-        while data.len() >= self.samples_remaining {
-            self.sample_channel
-                .fill_buffer(sample_rate, &mut data[..self.samples_remaining]);
-
-            if let Some(sequence) = &mut self.sequence {
-                if !sequence.step_frame(&self.bank, &mut self.sample_channel, &self.options) {
-                    self.sequence = None;
+    // What the GUI shows of the channel. Cloning the sequence doesn't
+    // allocate, so this can be done from the audio thread.
+    #[cfg(feature = "gui")]
+    fn status(&self) -> ChannelStatus {
+        ChannelStatus {
+            bank: self.bank.clone(),
+            active: self.is_active(),
+            frozen: self.frozen.is_some(),
+            stepping: self.stepping,
+            volume: self.sample_channel.volume,
+            pitch: self.sample_channel.pitch,
+            stack_overflow: self.stack_overflow,
+            sequence: self.sequence.clone(),
+        }
+    }
+
+    // Whether frozen audio still matches what the channel would play.
+    fn is_in_step(&self, frozen: &Frozen, tempo_scale: f32) -> bool {
+        if tempo_scale != 1.0 || self.sample_channel.transpose != frozen.transpose {
+            return false;
+        }
+        match &self.sequence {
+            Some(sequence) => sequence.start_addr == frozen.start_addr && !sequence.is_held(),
+            // Sequences that don't loop play out their last note.
+            None => frozen.loop_start.is_none(),
+        }
+    }
+
+    // Advance the sequence or audition by one frame.
+    fn tick(&mut self, breakpoints: &Breakpoints, tempo_scale: f32) {
+        if let Some(frozen) = &self.frozen {
+            if !self.is_in_step(frozen, tempo_scale) {
+                self.frozen = None;
+            }
+        }
+        self.trace.tick();
+        if let Some(sequence) = &mut self.sequence {
+            if !sequence.step_frame(
+                &self.bank,
+                &mut self.sample_channel,
+                &self.options,
+                breakpoints,
+                tempo_scale,
+                &mut self.trace,
+            ) {
+                self.stack_overflow = sequence.loop_stack.overflow;
+                self.sequence = None;
+            } else if sequence.step_budget.is_some() {
+                // Hit a breakpoint, so show the debugger.
+                self.stepping = true;
+            }
+        } else if let Some(audition) = &mut self.audition {
+            audition.step_frame(&mut self.sample_channel, &self.options);
+        }
+
+        if self.pending_bank.is_some() && !self.is_active() {
+            self.adopt_pending_bank();
+        }
+        self.preview_while_dragging();
+    }
+
+    // Render, held back by the given number of samples.
+    fn render(&mut self, sample_rate: u32, delay: usize, data: &mut [f32]) {
+        match &mut self.frozen {
+            Some(frozen) if frozen.sample_rate == sample_rate => {
+                if !frozen.fill(data) {
+                    // Played out, last note and all.
+                    self.frozen = None;
+                    if self.sequence.is_none() {
+                        self.sample_channel.stop_hard();
+                    }
                 }
             }
+            _ => {
+                self.frozen = None;
+                self.sample_channel.fill_buffer(sample_rate, data);
+            }
+        }
+        self.crossover.process(sample_rate, data);
+        self.delay.process(sample_rate, delay, data);
+        if self.level != 1.0 {
+            for x in data.iter_mut() {
+                *x *= self.level;
+            }
+        }
+    }
+}
 
-            data = &mut data[self.samples_remaining..];
-            self.samples_remaining = samples_per_frame;
+////////////////////////////////////////////////////////////////////////
+// Output stage. Paula hardwires channels 0 and 3 to the left output,
+// and 1 and 2 to the right. Each output then goes through a fixed RC
+// low-pass filter and is AC-coupled, which we model with one-pole
+// filters.
+
+const PAULA_BUS: [usize; 4] = [0, 1, 1, 0];
+// The A500's fixed filter is somewhere around here.
+const LOWPASS_HZ: f32 = 4900.0;
+const DC_BLOCK_HZ: f32 = 5.0;
+
+#[derive(Clone)]
+struct BusFilter {
+    lowpass: f32,
+    dc_in: f32,
+    dc_out: f32,
+}
+
+impl BusFilter {
+    fn new() -> BusFilter {
+        BusFilter {
+            lowpass: 0.0,
+            dc_in: 0.0,
+            dc_out: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample_rate: u32, data: &mut [f32]) {
+        let omega = 2.0 * std::f32::consts::PI / sample_rate as f32;
+        let lp_coeff = 1.0 - (-omega * LOWPASS_HZ).exp();
+        let hp_coeff = (-omega * DC_BLOCK_HZ).exp();
+        for x in data.iter_mut() {
+            self.lowpass += lp_coeff * (*x - self.lowpass);
+            self.dc_out = self.lowpass - self.dc_in + hp_coeff * self.dc_out;
+            self.dc_in = self.lowpass;
+            *x = self.dc_out;
+        }
+    }
+}
+
+// Left and right gains for a position from -1 (left) to 1 (right),
+// keeping the power constant, so a centred channel sounds as loud as
+// one to the side.
+fn pan_gains(position: f32) -> [f32; 2] {
+    let angle = (position.clamp(-1.0, 1.0) + 1.0) * std::f32::consts::FRAC_PI_4;
+    [angle.cos(), angle.sin()]
+}
+
+// e.g. "L 50", "C" or "R 100", as a percentage of the way over.
+#[cfg(feature = "gui")]
+fn pan_label(pan: f64) -> String {
+    let percent = (pan * 100.0).round() as i32;
+    match percent {
+        0 => "C".to_string(),
+        p if p < 0 => format!("L {}", -p),
+        p => format!("R {}", p),
+    }
+}
+
+// Turn interleaved mixed frames into output samples, applying the
+// master volume and then the limiter, if it's in use.
+#[cfg(any(feature = "audio", feature = "export"))]
+fn convert_output<T: OutputSample>(
+    sample_rate: u32,
+    num_channels: usize,
+    gain: f32,
+    mut limiter: Option<&mut Limiter>,
+    src: &[f32],
+    dst: &mut [T],
+) {
+    for (dsts, srcs) in dst.chunks_mut(num_channels).zip(src.chunks(num_channels)) {
+        let Some(limiter) = limiter.as_deref_mut() else {
+            for (dst, src) in dsts.iter_mut().zip(srcs.iter()) {
+                *dst = T::from_f32(gain * src);
+            }
+            continue;
+        };
+        let peak = srcs.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+        let gain = gain * limiter.gain(sample_rate, gain * peak);
+        for (dst, src) in dsts.iter_mut().zip(srcs.iter()) {
+            *dst = T::from_f32(limiter::soft_clip(gain * src));
         }
+    }
+}
+
+// The A500's "LED" filter, switched with the power light: a
+// 12dB/octave Butterworth low-pass, on top of the fixed filter.
+const LED_FILTER_HZ: f32 = 3275.0;
+
+// Filtering applied to each output after mixing: the A500's fixed
+// filter, for when the authentic bus isn't already applying it, and
+// the LED filter.
+#[derive(Clone, Default)]
+struct OutputFilter {
+    lowpass: f32,
+    // The LED filter's last two inputs and outputs, latest first.
+    led_in: [f32; 2],
+    led_out: [f32; 2],
+}
 
-        // And fill any leftover.
-        self.sample_channel.fill_buffer(sample_rate, data);
-        self.samples_remaining -= data.len();
+impl OutputFilter {
+    fn process<'a>(
+        &mut self,
+        sample_rate: u32,
+        data: impl Iterator<Item = &'a mut f32>,
+        fixed: bool,
+        led: bool,
+    ) {
+        let omega = 2.0 * std::f32::consts::PI / sample_rate as f32;
+        let lp_coeff = 1.0 - (-omega * LOWPASS_HZ).exp();
+        // The Butterworth as a biquad, from the bilinear transform.
+        let (sin, cos) = (omega * LED_FILTER_HZ).sin_cos();
+        let alpha = sin * std::f32::consts::FRAC_1_SQRT_2;
+        let a0 = 1.0 + alpha;
+        let b0 = (1.0 - cos) / 2.0 / a0;
+        let b1 = (1.0 - cos) / a0;
+        let a1 = -2.0 * cos / a0;
+        let a2 = (1.0 - alpha) / a0;
+        if !led {
+            // Start afresh when switched back on.
+            self.led_in = [0.0; 2];
+            self.led_out = [0.0; 2];
+        }
+        for x in data {
+            if fixed {
+                self.lowpass += lp_coeff * (*x - self.lowpass);
+                *x = self.lowpass;
+            }
+            if led {
+                let [in1, in2] = self.led_in;
+                let [out1, out2] = self.led_out;
+                let y = b0 * (*x + in2) + b1 * in1 - a1 * out1 - a2 * out2;
+                self.led_in = [*x, in1];
+                self.led_out = [y, out1];
+                *x = y;
+            }
+        }
     }
 }
 
 ////////////////////////////////////////////////////////////////////////
 // 4-channel synthesiser
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg(feature = "gui")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PlayMode {
     Speakers,
     WaveFile,
 }
 
+#[cfg(feature = "gui")]
+fn master_volume_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("speedball2-sound-player").join("master-volume"))
+}
+
+// Failing to save it just means it's not remembered.
+#[cfg(feature = "gui")]
+fn save_master_volume(db: f32) {
+    if let Some(path) = master_volume_path() {
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let _ = std::fs::write(path, format!("{}\n", db));
+    }
+}
+
 #[derive(Clone)]
 pub struct Synth {
     pub channels: [SoundChannel; 4],
+    // An extra channel for auditioning instruments, so that browsing
+    // doesn't steal a channel from whatever else is playing.
+    audition_channel: SoundChannel,
+    #[cfg(any(feature = "audio", feature = "export"))]
     bank: Arc<SoundBank>,
     stereo: bool,
+    // Which output each channel is sent to, when in stereo. With a
+    // multichannel device, channels can each get their own output.
+    routing: [usize; 4],
+    // With a stereo device, where each channel sits instead, from -1
+    // (left) to 1 (right), and how far apart the two sides are, from
+    // 0 (mono) to 1. The separation also applies to the authentic
+    // bus.
+    pans: [f32; 4],
+    separation: f32,
+    // Number of outputs on the device we're playing to.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    num_outputs: usize,
+    // Sum channels onto the two hardware buses, and filter each bus,
+    // as the real machine does. Overrides the routing.
+    authentic: bool,
+    buses: [BusFilter; 2],
+    // The A500's filters, after the mix, and their state for each
+    // output.
+    a500_filter: bool,
+    led_filter: bool,
+    output_filters: Vec<OutputFilter>,
+    // Limit the final output, after the master volume, so it doesn't
+    // clip.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    limiter: bool,
+    #[cfg(any(feature = "audio", feature = "export"))]
+    output_limiter: Limiter,
+    #[cfg(feature = "gui")]
     play_mode: PlayMode,
+    #[cfg(feature = "gui")]
     max_rec_time_s: f32,
+    // Record looping music once through, rather than for
+    // max_rec_time_s.
+    #[cfg(feature = "gui")]
+    auto_length: bool,
+    // What to record after the sound finishes.
+    #[cfg(feature = "gui")]
+    tail: Tail,
+    // Record just what the machine would play, as 32-bit float, for
+    // comparing with emulator captures (see bypass_processing).
+    #[cfg(feature = "export")]
+    measurement: bool,
+    // Playback of an exported file, for comparison.
+    #[cfg(feature = "gui")]
+    preview: Preview,
+    // Channel whose sequence the bar counter follows, and how the
+    // driver's beats (really steps) group into musical beats and
+    // bars.
+    music_master: Option<usize>,
+    steps_per_beat: usize,
+    #[cfg(feature = "gui")]
+    beats_per_bar: usize,
+    breakpoints: Breakpoints,
+    // Where to send MIDI, if anywhere, and the clock following the
+    // music master, if it's turned on.
+    midi_out: Option<Arc<midi::Port>>,
+    midi_clock: Option<midi::Clock>,
+    // Send the notes each channel plays, on the MIDI channel of the
+    // same number.
+    midi_notes: bool,
+    // Whether exports write a .mid of the notes next to the .wav, and
+    // the notes recorded so far while exporting.
+    #[cfg(feature = "gui")]
+    record_midi: bool,
+    midi_recording: Option<midi::Recording>,
+    // Speeds sequences up or slows them down, without changing pitch.
+    tempo_scale: f32,
+    // Speed of the output, time-stretched without changing pitch, and
+    // whether the stretcher was in use last time round.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    stretch_speed: f32,
+    #[cfg(any(feature = "audio", feature = "export"))]
+    stretching: bool,
+    #[cfg(any(feature = "audio", feature = "export"))]
+    stretcher: Stretcher,
+    // Overall volume, in dB, applied last. It stands in for the
+    // system volume, so isn't exported, and is kept between sessions.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    master_db: f32,
+    // A sine wave to tune against, and the channel output the tuner
+    // is listening to.
+    reference_tone: ReferenceTone,
+    capture: Capture,
+    // Samples until the next frame, when driven by fill_buffer, and
+    // the rate they're played at (0 until the output's opened).
+    #[cfg(any(feature = "audio", feature = "export"))]
+    samples_remaining: usize,
+    #[cfg(feature = "gui")]
+    sample_rate: u32,
+    scratch: Scratch,
+    // Where to publish what's playing for the GUI, if anywhere, and
+    // how many frozen renders have come back too late to use.
+    #[cfg(feature = "gui")]
+    published: Option<Arc<Mutex<Published>>>,
+    #[cfg(feature = "gui")]
+    freeze_failures: usize,
 }
 
-impl Synth {
-    pub fn new(bank: Arc<SoundBank>) -> Synth {
-        Synth {
-            // Simplest way I could find to do this!
-            channels: [(); 4].map(|()| SoundChannel::new(bank.clone())),
-            bank,
-            stereo: true,
-            play_mode: PlayMode::Speakers,
-            max_rec_time_s: 3.0,
+// Buffers for mixing, kept between calls so that the audio callback
+// doesn't allocate. mix() leaves its result in `out`.
+#[derive(Clone, Default)]
+struct Scratch {
+    out: Vec<f32>,
+    tmp: Vec<f32>,
+    buses: [Vec<f32>; 2],
+}
+
+impl Scratch {
+    // Make room for mixes of up to this size.
+    #[cfg(feature = "audio")]
+    fn reserve(&mut self, frames: usize, num_outputs: usize) {
+        self.out.reserve(frames * num_outputs);
+        self.tmp.reserve(frames);
+        for bus in self.buses.iter_mut() {
+            bus.reserve(frames);
         }
     }
 
-    // A wrapper that can either call a function normally, or redirect
-    // the call to a clone of this synth and then redirect the sound
-    // to a .wav file. Fun!
-    pub fn route<F>(&mut self, f: F)
-    where
-        F: FnOnce(&mut Synth),
-    {
-        match self.play_mode {
-            PlayMode::Speakers => f(self),
-            PlayMode::WaveFile => {
-                let mut clone = self.clone();
-                // Ensure clone is in quiescent state first.
-                for ch in clone.channels.iter_mut() {
-                    ch.stop_hard();
-                }
-                // Start the sound...
-                f(&mut clone);
-                // And record it in a background thread, so as not to
-                // block the realtime music thread.
-                thread::spawn(move || clone.record());
-                // I'm ok to just detach the thread for a toy app like
-                // this.
-            }
+    // Size the buffers for a mix, zeroed. Only allocates if they
+    // haven't been reserved big enough.
+    fn clear(&mut self, frames: usize, num_outputs: usize) {
+        let zero = |buf: &mut Vec<f32>, len| {
+            buf.clear();
+            buf.resize(len, 0.0);
+        };
+        zero(&mut self.out, frames * num_outputs);
+        zero(&mut self.tmp, frames);
+        for bus in self.buses.iter_mut() {
+            zero(bus, frames);
         }
     }
+}
 
-    fn record(&mut self) {
-	cpal_wrapper::write_wav(self, self.stereo, self.max_rec_time_s);
-    }
+// Everything the mixer controls, for A/B comparisons: each channel's
+// settings (the audition channel's last), the routing, and the
+// output stage.
+#[cfg(feature = "gui")]
+#[derive(Clone, Copy, PartialEq)]
+struct Mix {
+    channels: [ChannelMix; 5],
+    routing: [usize; 4],
+    pans: [f32; 4],
+    separation: f32,
+    stereo: bool,
+    authentic: bool,
+    a500_filter: bool,
+    led_filter: bool,
+    limiter: bool,
+}
 
-    pub fn play_instr(&mut self, instr: &Instrument) {
-        self.route(|synth| synth.channels[0].play_instr(instr));
-    }
+#[cfg(feature = "gui")]
+const MIX_NAMES: [&str; 2] = ["A", "B"];
+
+// Ways of linking the game channels into pairs, whose level, mute,
+// solo and pan then move together. Sides pairs the channels Paula
+// puts on the same output.
+#[cfg(feature = "gui")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Pairing {
+    Unlinked,
+    Sides,
+    Neighbours,
+    Alternate,
+}
+
+#[cfg(feature = "gui")]
+impl Pairing {
+    const ALL: [Pairing; 4] = [
+        Pairing::Unlinked,
+        Pairing::Sides,
+        Pairing::Neighbours,
+        Pairing::Alternate,
+    ];
 
-    pub fn play_seq(&mut self, idx: usize) {
-        self.route(|synth| synth.channels[0].play_seq(idx));
+    fn partner(self, idx: usize) -> Option<usize> {
+        match self {
+            Pairing::Unlinked => None,
+            Pairing::Sides => Some(3 - idx),
+            Pairing::Neighbours => Some(idx ^ 1),
+            Pairing::Alternate => Some(idx ^ 2),
+        }
     }
 
-    pub fn play_sound(&mut self, sound: &Sound) {
-        self.route(|synth| {
-            for (channel, seq) in synth.channels.iter_mut().zip(sound.sequences.iter()) {
-                if *seq != 0 {
-                    channel.play_seq(*seq);
-                }
-            }
-        });
+    fn label(self) -> &'static str {
+        match self {
+            Pairing::Unlinked => "unlinked",
+            Pairing::Sides => "0+3, 1+2",
+            Pairing::Neighbours => "0+1, 2+3",
+            Pairing::Alternate => "0+2, 1+3",
+        }
     }
+}
 
-    pub fn sound_ui(&mut self, ui: &mut Ui) {
-        CollapsingHeader::new("Sounds")
-            .default_open(true)
-            .show(ui, |ui| {
-                for (idx, sound) in SOUNDS.iter().enumerate() {
-                    CollapsingHeader::new(format!("Sound {:02x}", idx))
-                        .default_open(true)
-                        .show(ui, |ui| {
-                            ui.horizontal(|ui| {
-                                if ui
-                                    .add(Button::new("Play").fill(Color32::DARK_RED))
-                                    .clicked()
-                                {
-                                    self.play_sound(sound);
-                                }
-                                ui.label(&format!("{:?}", sound));
-                            });
-                        });
-                }
-            });
+// The controls a channel shares with its partner.
+#[cfg(feature = "gui")]
+#[derive(Clone, Copy, PartialEq)]
+struct Linked {
+    level: f32,
+    mute: bool,
+    solo: bool,
+    pan: f32,
+}
+
+// Everything the mixer controls, as the GUI keeps it, and sends over
+// whenever it changes: the mix, each channel's settings (the
+// audition channel's last), and the synth's own.
+#[cfg(feature = "gui")]
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    mix: Mix,
+    stop_modes: [StopMode; 5],
+    options: [Options; 5],
+    master_db: f32,
+    tempo_scale: f32,
+    stretch_speed: f32,
+    transpose: isize,
+    midi_notes: bool,
+    play_mode: PlayMode,
+    max_rec_time_s: f32,
+    auto_length: bool,
+    tail: Tail,
+    record_midi: bool,
+    measurement: bool,
+    music_master: Option<usize>,
+    steps_per_beat: usize,
+    beats_per_bar: usize,
+    midi_clock: bool,
+    breakpoints: Breakpoints,
+}
+
+#[cfg(feature = "gui")]
+impl Settings {
+    // Set up a game channel, 0-3, or the audition channel, 4.
+    fn configure(&self, idx: usize, channel: &mut SoundChannel) {
+        channel.set_mix(&self.mix.channels[idx]);
+        channel.sample_channel.stop_mode = self.stop_modes[idx];
+        channel.sample_channel.transpose = self.transpose;
+        channel.options = self.options[idx];
     }
 
-    pub fn ui(&mut self, ui: &mut Ui) {
-        ui.horizontal(|ui| {
-            ui.checkbox(&mut self.stereo, "Stereo");
-            ui.label("Output to");
-            egui::ComboBox::from_id_source("PlayMode")
-                .selected_text(format!("{:?}", self.play_mode))
-                .show_ui(ui, |ui| {
-                    ui.selectable_value(&mut self.play_mode, PlayMode::Speakers, "Speakers");
-                    ui.selectable_value(&mut self.play_mode, PlayMode::WaveFile, "WaveFile");
-                });
-            if self.play_mode == PlayMode::WaveFile {
-                ui.label("up to");
-                ui.add(DragValue::new(&mut self.max_rec_time_s).speed(0.1));
-                ui.label("seconds");
-            }
-        });
-        for (idx, channel) in self.channels.iter_mut().enumerate() {
-            ui.horizontal(|ui| {
-                // Cheap alignment.
-                ui.label(RichText::new(format!("Ch {}", idx)).monospace());
-                channel.ui(ui);
-            });
+    fn linked(&self, idx: usize) -> Linked {
+        let channel = &self.mix.channels[idx];
+        Linked {
+            level: channel.level,
+            mute: channel.mute,
+            solo: channel.solo,
+            pan: self.mix.pans[idx],
         }
+    }
 
-        egui::ScrollArea::vertical()
-            .auto_shrink([false, false])
-            .show(ui, |ui| {
-                // Instruments and Sequences - use channel 0.
-                let bank = self.bank.clone();
-                bank.ui(ui, self);
-                // And sounds
-                self.sound_ui(ui);
-            });
+    fn set_linked(&mut self, idx: usize, linked: Linked) {
+        let channel = &mut self.mix.channels[idx];
+        channel.level = linked.level;
+        channel.mute = linked.mute;
+        channel.solo = linked.solo;
+        self.mix.pans[idx] = linked.pan;
     }
 }
 
-impl cpal_wrapper::SoundSource for Synth {
-    fn fill_buffer<T: Sample + cpal::FromSample<f32> + std::ops::Add<Output = T>>(
-        &mut self,
-        num_channels: u16,
-        sample_rate: u32,
-        data: &mut [T],
-    ) {
-        data.fill(Sample::EQUILIBRIUM);
+// What the GUI shows of what's playing, as last published by the
+// audio thread.
+#[cfg(feature = "gui")]
+#[derive(Clone)]
+pub struct Status {
+    bank: Arc<SoundBank>,
+    channels: [ChannelStatus; 5],
+    num_outputs: usize,
+    sample_rate: u32,
+    // The limiter's deepest gain reduction since the GUI last looked.
+    reduction_db: f32,
+    freeze_failures: usize,
+    midi_out: Option<Arc<midi::Port>>,
+    preview: Preview,
+}
 
-        let mixer_scale = 1.0 / self.channels.len() as f32;
-        let mut tmp = vec![0.0; data.len() / num_channels as usize];
-
-        if self.stereo && num_channels > 1 {
-            for (ch_idx, channel) in self.channels.iter_mut().enumerate() {
-                channel.fill_buffer(sample_rate, &mut tmp);
-                // Odd channels on left, even channels on right.
-                let offset = ch_idx & 1;
-                // Build an iterator for exactly where we'll be writing.
-                let dst_iter = data.iter_mut().skip(offset).step_by(num_channels as usize);
-                for (dst, src) in dst_iter.zip(tmp.iter()) {
-                    *dst = dst.add_amp((mixer_scale * src).to_sample::<T>().to_signed_sample());
+#[cfg(feature = "gui")]
+impl Status {
+    pub fn bank(&self) -> &Arc<SoundBank> {
+        &self.bank
+    }
+
+    // Is any channel making sound?
+    pub fn is_playing(&self) -> bool {
+        self.channels.iter().any(|channel| channel.active)
+    }
+
+    // The sequence each game channel is playing, and how many frames
+    // it's run for.
+    pub fn positions(&self) -> [Option<(usize, usize)>; 4] {
+        let [a, b, c, d, _] = &self.channels;
+        [a, b, c, d].map(|channel| {
+            let sequence = channel.sequence.as_ref()?;
+            let addr = sequence.start_addr;
+            let idx = self.bank.sequences.iter().position(|&a| a == addr)?;
+            Some((idx, sequence.frames))
+        })
+    }
+
+    // How many frozen renders have come back too late to use.
+    pub fn freeze_failures(&self) -> usize {
+        self.freeze_failures
+    }
+}
+
+// Where the audio thread leaves what's playing for the GUI, along
+// with the commands traced and the tuner's samples since the GUI
+// last took them. The vectors are allocated up front, for the audio
+// thread to fill without allocating.
+#[cfg(feature = "gui")]
+pub struct Published {
+    status: Status,
+    pub trace: Vec<trace::Event>,
+    pub tuner: Vec<f32>,
+    pub tuner_rate: u32,
+}
+
+#[cfg(feature = "gui")]
+impl Published {
+    // Take a copy of the status, starting the limiter's meter again.
+    pub fn take_status(&mut self) -> Status {
+        let status = self.status.clone();
+        self.status.reduction_db = 0.0;
+        status
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+}
+
+// The game's music mostly uses notes of a few driver beats, so a
+// musical beat is several of those.
+const DEFAULT_STEPS_PER_BEAT: usize = 8;
+#[cfg(feature = "gui")]
+const DEFAULT_BEATS_PER_BAR: usize = 4;
+
+// Interpolation and bus filter settings compared by
+// record_qualities, with the labels used in file names.
+#[cfg(feature = "gui")]
+const QUALITIES: [(&str, Interpolation, bool); 8] = [
+    ("nearest", Interpolation::Nearest, false),
+    ("linear", Interpolation::Linear, false),
+    ("sinc", Interpolation::Sinc, false),
+    ("paula", Interpolation::Paula, false),
+    ("nearest_filtered", Interpolation::Nearest, true),
+    ("linear_filtered", Interpolation::Linear, true),
+    ("sinc_filtered", Interpolation::Sinc, true),
+    ("paula_filtered", Interpolation::Paula, true),
+];
+
+// Requests for the synth to change what it's doing. These are sent from the UI
+// over a channel and applied by the audio thread, so that the UI
+// needn't hold the synth's lock while drawing the bank browser or
+// the mixer.
+pub enum Command {
+    #[cfg(any(feature = "audio", feature = "export"))]
+    Instrument {
+        instr: usize,
+        effect: usize,
+        automation: Option<Automation>,
+        // The audition channel's raw pitch to play at, in quarter
+        // semitones. Always given, so that no audition inherits the
+        // last one's.
+        pitch: usize,
+    },
+    Sequence(usize),
+    // Play a sequence from a byte offset into it, with its state
+    // inferred by running up to there, or left at the defaults.
+    #[cfg(feature = "gui")]
+    SequenceFrom {
+        idx: usize,
+        offset: usize,
+        infer: bool,
+    },
+    #[cfg(any(feature = "audio", feature = "export"))]
+    Sound(usize),
+    // Stop everything before playing the sound, as the game does
+    // when changing the music.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    Music(usize),
+    // Let the given channel's note finish, and stop its sequence.
+    // Channel 4 is the audition channel.
+    #[cfg(feature = "audio")]
+    Stop(usize),
+    #[cfg(any(feature = "audio", feature = "export"))]
+    StopAll,
+    // Stop everything, and play a sequence on each channel (0 for
+    // none), as for an imported MIDI file.
+    #[cfg(feature = "gui")]
+    Song([usize; 4]),
+    // Switch to a new version of the bank.
+    #[cfg(feature = "gui")]
+    SetBank(Arc<SoundBank>),
+    // Play an exported file.
+    #[cfg(feature = "gui")]
+    Preview(Arc<Clip>),
+    // Play or pause the exported file, from a position in seconds.
+    #[cfg(feature = "gui")]
+    PreviewAt {
+        playing: bool,
+        pos_s: f64,
+    },
+    // Take on the mixer's settings.
+    #[cfg(feature = "gui")]
+    Settings(Box<Settings>),
+    // Go back to playing a frozen channel live, or swap it over to
+    // its frozen audio.
+    #[cfg(feature = "gui")]
+    Thaw(usize),
+    #[cfg(feature = "gui")]
+    Freeze(usize, Frozen),
+    // Move a channel's sequence to a driver beat.
+    #[cfg(feature = "gui")]
+    Seek(usize, usize),
+    // Start or stop single-stepping a channel, and run its next
+    // command.
+    #[cfg(feature = "gui")]
+    SetStepping(usize, bool),
+    #[cfg(feature = "gui")]
+    Step(usize),
+    // Set a channel's volume or pitch, as the driver would, and say
+    // whether they're being dragged.
+    #[cfg(feature = "gui")]
+    SetVolume(usize, f32),
+    #[cfg(feature = "gui")]
+    SetPitch(usize, usize),
+    #[cfg(feature = "gui")]
+    Drag(usize, bool),
+    #[cfg(feature = "gui")]
+    SetTracing(bool),
+    #[cfg(feature = "gui")]
+    ReferenceTone(ReferenceTone),
+    // Which channel the tuner listens to, if any.
+    #[cfg(feature = "gui")]
+    TunerChannel(Option<usize>),
+}
+
+impl Synth {
+    pub fn new(bank: Arc<SoundBank>) -> Synth {
+        Synth {
+            // Simplest way I could find to do this!
+            channels: [0, 1, 2, 3].map(|idx| SoundChannel::new(bank.clone(), idx)),
+            audition_channel: SoundChannel::new(bank.clone(), 4),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            bank,
+            stereo: true,
+            routing: [0, 1, 0, 1],
+            pans: [-1.0, 1.0, -1.0, 1.0],
+            separation: 1.0,
+            #[cfg(any(feature = "audio", feature = "export"))]
+            num_outputs: 2,
+            authentic: false,
+            buses: [BusFilter::new(), BusFilter::new()],
+            a500_filter: false,
+            led_filter: false,
+            output_filters: Vec::new(),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            limiter: false,
+            #[cfg(any(feature = "audio", feature = "export"))]
+            output_limiter: Limiter::new(),
+            #[cfg(feature = "gui")]
+            play_mode: PlayMode::Speakers,
+            #[cfg(feature = "gui")]
+            max_rec_time_s: 3.0,
+            #[cfg(feature = "gui")]
+            auto_length: true,
+            #[cfg(feature = "gui")]
+            tail: Tail::Cut,
+            #[cfg(feature = "export")]
+            measurement: false,
+            #[cfg(feature = "gui")]
+            preview: Preview::default(),
+            music_master: None,
+            steps_per_beat: DEFAULT_STEPS_PER_BEAT,
+            #[cfg(feature = "gui")]
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            breakpoints: Breakpoints::default(),
+            midi_out: None,
+            midi_clock: None,
+            midi_notes: false,
+            #[cfg(feature = "gui")]
+            record_midi: false,
+            midi_recording: None,
+            tempo_scale: 1.0,
+            #[cfg(any(feature = "audio", feature = "export"))]
+            stretch_speed: 1.0,
+            #[cfg(any(feature = "audio", feature = "export"))]
+            stretching: false,
+            #[cfg(any(feature = "audio", feature = "export"))]
+            stretcher: Stretcher::default(),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            master_db: 0.0,
+            reference_tone: ReferenceTone::new(),
+            capture: Capture::new(),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            samples_remaining: 0,
+            #[cfg(feature = "gui")]
+            sample_rate: 0,
+            scratch: Scratch::default(),
+            #[cfg(feature = "gui")]
+            published: None,
+            #[cfg(feature = "gui")]
+            freeze_failures: 0,
+        }
+    }
+
+    // Swap the channel over to the frozen audio, at the point the
+    // sequence has reached since the freezer was made. Returns false
+    // if the channel's changed since, so the audio's no use.
+    #[cfg(feature = "gui")]
+    fn freeze(&mut self, idx: usize, mut frozen: Frozen) -> bool {
+        let channel = &mut self.channels[idx];
+        let in_step = channel
+            .position()
+            .is_some_and(|(addr, _)| addr == frozen.start_addr)
+            && frozen.sample_rate == self.sample_rate
+            && channel.is_in_step(&frozen, self.tempo_scale);
+        let Some((_, frames)) = channel.position().filter(|_| in_step) else {
+            return false;
+        };
+        // The sequence has run `frames` frames, of which the last has
+        // samples_remaining still to play.
+        let samples_per_frame = (self.sample_rate as f32 / self.bank.quirks.frame_rate) as usize;
+        let pos = (frames * samples_per_frame).saturating_sub(self.samples_remaining);
+        let len = frozen.samples.len();
+        frozen.pos = match frozen.loop_start {
+            Some(start) if pos >= len && len > start => start + (pos - start) % (len - start),
+            _ => pos,
+        };
+        channel.frozen = Some(frozen);
+        true
+    }
+
+    // Is any channel making sound?
+    #[cfg(feature = "export")]
+    pub fn is_playing(&self) -> bool {
+        self.channels.iter().any(|ch| ch.is_active()) || self.audition_channel.is_active()
+    }
+
+    // Rather than playing the command, run it on a clone of this
+    // synth and then redirect the sound to a .wav file. Fun!
+    #[cfg(feature = "gui")]
+    pub fn record_command(&self, command: Command) {
+        let max_time_s = if self.auto_length {
+            duration::record_seconds(&self.bank, &command, self.max_passes(), self.max_rec_time_s)
+        } else {
+            self.max_rec_time_s
+        };
+        let mut clone = self.with_command(command);
+        clone.max_rec_time_s = max_time_s;
+        // And record it in a background thread, so as not to
+        // block the realtime music thread.
+        thread::spawn(move || clone.record());
+        // I'm ok to just detach the thread for a toy app like
+        // this.
+    }
+
+    // Record the commands each channel runs, for publishing.
+    #[cfg(feature = "gui")]
+    fn set_tracing(&mut self, enabled: bool) {
+        for channel in self.channels.iter_mut() {
+            channel.trace.set_enabled(enabled);
+        }
+        self.audition_channel.trace.set_enabled(enabled);
+    }
+
+    // Play repeating sequences this many times on every channel, or
+    // forever if 0.
+    pub fn set_max_passes(&mut self, passes: usize) {
+        for channel in self.channels.iter_mut() {
+            channel.options.max_passes = passes;
+        }
+        self.audition_channel.options.max_passes = passes;
+    }
+
+    #[cfg(feature = "export")]
+    pub fn max_passes(&self) -> usize {
+        self.channels[0].options.max_passes
+    }
+
+    // Shift everything played up or down by this many semitones, on
+    // top of the sequences' own transposition.
+    #[cfg(feature = "export")]
+    pub fn set_transpose(&mut self, semitones: isize) {
+        for channel in self.channels.iter_mut() {
+            channel.sample_channel.transpose = semitones;
+        }
+        self.audition_channel.sample_channel.transpose = semitones;
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn transpose(&self) -> isize {
+        self.channels[0].sample_channel.transpose
+    }
+
+    // Pick up the master volume from the last session, if there was
+    // one.
+    #[cfg(feature = "gui")]
+    pub fn restore_master_volume(&mut self) {
+        let saved = master_volume_path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|text| text.trim().parse::<f32>().ok());
+        if let Some(db) = saved {
+            self.master_db = db.clamp(MIN_MASTER_DB, MAX_MASTER_DB);
+        }
+    }
+
+    #[cfg(feature = "audio")]
+    pub fn set_midi_out(&mut self, port: Arc<midi::Port>) {
+        self.midi_out = Some(port);
+    }
+
+    // Record the notes every channel plays from now on, with each
+    // tick() a frame.
+    #[cfg(feature = "export")]
+    pub fn start_midi_recording(&mut self) {
+        self.midi_recording = Some(midi::Recording::new(self.bank.quirks.frame_rate));
+    }
+
+    #[cfg(feature = "export")]
+    pub fn take_midi_recording(&mut self) -> Option<midi::Recording> {
+        self.midi_recording.take()
+    }
+
+    // A copy of this synth, with the same settings, playing nothing
+    // but the command.
+    #[cfg(feature = "export")]
+    pub fn with_command(&self, command: Command) -> Synth {
+        let mut clone = self.clone();
+        // Copies render faster than real time, so mustn't send MIDI,
+        // and the GUI's only interested in the live synth.
+        clone.midi_out = None;
+        #[cfg(feature = "gui")]
+        {
+            clone.published = None;
+        }
+        // Nor should the reference tone, time stretch or master
+        // volume end up in exports.
+        clone.reference_tone.enabled = false;
+        clone.stretch_speed = 1.0;
+        clone.master_db = 0.0;
+        if clone.measurement {
+            clone.bypass_processing();
+        }
+        // Ensure clone is in quiescent state first.
+        clone.stop_all();
+        // Start the sound...
+        clone.apply(command);
+        clone
+    }
+
+    // Turn off everything the real machine doesn't do, leaving each
+    // channel's samples stepped as Paula steps them, and summed onto
+    // its two outputs, unfiltered: the same as an emulator with its
+    // filters off. Mutes and solos still apply, so channels can be
+    // compared one at a time.
+    #[cfg(feature = "export")]
+    pub fn bypass_processing(&mut self) {
+        let [a, b, c, d] = &mut self.channels;
+        for channel in [a, b, c, d, &mut self.audition_channel] {
+            channel.level = 1.0;
+            channel.crossover.band = Band::Full;
+            channel.offset_ms = 0.0;
+            channel.sample_channel.interpolation = Interpolation::Paula;
+        }
+        self.set_transpose(0);
+        self.tempo_scale = 1.0;
+        self.stretch_speed = 1.0;
+        self.reference_tone.enabled = false;
+        // The bus mix without the bus filters.
+        self.authentic = false;
+        self.routing = PAULA_BUS;
+        self.pans = PAULA_BUS.map(|bus| if bus == 0 { -1.0 } else { 1.0 });
+        self.separation = 1.0;
+        self.a500_filter = false;
+        self.led_filter = false;
+        self.limiter = false;
+    }
+
+    // The format recordings are saved in.
+    #[cfg(feature = "gui")]
+    pub fn wav_format(&self) -> WavFormat {
+        if self.measurement {
+            WavFormat::Float32
+        } else {
+            WavFormat::Pcm16
+        }
+    }
+
+    #[cfg(feature = "export")]
+    pub fn bank(&self) -> &Arc<SoundBank> {
+        &self.bank
+    }
+
+    #[cfg(feature = "gui")]
+    fn record(&mut self) {
+        if self.record_midi {
+            self.start_midi_recording();
+        }
+        let Some(wav_name) = cpal_wrapper::write_wav(
+            self,
+            self.stereo,
+            self.max_rec_time_s,
+            self.tail,
+            self.wav_format(),
+        ) else {
+            return;
+        };
+        if let Some(recording) = self.take_midi_recording() {
+            let midi_name = wav_name.with_extension("mid");
+            if let Err(err) = std::fs::write(&midi_name, recording.to_smf()) {
+                eprintln!("Couldn't write '{}': {}", midi_name.display(), err);
+            }
+        }
+    }
+
+    // Record the command once with each interpolation and filter
+    // setting, to labelled files in a directory, for comparing
+    // fidelity. Other settings are as for normal recording.
+    #[cfg(feature = "gui")]
+    pub fn record_qualities(&self, command: Command, stem: String) {
+        let base = self.with_command(command);
+        thread::spawn(move || {
+            let Some(dir) = rfd::FileDialog::new().pick_folder() else {
+                return;
+            };
+            for (label, interpolation, authentic) in QUALITIES {
+                let mut synth = base.clone();
+                for channel in synth.channels.iter_mut() {
+                    channel.sample_channel.interpolation = interpolation;
+                }
+                synth.audition_channel.sample_channel.interpolation = interpolation;
+                synth.authentic = authentic;
+                let path = dir.join(format!("{}_{}.wav", stem, label));
+                cpal_wrapper::export_wav(
+                    &mut synth,
+                    &path,
+                    base.stereo,
+                    base.max_rec_time_s,
+                    base.tail,
+                    base.wav_format(),
+                )
+                .unwrap_or_else(|err| panic!("{}", err));
+            }
+        });
+    }
+
+    #[cfg(any(feature = "audio", feature = "export"))]
+    fn play_instr(&mut self, instr: usize, effect: usize, automation: Option<&Automation>) {
+        let instr = self.bank.instruments[instr].clone();
+        self.audition_channel
+            .play_instr(&instr, &EFFECTS[effect], automation);
+    }
+
+    fn play_seq(&mut self, idx: usize) {
+        self.channels[0].play_seq(idx);
+    }
+
+    #[cfg(feature = "gui")]
+    fn set_bank(&mut self, bank: Arc<SoundBank>) {
+        for channel in self.channels.iter_mut() {
+            channel.set_bank(bank.clone());
+        }
+        self.audition_channel.set_bank(bank.clone());
+        self.bank = bank;
+    }
+
+    // The channels share the synth's frame clock (see tick() and
+    // fill_buffer), so the sequences all start on the same frame, and
+    // stay phase-locked, as on the real hardware's vertical blank.
+    #[cfg(any(feature = "audio", feature = "export"))]
+    fn play_sound(&mut self, sound: &Sound) {
+        for (channel, seq) in self.channels.iter_mut().zip(sound.sequences.iter()) {
+            // Not all banks have all the sequences the sounds use.
+            if *seq != 0 && *seq < self.bank.sequences.len() {
+                channel.play_seq(*seq);
+            }
+        }
+    }
+
+    // Advance every channel by one frame, as the game's vblank
+    // interrupt does. Hosts that drive the timing themselves should
+    // call this 50 times a second, and render() the audio in between.
+    pub fn tick(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.tick(&self.breakpoints, self.tempo_scale);
+        }
+        self.audition_channel
+            .tick(&self.breakpoints, self.tempo_scale);
+
+        // Exports are rendered on copies without a MIDI port, so notes
+        // are never both sent live and recorded.
+        if self.midi_out.is_some() || self.midi_recording.is_some() {
+            let enabled = self.midi_notes || self.midi_recording.is_some();
+            for (idx, channel) in self.channels.iter_mut().enumerate() {
+                let messages = channel.midi_note_messages(idx as u8, enabled);
+                for message in messages.into_iter().flatten() {
+                    if let Some(port) = &self.midi_out {
+                        port.send(&message);
+                    }
+                    if let Some(recording) = &mut self.midi_recording {
+                        recording.push(message);
+                    }
                 }
             }
+        }
+        if let Some(recording) = &mut self.midi_recording {
+            let frames_per_quarter = self.channels[0]
+                .frames_per_beat()
+                .map(|frames| frames * self.steps_per_beat);
+            recording.end_frame(frames_per_quarter);
+        }
+        if let (Some(port), Some(clock)) = (&self.midi_out, &mut self.midi_clock) {
+            let frames_per_quarter = self
+                .music_master
+                .and_then(|idx| self.channels[idx].frames_per_beat())
+                .map(|frames| (frames * self.steps_per_beat) as f32 / self.tempo_scale);
+            clock.frame(port, frames_per_quarter);
+        }
+    }
+
+    // Render interleaved left/right audio to fill the buffer, without
+    // advancing any sequences, for hosts that drive the timing
+    // themselves. The master volume and limiter aren't applied.
+    pub fn render(&mut self, sample_rate: u32, out: &mut [f32]) {
+        self.mix(sample_rate, out.len() / 2, 2);
+        out.copy_from_slice(&self.scratch.out);
+    }
+
+    // Mix everything into interleaved samples for the given number of
+    // outputs, in the scratch `out` buffer.
+    fn mix(&mut self, sample_rate: u32, frames: usize, num_outputs: usize) {
+        self.scratch.clear(frames, num_outputs);
+        if self.authentic {
+            self.mix_buses(sample_rate, num_outputs);
         } else {
-            // Mono: repeat the sample.
-            for channel in self.channels.iter_mut() {
-                channel.fill_buffer(sample_rate, &mut tmp);
-                for (dsts, src) in data.chunks_mut(num_channels as usize).zip(tmp.iter()) {
+            self.mix_routed(sample_rate, num_outputs);
+        }
+        self.filter_outputs(sample_rate, num_outputs);
+
+        // The audition channel isn't part of the game's sound, so it
+        // goes to every output, and skips any bus emulation.
+        let mixer_scale = 1.0 / self.channels.len() as f32;
+        let audition_audible = self.audible()[4];
+        let Scratch { out, tmp, .. } = &mut self.scratch;
+        self.audition_channel.render(sample_rate, 0, tmp);
+        self.capture.push(4, sample_rate, tmp);
+        if !audition_audible {
+            tmp.fill(0.0);
+        }
+        for (dsts, src) in out.chunks_mut(num_outputs).zip(tmp.iter()) {
+            for dst in dsts.iter_mut() {
+                *dst += mixer_scale * src;
+            }
+        }
+        #[cfg(feature = "gui")]
+        self.preview.render(sample_rate, out, num_outputs);
+        self.reference_tone.render(sample_rate, out, num_outputs);
+    }
+
+    // Which game channels are heard, and then the audition channel:
+    // if any are soloed, just those, otherwise all those not muted.
+    fn audible(&self) -> [bool; 5] {
+        let [a, b, c, d] = &self.channels;
+        let all = [a, b, c, d, &self.audition_channel];
+        let any_solo = all.iter().any(|channel| channel.solo);
+        all.map(|channel| {
+            if any_solo {
+                channel.solo
+            } else {
+                !channel.mute
+            }
+        })
+    }
+
+    // Each game channel's offset as a delay in samples. Earlier
+    // channels are done by delaying the rest.
+    fn channel_delays(&self, sample_rate: u32) -> [usize; 4] {
+        let earliest = self
+            .channels
+            .iter()
+            .map(|channel| channel.offset_ms)
+            .fold(0.0, f32::min);
+        self.channels.each_ref().map(|channel| {
+            ((channel.offset_ms - earliest) * sample_rate as f32 / 1000.0).round() as usize
+        })
+    }
+
+    // Mix the game channels according to the routing. Channels routed
+    // to outputs we don't have wrap around.
+    fn mix_routed(&mut self, sample_rate: u32, num_outputs: usize) {
+        let mixer_scale = 1.0 / self.channels.len() as f32;
+        let stereo = self.stereo && num_outputs > 1;
+        let audible = self.audible();
+        let delays = self.channel_delays(sample_rate);
+        let Scratch { out, tmp, .. } = &mut self.scratch;
+        for (ch_idx, channel) in self.channels.iter_mut().enumerate() {
+            // Silent channels still render, to keep their place.
+            channel.render(sample_rate, delays[ch_idx], tmp);
+            self.capture.push(ch_idx, sample_rate, tmp);
+            if !audible[ch_idx] {
+                continue;
+            }
+            let output = self.routing[ch_idx] % num_outputs;
+            let [left, right] = pan_gains(self.pans[ch_idx] * self.separation);
+            for (dsts, src) in out.chunks_mut(num_outputs).zip(tmp.iter()) {
+                if stereo && num_outputs == 2 {
+                    dsts[0] += left * mixer_scale * src;
+                    dsts[1] += right * mixer_scale * src;
+                } else if stereo {
+                    dsts[output] += mixer_scale * src;
+                } else {
+                    // Mono: repeat the sample.
                     for dst in dsts.iter_mut() {
-                        *dst = dst.add_amp((mixer_scale * src).to_sample::<T>().to_signed_sample());
+                        *dst += mixer_scale * src;
+                    }
+                }
+            }
+        }
+    }
+
+    // Mix the channels onto Paula's two buses, and filter each bus.
+    fn mix_buses(&mut self, sample_rate: u32, num_outputs: usize) {
+        let mixer_scale = 1.0 / self.channels.len() as f32;
+        let audible = self.audible();
+        let delays = self.channel_delays(sample_rate);
+        let Scratch { out, tmp, buses } = &mut self.scratch;
+        for (ch_idx, channel) in self.channels.iter_mut().enumerate() {
+            channel.render(sample_rate, delays[ch_idx], tmp);
+            self.capture.push(ch_idx, sample_rate, tmp);
+            if !audible[ch_idx] {
+                continue;
+            }
+            for (dst, src) in buses[PAULA_BUS[ch_idx]].iter_mut().zip(tmp.iter()) {
+                *dst += mixer_scale * src;
+            }
+        }
+        for (bus, filter) in buses.iter_mut().zip(self.buses.iter_mut()) {
+            filter.process(sample_rate, bus);
+        }
+
+        let stereo = self.stereo && num_outputs > 1;
+        // Narrowing blends some of each bus into the other side.
+        let near = (1.0 + self.separation) / 2.0;
+        let far = 1.0 - near;
+        for (idx, dsts) in out.chunks_mut(num_outputs).enumerate() {
+            if stereo {
+                dsts[0] = near * buses[0][idx] + far * buses[1][idx];
+                dsts[1] = near * buses[1][idx] + far * buses[0][idx];
+            } else {
+                // Mono: both buses everywhere.
+                dsts.fill(buses[0][idx] + buses[1][idx]);
+            }
+        }
+    }
+
+    // Apply the A500's filters to the game channels' mix. The
+    // authentic bus has already applied the fixed one.
+    fn filter_outputs(&mut self, sample_rate: u32, num_outputs: usize) {
+        let fixed = self.a500_filter && !self.authentic;
+        if !fixed && !self.led_filter {
+            return;
+        }
+        // Only allocates if prepare() wasn't told of this many.
+        if self.output_filters.len() < num_outputs {
+            self.output_filters
+                .resize(num_outputs, OutputFilter::default());
+        }
+        for (output, filter) in self.output_filters[..num_outputs].iter_mut().enumerate() {
+            let data = self.scratch.out[output..].iter_mut().step_by(num_outputs);
+            filter.process(sample_rate, data, fixed, self.led_filter);
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn mix_settings(&self) -> Mix {
+        let [a, b, c, d] = &self.channels;
+        Mix {
+            channels: [a, b, c, d, &self.audition_channel].map(SoundChannel::mix),
+            routing: self.routing,
+            pans: self.pans,
+            separation: self.separation,
+            stereo: self.stereo,
+            authentic: self.authentic,
+            a500_filter: self.a500_filter,
+            led_filter: self.led_filter,
+            limiter: self.limiter,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    fn set_mix_settings(&mut self, mix: &Mix) {
+        let (game, audition) = mix.channels.split_at(4);
+        for (channel, settings) in self.channels.iter_mut().zip(game.iter()) {
+            channel.set_mix(settings);
+        }
+        self.audition_channel.set_mix(&audition[0]);
+        self.routing = mix.routing;
+        self.pans = mix.pans;
+        self.separation = mix.separation;
+        self.stereo = mix.stereo;
+        self.authentic = mix.authentic;
+        self.a500_filter = mix.a500_filter;
+        self.led_filter = mix.led_filter;
+        self.limiter = mix.limiter;
+    }
+
+    // Everything the mixer controls, for the GUI to start from.
+    #[cfg(feature = "gui")]
+    pub fn settings(&self) -> Settings {
+        let [a, b, c, d] = &self.channels;
+        let all = [a, b, c, d, &self.audition_channel];
+        Settings {
+            mix: self.mix_settings(),
+            stop_modes: all.map(|channel| channel.sample_channel.stop_mode),
+            options: all.map(|channel| channel.options),
+            master_db: self.master_db,
+            tempo_scale: self.tempo_scale,
+            stretch_speed: self.stretch_speed,
+            transpose: self.transpose(),
+            midi_notes: self.midi_notes,
+            play_mode: self.play_mode,
+            max_rec_time_s: self.max_rec_time_s,
+            auto_length: self.auto_length,
+            tail: self.tail,
+            record_midi: self.record_midi,
+            measurement: self.measurement,
+            music_master: self.music_master,
+            steps_per_beat: self.steps_per_beat,
+            beats_per_bar: self.beats_per_bar,
+            midi_clock: self.midi_clock.is_some(),
+            breakpoints: self.breakpoints.clone(),
+        }
+    }
+
+    // Take on the mixer's settings. Doesn't allocate, so the audio
+    // thread can do it.
+    #[cfg(feature = "gui")]
+    fn set_settings(&mut self, settings: Settings) {
+        self.set_mix_settings(&settings.mix);
+        for idx in 0..=self.channels.len() {
+            settings.configure(idx, self.channel_mut(idx));
+        }
+        self.master_db = settings.master_db;
+        self.tempo_scale = settings.tempo_scale;
+        self.stretch_speed = settings.stretch_speed;
+        self.midi_notes = settings.midi_notes;
+        self.play_mode = settings.play_mode;
+        self.max_rec_time_s = settings.max_rec_time_s;
+        self.auto_length = settings.auto_length;
+        self.tail = settings.tail;
+        self.record_midi = settings.record_midi;
+        self.measurement = settings.measurement;
+        self.music_master = settings.music_master;
+        self.steps_per_beat = settings.steps_per_beat;
+        self.beats_per_bar = settings.beats_per_bar;
+        if settings.midi_clock != self.midi_clock.is_some() {
+            self.midi_clock = settings.midi_clock.then(midi::Clock::default);
+        }
+        self.breakpoints = settings.breakpoints;
+    }
+
+    // A game channel, 0-3, or 4 for the audition channel.
+    #[cfg(feature = "audio")]
+    fn channel_mut(&mut self, idx: usize) -> &mut SoundChannel {
+        if idx < self.channels.len() {
+            &mut self.channels[idx]
+        } else {
+            &mut self.audition_channel
+        }
+    }
+
+    #[cfg(any(feature = "audio", feature = "export"))]
+    fn stop_all(&mut self) {
+        for channel in self.channels.iter_mut() {
+            channel.stop_hard();
+        }
+        self.audition_channel.stop_hard();
+        #[cfg(feature = "gui")]
+        self.preview.stop();
+    }
+
+    // What the GUI shows of what's playing. Only clones Arcs, so
+    // doesn't allocate.
+    #[cfg(feature = "gui")]
+    fn status(&self) -> Status {
+        let [a, b, c, d] = &self.channels;
+        Status {
+            bank: self.bank.clone(),
+            channels: [a, b, c, d, &self.audition_channel].map(SoundChannel::status),
+            num_outputs: self.num_outputs,
+            sample_rate: self.sample_rate,
+            reduction_db: 0.0,
+            freeze_failures: self.freeze_failures,
+            midi_out: self.midi_out.clone(),
+            preview: self.preview.clone(),
+        }
+    }
+
+    // Start publishing what's playing after each buffer, to the
+    // returned copy, so that the GUI needn't lock the synth to show
+    // it.
+    #[cfg(feature = "gui")]
+    pub fn publish_status(&mut self) -> Arc<Mutex<Published>> {
+        let published = Arc::new(Mutex::new(Published {
+            status: self.status(),
+            // Room for every channel's ring to be emptied into.
+            trace: Vec::with_capacity((self.channels.len() + 1) * trace::RING_SIZE),
+            tuner: Vec::with_capacity(tuner::WINDOW),
+            tuner_rate: 0,
+        }));
+        self.published = Some(published.clone());
+        published
+    }
+
+    // Copy what's playing to where the GUI reads it. If the GUI's in
+    // the middle of reading the last lot, it gets the next lot
+    // instead, so the audio never waits for the GUI. Doesn't allocate.
+    #[cfg(feature = "gui")]
+    fn publish(&mut self) {
+        let Some(published) = &self.published else {
+            return;
+        };
+        let Ok(mut published) = published.try_lock() else {
+            return;
+        };
+        let reduction_db = published
+            .status
+            .reduction_db
+            .max(self.output_limiter.take_reduction_db());
+        published.status = self.status();
+        published.status.reduction_db = reduction_db;
+        let [a, b, c, d] = &mut self.channels;
+        for channel in [a, b, c, d, &mut self.audition_channel] {
+            // If the GUI isn't keeping up, the rings keep the latest.
+            let room = published.trace.capacity() - published.trace.len();
+            if room >= channel.trace.pending() {
+                channel.trace.drain_into(&mut published.trace);
+            }
+        }
+        if self.capture.channel().is_some() {
+            let Published {
+                tuner, tuner_rate, ..
+            } = &mut *published;
+            *tuner_rate = self.capture.snapshot(tuner);
+        }
+    }
+}
+
+// The GUI's side of the synth. It keeps its own copy of the settings,
+// which it draws and edits without touching the synth, and sends over
+// as a command whenever they change, along with commands for the
+// other controls. What's playing comes from the Status the audio
+// thread publishes.
+#[cfg(feature = "gui")]
+pub struct Mixer {
+    settings: Settings,
+    // A/B snapshots of the mix, and which one is live. Switching
+    // stores the live mix in the one being left.
+    mixes: [Option<Mix>; 2],
+    live_mix: usize,
+    // Which channels' mixer controls are linked.
+    pairing: Pairing,
+    // Bar to seek to, counting from 1.
+    seek_bar: usize,
+    // The breakpoint address being typed in.
+    new_breakpoint: String,
+    // Channels whose volume or pitch is being dragged.
+    dragging: [bool; 5],
+    // The channel the user's asked to freeze, if any.
+    freeze_requested: Option<usize>,
+}
+
+#[cfg(feature = "gui")]
+impl Mixer {
+    pub fn new(settings: Settings) -> Mixer {
+        Mixer {
+            settings,
+            mixes: [None, None],
+            live_mix: 0,
+            pairing: Pairing::Unlinked,
+            seek_bar: 1,
+            new_breakpoint: String::new(),
+            dragging: [false; 5],
+            freeze_requested: None,
+        }
+    }
+
+    // Are we sending sounds to a .wav file rather than the speakers?
+    pub fn is_recording(&self) -> bool {
+        self.settings.play_mode == PlayMode::WaveFile
+    }
+
+    pub fn max_passes(&self) -> usize {
+        self.settings.options[0].max_passes
+    }
+
+    pub fn take_freeze_request(&mut self) -> Option<usize> {
+        self.freeze_requested.take()
+    }
+
+    // A copy of a channel to freeze, made from the settings and the
+    // sequence last published, to render without touching the synth.
+    pub fn freezer(&self, status: &Status, idx: usize) -> Result<Freezer, String> {
+        if status.sample_rate == 0 {
+            return Err("There's no audio output to render for".to_string());
+        }
+        if self.settings.tempo_scale != 1.0 {
+            return Err("Channels can only be frozen at normal tempo".to_string());
+        }
+        let published = &status.channels[idx];
+        let mut source = SoundChannel::new(published.bank.clone(), idx);
+        self.settings.configure(idx, &mut source);
+        source.sequence = published.sequence.clone();
+        Ok(Freezer {
+            channel: idx,
+            source,
+            sample_rate: status.sample_rate,
+        })
+    }
+
+    // A synth of our own, with these settings, to record on, so that
+    // the live one's left alone, and gets no say in what's recorded.
+    fn synth(&self, bank: Arc<SoundBank>) -> Synth {
+        let mut synth = Synth::new(bank);
+        synth.set_settings(self.settings.clone());
+        synth
+    }
+
+    pub fn record_command(&self, bank: Arc<SoundBank>, command: Command) {
+        self.synth(bank).record_command(command);
+    }
+
+    pub fn record_qualities(&self, bank: Arc<SoundBank>, command: Command, stem: String) {
+        self.synth(bank).record_qualities(command, stem);
+    }
+
+    // Make the other snapshot live, keeping the live mix in the one
+    // being left. The first switch to an empty snapshot copies the
+    // live mix, to be changed from there.
+    fn switch_mix(&mut self, idx: usize) {
+        if idx == self.live_mix {
+            return;
+        }
+        let live = self.settings.mix;
+        self.mixes[self.live_mix] = Some(live);
+        self.settings.mix = self.mixes[idx].unwrap_or(live);
+        self.live_mix = idx;
+    }
+
+    fn mix_ui(&mut self, ui: &mut Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Mixer");
+            for (idx, name) in MIX_NAMES.iter().enumerate() {
+                if ui
+                    .selectable_label(self.live_mix == idx, *name)
+                    .on_hover_text("Switch mixer settings, keeping these for switching back")
+                    .clicked()
+                {
+                    self.switch_mix(idx);
+                }
+            }
+            let other = 1 - self.live_mix;
+            if ui.button(format!("Copy to {}", MIX_NAMES[other])).clicked() {
+                self.mixes[other] = Some(self.settings.mix);
+            }
+            if let Some(stored) = &self.mixes[other] {
+                if *stored == self.settings.mix {
+                    ui.label(format!("(same as {})", MIX_NAMES[other]));
+                }
+            }
+            ui.label("Link");
+            egui::ComboBox::from_id_source("Pairing")
+                .selected_text(self.pairing.label())
+                .show_ui(ui, |ui| {
+                    for option in Pairing::ALL {
+                        ui.selectable_value(&mut self.pairing, option, option.label());
+                    }
+                })
+                .response
+                .on_hover_text("Pairs of channels whose level, mute, solo and pan change together");
+        });
+    }
+
+    // Draw the controls, returning the commands for the changes made.
+    pub fn ui(&mut self, ui: &mut Ui, status: &Status) -> Vec<Command> {
+        let before = self.settings.clone();
+        let mut commands = Vec::new();
+        ui.horizontal(|ui| {
+            let settings = &mut self.settings;
+            let master = ui
+                .add(
+                    Slider::new(&mut settings.master_db, MIN_MASTER_DB..=MAX_MASTER_DB)
+                        .text("Master")
+                        .suffix(" dB"),
+                )
+                .on_hover_text("Overall volume, for listening. Exports aren't affected");
+            // Not while dragging, to save writing the file every
+            // frame.
+            if master.drag_released() || (master.changed() && !master.dragged()) {
+                save_master_volume(settings.master_db);
+            }
+            let mix = &mut settings.mix;
+            ui.checkbox(&mut mix.stereo, "Stereo");
+            let mut percent = mix.separation * 100.0;
+            let separation = ui
+                .add_enabled(
+                    mix.stereo,
+                    Slider::new(&mut percent, 0.0..=100.0)
+                        .text("Separation")
+                        .suffix("%"),
+                )
+                .on_hover_text("How far apart the two sides are, from mono to fully apart");
+            if separation.changed() {
+                mix.separation = percent / 100.0;
+            }
+            ui.checkbox(&mut mix.authentic, "Authentic bus");
+            ui.add_enabled(
+                !mix.authentic,
+                Checkbox::new(&mut mix.a500_filter, "A500 filter"),
+            )
+            .on_hover_text("The A500's fixed low-pass filter (the authentic bus includes it)");
+            ui.checkbox(&mut mix.led_filter, "LED filter")
+                .on_hover_text("The A500's switchable filter, on when the power light is bright");
+            ui.checkbox(&mut mix.limiter, "Limiter")
+                .on_hover_text("Turn down peaks in the output, rather than letting them clip");
+            if mix.limiter {
+                ui.add(
+                    ProgressBar::new(status.reduction_db / METER_RANGE_DB)
+                        .desired_width(60.0)
+                        .text(format!("-{:.1} dB", status.reduction_db)),
+                )
+                .on_hover_text("Gain reduction");
+            }
+            ui.add(
+                Slider::new(&mut settings.tempo_scale, MIN_TEMPO_SCALE..=MAX_TEMPO_SCALE)
+                    .logarithmic(true)
+                    .text("Tempo")
+                    .suffix("x"),
+            );
+            ui.add(
+                Slider::new(
+                    &mut settings.stretch_speed,
+                    stretch::MIN_SPEED..=stretch::MAX_SPEED,
+                )
+                .text("Stretch")
+                .suffix("x"),
+            )
+            .on_hover_text("Slow down or speed up what's heard, keeping its pitch");
+            if settings.stretch_speed != 1.0 && ui.small_button("1x").clicked() {
+                settings.stretch_speed = 1.0;
+            }
+            ui.add(
+                Slider::new(&mut settings.transpose, -MAX_TRANSPOSE..=MAX_TRANSPOSE)
+                    .text("Transpose")
+                    .suffix(" st"),
+            );
+            ui.add_enabled_ui(status.midi_out.is_some(), |ui| {
+                ui.checkbox(&mut settings.midi_notes, "Send notes as MIDI")
+            })
+            .inner
+            .on_disabled_hover_text("Start with --midi-out to send MIDI");
+            ui.label("Output to");
+            egui::ComboBox::from_id_source("PlayMode")
+                .selected_text(format!("{:?}", settings.play_mode))
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.play_mode, PlayMode::Speakers, "Speakers");
+                    ui.selectable_value(&mut settings.play_mode, PlayMode::WaveFile, "WaveFile");
+                });
+            if settings.play_mode == PlayMode::WaveFile {
+                ui.label("up to");
+                ui.add(DragValue::new(&mut settings.max_rec_time_s).speed(0.1));
+                ui.label("seconds");
+                ui.checkbox(&mut settings.auto_length, "or once through")
+                    .on_hover_text("Stop looping music after one pass, from its estimated length");
+                ui.label("ending");
+                let is_seconds = matches!(settings.tail, Tail::Seconds(_));
+                egui::ComboBox::from_id_source("Tail")
+                    .selected_text(match settings.tail {
+                        Tail::Cut => "cut",
+                        Tail::Silence => "until silence",
+                        Tail::Seconds(_) => "extra seconds",
+                    })
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut settings.tail, Tail::Cut, "cut");
+                        ui.selectable_value(&mut settings.tail, Tail::Silence, "until silence");
+                        if ui.selectable_label(is_seconds, "extra seconds").clicked() && !is_seconds
+                        {
+                            settings.tail = Tail::Seconds(1.0);
+                        }
+                    });
+                if let Tail::Seconds(s) = &mut settings.tail {
+                    ui.add(DragValue::new(s).speed(0.1).clamp_range(0.0..=60.0));
+                }
+                ui.checkbox(&mut settings.record_midi, "with MIDI")
+                    .on_hover_text("Also write the notes played to a .mid file");
+                ui.checkbox(&mut settings.measurement, "for measurement")
+                    .on_hover_text(
+                        "Record only what the machine itself would play - no filters, \
+                     interpolation, levels or panning - as 32-bit float, for comparing \
+                     with emulator captures",
+                    );
+            }
+        });
+        for idx in 0..4 {
+            let before = self.settings.linked(idx);
+            ui.horizontal(|ui| {
+                // Cheap alignment.
+                ui.label(RichText::new(format!("Ch {}", idx)).monospace());
+                let mix = &mut self.settings.mix;
+                ui.add_enabled_ui(mix.stereo && !mix.authentic, |ui| {
+                    if status.num_outputs == 2 {
+                        ui.add(
+                            DragValue::new(&mut mix.pans[idx])
+                                .clamp_range(-1.0..=1.0)
+                                .speed(0.01)
+                                .custom_formatter(|pan, _| pan_label(pan)),
+                        )
+                        .on_hover_text("Pan");
+                        return;
+                    }
+                    let output = &mut mix.routing[idx];
+                    egui::ComboBox::from_id_source(format!("Routing {}", idx))
+                        .selected_text(format!("Out {}", *output % status.num_outputs))
+                        .width(60.0)
+                        .show_ui(ui, |ui| {
+                            for i in 0..status.num_outputs {
+                                ui.selectable_value(output, i, format!("Out {}", i));
+                            }
+                        });
+                });
+                self.channel_ui(ui, idx, status, &mut commands);
+            });
+            status.channels[idx].debugger_ui(ui, idx, &mut commands);
+            let after = self.settings.linked(idx);
+            if let Some(partner) = self.pairing.partner(idx).filter(|_| after != before) {
+                self.settings.set_linked(partner, after);
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.label(RichText::new("Aud ").monospace());
+            self.channel_ui(ui, 4, status, &mut commands);
+        });
+        self.mix_ui(ui);
+        self.metronome_ui(ui, status, &mut commands);
+        self.settings.breakpoints.ui(ui, &mut self.new_breakpoint);
+        if let Some((playing, pos_s)) = status.preview.ui(ui) {
+            commands.push(Command::PreviewAt { playing, pos_s });
+        }
+        // The settings go first, for the other commands to follow.
+        if self.settings != before {
+            commands.insert(0, Command::Settings(Box::new(self.settings.clone())));
+        }
+        commands
+    }
+
+    // A game channel's controls, 0-3, or the audition channel's, 4.
+    fn channel_ui(
+        &mut self,
+        ui: &mut Ui,
+        idx: usize,
+        status: &Status,
+        commands: &mut Vec<Command>,
+    ) {
+        let channel = &status.channels[idx];
+        let stop_colour = if channel.active {
+            Color32::DARK_RED
+        } else {
+            Color32::DARK_GRAY
+        };
+        if ui.add(Button::new("Stop").fill(stop_colour)).clicked() {
+            commands.push(Command::Stop(idx));
+        }
+        let mix = &mut self.settings.mix.channels[idx];
+        ui.toggle_value(&mut mix.mute, "M").on_hover_text("Mute");
+        ui.toggle_value(&mut mix.solo, "S").on_hover_text("Solo");
+        ui.add(
+            DragValue::new(&mut mix.level)
+                .clamp_range(0.0..=2.0)
+                .speed(0.01)
+                .prefix("Level "),
+        )
+        .on_hover_text("The mixer's gain for the channel");
+        if channel.frozen {
+            if ui
+                .button("Thaw")
+                .on_hover_text("Go back to playing the channel live")
+                .clicked()
+            {
+                commands.push(Command::Thaw(idx));
+            }
+        } else if idx < 4
+            && ui
+                .add_enabled(channel.sequence.is_some(), Button::new("Freeze"))
+                .on_hover_text("Render the sequence ahead of time, and play that back, to save CPU")
+                .clicked()
+        {
+            self.freeze_requested = Some(idx);
+        }
+        let interpolation = &mut mix.interpolation;
+        egui::ComboBox::from_id_source(ui.id().with("Interpolation"))
+            .selected_text(format!("Interpolation: {:?}", interpolation))
+            .show_ui(ui, |ui| {
+                for option in Interpolation::ALL {
+                    ui.selectable_value(interpolation, option, format!("{:?}", option));
+                }
+            })
+            .response
+            .on_hover_text("Sinc is cleanest for high notes, but costs the most CPU");
+        let stop_mode = &mut self.settings.stop_modes[idx];
+        egui::ComboBox::from_id_source(ui.id().with("StopMode"))
+            .selected_text(format!("Stop: {:?}", stop_mode))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(stop_mode, StopMode::OneShot, "OneShot");
+                ui.selectable_value(stop_mode, StopMode::Fade, "Fade");
+            });
+        // These are the channel's own, which its sequence changes, so
+        // they're shown as published, and changes sent as commands.
+        let mut volume = channel.volume;
+        let mut pitch = channel.pitch;
+        ui.label("Volume");
+        let volume_drag = ui.add(DragValue::new(&mut volume));
+        ui.label("Pitch");
+        let pitch_drag = ui.add(DragValue::new(&mut pitch));
+        if volume_drag.changed() {
+            commands.push(Command::SetVolume(idx, volume));
+        }
+        if pitch_drag.changed() {
+            commands.push(Command::SetPitch(idx, pitch));
+        }
+        let dragging = volume_drag.dragged() || pitch_drag.dragged();
+        if dragging != self.dragging[idx] {
+            self.dragging[idx] = dragging;
+            commands.push(Command::Drag(idx, dragging));
+        }
+        ui.label("Offset");
+        ui.add(
+            DragValue::new(&mut mix.offset_ms)
+                .clamp_range(-MAX_OFFSET_MS..=MAX_OFFSET_MS)
+                .speed(0.01)
+                .max_decimals(2)
+                .suffix(" ms"),
+        )
+        .on_hover_text("Play this channel later (or earlier) than the others");
+
+        self.settings.options[idx].ui(ui);
+        mix.crossover_ui(ui);
+        if let Some(sequence) = &channel.sequence {
+            let mut beat = sequence.beats;
+            let range = 0..=(beat + 1).max(SEEK_RANGE_BEATS);
+            if ui.add(Slider::new(&mut beat, range).text("Beat")).changed() {
+                commands.push(Command::Seek(idx, beat));
+            }
+            // The sequence's transposition is in quarter semitones.
+            let transposition =
+                sequence.transposition as f32 / 4.0 + self.settings.transpose as f32;
+            ui.label(format!("Transposition {:+} st", transposition))
+                .on_hover_text("The sequence's own, plus the global transposition");
+            let stack = &sequence.loop_stack;
+            ui.label(format!(
+                "Stack {}/{} (peak {})",
+                stack.len, self.settings.options[idx].stack_depth, stack.peak
+            ));
+        } else if let Some(addr) = channel.stack_overflow {
+            ui.colored_label(Color32::RED, format!("Stack overflow at 0x{:06x}", addr));
+        }
+        let mut stepping = channel.stepping;
+        if ui.checkbox(&mut stepping, "Step").changed() {
+            commands.push(Command::SetStepping(idx, stepping));
+        }
+    }
+
+    // Bar and beat of the music master's sequence, counting from 1,
+    // with a light on each beat.
+    fn metronome_ui(&mut self, ui: &mut Ui, status: &Status, commands: &mut Vec<Command>) {
+        ui.horizontal(|ui| {
+            let settings = &mut self.settings;
+            ui.label("Bar counter follows");
+            egui::ComboBox::from_id_source("MusicMaster")
+                .selected_text(match settings.music_master {
+                    Some(idx) => format!("Ch {}", idx),
+                    None => "nothing".to_string(),
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut settings.music_master, None, "nothing");
+                    for idx in 0..4 {
+                        ui.selectable_value(
+                            &mut settings.music_master,
+                            Some(idx),
+                            format!("Ch {}", idx),
+                        );
+                    }
+                });
+            let Some(master) = settings.music_master else {
+                return;
+            };
+            ui.add(DragValue::new(&mut settings.steps_per_beat).clamp_range(1..=64));
+            ui.label("steps per beat,");
+            ui.add(DragValue::new(&mut settings.beats_per_bar).clamp_range(1..=16));
+            ui.label("beats per bar");
+            let checkbox = ui
+                .add_enabled_ui(status.midi_out.is_some(), |ui| {
+                    ui.checkbox(&mut settings.midi_clock, "Send MIDI clock")
+                })
+                .inner
+                .on_disabled_hover_text("Start with --midi-out to send MIDI");
+            if let Some(port) = &status.midi_out {
+                checkbox.on_hover_text(format!("To {}", port.name));
+            }
+            let beats = status.channels[master]
+                .sequence
+                .as_ref()
+                .map(|sequence| sequence.beats);
+            match beats {
+                Some(steps) => {
+                    let beat = steps / settings.steps_per_beat;
+                    let on_beat = steps % settings.steps_per_beat == 0;
+                    ui.colored_label(
+                        if on_beat {
+                            Color32::LIGHT_GREEN
+                        } else {
+                            Color32::DARK_GRAY
+                        },
+                        "\u{25cf}",
+                    );
+                    ui.label(
+                        RichText::new(format!(
+                            "Bar {} beat {}",
+                            beat / settings.beats_per_bar + 1,
+                            beat % settings.beats_per_bar + 1
+                        ))
+                        .monospace(),
+                    );
+                    // Seek every channel, so the parts stay together.
+                    if ui.button("Seek all to bar").clicked() {
+                        let beat =
+                            (self.seek_bar - 1) * settings.beats_per_bar * settings.steps_per_beat;
+                        for idx in 0..4 {
+                            commands.push(Command::Seek(idx, beat));
+                        }
                     }
+                    ui.add(DragValue::new(&mut self.seek_bar).clamp_range(1..=9999));
                 }
+                None => {
+                    ui.label("Not playing");
+                }
+            }
+        });
+    }
+}
+
+#[cfg(any(feature = "audio", feature = "export"))]
+impl Synth {
+    // Mix up to the given number of frames into the scratch `out`
+    // buffer, stopping at the next driver frame, and return how many.
+    fn mix_frames(&mut self, sample_rate: u32, num_outputs: usize, max_frames: usize) -> usize {
+        // Not going to try to do sub-sample accuracy.
+        let samples_per_frame = (sample_rate as f32 / self.bank.quirks.frame_rate) as usize;
+        if self.samples_remaining == 0 {
+            self.tick();
+            self.samples_remaining = samples_per_frame;
+        }
+        let frames = self.samples_remaining.min(max_frames);
+        self.mix(sample_rate, frames, num_outputs);
+        self.samples_remaining -= frames;
+        frames
+    }
+}
+
+impl cpal_wrapper::SoundSource for Synth {
+    type Command = Command;
+
+    #[cfg(feature = "audio")]
+    fn prepare(&mut self, num_channels: u16, sample_rate: u32) {
+        // fill_buffer mixes at most a frame at a time.
+        let frames = (sample_rate as f32 / quirks::MIN_FRAME_RATE).ceil() as usize;
+        self.scratch.reserve(frames, num_channels as usize);
+        // Frozen renders are made at the output's rate.
+        #[cfg(feature = "gui")]
+        {
+            self.sample_rate = sample_rate;
+        }
+        self.stretcher.prepare(sample_rate, num_channels as usize);
+        self.output_filters
+            .resize(num_channels as usize, OutputFilter::default());
+        for channel in self.channels.iter_mut() {
+            channel.delay.prepare(sample_rate);
+        }
+        // Rings for the GUI, filled from here on.
+        self.capture.prepare();
+        for channel in self.channels.iter_mut() {
+            channel.trace.prepare();
+        }
+        self.audition_channel.trace.prepare();
+    }
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            // Instruments use the audition channel, and Sequences
+            // channel 0.
+            #[cfg(any(feature = "audio", feature = "export"))]
+            Command::Instrument {
+                instr,
+                effect,
+                automation,
+                pitch,
+            } => {
+                self.audition_channel.sample_channel.pitch = pitch;
+                self.play_instr(instr, effect, automation.as_ref())
+            }
+            Command::Sequence(idx) => self.play_seq(idx),
+            #[cfg(feature = "gui")]
+            Command::SequenceFrom { idx, offset, infer } => {
+                self.channels[0].play_seq_from(idx, offset, infer)
+            }
+            #[cfg(any(feature = "audio", feature = "export"))]
+            Command::Sound(idx) => self.play_sound(&SOUNDS[idx]),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            Command::Music(idx) => {
+                self.stop_all();
+                self.play_sound(&SOUNDS[idx]);
+            }
+            #[cfg(feature = "audio")]
+            Command::Stop(channel) => self.channel_mut(channel).stop(),
+            #[cfg(any(feature = "audio", feature = "export"))]
+            Command::StopAll => self.stop_all(),
+            #[cfg(feature = "gui")]
+            Command::Song(sequences) => {
+                self.stop_all();
+                self.play_sound(&Sound { sequences });
+            }
+            #[cfg(feature = "gui")]
+            Command::SetBank(bank) => self.set_bank(bank),
+            #[cfg(feature = "gui")]
+            Command::Preview(clip) => self.preview.set_clip(clip),
+            #[cfg(feature = "gui")]
+            Command::PreviewAt { playing, pos_s } => self.preview.seek(playing, pos_s),
+            #[cfg(feature = "gui")]
+            Command::Settings(settings) => self.set_settings(*settings),
+            #[cfg(feature = "gui")]
+            Command::Thaw(idx) => self.channel_mut(idx).frozen = None,
+            #[cfg(feature = "gui")]
+            Command::Freeze(idx, frozen) => {
+                if !self.freeze(idx, frozen) {
+                    self.freeze_failures += 1;
+                }
+            }
+            #[cfg(feature = "gui")]
+            Command::Seek(idx, beat) => self.channel_mut(idx).seek(beat),
+            #[cfg(feature = "gui")]
+            Command::SetStepping(idx, stepping) => self.channel_mut(idx).set_stepping(stepping),
+            #[cfg(feature = "gui")]
+            Command::Step(idx) => self.channel_mut(idx).step(),
+            #[cfg(feature = "gui")]
+            Command::SetVolume(idx, volume) => self.channel_mut(idx).sample_channel.volume = volume,
+            #[cfg(feature = "gui")]
+            Command::SetPitch(idx, pitch) => self.channel_mut(idx).sample_channel.pitch = pitch,
+            #[cfg(feature = "gui")]
+            Command::Drag(idx, dragging) => self.channel_mut(idx).dragging = dragging,
+            #[cfg(feature = "gui")]
+            Command::SetTracing(enabled) => self.set_tracing(enabled),
+            #[cfg(feature = "gui")]
+            Command::ReferenceTone(tone) => self.reference_tone.adjust(&tone),
+            #[cfg(feature = "gui")]
+            Command::TunerChannel(channel) => self.capture.set_channel(channel),
+        }
+    }
+
+    #[cfg(any(feature = "audio", feature = "export"))]
+    fn fill_buffer<T: OutputSample>(
+        &mut self,
+        num_channels: u16,
+        sample_rate: u32,
+        data: &mut [T],
+    ) {
+        let num_channels = num_channels as usize;
+        self.num_outputs = num_channels;
+
+        let stretching =
+            self.stretch_speed != 1.0 && self.stretcher.is_prepared(sample_rate, num_channels);
+        if stretching && !self.stretching {
+            self.stretcher.reset();
+        }
+        self.stretching = stretching;
+
+        let gain = 10f32.powf(self.master_db / 20.0);
+        let mut data = data;
+        while data.len() >= num_channels {
+            if stretching {
+                // Feed the stretcher as much as it needs, then take
+                // what it makes.
+                let wanted = self.stretcher.input_wanted();
+                if wanted > 0 {
+                    self.mix_frames(sample_rate, num_channels, wanted);
+                    self.stretcher.push(&self.scratch.out);
+                    continue;
+                }
+                let stretched = self
+                    .stretcher
+                    .pull(self.stretch_speed, data.len() / num_channels);
+                let limiter = self.limiter.then_some(&mut self.output_limiter);
+                convert_output(sample_rate, num_channels, gain, limiter, stretched, data);
+                data = &mut data[stretched.len()..];
+            } else {
+                let frames = self.mix_frames(sample_rate, num_channels, data.len() / num_channels);
+                let limiter = self.limiter.then_some(&mut self.output_limiter);
+                convert_output(
+                    sample_rate,
+                    num_channels,
+                    gain,
+                    limiter,
+                    &self.scratch.out,
+                    data,
+                );
+                data = &mut data[frames * num_channels..];
             }
         }
+        #[cfg(feature = "gui")]
+        self.publish();
     }
 
+    #[cfg(feature = "export")]
     fn stream_done(&self) -> bool {
-	self.channels.iter().any(|ch| ch.is_active())
+        self.is_playing()
     }
 }
@@ -0,0 +1,274 @@
+//
+// Speedball 2 Sound player
+//
+// stats.rs: Usage statistics, kept for the user's own interest: how
+// often each sound, sequence and instrument has been played, and how
+// long has been spent listening, per bank. Handy for spotting the
+// sequences never auditioned.
+//
+// Nothing is collected unless turned on, and nothing leaves the
+// machine: the figures are kept in a JSON file in the user's config
+// directory, so they carry over between sessions.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use egui::{Grid, Ui};
+
+use crate::json::{self, Value};
+use crate::sound_data::SOUNDS;
+use crate::sound_player::Command;
+
+// Listening time is saved this often, as well as whenever something
+// is played.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+// Entries shown in each "most played" list.
+const TOP_COUNT: usize = 10;
+
+// Play counts, by index.
+type Counts = BTreeMap<usize, usize>;
+
+#[derive(Default)]
+struct BankStats {
+    sounds: Counts,
+    sequences: Counts,
+    instruments: Counts,
+    listened_s: f64,
+}
+
+pub struct Stats {
+    enabled: bool,
+    // By bank name.
+    banks: BTreeMap<String, BankStats>,
+    // When listening time was last counted, if something was playing.
+    last_playing: Option<Instant>,
+    last_saved: Instant,
+    dirty: bool,
+    // Why loading or saving last failed, if it did.
+    error: Option<String>,
+}
+
+fn stats_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("speedball2-sound-player").join("stats.json"))
+}
+
+fn counts_to_json(counts: &Counts) -> Value {
+    Value::Object(
+        counts
+            .iter()
+            .map(|(idx, count)| (format!("0x{:02x}", idx), Value::from(*count)))
+            .collect(),
+    )
+}
+
+fn counts_from_json(value: Option<&Value>) -> Counts {
+    let Some(Value::Object(fields)) = value else {
+        return Counts::new();
+    };
+    fields
+        .iter()
+        .filter_map(|(idx, count)| Some((Value::from(idx.as_str()).as_usize()?, count.as_usize()?)))
+        .collect()
+}
+
+impl BankStats {
+    fn to_json(&self) -> Value {
+        json::object(vec![
+            ("listened_s", Value::from(self.listened_s)),
+            ("sounds", counts_to_json(&self.sounds)),
+            ("sequences", counts_to_json(&self.sequences)),
+            ("instruments", counts_to_json(&self.instruments)),
+        ])
+    }
+
+    fn from_json(value: &Value) -> BankStats {
+        BankStats {
+            sounds: counts_from_json(value.get("sounds")),
+            sequences: counts_from_json(value.get("sequences")),
+            instruments: counts_from_json(value.get("instruments")),
+            listened_s: match value.get("listened_s") {
+                Some(Value::Number(n)) => *n,
+                _ => 0.0,
+            },
+        }
+    }
+}
+
+// The most played entries, most first.
+fn top(counts: &Counts) -> Vec<(usize, usize)> {
+    let mut top = counts
+        .iter()
+        .map(|(idx, count)| (*idx, *count))
+        .collect::<Vec<_>>();
+    top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    top.truncate(TOP_COUNT);
+    top
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds as u64;
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+impl Stats {
+    // Loads the figures from previous sessions. If there aren't any,
+    // collection is off until turned on.
+    pub fn load() -> Stats {
+        let mut stats = Stats {
+            enabled: false,
+            banks: BTreeMap::new(),
+            last_playing: None,
+            last_saved: Instant::now(),
+            dirty: false,
+            error: None,
+        };
+        let Some(path) = stats_path() else {
+            return stats;
+        };
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            return stats;
+        };
+        match json::parse(&text) {
+            Ok(doc) => {
+                stats.enabled = doc.get("enabled") == Some(&Value::Bool(true));
+                if let Some(Value::Object(banks)) = doc.get("banks") {
+                    stats.banks = banks
+                        .iter()
+                        .map(|(name, bank)| (name.clone(), BankStats::from_json(bank)))
+                        .collect();
+                }
+            }
+            Err(err) => stats.error = Some(format!("{}: {}", path.display(), err)),
+        }
+        stats
+    }
+
+    fn save(&mut self) {
+        self.dirty = false;
+        self.last_saved = Instant::now();
+        let Some(path) = stats_path() else {
+            return;
+        };
+        let banks = self
+            .banks
+            .iter()
+            .map(|(name, bank)| (name.clone(), bank.to_json()))
+            .collect();
+        let doc = json::object(vec![
+            ("enabled", Value::from(self.enabled)),
+            ("banks", Value::Object(banks)),
+        ]);
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| std::fs::write(&path, format!("{}\n", doc)));
+        self.error = result
+            .err()
+            .map(|err| format!("Couldn't save {}: {}", path.display(), err));
+    }
+
+    // Count a command sent to the synth. Sounds count as plays of
+    // the sequences they start, too.
+    pub fn observe(&mut self, bank: &str, command: &Command) {
+        if !self.enabled {
+            return;
+        }
+        let stats = self.banks.entry(bank.to_string()).or_default();
+        match command {
+            Command::Sound(idx) | Command::Music(idx) => {
+                *stats.sounds.entry(*idx).or_default() += 1;
+                for &seq in SOUNDS[*idx].sequences.iter().filter(|&&seq| seq != 0) {
+                    *stats.sequences.entry(seq).or_default() += 1;
+                }
+            }
+            Command::Sequence(idx) | Command::SequenceFrom { idx, .. } => {
+                *stats.sequences.entry(*idx).or_default() += 1
+            }
+            Command::Instrument { instr, .. } => *stats.instruments.entry(*instr).or_default() += 1,
+            _ => return,
+        }
+        self.save();
+    }
+
+    // Called every UI update, with whether the synth is making any
+    // sound, to count listening time.
+    pub fn listen(&mut self, bank: &str, playing: bool) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if let Some(last) = self.last_playing {
+            let stats = self.banks.entry(bank.to_string()).or_default();
+            stats.listened_s += now.duration_since(last).as_secs_f64();
+            self.dirty = true;
+        }
+        self.last_playing = playing.then_some(now);
+        if self.dirty && now.duration_since(self.last_saved) >= SAVE_INTERVAL {
+            self.save();
+        }
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, bank: &str, num_sequences: usize) {
+        if ui
+            .checkbox(&mut self.enabled, "Collect usage statistics")
+            .on_hover_text("Kept on this computer only, in the config directory")
+            .changed()
+        {
+            self.last_playing = None;
+            self.save();
+        }
+        if let Some(err) = &self.error {
+            ui.colored_label(egui::Color32::RED, err);
+        }
+        let Some(stats) = self.banks.get(bank) else {
+            ui.label(format!("Nothing played from the {} bank yet.", bank));
+            return;
+        };
+        ui.label(format!(
+            "Time listened to the {} bank: {}",
+            bank,
+            format_duration(stats.listened_s)
+        ));
+        ui.horizontal_top(|ui| {
+            for (title, counts) in [
+                ("Sounds", &stats.sounds),
+                ("Sequences", &stats.sequences),
+                ("Instruments", &stats.instruments),
+            ] {
+                ui.vertical(|ui| {
+                    ui.strong(format!("Most played {}", title.to_lowercase()));
+                    Grid::new(title).striped(true).show(ui, |ui| {
+                        for (idx, count) in top(counts) {
+                            ui.monospace(format!("{:02x}", idx));
+                            ui.label(count.to_string());
+                            ui.end_row();
+                        }
+                    });
+                });
+            }
+        });
+        // Sequence 0 is the empty one.
+        let unplayed = (1..num_sequences)
+            .filter(|idx| !stats.sequences.contains_key(idx))
+            .map(|idx| format!("{:02x}", idx))
+            .collect::<Vec<_>>();
+        ui.label(format!(
+            "Sequences never played ({}): {}",
+            unplayed.len(),
+            unplayed.join(" ")
+        ));
+        if ui.button("Reset this bank's statistics").clicked() {
+            self.banks.remove(bank);
+            self.save();
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//
+// Speedball 2 Sound player
+//
+// stretch.rs: Slowing down (or speeding up) the synth's output without
+// changing its pitch, for transcribing fast passages. Unlike the tempo
+// control, which runs the sequences at a different rate, this
+// stretches the rendered audio itself, effects and all, so it sounds
+// like a slowed-down recording.
+//
+// It uses WSOLA (waveform-similarity overlap-add): the output is built
+// from overlapping windowed segments of the input, taken further
+// apart or closer together than they're laid down, each nudged to
+// where it best lines up with the previous segment's continuation,
+// so that the waveform joins smoothly. The synth's audio is pulled in
+// as needed, into buffers sized up front so the audio thread doesn't
+// allocate.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+pub const MIN_SPEED: f32 = 0.5;
+#[cfg(feature = "audio")]
+pub const MAX_SPEED: f32 = 1.5;
+
+// Segment length, search range either side of the ideal position, and
+// the length of the stretch compared to find the best fit.
+#[cfg(feature = "audio")]
+const SEGMENT_S: f32 = 0.04;
+#[cfg(feature = "audio")]
+const TOLERANCE_S: f32 = 0.01;
+#[cfg(feature = "audio")]
+const COMPARE_S: f32 = 0.01;
+
+#[derive(Clone, Default)]
+pub struct Stretcher {
+    // What the buffers were sized for, if anything.
+    sample_rate: u32,
+    channels: usize,
+    segment: usize,
+    hop: usize,
+    tolerance: usize,
+    compare: usize,
+    window: Vec<f32>,
+    // Interleaved input, starting at input frame `input_start`.
+    input: Vec<f32>,
+    input_start: usize,
+    // Where the next segment should ideally come from, in input
+    // frames, and where the last one did.
+    target: f64,
+    last: Option<usize>,
+    // Segments being overlap-added, and finished output waiting to be
+    // taken.
+    accum: Vec<f32>,
+    output: Vec<f32>,
+    output_pos: usize,
+}
+
+impl Stretcher {
+    // Allocate for the given output. Not called on the audio thread.
+    #[cfg(feature = "audio")]
+    pub fn prepare(&mut self, sample_rate: u32, channels: usize) {
+        let frames = |s: f32| (s * sample_rate as f32) as usize;
+        // An even segment, so that windows half a segment apart sum
+        // to one.
+        let segment = frames(SEGMENT_S) & !1;
+        let hop = segment / 2;
+        let tolerance = frames(TOLERANCE_S);
+        let compare = frames(COMPARE_S);
+        // Enough for a segment with the search either side, however
+        // far the next one's target is, plus a batch of new input.
+        let input_frames = segment + 2 * tolerance + (2.0 * MAX_SPEED * hop as f32) as usize;
+        *self = Stretcher {
+            sample_rate,
+            channels,
+            segment,
+            hop,
+            tolerance,
+            compare,
+            window: (0..segment)
+                .map(|idx| {
+                    let x = idx as f32 / segment as f32;
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * x).cos()
+                })
+                .collect(),
+            input: Vec::with_capacity(input_frames * channels),
+            input_start: 0,
+            target: 0.0,
+            last: None,
+            accum: vec![0.0; segment * channels],
+            output: Vec::with_capacity(hop * channels),
+            output_pos: 0,
+        };
+    }
+
+    pub fn is_prepared(&self, sample_rate: u32, channels: usize) -> bool {
+        self.sample_rate == sample_rate && self.channels == channels && self.segment > 0
+    }
+
+    // Start afresh, e.g. when switched on.
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.input_start = 0;
+        self.target = 0.0;
+        self.last = None;
+        self.accum.fill(0.0);
+        self.output.clear();
+        self.output_pos = 0;
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels.max(1)
+    }
+
+    // Input frames still needed before the next segment can be made.
+    pub fn input_wanted(&self) -> usize {
+        let target = self.target.round() as usize;
+        let mut end = target + self.tolerance + self.segment;
+        if let Some(last) = self.last {
+            end = end.max(last + self.hop + self.compare);
+        }
+        end.saturating_sub(self.input_end())
+    }
+
+    // Add interleaved input. Anything beyond the buffer's room is
+    // dropped, so ask for no more than input_wanted().
+    pub fn push(&mut self, data: &[f32]) {
+        let room = self.input.capacity() - self.input.len();
+        self.input.extend_from_slice(&data[..data.len().min(room)]);
+    }
+
+    // Mono sample at an input frame, for comparing segments.
+    fn mono(&self, frame: usize) -> f32 {
+        let idx = (frame - self.input_start) * self.channels;
+        self.input[idx..idx + self.channels].iter().sum()
+    }
+
+    // How well the input at `start` matches the continuation of the
+    // last segment, normalised so loud and quiet candidates compare
+    // fairly.
+    fn similarity(&self, natural: usize, start: usize) -> f32 {
+        let mut cross = 0.0;
+        let mut energy = 0.0;
+        for idx in 0..self.compare {
+            let candidate = self.mono(start + idx);
+            cross += self.mono(natural + idx) * candidate;
+            energy += candidate * candidate;
+        }
+        if energy > 0.0 {
+            cross / energy.sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    // Where to take the next segment from: near the target, where it
+    // best carries on from the last one. Searches coarsely, then
+    // refines.
+    fn best_start(&self) -> usize {
+        let target = self.target.round() as usize;
+        let Some(last) = self.last else {
+            return target;
+        };
+        let natural = last + self.hop;
+        let lowest = target.saturating_sub(self.tolerance).max(self.input_start);
+        let highest = target + self.tolerance;
+        let best_of = |candidates: &mut dyn Iterator<Item = usize>| {
+            candidates
+                .map(|start| (start, self.similarity(natural, start)))
+                .fold((target, f32::MIN), |best, next| {
+                    if next.1 > best.1 {
+                        next
+                    } else {
+                        best
+                    }
+                })
+                .0
+        };
+        let coarse = best_of(&mut (lowest..=highest).step_by(4));
+        let fine_low = coarse.saturating_sub(3).max(lowest);
+        let fine_high = (coarse + 3).min(highest);
+        best_of(&mut (fine_low..=fine_high))
+    }
+
+    // Overlap-add the next segment, making another hop of output.
+    fn step(&mut self, speed: f32) {
+        let start = self.best_start();
+        let channels = self.channels;
+        let offset = (start - self.input_start) * channels;
+        for (idx, (dst, src)) in self
+            .accum
+            .iter_mut()
+            .zip(self.input[offset..].iter())
+            .enumerate()
+        {
+            *dst += self.window[idx / channels] * src;
+        }
+        let hop = self.hop * channels;
+        self.output.clear();
+        self.output.extend_from_slice(&self.accum[..hop]);
+        self.output_pos = 0;
+        self.accum.copy_within(hop.., 0);
+        let len = self.accum.len();
+        self.accum[len - hop..].fill(0.0);
+
+        self.last = Some(start);
+        self.target += self.hop as f64 * speed as f64;
+        // Drop input nothing will look at again.
+        let keep_from = (self.target.round() as usize)
+            .saturating_sub(self.tolerance)
+            .min(start + self.hop)
+            .max(self.input_start);
+        let drop = (keep_from - self.input_start) * channels;
+        self.input.drain(..drop);
+        self.input_start = keep_from;
+    }
+
+    // Finished output, interleaved, making more if the input's there.
+    // Returns an empty slice when more input is wanted.
+    pub fn pull(&mut self, speed: f32, max_frames: usize) -> &[f32] {
+        if self.output_pos >= self.output.len() {
+            if self.input_wanted() > 0 {
+                return &[];
+            }
+            self.step(speed);
+        }
+        let len = (max_frames * self.channels).min(self.output.len() - self.output_pos);
+        let start = self.output_pos;
+        self.output_pos += len;
+        &self.output[start..start + len]
+    }
+}
@@ -0,0 +1,177 @@
+//
+// Speedball 2 Sound player
+//
+// timeline.rs: A timeline of what the four game channels are about to
+// play, one lane each, scrolling past a playhead. The notes come from
+// walking each channel's sequence (see duration.rs), and the playhead
+// from the frames the synth has run it for, so looping sequences are
+// drawn going round again, as they'll be played.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use egui::{Align2, Color32, FontId, Rect, Sense, Stroke, Ui};
+
+use crate::annotations::Annotations;
+use crate::disasm;
+use crate::duration::{self, Estimate, Note};
+use crate::piano_roll::instrument_colour;
+use crate::sound_player::{SoundBank, Status};
+
+// Seconds shown before and after the playhead.
+const PAST_S: f32 = 1.0;
+const AHEAD_S: f32 = 7.0;
+const LANE_HEIGHT: f32 = 28.0;
+const LABEL_WIDTH: f32 = 160.0;
+// Notes narrower than this, in points, go unlabelled.
+const MIN_LABEL_WIDTH: f32 = 24.0;
+
+type Walked = (Vec<Note>, Option<Estimate>);
+
+// The notes of a pass through the sequence that overlap the frames
+// from..to, with the frames they start at, following the sequence
+// round its loop as often as needed.
+fn visible(walked: &Walked, from: usize, to: usize) -> Vec<(usize, &Note)> {
+    let (notes, estimate) = walked;
+    let overlaps = |start: usize, note: &Note| start < to && start + note.frames > from;
+    let mut shown = notes
+        .iter()
+        .filter(|note| overlaps(note.frame, note))
+        .map(|note| (note.frame, note))
+        .collect::<Vec<_>>();
+    let Some(estimate) = estimate.filter(|e| e.loops()) else {
+        return shown;
+    };
+    let period = estimate.frames.saturating_sub(estimate.loop_frame);
+    if period == 0 {
+        return shown;
+    }
+    // Repeats only go round the part after the loop point.
+    let first = (from.saturating_sub(estimate.frames) / period).max(1);
+    let last = to.saturating_sub(estimate.loop_frame) / period + 1;
+    for pass in first..=last {
+        let offset = pass * period;
+        shown.extend(
+            notes
+                .iter()
+                .filter(|note| note.frame >= estimate.loop_frame)
+                .map(|note| (note.frame + offset, note))
+                .filter(|(start, note)| overlaps(*start, note)),
+        );
+    }
+    shown
+}
+
+pub struct Timeline {
+    // The bank the cache is for.
+    bank: Option<Arc<SoundBank>>,
+    walked: HashMap<usize, Walked>,
+}
+
+impl Timeline {
+    pub fn new() -> Timeline {
+        Timeline {
+            bank: None,
+            walked: HashMap::new(),
+        }
+    }
+
+    // Walking a sequence is too slow to do every frame, so keep the
+    // results until the bank changes.
+    fn walk(&mut self, bank: &Arc<SoundBank>, idx: usize) -> &Walked {
+        if !self.bank.as_ref().is_some_and(|b| Arc::ptr_eq(b, bank)) {
+            self.bank = Some(bank.clone());
+            self.walked.clear();
+        }
+        self.walked
+            .entry(idx)
+            .or_insert_with(|| duration::notes(bank, idx))
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, status: &Status, annotations: &Annotations) {
+        let positions = status.positions();
+        let bank = status.bank().clone();
+        if positions.iter().all(Option::is_none) {
+            ui.label("Nothing playing");
+            return;
+        }
+        // Keep the playhead moving smoothly.
+        ui.ctx().request_repaint();
+
+        let frame_rate = bank.quirks.frame_rate;
+        let past = (PAST_S * frame_rate) as usize;
+        let ahead = (AHEAD_S * frame_rate) as usize;
+        let width = ui.available_width().max(LABEL_WIDTH * 2.0);
+        let (rect, _) = ui.allocate_exact_size(
+            egui::vec2(width, LANE_HEIGHT * positions.len() as f32),
+            Sense::hover(),
+        );
+        let painter = ui.painter_at(rect);
+        let lanes_left = rect.left() + LABEL_WIDTH;
+        let points_per_frame = (rect.right() - lanes_left) / (past + ahead) as f32;
+        let playhead_x = lanes_left + past as f32 * points_per_frame;
+        let font = FontId::proportional(12.0);
+
+        for (channel, position) in positions.iter().enumerate() {
+            let top = rect.top() + channel as f32 * LANE_HEIGHT;
+            let lane = Rect::from_min_max(
+                egui::pos2(lanes_left, top + 1.0),
+                egui::pos2(rect.right(), top + LANE_HEIGHT - 1.0),
+            );
+            painter.rect_filled(lane, 2.0, Color32::from_gray(30));
+            let Some((seq, now)) = *position else {
+                painter.text(
+                    egui::pos2(rect.left(), lane.center().y),
+                    Align2::LEFT_CENTER,
+                    format!("Ch {}: idle", channel),
+                    font.clone(),
+                    Color32::GRAY,
+                );
+                continue;
+            };
+            painter.text(
+                egui::pos2(rect.left(), lane.center().y),
+                Align2::LEFT_CENTER,
+                format!("Ch {}: {}", channel, annotations.sequence_label(seq)),
+                font.clone(),
+                ui.visuals().text_color(),
+            );
+            // The window may start before the sequence did.
+            let from = now.saturating_sub(past);
+            let x = |frame: usize| playhead_x + (frame as f32 - now as f32) * points_per_frame;
+            for (start, note) in visible(self.walk(&bank, seq), from, now + ahead) {
+                let left = x(start).max(lanes_left);
+                let right = x(start + note.frames).min(rect.right());
+                let bar = Rect::from_min_max(
+                    egui::pos2(left, lane.top() + 3.0),
+                    egui::pos2(right - 1.0, lane.bottom() - 3.0),
+                );
+                let colour = instrument_colour(note.instrument);
+                // Notes that have been played are dimmed.
+                let colour = if start + note.frames <= now {
+                    colour.gamma_multiply(0.4)
+                } else {
+                    colour
+                };
+                painter.rect_filled(bar, 2.0, colour);
+                if bar.width() >= MIN_LABEL_WIDTH && note.pitch >= 0 {
+                    painter.text(
+                        bar.left_center() + egui::vec2(3.0, 0.0),
+                        Align2::LEFT_CENTER,
+                        disasm::note_name((note.pitch / 4) as usize),
+                        font.clone(),
+                        Color32::BLACK,
+                    );
+                }
+            }
+        }
+        painter.vline(playhead_x, rect.y_range(), Stroke::new(2.0, Color32::WHITE));
+        ui.label(format!(
+            "{:.0}s before the playhead, {:.0}s after. Bars are coloured by instrument.",
+            PAST_S, AHEAD_S
+        ));
+    }
+}
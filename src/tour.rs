@@ -0,0 +1,183 @@
+//
+// Speedball 2 Sound player
+//
+// tour.rs: A guided tour for first-time users, walking through
+// choosing a bank, playing a sequence, the effects, and exporting a
+// .wav file. It sits in a window over the main UI, moving on by
+// itself when the user does what a step asks, or when they press
+// "Next".
+//
+// Once finished or skipped it's not shown again, which is recorded
+// by a marker file in the user's config directory.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+use std::path::PathBuf;
+
+use egui::{Align2, Context, Window};
+
+use crate::sound_player::Command;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Step {
+    ChooseBank,
+    PlaySequence,
+    Effects,
+    Export,
+    Done,
+}
+
+impl Step {
+    fn title(self) -> &'static str {
+        match self {
+            Step::ChooseBank => "1. Choosing a bank",
+            Step::PlaySequence => "2. Playing a sequence",
+            Step::Effects => "3. Effects",
+            Step::Export => "4. Exporting a .wav",
+            Step::Done => "Done",
+        }
+    }
+
+    fn text(self) -> &'static str {
+        match self {
+            Step::ChooseBank => {
+                "The game has two sound banks: \"intro\", with the title music, and \
+                 \"game\", with the in-game effects. The selector at the top left of \
+                 the synth controls switches between them. Banks can also be loaded \
+                 from archives, disk images or the game's executable, from the \
+                 command line (see --help)."
+            }
+            Step::PlaySequence => {
+                "Sequences are the tunes and effects, each played on one channel. \
+                 Open \"Sequences\" below, then one of the groups, and press \"Play\" \
+                 next to a sequence."
+            }
+            Step::Effects => {
+                "Each channel row has checkboxes for the tremolo and vibrato effects, \
+                 and for whether sequences repeat. Try turning them off and playing \
+                 the sequence again to hear what they add."
+            }
+            Step::Export => {
+                "To save a sound, set \"Output to\" to WaveFile, choose how long to \
+                 record for, then press \"Play\" on an item. You'll be asked where to \
+                 save the file. Set it back to Speakers afterwards."
+            }
+            Step::Done => {
+                "That's the basics. \"Help\" describes the bank format and the \
+                 sequence commands, and the README covers everything else. Press \
+                 \"Tour\" to see this again."
+            }
+        }
+    }
+
+    fn next(self) -> Step {
+        match self {
+            Step::ChooseBank => Step::PlaySequence,
+            Step::PlaySequence => Step::Effects,
+            Step::Effects => Step::Export,
+            Step::Export | Step::Done => Step::Done,
+        }
+    }
+
+    fn prev(self) -> Step {
+        match self {
+            Step::ChooseBank | Step::PlaySequence => Step::ChooseBank,
+            Step::Effects => Step::PlaySequence,
+            Step::Export => Step::Effects,
+            Step::Done => Step::Export,
+        }
+    }
+}
+
+pub struct Tour {
+    // None when the tour isn't showing.
+    step: Option<Step>,
+}
+
+fn marker_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("speedball2-sound-player").join("tour-done"))
+}
+
+impl Tour {
+    // Shows the tour if it hasn't been seen before.
+    pub fn new() -> Tour {
+        let seen = marker_path().is_some_and(|path| path.exists());
+        Tour {
+            step: (!seen).then_some(Step::ChooseBank),
+        }
+    }
+
+    pub fn start(&mut self) {
+        self.step = Some(Step::ChooseBank);
+    }
+
+    // Don't show the tour automatically again. Failing to record
+    // that just means it'll be shown next time too.
+    fn finish(&mut self) {
+        self.step = None;
+        if let Some(path) = marker_path() {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, "");
+        }
+    }
+
+    // Move on when the user does what the current step asks.
+    pub fn observe(&mut self, command: &Command, recording: bool) {
+        let done = match (self.step, command) {
+            (Some(Step::PlaySequence), Command::Sequence(_)) => true,
+            (Some(Step::Export), _) => recording,
+            _ => false,
+        };
+        if done {
+            self.step = self.step.map(Step::next);
+        }
+    }
+
+    // Bank switches happen in the synth, so we're told about them
+    // separately.
+    pub fn bank_changed(&mut self) {
+        if self.step == Some(Step::ChooseBank) {
+            self.step = Some(Step::PlaySequence);
+        }
+    }
+
+    pub fn ui(&mut self, ctx: &Context) {
+        let Some(step) = self.step else {
+            return;
+        };
+        let mut next_step = Some(step);
+        Window::new("Tour")
+            .anchor(Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .collapsible(false)
+            .resizable(false)
+            .default_width(320.0)
+            .show(ctx, |ui| {
+                ui.heading(step.title());
+                ui.label(step.text());
+                ui.horizontal(|ui| {
+                    if step != Step::ChooseBank && ui.button("Back").clicked() {
+                        next_step = Some(step.prev());
+                    }
+                    if step == Step::Done {
+                        if ui.button("Close").clicked() {
+                            next_step = None;
+                        }
+                    } else {
+                        if ui.button("Next").clicked() {
+                            next_step = Some(step.next());
+                        }
+                        if ui.button("Skip tour").clicked() {
+                            next_step = None;
+                        }
+                    }
+                });
+            });
+        match next_step {
+            Some(step) => self.step = Some(step),
+            None => self.finish(),
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//
+// Speedball 2 Sound player
+//
+// trace.rs: A log of every command the channels' sequences run, with
+// when, for following what the driver does. Each channel records into
+// its own fixed-size ring buffer, allocated when the audio starts, so
+// that the audio thread never allocates. The audio thread publishes
+// what's in the rings, and the GUI collects it into a scrolling
+// panel, filtered by channel and command, which can be saved as text.
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use std::collections::VecDeque;
+#[cfg(feature = "gui")]
+use std::fmt::Write;
+#[cfg(feature = "gui")]
+use std::sync::mpsc::Sender;
+
+#[cfg(feature = "gui")]
+use egui::{Color32, ScrollArea, Ui};
+#[cfg(feature = "gui")]
+use rfd::FileDialog;
+
+#[cfg(feature = "gui")]
+use crate::cpal_wrapper::Timed;
+#[cfg(feature = "gui")]
+use crate::disasm;
+use crate::driver::Op;
+#[cfg(feature = "gui")]
+use crate::quirks;
+#[cfg(feature = "gui")]
+use crate::sound_player::{Command, Published};
+
+// Commands each channel holds between the GUI collecting them. Enough
+// for a fast sequence over a few seconds.
+pub const RING_SIZE: usize = 2048;
+// Commands the panel keeps.
+#[cfg(feature = "gui")]
+const MAX_SHOWN: usize = 20_000;
+
+// Recorded whatever the build, but only the GUI reads them back.
+#[cfg_attr(not(feature = "gui"), allow(dead_code))]
+#[derive(Clone, Copy, Debug)]
+pub struct Event {
+    // Frames since the synth started.
+    pub frame: u64,
+    pub channel: usize,
+    pub addr: usize,
+    // None for a note.
+    pub op: Option<Op>,
+    // The note for notes, the operand byte for commands with one.
+    pub operand: u8,
+}
+
+#[derive(Clone)]
+pub struct Trace {
+    channel: usize,
+    enabled: bool,
+    ring: Vec<Event>,
+    // Where the next event goes, once the ring is full.
+    next: usize,
+    frame: u64,
+}
+
+impl Trace {
+    pub fn new(channel: usize) -> Trace {
+        Trace {
+            channel,
+            enabled: false,
+            ring: Vec::new(),
+            next: 0,
+            frame: 0,
+        }
+    }
+
+    // Called before the audio starts, as the ring mustn't be
+    // allocated from the audio thread.
+    #[cfg(feature = "audio")]
+    pub fn prepare(&mut self) {
+        self.ring.reserve_exact(RING_SIZE);
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    pub fn tick(&mut self) {
+        self.frame += 1;
+    }
+
+    pub fn record(&mut self, addr: usize, op: Option<Op>, operand: u8) {
+        if !self.enabled {
+            return;
+        }
+        let event = Event {
+            frame: self.frame,
+            channel: self.channel,
+            addr,
+            op,
+            operand,
+        };
+        if self.ring.len() < RING_SIZE {
+            self.ring.push(event);
+        } else {
+            self.ring[self.next] = event;
+            self.next = (self.next + 1) % RING_SIZE;
+        }
+    }
+
+    // Events waiting to be drained.
+    #[cfg(feature = "gui")]
+    pub fn pending(&self) -> usize {
+        self.ring.len()
+    }
+
+    // Move the events out, oldest first.
+    #[cfg(feature = "gui")]
+    pub fn drain_into(&mut self, out: &mut Vec<Event>) {
+        let (newer, older) = self.ring.split_at(self.next);
+        out.extend_from_slice(older);
+        out.extend_from_slice(newer);
+        self.ring.clear();
+        self.next = 0;
+    }
+}
+
+#[cfg(feature = "gui")]
+fn event_text(event: &Event, frame_rate: f32) -> String {
+    let command = match event.op {
+        Some(op) => disasm::command_text(op, Some(event.operand)),
+        None => disasm::note_name(event.operand as usize),
+    };
+    format!(
+        "{:9.2}s  ch {}  0x{:06x}  {}",
+        event.frame as f32 / frame_rate,
+        event.channel,
+        event.addr,
+        command
+    )
+}
+
+// Notes, then each command.
+#[cfg(feature = "gui")]
+const NUM_KINDS: usize = Op::ALL.len() + 1;
+
+#[cfg(feature = "gui")]
+fn kind(op: Option<Op>) -> usize {
+    op.map_or(0, |op| Op::ALL.iter().position(|&o| o == op).unwrap() + 1)
+}
+
+#[cfg(feature = "gui")]
+pub struct TraceView {
+    enabled: bool,
+    events: VecDeque<Event>,
+    // Channels 0-3, then the audition channel.
+    channels: [bool; 5],
+    kinds: [bool; NUM_KINDS],
+    // Of the bank's driver, for timestamps.
+    frame_rate: f32,
+    error: Option<String>,
+}
+
+#[cfg(feature = "gui")]
+impl TraceView {
+    pub fn new() -> TraceView {
+        TraceView {
+            enabled: false,
+            events: VecDeque::new(),
+            channels: [true; 5],
+            kinds: [true; NUM_KINDS],
+            frame_rate: quirks::SPEEDBALL2_PAL.frame_rate,
+            error: None,
+        }
+    }
+
+    fn is_shown(&self, event: &Event) -> bool {
+        self.channels.get(event.channel) == Some(&true) && self.kinds[kind(event.op)]
+    }
+
+    // Collect what's been published since the last call. Called
+    // every update, so the published events don't pile up while the
+    // panel is shut.
+    pub fn collect(&mut self, published: &mut Published) {
+        self.frame_rate = published.status().bank().quirks.frame_rate;
+        let incoming = &mut published.trace;
+        if !self.enabled {
+            incoming.clear();
+            return;
+        }
+        // Each channel's events are in order, but not across them.
+        incoming.sort_by_key(|event| (event.frame, event.channel));
+        self.events.extend(incoming.drain(..));
+        let excess = self.events.len().saturating_sub(MAX_SHOWN);
+        self.events.drain(..excess);
+    }
+
+    fn save(&mut self) {
+        let Some(path) = FileDialog::new()
+            .add_filter("Text", &["txt"])
+            .set_file_name("trace.txt")
+            .save_file()
+        else {
+            return;
+        };
+        let mut text = String::new();
+        for event in self.events.iter().filter(|event| self.is_shown(event)) {
+            let _ = writeln!(text, "{}", event_text(event, self.frame_rate));
+        }
+        self.error = std::fs::write(&path, text)
+            .map_err(|err| format!("{}: {}", path.display(), err))
+            .err();
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, commands: &Sender<Timed<Command>>) {
+        ui.horizontal(|ui| {
+            if ui.checkbox(&mut self.enabled, "Record").changed() {
+                let _ = commands.send(Timed::now(Command::SetTracing(self.enabled)));
+            }
+            if ui.button("Clear").clicked() {
+                self.events.clear();
+            }
+            if ui.button("Save...").clicked() {
+                self.save();
+            }
+            ui.label(format!("{} commands", self.events.len()));
+        });
+        if let Some(err) = &self.error {
+            ui.colored_label(Color32::RED, err);
+        }
+        ui.horizontal(|ui| {
+            ui.label("Channels:");
+            for (idx, shown) in self.channels.iter_mut().enumerate() {
+                let label = if idx < 4 {
+                    idx.to_string()
+                } else {
+                    "audition".to_string()
+                };
+                ui.checkbox(shown, label);
+            }
+        });
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Commands:");
+            ui.checkbox(&mut self.kinds[0], "Note");
+            for op in Op::ALL {
+                ui.checkbox(&mut self.kinds[kind(Some(op))], format!("{:?}", op));
+            }
+        });
+        ui.separator();
+        let shown = self
+            .events
+            .iter()
+            .filter(|event| self.is_shown(event))
+            .collect::<Vec<_>>();
+        let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+        ScrollArea::vertical()
+            .auto_shrink([false, false])
+            .stick_to_bottom(true)
+            .show_rows(ui, row_height, shown.len(), |ui, rows| {
+                for event in &shown[rows] {
+                    ui.monospace(event_text(event, self.frame_rate));
+                }
+            });
+    }
+}
@@ -0,0 +1,389 @@
+//
+// Speedball 2 Sound player
+//
+// tuner.rs: A reference tone, and a tuner showing the pitch a channel
+// is playing, for checking period tables and fine tuning by ear and
+// by meter. The synth keeps the last few thousand samples of the
+// channel being tuned in a ring, allocated when the audio starts,
+// and publishes them for the GUI, which copies them out and finds
+// their fundamental with a normalised autocorrelation (McLeod's
+// method, roughly).
+//
+// (C) Copyright 2023 Simon Frankau. All Rights Reserved, see LICENSE.
+//
+
+#[cfg(feature = "gui")]
+use std::sync::mpsc::Sender;
+#[cfg(feature = "gui")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "gui")]
+use egui::{Color32, DragValue, RichText, Slider, Ui};
+
+#[cfg(feature = "gui")]
+use crate::cpal_wrapper::Timed;
+#[cfg(feature = "gui")]
+use crate::disasm::NOTE_NAMES;
+#[cfg(feature = "gui")]
+use crate::sound_player::{Command, Published};
+
+pub const DEFAULT_A4_HZ: f32 = 440.0;
+// Samples kept for the tuner. Long enough for a few periods of the
+// lowest note we look for.
+#[cfg(feature = "audio")]
+pub const WINDOW: usize = 4096;
+// Range of fundamentals looked for.
+#[cfg(feature = "gui")]
+const MIN_HZ: f32 = 40.0;
+#[cfg(feature = "gui")]
+const MAX_HZ: f32 = 2000.0;
+// Mean square below which a channel is taken to be silent.
+#[cfg(feature = "gui")]
+const SILENCE: f32 = 1e-6;
+// Peaks within this fraction of the highest count as the period,
+// taking the shortest, so we don't lock onto multiples of it.
+#[cfg(feature = "gui")]
+const PEAK_THRESHOLD: f32 = 0.9;
+// Below this, there's no clear pitch to show.
+#[cfg(feature = "gui")]
+const MIN_CLARITY: f32 = 0.5;
+
+////////////////////////////////////////////////////////////////////////
+// Reference tone
+
+#[derive(Clone, PartialEq)]
+pub struct ReferenceTone {
+    pub enabled: bool,
+    pub freq_hz: f32,
+    pub level: f32,
+    // In cycles.
+    phase: f32,
+}
+
+impl ReferenceTone {
+    pub fn new() -> ReferenceTone {
+        ReferenceTone {
+            enabled: false,
+            freq_hz: DEFAULT_A4_HZ,
+            level: 0.25,
+            phase: 0.0,
+        }
+    }
+
+    // Take on another tone's settings, carrying on from the same
+    // phase, so there's no click.
+    #[cfg(feature = "gui")]
+    pub fn adjust(&mut self, tone: &ReferenceTone) {
+        self.enabled = tone.enabled;
+        self.freq_hz = tone.freq_hz;
+        self.level = tone.level;
+    }
+
+    // Add a sine wave to every output.
+    pub fn render(&mut self, sample_rate: u32, out: &mut [f32], num_outputs: usize) {
+        if !self.enabled {
+            return;
+        }
+        let step = self.freq_hz / sample_rate as f32;
+        for dsts in out.chunks_mut(num_outputs) {
+            let x = self.level * (2.0 * std::f32::consts::PI * self.phase).sin();
+            for dst in dsts.iter_mut() {
+                *dst += x;
+            }
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Capturing a channel
+
+#[derive(Clone)]
+pub struct Capture {
+    // Channels 0-3, or 4 for the audition channel.
+    channel: Option<usize>,
+    ring: Vec<f32>,
+    // Where the next sample goes.
+    pos: usize,
+    sample_rate: u32,
+}
+
+impl Capture {
+    pub fn new() -> Capture {
+        Capture {
+            channel: None,
+            ring: Vec::new(),
+            pos: 0,
+            sample_rate: 0,
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn channel(&self) -> Option<usize> {
+        self.channel
+    }
+
+    // Called before the audio starts, as the ring mustn't be
+    // allocated from the audio thread.
+    #[cfg(feature = "audio")]
+    pub fn prepare(&mut self) {
+        if self.ring.is_empty() {
+            self.ring = vec![0.0; WINDOW];
+        }
+    }
+
+    #[cfg(feature = "gui")]
+    pub fn set_channel(&mut self, channel: Option<usize>) {
+        if channel != self.channel {
+            self.ring.fill(0.0);
+        }
+        self.channel = channel;
+    }
+
+    // Keep the channel's output, if it's the one being tuned.
+    pub fn push(&mut self, channel: usize, sample_rate: u32, data: &[f32]) {
+        if self.channel != Some(channel) || self.ring.is_empty() {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        for &x in data.iter() {
+            self.ring[self.pos] = x;
+            self.pos = (self.pos + 1) % self.ring.len();
+        }
+    }
+
+    // The samples kept, oldest first, and their rate. Doesn't
+    // allocate if `out` has room for WINDOW samples.
+    #[cfg(feature = "gui")]
+    pub fn snapshot(&self, out: &mut Vec<f32>) -> u32 {
+        out.clear();
+        out.extend_from_slice(&self.ring[self.pos..]);
+        out.extend_from_slice(&self.ring[..self.pos]);
+        self.sample_rate
+    }
+}
+
+////////////////////////////////////////////////////////////////////////
+// Pitch detection
+
+// The fundamental of the samples, and how clearly periodic they are
+// (1.0 for exactly), if there's anything to go on.
+#[cfg(feature = "gui")]
+pub fn detect(samples: &[f32], sample_rate: u32) -> Option<(f32, f32)> {
+    let len = samples.len();
+    if len == 0 || sample_rate == 0 {
+        return None;
+    }
+    // Sums of squares of the first n samples, so the normalisation
+    // for each lag is cheap.
+    let mut squares = Vec::with_capacity(len + 1);
+    squares.push(0.0);
+    for x in samples.iter() {
+        squares.push(squares.last().unwrap() + x * x);
+    }
+    if squares[len] / (len as f32) < SILENCE {
+        return None;
+    }
+
+    let min_lag = ((sample_rate as f32 / MAX_HZ) as usize).max(1);
+    let max_lag = ((sample_rate as f32 / MIN_HZ) as usize).min(len / 2);
+    if min_lag + 2 > max_lag {
+        return None;
+    }
+    let nsdf = (0..=max_lag + 1)
+        .map(|lag| {
+            let acf = samples[..len - lag]
+                .iter()
+                .zip(samples[lag..].iter())
+                .map(|(a, b)| a * b)
+                .sum::<f32>();
+            let norm = squares[len - lag] + squares[len] - squares[lag];
+            if norm > 0.0 {
+                2.0 * acf / norm
+            } else {
+                0.0
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The peaks after the function first goes negative, one per
+    // positive stretch.
+    let start = nsdf.iter().position(|&x| x < 0.0)?.max(min_lag);
+    let mut peaks = Vec::new();
+    let mut best: Option<usize> = None;
+    for lag in start..=max_lag {
+        if nsdf[lag] > 0.0 {
+            if best.is_none_or(|b| nsdf[lag] > nsdf[b]) {
+                best = Some(lag);
+            }
+        } else if let Some(b) = best.take() {
+            peaks.push(b);
+        }
+    }
+    peaks.extend(best);
+    let highest = peaks.iter().map(|&lag| nsdf[lag]).fold(0.0, f32::max);
+    let lag = *peaks
+        .iter()
+        .find(|&&lag| nsdf[lag] >= PEAK_THRESHOLD * highest)?;
+    if nsdf[lag] < MIN_CLARITY {
+        return None;
+    }
+
+    // Fit a parabola through the peak, for a fractional period.
+    let (a, b, c) = (nsdf[lag - 1], nsdf[lag], nsdf[lag + 1]);
+    let denom = a - 2.0 * b + c;
+    let shift = if denom != 0.0 {
+        0.5 * (a - c) / denom
+    } else {
+        0.0
+    };
+    Some((sample_rate as f32 / (lag as f32 + shift), b))
+}
+
+// The nearest note to a frequency, in scientific pitch notation (A4
+// being the reference), and how many cents sharp of it we are.
+#[cfg(feature = "gui")]
+pub fn nearest_note(freq_hz: f32, a4_hz: f32) -> (String, f32) {
+    let midi = 69.0 + 12.0 * (freq_hz / a4_hz).log2();
+    let note = midi.round();
+    let name = NOTE_NAMES[(note as i32).rem_euclid(12) as usize];
+    let octave = (note as i32).div_euclid(12) - 1;
+    (format!("{}{}", name, octave), 100.0 * (midi - note))
+}
+
+////////////////////////////////////////////////////////////////////////
+// UI
+
+// Cents within which a note counts as in tune.
+#[cfg(feature = "gui")]
+const IN_TUNE_CENTS: f32 = 5.0;
+#[cfg(feature = "gui")]
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+
+#[cfg(feature = "gui")]
+pub struct TunerView {
+    // The GUI's copies of the reference tone and the channel tuned,
+    // sent to the synth when they change.
+    tone: ReferenceTone,
+    channel: Option<usize>,
+    samples: Vec<f32>,
+    sample_rate: u32,
+    // Whether samples have been collected since the last reading.
+    new_samples: bool,
+    reading: Option<(f32, f32)>,
+    last_update: Option<Instant>,
+}
+
+#[cfg(feature = "gui")]
+impl TunerView {
+    pub fn new() -> TunerView {
+        TunerView {
+            tone: ReferenceTone::new(),
+            channel: None,
+            samples: Vec::new(),
+            sample_rate: 0,
+            new_samples: false,
+            reading: None,
+            last_update: None,
+        }
+    }
+
+    // Copy out the samples published, every so often. Done while the
+    // GUI holds what's published, so the analysis is left for ui().
+    pub fn collect(&mut self, published: &Published) {
+        if self.channel.is_none()
+            || self
+                .last_update
+                .is_some_and(|last| last.elapsed() < UPDATE_INTERVAL)
+        {
+            return;
+        }
+        self.last_update = Some(Instant::now());
+        self.samples.clear();
+        self.samples.extend_from_slice(&published.tuner);
+        self.sample_rate = published.tuner_rate;
+        self.new_samples = true;
+    }
+
+    pub fn ui(&mut self, ui: &mut Ui, commands: &Sender<Timed<Command>>) {
+        let before = self.tone.clone();
+        let tone = &mut self.tone;
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut tone.enabled, "Reference tone");
+            ui.add(
+                DragValue::new(&mut tone.freq_hz)
+                    .clamp_range(400.0..=480.0)
+                    .speed(0.1)
+                    .suffix(" Hz"),
+            )
+            .on_hover_text("Concert A (A4), which the tuner also measures against");
+            ui.add(Slider::new(&mut tone.level, 0.0..=1.0).text("Level"));
+        });
+        if self.tone != before {
+            let _ = commands.send(Timed::now(Command::ReferenceTone(self.tone.clone())));
+        }
+        let a4_hz = self.tone.freq_hz;
+
+        let mut channel = self.channel;
+        let label = |channel: Option<usize>| match channel {
+            None => "nothing".to_string(),
+            Some(4) => "Audition".to_string(),
+            Some(idx) => format!("Ch {}", idx),
+        };
+        ui.horizontal(|ui| {
+            ui.label("Tune");
+            egui::ComboBox::from_id_source("TunerChannel")
+                .selected_text(label(channel))
+                .show_ui(ui, |ui| {
+                    for option in [None, Some(0), Some(1), Some(2), Some(3), Some(4)] {
+                        ui.selectable_value(&mut channel, option, label(option));
+                    }
+                });
+        });
+        if channel != self.channel {
+            self.channel = channel;
+            let _ = commands.send(Timed::now(Command::TunerChannel(channel)));
+            // What's published is still the old channel's until the
+            // synth has caught up, so wait a while before reading.
+            self.reading = None;
+            self.new_samples = false;
+            self.last_update = Some(Instant::now());
+        }
+        if channel.is_none() {
+            return;
+        }
+
+        if std::mem::take(&mut self.new_samples) {
+            self.reading = detect(&self.samples, self.sample_rate);
+        }
+        let Some((freq_hz, clarity)) = self.reading else {
+            ui.label(RichText::new("No clear pitch").size(24.0));
+            return;
+        };
+        let (note, cents) = nearest_note(freq_hz, a4_hz);
+        let colour = if cents.abs() <= IN_TUNE_CENTS {
+            Color32::GREEN
+        } else {
+            Color32::YELLOW
+        };
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(&note).size(32.0).strong());
+            ui.label(
+                RichText::new(format!("{:+.1} cents", cents))
+                    .size(24.0)
+                    .color(colour),
+            );
+        });
+        // A needle, from 50 cents flat to 50 cents sharp.
+        let (rect, _) = ui.allocate_exact_size(egui::vec2(200.0, 16.0), egui::Sense::hover());
+        let painter = ui.painter();
+        painter.rect_filled(rect, 2.0, Color32::from_gray(40));
+        painter.vline(rect.center().x, rect.y_range(), (1.0, Color32::GRAY));
+        let x = rect.center().x + rect.width() * cents / 100.0;
+        painter.vline(x, rect.y_range(), (3.0, colour));
+        ui.label(format!(
+            "{:.2} Hz (clarity {:.2}), A4 = {} Hz",
+            freq_hz, clarity, a4_hz
+        ));
+    }
+}